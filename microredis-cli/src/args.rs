@@ -0,0 +1,89 @@
+//! # Argument splitting
+//!
+//! Splits a line typed in the REPL into the arguments of a command, following the same rules as
+//! redis-cli: arguments are separated by spaces, double quoted arguments support escape sequences
+//! (`\n`, `\r`, `\t`, `\b`, `\a`, `\\`, `\"` and `\xHH`) and single quoted arguments only support
+//! `\'`.
+
+/// Splits a line into arguments. Unbalanced quotes return None.
+pub fn split(line: &str) -> Option<Vec<Vec<u8>>> {
+    let mut args = vec![];
+    let mut bytes = line.as_bytes().iter().copied().peekable();
+
+    loop {
+        while bytes.peek().map(|b| b.is_ascii_whitespace()) == Some(true) {
+            bytes.next();
+        }
+        let quote = match bytes.peek() {
+            None => return Some(args),
+            Some(b'"') | Some(b'\'') => bytes.next(),
+            _ => None,
+        };
+
+        let mut arg = vec![];
+        loop {
+            let byte = match (bytes.next(), quote) {
+                (None, Some(_)) => return None,
+                (None, None) => break,
+                (Some(b), None) if b.is_ascii_whitespace() => break,
+                (Some(b), Some(q)) if b == q => {
+                    // the closing quote must be followed by a space or the end of the line
+                    if bytes.peek().map(|b| !b.is_ascii_whitespace()) == Some(true) {
+                        return None;
+                    }
+                    break;
+                }
+                (Some(b'\\'), Some(b'"')) => match bytes.next()? {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'b' => 8,
+                    b'a' => 7,
+                    b'x' => {
+                        let hex = [bytes.next()?, bytes.next()?];
+                        u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+                    }
+                    other => other,
+                },
+                (Some(b'\\'), Some(b'\'')) if bytes.peek() == Some(&b'\'') => bytes.next()?,
+                (Some(b), _) => b,
+            };
+            arg.push(byte);
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn to_vec(args: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(to_vec(&["set", "foo", "bar"]), split("  set foo\tbar "));
+        assert_eq!(to_vec(&[]), split(""));
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(to_vec(&["set", "foo bar", ""]), split("set \"foo bar\" ''"));
+        assert_eq!(
+            to_vec(&["a\nb\"", "\x01"]),
+            split("\"a\\nb\\\"\" \"\\x01\"")
+        );
+        assert_eq!(to_vec(&["it's", "a\\n"]), split("'it\\'s' 'a\\n'"));
+        assert_eq!(Some(vec![vec![0xff]]), split("\"\\xff\""));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(None, split("set \"foo"));
+        assert_eq!(None, split("set 'foo"));
+        assert_eq!(None, split("set \"foo\"bar"));
+        assert_eq!(None, split("\"\\xzz\""));
+    }
+}
@@ -0,0 +1,223 @@
+//! # Connection
+//!
+//! Blocking connection to the server, over TCP or an unix socket, speaking RESP.
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+use redis_zero_protocol_parser::{parse, Value};
+
+/// Maximum nesting depth of the arrays of a reply. The parser recurses on each nested array, so
+/// deeper replies are rejected before parsing them, instead of overflowing the stack.
+const MAX_NESTING_DEPTH: usize = 128;
+
+/// Stream to the server
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Connection to the server
+pub struct Connection {
+    stream: Box<dyn Stream>,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    /// Connects to a server through TCP
+    pub fn tcp(host: &str, port: u16) -> io::Result<Self> {
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(Box::new(stream)))
+    }
+
+    /// Connects to a server through an unix socket
+    #[cfg(unix)]
+    pub fn unix(path: &str) -> io::Result<Self> {
+        Ok(Self::new(Box::new(UnixStream::connect(path)?)))
+    }
+
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream,
+            buffer: vec![],
+        }
+    }
+
+    /// Sends a command and waits for its reply. The reply is passed to a callback because it
+    /// borrows from the connection read buffer.
+    pub fn query<T, F: FnOnce(&Value) -> T>(&mut self, args: &[Vec<u8>], f: F) -> io::Result<T> {
+        self.stream.write_all(&encode(args))?;
+
+        let mut chunk = [0; 4096];
+        let mut scanner = ReplyScanner::default();
+        loop {
+            // the reply is only parsed once it has been read in full
+            if let Some(len) = scanner.scan(&self.buffer, MAX_NESTING_DEPTH)? {
+                let result = match parse(&self.buffer[..len]) {
+                    Ok((_, value)) => f(&value),
+                    Err(err) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("protocol error: {:?}", err),
+                        ))
+                    }
+                };
+                self.buffer.drain(..len);
+                return Ok(result);
+            }
+
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+/// Encodes a command as an array of blobs
+fn encode(args: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args.iter() {
+        bytes.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        bytes.extend_from_slice(arg);
+        bytes.extend_from_slice(b"\r\n");
+    }
+    bytes
+}
+
+/// Finds where the reply at the beginning of the buffer ends, without recursion, while the buffer
+/// is being filled. Each call resumes where the previous one stopped, so every byte of a reply is
+/// scanned once however many reads it takes. The arrays are checked not to be nested deeper than
+/// the maximum depth as they are read, before the reply is parsed.
+#[derive(Default)]
+struct ReplyScanner {
+    /// Offset where the next line starts
+    scanned: usize,
+    /// Offset up to which the next line was searched for its end
+    searched: usize,
+    /// Number of elements left to read of each open array
+    open: Vec<i64>,
+}
+
+impl ReplyScanner {
+    /// Scans the bytes added to the buffer since the last call, returning the length of the reply
+    /// once it is complete.
+    fn scan(&mut self, buffer: &[u8], max_depth: usize) -> io::Result<Option<usize>> {
+        loop {
+            let from = self.searched.max(self.scanned);
+            let end = match buffer[from..].iter().position(|b| *b == b'\n') {
+                Some(end) => from + end,
+                None => {
+                    self.searched = buffer.len();
+                    return Ok(None);
+                }
+            };
+            let line = &buffer[self.scanned..end];
+            let mut next = end + 1;
+            let number = std::str::from_utf8(line.get(1..).unwrap_or_default())
+                .ok()
+                .and_then(|n| n.trim_end_matches('\r').parse::<i64>().ok());
+
+            match (line.first(), number) {
+                (Some(b'*'), Some(len)) if len > 0 => {
+                    if self.open.len() >= max_depth {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "protocol error: too many nested arrays",
+                        ));
+                    }
+                    self.open.push(len);
+                    self.scanned = next;
+                    continue;
+                }
+                (Some(b'$'), Some(len)) if len >= 0 => {
+                    // the header is scanned again once the whole blob has been read
+                    next = next.saturating_add(len as usize).saturating_add(2);
+                    if next > buffer.len() {
+                        self.searched = end;
+                        return Ok(None);
+                    }
+                }
+                _ => {}
+            }
+            self.scanned = next;
+
+            // a value has been read, close the arrays it completes
+            loop {
+                match self.open.last_mut() {
+                    Some(left) if *left > 1 => {
+                        *left -= 1;
+                        break;
+                    }
+                    Some(_) => {
+                        self.open.pop();
+                    }
+                    None => {
+                        let len = self.scanned;
+                        *self = Self::default();
+                        return Ok(Some(len));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_command() {
+        assert_eq!(
+            b"*2\r\n$3\r\nget\r\n$0\r\n\r\n".to_vec(),
+            encode(&[b"get".to_vec(), vec![]])
+        );
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let check_depth = |bytes: &[u8], max_depth| ReplyScanner::default().scan(bytes, max_depth);
+        let nested = |depth: usize| {
+            let mut reply = b"*1\r\n".repeat(depth);
+            reply.extend_from_slice(b"$3\r\nfoo\r\n");
+            reply
+        };
+        assert_eq!(Some(nested(3).len()), check_depth(&nested(3), 3).unwrap());
+        assert!(check_depth(&nested(4), 3).is_err());
+        // incomplete replies are rejected as soon as they are too deep
+        assert!(check_depth(&b"*1\r\n".repeat(4), 3).is_err());
+        // siblings don't add up
+        assert!(check_depth(b"*2\r\n*1\r\n:1\r\n*1\r\n$2\r\n\r\n\r\n", 2).is_ok());
+        assert!(check_depth(b"*2\r\n*1\r\n:1\r\n*1\r\n*1\r\n:1\r\n", 2).is_err());
+
+        let deep = nested(100_000);
+        assert!(check_depth(&deep, MAX_NESTING_DEPTH).is_err());
+    }
+
+    #[test]
+    fn scan_in_chunks() {
+        let reply = b"*3\r\n$5\r\nhello\r\n*-1\r\n*2\r\n:1\r\n$0\r\n\r\n";
+        let mut input = reply.to_vec();
+        input.extend_from_slice(b"+next reply\r\n");
+
+        for chunk_len in 1..input.len() {
+            let mut scanner = ReplyScanner::default();
+            let mut buffer = vec![];
+            let mut found = None;
+            for chunk in input.chunks(chunk_len) {
+                buffer.extend_from_slice(chunk);
+                found = scanner.scan(&buffer, MAX_NESTING_DEPTH).unwrap();
+                if found.is_some() {
+                    break;
+                }
+            }
+            assert_eq!(Some(reply.len()), found, "chunks of {}", chunk_len);
+            assert!(parse(&buffer[..reply.len()]).is_ok());
+        }
+    }
+}
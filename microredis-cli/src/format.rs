@@ -0,0 +1,102 @@
+//! # Reply pretty-printing
+//!
+//! Formats replies the same way redis-cli does when its output is a terminal.
+use redis_zero_protocol_parser::Value;
+
+/// Formats a reply for humans
+pub fn reply(value: &Value) -> String {
+    match value {
+        Value::Array(values) if values.is_empty() => "(empty array)".to_owned(),
+        Value::Array(values) => {
+            let width = values.len().to_string().len();
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let prefix = format!("{:>width$}) ", i + 1, width = width);
+                    let padding = " ".repeat(prefix.len());
+                    reply(value)
+                        .lines()
+                        .enumerate()
+                        .map(|(n, line)| {
+                            if n == 0 {
+                                format!("{}{}", prefix, line)
+                            } else {
+                                format!("{}{}", padding, line)
+                            }
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        Value::Blob(bytes) => quote(bytes),
+        Value::String(string) => string.to_string(),
+        Value::Error(prefix, message) => format!("(error) {} {}", prefix, message),
+        Value::Integer(number) => format!("(integer) {}", number),
+        Value::BigInteger(number) => format!("(big number) {}", number),
+        Value::Float(number) => format!("(double) {}", number),
+        Value::Boolean(true) => "(true)".to_owned(),
+        Value::Boolean(false) => "(false)".to_owned(),
+        Value::Null => "(nil)".to_owned(),
+    }
+}
+
+/// Quotes binary data, escaping any non-printable byte
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::with_capacity(bytes.len() + 2);
+    quoted.push('"');
+    for byte in bytes.iter() {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            7 => quoted.push_str("\\a"),
+            8 => quoted.push_str("\\b"),
+            b' '..=b'~' => quoted.push(*byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scalars() {
+        assert_eq!("\"foo\"", reply(&Value::Blob(b"foo")));
+        assert_eq!("\"a\\nb\\x00\\\"\"", reply(&Value::Blob(b"a\nb\x00\"")));
+        assert_eq!("OK", reply(&Value::String("OK".into())));
+        assert_eq!(
+            "(error) ERR syntax error",
+            reply(&Value::Error("ERR".into(), "syntax error".into()))
+        );
+        assert_eq!("(integer) 10", reply(&Value::Integer(10)));
+        assert_eq!("(nil)", reply(&Value::Null));
+        assert_eq!("(true)", reply(&Value::Boolean(true)));
+        assert_eq!("(double) 1.5", reply(&Value::Float(1.5)));
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!("(empty array)", reply(&Value::Array(vec![])));
+        assert_eq!(
+            "1) \"foo\"\n2) 1) (integer) 1\n   2) (nil)\n3) (empty array)",
+            reply(&Value::Array(vec![
+                Value::Blob(b"foo"),
+                Value::Array(vec![Value::Integer(1), Value::Null]),
+                Value::Array(vec![]),
+            ]))
+        );
+        let values = (0..10).map(Value::Integer).collect();
+        let formatted = reply(&Value::Array(values));
+        assert!(formatted.starts_with(" 1) (integer) 0\n"));
+        assert!(formatted.ends_with("\n10) (integer) 9"));
+    }
+}
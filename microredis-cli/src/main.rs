@@ -0,0 +1,189 @@
+//! # microredis-cli
+//!
+//! Minimal command line client, to talk to microredis (or any redis server) without the real
+//! redis-cli. It either runs a single command given as arguments or starts an interactive REPL.
+#![deny(warnings)]
+#![deny(missing_docs)]
+
+mod args;
+mod connection;
+mod format;
+
+use connection::Connection;
+use rustyline::{error::ReadlineError, Editor};
+use std::{env, io, process};
+
+/// Where to connect
+#[derive(Debug, PartialEq)]
+enum Target {
+    /// TCP host and port
+    Tcp(String, u16),
+    /// Unix socket path
+    Unix(String),
+}
+
+impl Target {
+    fn connect(&self) -> io::Result<Connection> {
+        match self {
+            Self::Tcp(host, port) => Connection::tcp(host, *port),
+            #[cfg(unix)]
+            Self::Unix(path) => Connection::unix(path),
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    fn prompt(&self) -> String {
+        match self {
+            Self::Tcp(host, port) => format!("{}:{}> ", host, port),
+            Self::Unix(path) => format!("{}> ", path),
+        }
+    }
+}
+
+const USAGE: &str = "Usage: microredis-cli [-h host] [-p port] [-s socket] [cmd [arg ...]]";
+
+/// Parses the command line, returning where to connect and the command to run, if any
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<(Target, Vec<String>), String> {
+    let mut host = "127.0.0.1".to_owned();
+    let mut port = 6379;
+    let mut socket = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "-h" => host = value()?,
+            "-p" => port = value()?.parse().map_err(|_| "Invalid port".to_owned())?,
+            "-s" => socket = Some(value()?),
+            "--help" => return Err(USAGE.to_owned()),
+            _ => {
+                let command = std::iter::once(arg).chain(args).collect();
+                return Ok((target(host, port, socket), command));
+            }
+        }
+    }
+
+    Ok((target(host, port, socket), vec![]))
+}
+
+fn target(host: String, port: u16, socket: Option<String>) -> Target {
+    socket.map_or(Target::Tcp(host, port), Target::Unix)
+}
+
+/// Sends a command and prints its reply. The connection is dropped on error, so it is
+/// re-established on the next command.
+fn run(target: &Target, conn: &mut Option<Connection>, args: &[Vec<u8>]) -> io::Result<()> {
+    let result = match conn {
+        Some(conn) => conn.query(args, format::reply),
+        None => {
+            let mut new_conn = target.connect()?;
+            let result = new_conn.query(args, format::reply);
+            *conn = Some(new_conn);
+            result
+        }
+    };
+    match result {
+        Ok(reply) => {
+            println!("{}", reply);
+            Ok(())
+        }
+        Err(err) => {
+            *conn = None;
+            Err(err)
+        }
+    }
+}
+
+fn repl(target: &Target) {
+    let mut editor = Editor::<()>::new();
+    let mut conn = target.connect().ok();
+    if conn.is_none() {
+        eprintln!(
+            "Could not connect to {}",
+            target.prompt().trim_end_matches("> ")
+        );
+    }
+
+    loop {
+        let line = match editor.readline(&target.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        };
+        let args = match args::split(&line) {
+            Some(args) if args.is_empty() => continue,
+            Some(args) => args,
+            None => {
+                eprintln!("Invalid argument(s)");
+                continue;
+            }
+        };
+        editor.add_history_entry(line.as_str());
+
+        if args.len() == 1
+            && (args[0].eq_ignore_ascii_case(b"quit") || args[0].eq_ignore_ascii_case(b"exit"))
+        {
+            break;
+        }
+
+        if let Err(err) = run(target, &mut conn, &args) {
+            eprintln!("Error: {}", err);
+        }
+    }
+}
+
+fn main() {
+    let (target, command) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
+
+    if command.is_empty() {
+        return repl(&target);
+    }
+
+    let args = command
+        .into_iter()
+        .map(|arg| arg.into_bytes())
+        .collect::<Vec<Vec<u8>>>();
+    if let Err(err) = run(&target, &mut None, &args) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Target, Vec<String>), String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn command_line() {
+        assert_eq!(
+            Ok((Target::Tcp("127.0.0.1".to_owned(), 6379), vec![])),
+            parse(&[])
+        );
+        assert_eq!(
+            Ok((
+                Target::Tcp("::1".to_owned(), 7777),
+                vec!["get".to_owned(), "-p".to_owned()]
+            )),
+            parse(&["-h", "::1", "-p", "7777", "get", "-p"])
+        );
+        assert_eq!(
+            Ok((Target::Unix("/tmp/redis.sock".to_owned()), vec![])),
+            parse(&["-s", "/tmp/redis.sock"])
+        );
+        assert_eq!(Err("Missing value for -p".to_owned()), parse(&["-p"]));
+        assert_eq!(Err("Invalid port".to_owned()), parse(&["-p", "x"]));
+    }
+}
@@ -1,7 +1,12 @@
+//! # Deserializer
+//!
+//! Deserializes a configuration file into any type implementing `serde::Deserialize`. Every
+//! directive is a key of a map, and its arguments are deserialized as a single value, or as a
+//! sequence when there are several of them. `yes` and `no` are accepted as booleans.
 use crate::parser::{parse, ConfigValue, Error as ParsingError};
 use args::ArgsDeserializer;
 use serde::de::{self, IntoDeserializer};
-use std::str;
+use std::{fmt, str};
 use thiserror::Error as ThisError;
 
 mod args;
@@ -14,22 +19,61 @@ pub enum Error {
     #[error("Unexpected end of file")]
     UnexpectedEof(ErrorInfo),
 
+    /// There are no more directives to read
     #[error("End of stream")]
     EndOfStream,
 
     /// Custom errors
-    #[error("Custom error")]
+    #[error("{0}")]
     Custom(ErrorInfo),
 }
 
+/// Details of an error: its message, and where it happened
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ErrorInfo {
     line: Option<usize>,
     col: usize,
     at: Option<usize>,
+    directive: Option<String>,
     message: String,
 }
 
+impl ErrorInfo {
+    /// Line, starting at 1, of the directive with the error
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    /// Column, starting at 1, of the directive with the error
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    /// Name of the directive with the error
+    pub fn directive(&self) -> Option<&str> {
+        self.directive.as_deref()
+    }
+
+    /// Error message
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(directive)) = (self.line, &self.directive) {
+            write!(
+                f,
+                "line {}, column {}: invalid value for '{}': ",
+                line, self.col, directive
+            )?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Deserializes an instance of `T` from a string
 pub fn from_str<'de, T>(s: &'de str) -> Result<T, Error>
 where
     T: de::Deserialize<'de>,
@@ -37,6 +81,7 @@ where
     from_slice(s.as_bytes())
 }
 
+/// Deserializes an instance of `T` from a slice of bytes
 pub fn from_slice<'de, T>(bytes: &'de [u8]) -> Result<T, Error>
 where
     T: de::Deserialize<'de>,
@@ -49,32 +94,53 @@ where
 
 /// Deserialization implementation for Config protocol
 pub struct Deserializer<'a> {
+    full_input: &'a [u8],
     input: &'a [u8],
 }
 
 impl<'a> Deserializer<'a> {
+    /// Creates a new deserializer
     pub fn new(input: &'a [u8]) -> Self {
-        Self { input }
+        Self {
+            full_input: input,
+            input,
+        }
     }
 
+    /// Finishes the deserialization
     pub fn end(&mut self) -> Result<(), Error> {
         Ok(())
     }
 
-    /// Return the next value
+    /// Return the next value. Its offset is relative to the whole input.
     #[inline]
     pub fn parse_next(&mut self) -> Result<ConfigValue<'a>, Error> {
+        let consumed = self.full_input.len() - self.input.len();
         match parse(self.input) {
-            Ok((new_stream, value)) => {
+            Ok((new_stream, mut value)) => {
                 self.input = new_stream;
+                value.offset += consumed;
                 Ok(value)
             }
             Err(ParsingError::Partial) => Err(Error::EndOfStream),
         }
     }
+
+    /// Returns the line and column, both starting at 1, of an offset of the input
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.full_input[..offset.min(self.full_input.len())];
+        let line_start = before
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |pos| pos + 1);
+        (
+            before.iter().filter(|byte| **byte == b'\n').count() + 1,
+            offset - line_start + 1,
+        )
+    }
 }
 
-impl<'de, 'b> de::Deserializer<'de> for &'b mut Deserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
@@ -122,21 +188,42 @@ impl<'de, 'b> de::MapAccess<'de> for MapVisitor<'de, 'b> {
     where
         V: de::DeserializeSeed<'de>,
     {
+        let value = self.last_value.as_ref().unwrap();
         seed.deserialize(ArgsDeserializer {
-            input: self.last_value.as_ref().unwrap().args.clone(),
+            input: value.args.clone(),
+        })
+        .map_err(|err| {
+            let (line, col) = self.de.position(value.offset);
+            err.at_directive(&value.name, line, col)
         })
     }
 }
 
 impl Error {
+    /// Creates a custom error, `at` is the offset in the input where it happened, if known
     pub fn custom(at: Option<usize>, s: String) -> Self {
         Self::Custom(ErrorInfo {
             line: None,
             col: 0,
             at,
+            directive: None,
             message: s,
         })
     }
+
+    /// Attaches the directive being deserialized, and its position, to the error. Errors which
+    /// already have a position are not changed.
+    fn at_directive(self, directive: &str, line: usize, col: usize) -> Self {
+        match self {
+            Self::Custom(mut info) if info.line.is_none() => {
+                info.line = Some(line);
+                info.col = col;
+                info.directive = Some(directive.to_owned());
+                Self::Custom(info)
+            }
+            err => err,
+        }
+    }
 }
 
 impl de::Error for Error {
@@ -161,17 +248,18 @@ mod test {
     #[derive(Deserialize, Debug, Default)]
     pub struct SaveInfo(pub u64, pub u64);
 
-    #[derive(Deserialize_enum_str, Debug, PartialEq)]
+    #[derive(Deserialize_enum_str, Debug, PartialEq, Default)]
     pub enum AppendFsync {
         #[serde(rename = "always")]
         Always,
         #[serde(rename = "everysec")]
         EverySecond,
         #[serde(other, rename = "no")]
+        #[default]
         No,
     }
 
-    #[derive(Deserialize_enum_str, Debug, PartialEq)]
+    #[derive(Deserialize_enum_str, Debug, PartialEq, Default)]
     pub enum LogLevel {
         #[serde(rename = "debug")]
         Debug,
@@ -180,21 +268,10 @@ mod test {
         #[serde(rename = "notice")]
         Notice,
         #[serde(rename = "warning")]
+        #[default]
         Warning,
     }
 
-    impl Default for LogLevel {
-        fn default() -> Self {
-            Self::Warning
-        }
-    }
-
-    impl Default for AppendFsync {
-        fn default() -> Self {
-            Self::No
-        }
-    }
-
     #[derive(Deserialize, Debug, Default)]
     pub struct Config {
         #[serde(rename = "always-show-logo")]
@@ -228,6 +305,21 @@ mod test {
         assert_eq!(3, x.foo.len());
     }
 
+    #[test]
+    fn error_position() {
+        let err = from_str::<Foo>("foo 1 2\n# comment\n  bar 1000\n").unwrap_err();
+        let info = match &err {
+            Error::Custom(info) => info,
+            _ => unreachable!(),
+        };
+        assert_eq!(Some(3), info.line());
+        assert_eq!(3, info.col());
+        assert_eq!(Some("bar"), info.directive());
+        assert!(err
+            .to_string()
+            .starts_with("line 3, column 3: invalid value for 'bar': "));
+    }
+
     #[test]
     fn real_config() {
         let x: Config = from_str(
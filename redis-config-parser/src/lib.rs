@@ -1,3 +1,36 @@
+//! # Redis configuration parser
+//!
+//! Parses files in the `redis.conf` format: one directive per line, made of a name followed by
+//! its arguments. Lines starting with `#` are comments.
+//!
+//! The [`parser`] module is a zero-copy streaming parser which yields one directive at a time,
+//! while the [`de`] module deserializes a whole file into any type implementing
+//! `serde::Deserialize`:
+//!
+//! ```
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize, Debug)]
+//! struct Config {
+//!     port: u16,
+//!     bind: Vec<String>,
+//!     #[serde(rename = "appendonly")]
+//!     append_only: bool,
+//! }
+//!
+//! let config: Config =
+//!     redis_config_parser::from_str("port 6379\n# comment\nbind 127.0.0.1 ::1\nappendonly yes\n")
+//!         .unwrap();
+//!
+//! assert_eq!(6379, config.port);
+//! assert_eq!(vec!["127.0.0.1", "::1"], config.bind);
+//! assert!(config.append_only);
+//! ```
+//!
+//! Deserialization errors carry the line, column and directive of the invalid value.
 #![deny(warnings)]
+#![deny(missing_docs)]
 pub mod de;
 pub mod parser;
+
+pub use de::{from_slice, from_str, Error};
@@ -1,5 +1,10 @@
+//! # Streaming parser
+//!
+//! Parses one directive at a time. Names and arguments are borrowed from the input, they are only
+//! copied when they are not valid UTF-8.
 use std::borrow::Cow;
 
+/// Parsing errors
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Error {
     /// The data is incomplete. This it not an error per-se, but rather a
@@ -8,17 +13,26 @@ pub enum Error {
     Partial,
 }
 
+/// Arguments of a directive
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Args<'a> {
+    /// The directive has no arguments
     None,
+    /// The directive has a single argument
     Single(Cow<'a, str>),
+    /// The directive has two or more arguments
     Multiple(Vec<Cow<'a, str>>),
 }
 
+/// A directive, with its arguments
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ConfigValue<'a> {
+    /// Name of the directive
     pub name: Cow<'a, str>,
+    /// Arguments of the directive
     pub args: Args<'a>,
+    /// Position of the name within the parsed input
+    pub offset: usize,
 }
 
 macro_rules! skip {
@@ -57,8 +71,65 @@ macro_rules! read_until {
     }};
 }
 
-pub fn parse(bytes: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
-    let bytes = skip!(bytes, [b' ', b'\t', b'\r', b'\n']);
+/// Parses a quoted argument the way redis.conf quotes them, returning it along with the length of
+/// its quoted form. Double quoted arguments understand the `\n`, `\r`, `\t`, `\b`, `\a` and
+/// `\xHH` escapes, and a backslash followed by any other character is that character. Single
+/// quoted arguments only understand `\'`. The argument is borrowed unless it has escapes.
+fn parse_quoted(bytes: &'_ [u8]) -> Result<(Cow<'_, str>, usize), Error> {
+    let quote = bytes[0];
+    let mut unescaped = vec![];
+    let mut has_escapes = false;
+    let mut i = 1;
+
+    loop {
+        let byte = *bytes.get(i).ok_or(Error::Partial)?;
+        if byte == quote {
+            break;
+        }
+        let next = bytes.get(i + 1).copied();
+        let (byte, escape_len) = match (quote, byte, next) {
+            (b'"', b'\\', Some(b'x')) => {
+                let hex = bytes.get(i + 2..i + 4).ok_or(Error::Partial)?;
+                match std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => (byte, 4),
+                    None => (b'x', 2),
+                }
+            }
+            (b'"', b'\\', Some(escaped)) => (
+                match escaped {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'b' => 0x08,
+                    b'a' => 0x07,
+                    escaped => escaped,
+                },
+                2,
+            ),
+            (b'\'', b'\\', Some(b'\'')) => (b'\'', 2),
+            (_, b'\\', None) => return Err(Error::Partial),
+            (_, byte, _) => (byte, 1),
+        };
+        has_escapes |= escape_len > 1;
+        unescaped.push(byte);
+        i += escape_len;
+    }
+
+    let arg = if has_escapes {
+        Cow::Owned(String::from_utf8_lossy(&unescaped).into_owned())
+    } else {
+        String::from_utf8_lossy(&bytes[1..i])
+    };
+    Ok((arg, i + 1))
+}
+
+/// Parses the next directive, skipping blank lines and comments. The remaining input is returned
+/// along with the directive, or `Error::Partial` if there is no complete directive left.
+pub fn parse(input: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
+    let bytes = skip!(input, [b' ', b'\t', b'\r', b'\n']);
     let bytes = if bytes.first() == Some(&b'#') {
         // The entire line is a comment, skip the whole line
         let (bytes, _) = read_until!(bytes, [b'\n']);
@@ -66,6 +137,7 @@ pub fn parse(bytes: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
     } else {
         bytes
     };
+    let offset = input.len() - bytes.len();
     let (bytes, name) = read_until!(bytes, [b' ', b'\t', b'\r']);
     let bytes = skip!(bytes, [b' ', b'\t', b'\r']);
 
@@ -73,8 +145,6 @@ pub fn parse(bytes: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
     let len = bytes.len();
     let mut i = 0;
 
-    #[allow(unused_variables)]
-    #[allow(unreachable_patterns)]
     loop {
         if i >= len {
             return Err(Error::Partial);
@@ -91,22 +161,9 @@ pub fn parse(bytes: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
                 break;
             }
             b'"' | b'\'' => {
-                let e = i;
-                let stop_at = bytes[e];
-                i += 1;
-                loop {
-                    if i >= len {
-                        return Err(Error::Partial);
-                    }
-                    match bytes[i] {
-                        stop_at => {
-                            args.push(String::from_utf8_lossy(&bytes[e + 1..i]));
-                            break;
-                        }
-                        b'\\' => i += 1,
-                        _ => continue,
-                    };
-                }
+                let (arg, quoted_len) = parse_quoted(&bytes[i..])?;
+                args.push(arg);
+                i += quoted_len - 1;
             }
             _ => {
                 let e = i;
@@ -139,7 +196,7 @@ pub fn parse(bytes: &'_ [u8]) -> Result<(&'_ [u8], ConfigValue<'_>), Error> {
         _ => Args::Multiple(args),
     };
 
-    Ok((bytes, ConfigValue { name, args }))
+    Ok((bytes, ConfigValue { name, args, offset }))
 }
 
 #[cfg(test)]
@@ -161,6 +218,39 @@ mod test {
         assert_eq!(b"something", bytes);
     }
 
+    #[test]
+    fn test_parse_offset() {
+        let data = b"\n  # comment\n  foo bar\n";
+        let (_, config) = parse(data).unwrap();
+        assert_eq!("foo", config.name);
+        assert_eq!(15, config.offset);
+    }
+
+    #[test]
+    fn test_parse_quoted_arguments() {
+        let data = b"foo \"a b\" 'c d' '' \"\" bar\n";
+        let (_, config) = parse(data).unwrap();
+        assert_eq!(
+            Args::Multiple(vec![
+                "a b".into(),
+                "c d".into(),
+                "".into(),
+                "".into(),
+                "bar".into()
+            ]),
+            config.args
+        );
+
+        let data = b"foo \"say \\\"hi\\\"\\n\\x41\\\\\" 'it\\'s \\n'\n";
+        let (_, config) = parse(data).unwrap();
+        assert_eq!(
+            Args::Multiple(vec!["say \"hi\"\nA\\".into(), "it's \\n".into()]),
+            config.args
+        );
+
+        assert_eq!(Err(Error::Partial), parse(b"foo \"bar\n"));
+    }
+
     #[test]
     fn test_parse_multi_argument() {
         let data = b"foo bar something\r\n";
@@ -0,0 +1,295 @@
+//! # Audit log
+//!
+//! Records every write command, along with the client that sent it and when, to a separate sink:
+//! a file, rotated once it reaches a given size, or a pubsub channel. Commands are formatted like
+//! the MONITOR output.
+//!
+//! Recording a command only formats it and queues it, the sink is written by a background task so
+//! commands are never slowed down by the disk. The queue is bounded: if the sink falls behind by
+//! more than [`QUEUE_CAPACITY`] records, new records are dropped and counted instead of growing
+//! the memory without limit.
+use crate::connection::{connections::Connections, Connection};
+use bytes::Bytes;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+/// Where the audit records are written to
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditSink {
+    /// Append the records to a file. Once the file is bigger than `max_size` it is renamed to
+    /// `<path>.1`, replacing any previous one, and a new file is started.
+    File {
+        /// Path of the file
+        path: PathBuf,
+        /// Maximum size of the file, in bytes. Zero means it is never rotated.
+        max_size: usize,
+    },
+    /// Publish each record to a pubsub channel
+    Pubsub(Bytes),
+}
+
+impl AuditSink {
+    /// Parses the `audit-log` setting: `pubsub:<channel>` publishes to a channel, anything else
+    /// is the path of a file.
+    pub fn new(setting: &str, max_size: usize) -> Self {
+        match setting.strip_prefix("pubsub:") {
+            Some(channel) => Self::Pubsub(Bytes::copy_from_slice(channel.as_bytes())),
+            None => Self::File {
+                path: setting.into(),
+                max_size,
+            },
+        }
+    }
+}
+
+/// Number of records waiting to be written before new records are dropped
+pub const QUEUE_CAPACITY: usize = 10_000;
+
+/// Handle to record commands in the audit log
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    records: mpsc::Sender<Bytes>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    /// Creates a new audit log, returning the receiving side of the records which must be passed
+    /// to [`write_records`]
+    pub fn new() -> (Self, mpsc::Receiver<Bytes>) {
+        Self::with_capacity(QUEUE_CAPACITY)
+    }
+
+    /// Creates a new audit log queueing up to `capacity` records
+    fn with_capacity(capacity: usize) -> (Self, mpsc::Receiver<Bytes>) {
+        let (records, receiver) = mpsc::channel(capacity);
+        (
+            Self {
+                records,
+                dropped: Arc::new(AtomicU64::new(0)),
+            },
+            receiver,
+        )
+    }
+
+    /// Returns the number of records dropped because the sink was falling behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Records a command executed by a connection
+    pub fn record(&self, conn: &Connection, command: &str, args: &VecDeque<Bytes>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = format!(
+            "{}.{:06} [{} {}] id={} \"{}\"",
+            now.as_secs(),
+            now.subsec_micros(),
+            conn.current_db(),
+            conn.addr(),
+            conn.client_id(),
+            command.to_lowercase(),
+        );
+        for arg in args.iter() {
+            record.push_str(&format!(" \"{}\"", arg.escape_ascii()));
+        }
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.records.try_send(record.into()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Opens the audit file in append mode, returning it along with its current size
+async fn open_file(path: &PathBuf) -> Option<(File, usize)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| warn!("cannot open the audit log {:?}: {}", path, e))
+        .ok()?;
+    let size = file.metadata().await.map_or(0, |m| m.len() as usize);
+    Some((file, size))
+}
+
+/// Writes the audit records to their sink, until every [`AuditLog`] handle is dropped
+pub async fn write_records(
+    sink: AuditSink,
+    mut records: mpsc::Receiver<Bytes>,
+    all_connections: Arc<Connections>,
+) {
+    match sink {
+        AuditSink::Pubsub(channel) => {
+            let pubsub = all_connections.pubsub();
+            while let Some(record) = records.recv().await {
+                pubsub.publish(&channel, &record).await;
+            }
+        }
+        AuditSink::File { path, max_size } => {
+            let (mut file, mut size) = match open_file(&path).await {
+                Some(file) => file,
+                None => return,
+            };
+            let mut rotated = path.clone().into_os_string();
+            rotated.push(".1");
+
+            while let Some(record) = records.recv().await {
+                if max_size > 0 && size > 0 && size + record.len() + 1 > max_size {
+                    let _ = file.flush().await;
+                    if let Err(e) = fs::rename(&path, &rotated).await {
+                        warn!("cannot rotate the audit log {:?}: {}", path, e);
+                    }
+                    (file, size) = match open_file(&path).await {
+                        Some(file) => file,
+                        None => return,
+                    };
+                }
+                let written = file
+                    .write_all(&record)
+                    .await
+                    .and(file.write_all(b"\n").await);
+                if let Err(e) = written {
+                    warn!("cannot write to the audit log {:?}: {}", path, e);
+                }
+                size += record.len() + 1;
+            }
+            let _ = file.flush().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cmd::test::{
+            create_connection_and_pubsub, create_new_connection_from_connection, run_command,
+        },
+        value::Value,
+    };
+    use tokio::time::{sleep, Duration};
+
+    #[test]
+    fn parse_sink() {
+        assert_eq!(
+            AuditSink::Pubsub("audit".into()),
+            AuditSink::new("pubsub:audit", 10)
+        );
+        assert_eq!(
+            AuditSink::File {
+                path: "/tmp/audit.log".into(),
+                max_size: 10
+            },
+            AuditSink::new("/tmp/audit.log", 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_to_pubsub() {
+        let (mut recv, c) = create_connection_and_pubsub();
+        let all_connections = c.all_connections();
+        let (audit, records) = AuditLog::new();
+        all_connections.set_audit_log(Some(audit));
+        tokio::spawn(write_records(
+            AuditSink::new("pubsub:audit", 0),
+            records,
+            all_connections.clone(),
+        ));
+
+        let _ = run_command(&c, &["subscribe", "audit"]).await;
+        let _confirmation = recv.recv().await;
+
+        let c2 = create_new_connection_from_connection(&c).1;
+        let _ = run_command(&c2, &["set", "foo", "bar\n"]).await;
+        let _ = run_command(&c2, &["get", "foo"]).await;
+        let _ = run_command(&c2, &["del", "foo"]).await;
+
+        // records carry the id CLIENT ID and CLIENT LIST report
+        let id = match run_command(&c2, &["client", "id"]).await {
+            Ok(Value::Integer(id)) => id,
+            x => panic!("unexpected reply {:?}", x),
+        };
+        for expected in [
+            format!(" id={} \"set\" \"foo\" \"bar\\n\"", id),
+            format!(" id={} \"del\" \"foo\"", id),
+        ] {
+            match recv.recv().await {
+                Some(Value::Array(message)) => match &message[2] {
+                    Value::Blob(record) => {
+                        let record = String::from_utf8_lossy(record);
+                        assert!(record.contains("[0 127.0.0.1:8080]"), "{}", record);
+                        assert!(record.ends_with(&expected), "{}", record);
+                    }
+                    x => panic!("unexpected record {:?}", x),
+                },
+                x => panic!("unexpected message {:?}", x),
+            }
+        }
+        sleep(Duration::from_millis(10)).await;
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_records_when_full() {
+        let c = create_connection_and_pubsub().1;
+        let (audit, mut records) = AuditLog::with_capacity(2);
+        let args = VecDeque::from([Bytes::from("key")]);
+        for _ in 0..5 {
+            audit.record(&c, "DEL", &args);
+        }
+        assert_eq!(3, audit.dropped());
+
+        assert!(records.recv().await.is_some());
+        audit.record(&c, "DEL", &args);
+        assert_eq!(3, audit.dropped());
+    }
+
+    #[tokio::test]
+    async fn audit_file_rotation() {
+        let c = create_connection_and_pubsub().1;
+        let path =
+            std::env::temp_dir().join(format!("microredis-audit-{}.log", std::process::id()));
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+
+        let (audit, records) = AuditLog::new();
+        let writer = tokio::spawn(write_records(
+            AuditSink::new(path.to_str().unwrap(), 150),
+            records,
+            c.all_connections(),
+        ));
+        for i in 0..3 {
+            audit.record(
+                &c,
+                "SET",
+                &VecDeque::from([Bytes::from("key"), i.to_string().into()]),
+            );
+        }
+        drop(audit);
+        writer.await.unwrap();
+
+        let current = std::fs::read_to_string(&path).unwrap();
+        let previous = std::fs::read_to_string(&rotated).unwrap();
+        assert!(current.len() <= 150);
+        assert!(previous.len() <= 150);
+        assert!(current.ends_with("\"set\" \"key\" \"2\"\n"), "{}", current);
+        assert_eq!(3, current.lines().count() + previous.lines().count());
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+    }
+}
@@ -6,6 +6,7 @@ use crate::{
 };
 use bytes::Bytes;
 use std::{collections::VecDeque, sync::Arc};
+use tokio::time::Duration;
 
 /// "client" command handler
 ///
@@ -15,23 +16,8 @@ pub async fn client(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Valu
     let sub = args.pop_front().ok_or(Error::Syntax)?;
     let sub = String::from_utf8_lossy(&sub);
 
-    let expected = match sub.to_lowercase().as_str() {
-        "setname" => Some(1),
-        "unblock" => None,
-        _ => Some(0),
-    };
-
-    if let Some(expected) = expected {
-        if args.len() != expected {
-            return Err(Error::WrongArgument(
-                "client".to_owned(),
-                sub.to_uppercase(),
-            ));
-        }
-    }
-
     match sub.to_lowercase().as_str() {
-        "id" => Ok((conn.id() as i64).into()),
+        "id" => Ok((conn.client_id() as i64).into()),
         "info" => Ok(conn.to_string().into()),
         "getname" => Ok(conn.name().into()),
         "list" => {
@@ -51,7 +37,7 @@ pub async fn client(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Valu
             };
             let other_conn = match conn
                 .all_connections()
-                .get_by_conn_id(bytes_to_int(&args[0])?)
+                .get_by_client_id(bytes_to_int(&args[0])?)
             {
                 Some(conn) => conn,
                 None => return Ok(0.into()),
@@ -68,18 +54,108 @@ pub async fn client(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Valu
                 0.into()
             })
         }
+        "kill" => kill(conn, args),
+        "pause" => pause(conn, args),
+        "unpause" => {
+            conn.all_connections().unpause();
+            Ok(Value::Ok)
+        }
         "setname" => {
             let name = String::from_utf8_lossy(&args[0]).to_string();
             conn.set_name(name);
             Ok(Value::Ok)
         }
-        _ => Err(Error::WrongArgument(
+        _ => Err(Error::SubCommandNotFound(
+            sub.to_lowercase(),
             "client".to_owned(),
-            sub.to_uppercase(),
         )),
     }
 }
 
+/// CLIENT KILL, in both forms.
+///
+/// The old form takes only the address of the client and replies with OK or an error. The new
+/// form takes `ID`, `ADDR` and `SKIPME` filters and replies with the number of killed clients.
+/// Killed clients are closed even if they are blocked.
+fn kill(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let all_connections = conn.all_connections();
+
+    if args.len() == 1 {
+        let addr = String::from_utf8_lossy(&args[0]);
+        let mut target = None;
+        all_connections.iter(&mut |other: Arc<Connection>| {
+            if other.addr() == addr {
+                target = Some(other.id());
+            }
+        });
+        return match target {
+            Some(id) if all_connections.kill(id) => Ok(Value::Ok),
+            _ => Err(Error::NoSuchClient),
+        };
+    }
+
+    if args.len() % 2 == 1 {
+        return Err(Error::Syntax);
+    }
+
+    let mut id = None;
+    let mut addr = None;
+    let mut skip_me = true;
+    for filter in args.make_contiguous().chunks(2) {
+        match String::from_utf8_lossy(&filter[0]).to_uppercase().as_str() {
+            "ID" => id = Some(bytes_to_number::<u64>(&filter[1])?),
+            "ADDR" => addr = Some(String::from_utf8_lossy(&filter[1]).to_string()),
+            "SKIPME" => {
+                skip_me = match String::from_utf8_lossy(&filter[1]).to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(Error::Syntax),
+                }
+            }
+            _ => return Err(Error::Syntax),
+        }
+    }
+
+    let mut targets = vec![];
+    all_connections.iter(&mut |other: Arc<Connection>| {
+        if id.is_some_and(|id| id != other.client_id())
+            || addr.as_ref().is_some_and(|addr| addr != other.addr())
+            || (skip_me && other.id() == conn.id())
+        {
+            return;
+        }
+        targets.push(other.id());
+    });
+
+    Ok(targets
+        .into_iter()
+        .filter(|id| all_connections.kill(*id))
+        .count()
+        .into())
+}
+
+/// CLIENT PAUSE timeout [ALL]: no command is read from any client until the timeout, in
+/// milliseconds, expires or CLIENT UNPAUSE is called. Only the ALL mode, the default, is
+/// supported.
+fn pause(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let timeout: i64 = bytes_to_number(&args[0])?;
+    if timeout < 0 {
+        return Err(Error::NegativeNumber("timeout".to_owned()));
+    }
+    match args.get(1) {
+        _ if args.len() > 2 => return Err(Error::Syntax),
+        None => {}
+        Some(mode) if mode.eq_ignore_ascii_case(b"all") => {}
+        Some(mode) if mode.eq_ignore_ascii_case(b"write") => {
+            return Err(Error::UnsupportedOption("WRITE".to_owned()));
+        }
+        Some(_) => return Err(Error::Syntax),
+    }
+    conn.all_connections()
+        .pause(Duration::from_millis(timeout as u64));
+    Ok(Value::Ok)
+}
+
 /// "echo" command handler
 ///
 /// Documentation:
@@ -124,7 +200,11 @@ pub async fn reset(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error
 #[cfg(test)]
 mod test {
     use crate::{
-        cmd::test::{create_connection, run_command},
+        cmd::test::{
+            create_connection, create_connection_and_pubsub, create_new_connection_from_connection,
+            run_command,
+        },
+        connection::connections::ServerEvent,
         error::Error,
         value::Value,
     };
@@ -153,6 +233,83 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn client_pause() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["client", "pause", "10000"]).await
+        );
+        assert!(all_connections.paused_until().is_some());
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["client", "unpause"]).await);
+        assert!(all_connections.paused_until().is_none());
+
+        assert_eq!(
+            Err(Error::NegativeNumber("timeout".to_owned())),
+            run_command(&c, &["client", "pause", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::UnsupportedOption("WRITE".to_owned())),
+            run_command(&c, &["client", "pause", "10", "write"]).await
+        );
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["client", "pause", "10", "all", "all"]).await
+        );
+        assert!(all_connections.paused_until().is_none());
+    }
+
+    #[tokio::test]
+    async fn client_kill() {
+        let (_, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+        let mut events = c1.all_connections().subscribe_to_events();
+        let id1 = c1.client_id().to_string();
+        let id2 = c2.client_id().to_string();
+
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c1, &["client", "kill", "id", &id2]).await
+        );
+        assert_eq!(Ok(ServerEvent::Kill(c2.id())), events.try_recv());
+
+        // The current connection is skipped unless SKIPME is no
+        assert_eq!(
+            Ok(0.into()),
+            run_command(&c1, &["client", "kill", "id", &id1]).await
+        );
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c1, &["client", "kill", "id", &id1, "skipme", "no"]).await
+        );
+        assert_eq!(Ok(ServerEvent::Kill(c1.id())), events.try_recv());
+        assert_eq!(
+            Ok(0.into()),
+            run_command(&c1, &["client", "kill", "addr", "10.0.0.1:80"]).await
+        );
+
+        // Old form
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c1, &["client", "kill", "127.0.0.1:8080"]).await
+        );
+        assert_eq!(
+            Err(Error::NoSuchClient),
+            run_command(&c1, &["client", "kill", "10.0.0.1:80"]).await
+        );
+
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c1, &["client", "kill", "id", "1", "skipme"]).await
+        );
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c1, &["client", "kill", "foo", "1"]).await
+        );
+    }
+
     #[tokio::test]
     async fn select_err_0() {
         let c = create_connection();
@@ -175,13 +332,26 @@ mod test {
     async fn client_wrong_args() {
         let c = create_connection();
         assert_eq!(
-            Err(Error::WrongArgument("client".to_owned(), "ID".to_owned())),
+            Err(Error::SubCommandNotFound(
+                "id".to_owned(),
+                "client".to_owned()
+            )),
             run_command(&c, &["client", "id", "xxx"]).await
         );
         assert_eq!(
-            Err(Error::WrongArgument("client".to_owned(), "XXX".to_owned())),
+            Err(Error::SubCommandNotFound(
+                "xxx".to_owned(),
+                "client".to_owned()
+            )),
             run_command(&c, &["client", "xxx"]).await
         );
+        assert_eq!(
+            Err(Error::SubCommandNotFound(
+                "setname".to_owned(),
+                "client".to_owned()
+            )),
+            run_command(&c, &["client", "setname"]).await
+        );
     }
 
     #[tokio::test]
@@ -189,11 +359,38 @@ mod test {
         let c = create_connection();
         assert_eq!(Ok(1.into()), run_command(&c, &["client", "id"]).await);
         assert_eq!(
-            Ok("id=1 addr=127.0.0.1:8080 name=None db=0\r\n".into()),
+            Ok("id=1 addr=127.0.0.1:8080 name=None db=0 qbuf=0 qbuf-peak=0\r\n".into()),
             run_command(&c, &["client", "info"]).await
         );
     }
 
+    #[tokio::test]
+    async fn client_ids_are_never_reused() {
+        let c1 = create_connection();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+        assert_eq!(Ok(2.into()), run_command(&c2, &["client", "id"]).await);
+        c2.destroy();
+
+        let (_, c3) = create_new_connection_from_connection(&c1);
+        assert_eq!(Ok(3.into()), run_command(&c3, &["client", "id"]).await);
+
+        let all_connections = c1.all_connections();
+        assert!(all_connections.get_by_client_id(2).is_none());
+        assert_eq!(
+            Some(c3.id()),
+            all_connections.get_by_client_id(3).map(|conn| conn.id())
+        );
+        match run_command(&c1, &["client", "list"]).await {
+            Ok(Value::Blob(list)) => {
+                let list = String::from_utf8_lossy(&list);
+                assert!(list.contains("id=1 "));
+                assert!(!list.contains("id=2 "));
+                assert!(list.contains("id=3 "));
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
     #[tokio::test]
     async fn client_set_name() {
         let c = create_connection();
@@ -0,0 +1,295 @@
+//! # Differential testing
+//!
+//! Random sequences of commands, one data type at a time, are executed against microredis and a
+//! real redis-server and their replies are compared. Only built with the `differential-testing`
+//! feature, since it needs a redis-server binary: `REDIS_SERVER` sets its path (`redis-server`
+//! from the `PATH` by default) and `DIFFERENTIAL_CASES` the number of sequences per data type.
+//!
+//! Replies are compared after being encoded with the protocol, so `+OK` and a status string are
+//! the same thing. Errors are compared by their type (`ERR`, `WRONGTYPE`...) and not by their
+//! message, and the replies of the commands returning unordered collections are sorted.
+use super::test::{create_connection, run_command};
+use crate::value::Value;
+use bytes::BytesMut;
+use proptest::{
+    prelude::*,
+    test_runner::{Config, TestRunner},
+};
+use redis_zero_protocol_parser::{parse, Error as ParseError};
+use std::{
+    net::TcpListener as StdTcpListener,
+    process::{Child, Command, Stdio},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    runtime::Runtime,
+    sync::Mutex,
+    time::{sleep, Duration},
+};
+
+/// Commands whose reply is an unordered collection
+const UNORDERED: [&str; 7] = [
+    "smembers", "sinter", "sunion", "sdiff", "hkeys", "hvals", "keys",
+];
+
+/// A redis-server process, killed once dropped
+struct RedisServer {
+    process: Child,
+    port: u16,
+}
+
+impl RedisServer {
+    fn spawn() -> Self {
+        let port = StdTcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("free port")
+            .port();
+        let bin = std::env::var("REDIS_SERVER").unwrap_or_else(|_| "redis-server".to_owned());
+        let process = Command::new(&bin)
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("cannot spawn {}: {}", bin, e));
+        Self { process, port }
+    }
+
+    async fn connect(&self) -> RedisClient {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", self.port)).await {
+                return RedisClient {
+                    stream,
+                    buffer: BytesMut::new(),
+                };
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("redis-server is not listening on port {}", self.port);
+    }
+}
+
+impl Drop for RedisServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Connection to redis-server
+struct RedisClient {
+    stream: TcpStream,
+    buffer: BytesMut,
+}
+
+impl RedisClient {
+    /// Sends a command and reads its reply
+    async fn command(&mut self, cmd: &[String]) -> Value {
+        let request = Value::Array(cmd.iter().map(|arg| Value::new(arg.as_bytes())).collect());
+        let request: Vec<u8> = request.into();
+        self.stream
+            .write_all(&request)
+            .await
+            .expect("write to redis");
+        loop {
+            match parse(&self.buffer) {
+                Ok((rest, reply)) => {
+                    assert!(rest.is_empty(), "unexpected data from redis");
+                    let reply = (&reply).into();
+                    self.buffer.clear();
+                    return reply;
+                }
+                Err(ParseError::Partial) => {
+                    let read = self
+                        .stream
+                        .read_buf(&mut self.buffer)
+                        .await
+                        .expect("read from redis");
+                    assert!(read > 0, "redis-server closed the connection");
+                }
+                Err(e) => panic!("invalid reply from redis {:?}", e),
+            }
+        }
+    }
+}
+
+/// Brings a reply to a form that can be compared
+fn normalize(cmd: &[String], reply: Value) -> Value {
+    // encoded and parsed, as the replies from redis-server
+    let raw: Vec<u8> = reply.into();
+    let reply: Value = match parse(&raw) {
+        Ok((_, reply)) => (&reply).into(),
+        Err(e) => panic!("cannot parse {:?}", e),
+    };
+    match reply {
+        Value::Err(err_type, _) => Value::Err(err_type, "".to_owned()),
+        Value::Array(mut items) if UNORDERED.contains(&cmd[0].as_str()) => {
+            items.sort_by_key(|item| format!("{:?}", item));
+            Value::Array(items)
+        }
+        reply => reply,
+    }
+}
+
+/// Runs the command sequences generated by `strategy` against both servers
+fn check(strategy: impl Strategy<Value = Vec<Vec<String>>>) {
+    let cases = std::env::var("DIFFERENTIAL_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(64);
+    let runtime = Runtime::new().expect("runtime");
+    let server = RedisServer::spawn();
+    let redis = Mutex::new(runtime.block_on(server.connect()));
+    let mut runner = TestRunner::new(Config {
+        cases,
+        ..Config::default()
+    });
+
+    let result = runner.run(&strategy, |commands| {
+        runtime.block_on(async {
+            let mut redis = redis.lock().await;
+            redis.command(&["flushall".to_owned()]).await;
+            let conn = create_connection();
+            for cmd in commands.iter() {
+                let args = cmd.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+                let expected = redis.command(cmd).await;
+                let reply = run_command(&conn, &args).await.unwrap_or_else(|e| e.into());
+                prop_assert_eq!(normalize(cmd, expected), normalize(cmd, reply), "{:?}", cmd);
+            }
+            Ok(())
+        })
+    });
+
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}
+
+/// Builds a command out of its arguments
+fn cmd<T: AsRef<str>>(args: &[T]) -> Vec<String> {
+    args.iter().map(|arg| arg.as_ref().to_owned()).collect()
+}
+
+/// A small keyspace, so commands hit the same keys often
+fn key() -> impl Strategy<Value = String> {
+    prop_oneof![Just("a"), Just("b"), Just("c")].prop_map(str::to_owned)
+}
+
+/// Values, some of them numbers
+fn value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop_oneof![Just("x"), Just("y"), Just("z"), Just(""), Just("foo bar")]
+            .prop_map(str::to_owned),
+        number(),
+    ]
+}
+
+/// Small numbers, negatives included
+fn number() -> impl Strategy<Value = String> {
+    (-5i64..20).prop_map(|n| n.to_string())
+}
+
+/// Commands that work with any type
+fn keyspace_command() -> impl Strategy<Value = Vec<String>> {
+    prop_oneof![
+        key().prop_map(|k| cmd(&["del", &k])),
+        key().prop_map(|k| cmd(&["exists", &k])),
+        key().prop_map(|k| cmd(&["type", &k])),
+        (key(), key()).prop_map(|(k, d)| cmd(&["rename", &k, &d])),
+        Just(cmd(&["keys", "*"])),
+    ]
+}
+
+/// Sequences of commands
+fn sequence(
+    command: impl Strategy<Value = Vec<String>>,
+) -> impl Strategy<Value = Vec<Vec<String>>> {
+    prop::collection::vec(prop_oneof![4 => command, 1 => keyspace_command()], 1..30)
+}
+
+#[test]
+fn strings() {
+    check(sequence(prop_oneof![
+        (key(), value()).prop_map(|(k, v)| cmd(&["set", &k, &v])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["setnx", &k, &v])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["getset", &k, &v])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["append", &k, &v])),
+        key().prop_map(|k| cmd(&["get", &k])),
+        key().prop_map(|k| cmd(&["strlen", &k])),
+        key().prop_map(|k| cmd(&["incr", &k])),
+        key().prop_map(|k| cmd(&["decr", &k])),
+        (key(), number()).prop_map(|(k, n)| cmd(&["incrby", &k, &n])),
+        (key(), number()).prop_map(|(k, n)| cmd(&["decrby", &k, &n])),
+        (key(), number(), number()).prop_map(|(k, s, e)| cmd(&["getrange", &k, &s, &e])),
+        (key(), 0u32..10, value()).prop_map(|(k, o, v)| cmd(&["setrange", &k, &o.to_string(), &v])),
+        (key(), key()).prop_map(|(k1, k2)| cmd(&["mget", &k1, &k2])),
+        (key(), value(), key(), value())
+            .prop_map(|(k1, v1, k2, v2)| cmd(&["mset", &k1, &v1, &k2, &v2])),
+    ]));
+}
+
+#[test]
+fn lists() {
+    check(sequence(prop_oneof![
+        (key(), value()).prop_map(|(k, v)| cmd(&["lpush", &k, &v])),
+        (key(), value(), value()).prop_map(|(k, v1, v2)| cmd(&["rpush", &k, &v1, &v2])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["lpushx", &k, &v])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["rpushx", &k, &v])),
+        key().prop_map(|k| cmd(&["lpop", &k])),
+        key().prop_map(|k| cmd(&["rpop", &k])),
+        key().prop_map(|k| cmd(&["llen", &k])),
+        (key(), number(), number()).prop_map(|(k, s, e)| cmd(&["lrange", &k, &s, &e])),
+        (key(), number()).prop_map(|(k, i)| cmd(&["lindex", &k, &i])),
+        (key(), number(), value()).prop_map(|(k, i, v)| cmd(&["lset", &k, &i, &v])),
+        (key(), number(), value()).prop_map(|(k, n, v)| cmd(&["lrem", &k, &n, &v])),
+        (
+            key(),
+            prop_oneof![Just("before"), Just("after")],
+            value(),
+            value()
+        )
+            .prop_map(|(k, w, p, v)| cmd(&["linsert", &k, w, &p, &v])),
+        (key(), number(), number()).prop_map(|(k, s, e)| cmd(&["ltrim", &k, &s, &e])),
+        (key(), key()).prop_map(|(s, d)| cmd(&["rpoplpush", &s, &d])),
+    ]));
+}
+
+#[test]
+fn sets() {
+    check(sequence(prop_oneof![
+        (key(), value()).prop_map(|(k, v)| cmd(&["sadd", &k, &v])),
+        (key(), value(), value()).prop_map(|(k, v1, v2)| cmd(&["sadd", &k, &v1, &v2])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["srem", &k, &v])),
+        key().prop_map(|k| cmd(&["scard", &k])),
+        key().prop_map(|k| cmd(&["smembers", &k])),
+        (key(), value()).prop_map(|(k, v)| cmd(&["sismember", &k, &v])),
+        (key(), key()).prop_map(|(k1, k2)| cmd(&["sinter", &k1, &k2])),
+        (key(), key()).prop_map(|(k1, k2)| cmd(&["sunion", &k1, &k2])),
+        (key(), key()).prop_map(|(k1, k2)| cmd(&["sdiff", &k1, &k2])),
+        (key(), key(), key()).prop_map(|(d, k1, k2)| cmd(&["sinterstore", &d, &k1, &k2])),
+        (key(), key(), value()).prop_map(|(s, d, v)| cmd(&["smove", &s, &d, &v])),
+    ]));
+}
+
+#[test]
+fn hashes() {
+    check(sequence(prop_oneof![
+        (key(), value(), value()).prop_map(|(k, f, v)| cmd(&["hset", &k, &f, &v])),
+        (key(), value(), value()).prop_map(|(k, f, v)| cmd(&["hsetnx", &k, &f, &v])),
+        (key(), value()).prop_map(|(k, f)| cmd(&["hget", &k, &f])),
+        (key(), value(), value()).prop_map(|(k, f1, f2)| cmd(&["hmget", &k, &f1, &f2])),
+        (key(), value()).prop_map(|(k, f)| cmd(&["hdel", &k, &f])),
+        (key(), value()).prop_map(|(k, f)| cmd(&["hexists", &k, &f])),
+        (key(), value()).prop_map(|(k, f)| cmd(&["hstrlen", &k, &f])),
+        (key(), value(), number()).prop_map(|(k, f, n)| cmd(&["hincrby", &k, &f, &n])),
+        key().prop_map(|k| cmd(&["hlen", &k])),
+        key().prop_map(|k| cmd(&["hkeys", &k])),
+        key().prop_map(|k| cmd(&["hvals", &k])),
+    ]));
+}
@@ -1,24 +1,29 @@
 //! # Hash command handlers
 use crate::{
-    check_arg,
+    cmd::is_option,
     connection::Connection,
+    db::{
+        random::{sample, sample_len},
+        utils::Number,
+    },
     error::Error,
     value::{bytes_to_number, float::Float, Value},
 };
 use bytes::Bytes;
-use rand::Rng;
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque},
+    collections::{HashMap, VecDeque},
     ops::Deref,
 };
 
 /// Removes the specified fields from the hash stored at key. Specified fields that do not exist
 /// within this hash are ignored. If key does not exist, it is treated as an empty hash and this
 /// command returns 0.
+///
+/// The key is removed once its last field is deleted.
 pub async fn hdel(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let mut is_empty = false;
     let key = args.pop_front().ok_or(Error::Syntax)?;
-    let result = conn
+    let total = conn
         .db()
         .get(&key)
         .map_mut(|v| match v {
@@ -33,19 +38,19 @@ pub async fn hdel(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value,
 
                 is_empty = h.is_empty();
 
-                Ok(total.into())
+                Ok(total)
             }
             _ => Err(Error::WrongType),
         })
-        .unwrap_or(Ok(0.into()))?;
+        .unwrap_or(Ok(0))?;
 
     if is_empty {
         let _ = conn.db().del(&[key]);
-    } else {
+    } else if total > 0 {
         conn.db().bump_version(&key);
     }
 
-    Ok(result)
+    Ok(total.into())
 }
 
 /// Returns if field is an existing field in the hash stored at key.
@@ -102,11 +107,11 @@ pub async fn hgetall(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value,
 pub async fn hincrby_int(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let result = conn
         .db()
-        .hincrby::<i64>(&args[0], &args[1], &args[2], "an integer")?;
+        .hincrby(&args[0], &args[1], i64::parse(&args[2])?)?;
 
     conn.db().bump_version(&args[0]);
 
-    Ok(result)
+    Ok(result.into())
 }
 
 /// Increment the specified field of a hash stored at key, and representing a number, by the
@@ -116,11 +121,11 @@ pub async fn hincrby_int(conn: &Connection, args: VecDeque<Bytes>) -> Result<Val
 pub async fn hincrby_float(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let result = conn
         .db()
-        .hincrby::<Float>(&args[0], &args[1], &args[2], "a float")?;
+        .hincrby(&args[0], &args[1], Float::parse(&args[2])?)?;
 
     conn.db().bump_version(&args[0]);
 
-    Ok(result)
+    Ok(Value::Blob(result.to_string().into()))
 }
 
 /// Returns all field names in the hash stored at key.
@@ -177,13 +182,15 @@ pub async fn hmget(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
     Ok(r)
 }
 
-/// Returns random keys (or values) from a hash
+/// Returns random fields from a hash, along with their values with WITHVALUES. A positive count
+/// returns up to `count` distinct fields, a negative count returns exactly `-count` fields, which
+/// may repeat. Without count a single field is returned, or Null if the key does not exist.
 pub async fn hrandfield(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let (count, with_values) = match args.len() {
         1 => (None, false),
         2 => (Some(bytes_to_number::<i64>(&args[1])?), false),
         3 => {
-            if !(check_arg!(args, 2, "WITHVALUES")) {
+            if !is_option(&args, 2, "WITHVALUES") {
                 return Err(Error::Syntax);
             }
             (Some(bytes_to_number::<i64>(&args[1])?), true)
@@ -191,77 +198,70 @@ pub async fn hrandfield(conn: &Connection, args: VecDeque<Bytes>) -> Result<Valu
         _ => return Err(Error::InvalidArgsCount("hrandfield".to_owned())),
     };
 
-    let (count, single, repeat) = match count {
-        Some(count) if count > 0 => (count, false, 1),
-        Some(count) => (count.abs(), false, count.abs()),
-        _ => (1, true, 1),
-    };
+    if count == Some(i64::MIN) {
+        return Err(Error::NotANumberType("an integer".to_owned()));
+    }
 
     conn.db()
         .get(&args[0])
-        .inner()
-        .map(|v| match v.deref() {
+        .map(|v| match v {
             Value::Hash(h) => {
-                let mut ret = vec![];
-                let mut i = 0;
-                let mut rand_sorted = BTreeMap::new();
-                let mut rng = rand::thread_rng();
-
-                for _ in 0..repeat {
-                    for (key, value) in h.iter() {
-                        let rand = rng.gen::<u64>();
-                        rand_sorted.insert((rand, i), (key, value));
-                        i += 1;
-                    }
-                }
-
-                i = 0;
-                for val in rand_sorted.values() {
-                    if single {
-                        return Ok(Value::new(val.0));
-                    }
-
-                    if i == count {
-                        break;
-                    }
-
-                    ret.push(Value::new(val.0));
-
-                    if with_values {
-                        ret.push(Value::new(val.1));
-                    }
-
-                    i += 1;
+                let all_connections = conn.all_connections();
+                if let Some(count) = count {
+                    all_connections.check_reply_elements(sample_len(h.len(), count))?;
                 }
-
-                Ok(ret.into())
+                let fields = h.iter().collect::<Vec<_>>();
+                let mut rng = all_connections.rng();
+                Ok(match count {
+                    Some(count) => sample(fields, count, &mut rng)
+                        .into_iter()
+                        .flat_map(|(field, value)| {
+                            if with_values {
+                                vec![Value::new(field), Value::new(value)]
+                            } else {
+                                vec![Value::new(field)]
+                            }
+                        })
+                        .collect::<Vec<Value>>()
+                        .into(),
+                    None => sample(fields, 1, &mut rng)
+                        .first()
+                        .map_or(Value::Null, |(field, _)| Value::new(field)),
+                })
             }
             _ => Err(Error::WrongType),
         })
-        .unwrap_or(Ok(Value::Array(vec![])))
+        .unwrap_or(Ok(if count.is_none() {
+            Value::Null
+        } else {
+            Value::Array(vec![])
+        }))
 }
 
-/// Sets field in the hash stored at key to value. If key does not exist, a new key holding a hash
-/// is created. If field already exists in the hash, it is overwritten.
-pub async fn hmset(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+/// Sets the field-value pairs in the hash stored at key, creating it if needed. Returns the number
+/// of fields that were added, HSET and HMSET only differ in their reply.
+fn set_fields(conn: &Connection, cmd: &str, mut args: VecDeque<Bytes>) -> Result<i64, Error> {
     let key = args.pop_front().ok_or(Error::Syntax)?;
     if args.len() % 2 == 1 {
-        return Err(Error::InvalidArgsCount("hset".to_owned()));
+        return Err(Error::InvalidArgsCount(cmd.to_owned()));
     }
     let result = conn
         .db()
         .get(&key)
         .map_mut(|v| match v {
             Value::Hash(h) => {
+                let mut e: i64 = 0;
                 loop {
                     if args.is_empty() {
                         break;
                     }
                     let key = args.pop_front().ok_or(Error::Syntax)?;
                     let value = args.pop_front().ok_or(Error::Syntax)?;
-                    h.insert(key, value);
+                    if h.insert(key, value).is_none() {
+                        e += 1;
+                    }
                 }
-                Ok(Value::Ok)
+                Ok(e)
             }
             _ => Err(Error::WrongType),
         })
@@ -276,9 +276,9 @@ pub async fn hmset(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
                 let value = args.pop_front().ok_or(Error::Syntax)?;
                 h.insert(key, value);
             }
-            let _len = h.len();
+            let len = h.len() as i64;
             conn.db().set(key.clone(), h.into(), None);
-            Ok(Value::Ok)
+            Ok(len)
         })?;
 
     conn.db().bump_version(&key);
@@ -288,50 +288,19 @@ pub async fn hmset(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
 
 /// Sets field in the hash stored at key to value. If key does not exist, a new key holding a hash
 /// is created. If field already exists in the hash, it is overwritten.
-pub async fn hset(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let key = args.pop_front().ok_or(Error::Syntax)?;
-    if args.len() % 2 == 1 {
-        return Err(Error::InvalidArgsCount("hset".to_owned()));
-    }
-    let result = conn
-        .db()
-        .get(&key)
-        .map_mut(|v| match v {
-            Value::Hash(h) => {
-                let mut e: i64 = 0;
-                loop {
-                    if args.is_empty() {
-                        break;
-                    }
-                    let key = args.pop_front().ok_or(Error::Syntax)?;
-                    let value = args.pop_front().ok_or(Error::Syntax)?;
-                    if h.insert(key, value).is_none() {
-                        e += 1;
-                    }
-                }
-                Ok(e.into())
-            }
-            _ => Err(Error::WrongType),
-        })
-        .unwrap_or_else(|| {
-            #[allow(clippy::mutable_key_type)]
-            let mut h = HashMap::new();
-            loop {
-                if args.is_empty() {
-                    break;
-                }
-                let key = args.pop_front().ok_or(Error::Syntax)?;
-                let value = args.pop_front().ok_or(Error::Syntax)?;
-                h.insert(key, value);
-            }
-            let len = h.len();
-            conn.db().set(key.clone(), h.into(), None);
-            Ok(len.into())
-        })?;
-
-    conn.db().bump_version(&key);
+///
+/// Deprecated since Redis 4.0, HSET takes multiple field-value pairs and replies with the number
+/// of fields added instead of OK.
+pub async fn hmset(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    set_fields(conn, "hmset", args).map(|_| Value::Ok)
+}
 
-    Ok(result)
+/// Sets field in the hash stored at key to value. If key does not exist, a new key holding a hash
+/// is created. If field already exists in the hash, it is overwritten.
+///
+/// Returns the number of fields that were added.
+pub async fn hset(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    set_fields(conn, "hset", args).map(Value::from)
 }
 
 /// Sets field in the hash stored at key to value, only if field does not yet exist. If key does
@@ -411,7 +380,8 @@ pub async fn hvals(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Er
 mod test {
     use crate::{
         cmd::test::{create_connection, invalid_type, run_command},
-        value::Value,
+        error::Error,
+        value::{Protocol, Value},
     };
 
     #[tokio::test]
@@ -446,6 +416,28 @@ mod test {
         };
     }
 
+    #[tokio::test]
+    async fn hgetall_resp3() {
+        let c = create_connection();
+        c.set_protocol(Protocol::Resp3);
+        let _ = run_command(&c, &["hset", "foo", "f1", "1"]).await;
+
+        assert_eq!(
+            Ok(Value::MapReply(vec!["f1".into(), "1".into()])),
+            run_command(&c, &["hgetall", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::MapReply(vec![])),
+            run_command(&c, &["hgetall", "bar"]).await
+        );
+
+        c.set_protocol(Protocol::Resp2);
+        assert_eq!(
+            Ok(Value::Array(vec!["f1".into(), "1".into()])),
+            run_command(&c, &["hgetall", "foo"]).await
+        );
+    }
+
     #[tokio::test]
     async fn hrandfield() {
         let c = create_connection();
@@ -463,6 +455,63 @@ mod test {
         };
     }
 
+    #[tokio::test]
+    async fn hrandfield_count() {
+        let c = create_connection();
+        let _ = run_command(&c, &["hset", "foo", "f1", "1", "f2", "2", "f3", "3"]).await;
+
+        let fields = |r: Result<Value, Error>| match r {
+            Ok(Value::Array(fields)) => fields,
+            r => panic!("Unexpected reply {:?}", r),
+        };
+        let mut picked = fields(run_command(&c, &["hrandfield", "foo", "5"]).await);
+        picked.sort_by_key(|field| format!("{:?}", field));
+        assert_eq!(vec![Value::from("f1"), "f2".into(), "f3".into()], picked);
+
+        let picked = fields(run_command(&c, &["hrandfield", "foo", "-10"]).await);
+        assert_eq!(10, picked.len());
+
+        let picked = fields(run_command(&c, &["hrandfield", "foo", "-4", "withvalues"]).await);
+        assert_eq!(8, picked.len());
+        for pair in picked.chunks(2) {
+            let field = match &pair[0] {
+                Value::Blob(field) => String::from_utf8_lossy(field).to_string(),
+                field => panic!("Unexpected field {:?}", field),
+            };
+            assert_eq!(
+                Ok(pair[1].clone()),
+                run_command(&c, &["hget", "foo", &field]).await
+            );
+        }
+
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["hrandfield", "foo", "0"]).await
+        );
+        assert_eq!(
+            Ok(Value::Null),
+            run_command(&c, &["hrandfield", "bar"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["hrandfield", "bar", "-3"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumberType("an integer".to_owned())),
+            run_command(&c, &["hrandfield", "foo", "-9223372036854775808"]).await
+        );
+
+        c.all_connections().set_max_reply_elements(3);
+        assert_eq!(
+            Err(Error::ReplyTooLarge(3)),
+            run_command(&c, &["hrandfield", "foo", "-1000000000"]).await
+        );
+        assert!(matches!(
+            run_command(&c, &["hrandfield", "foo", "-3", "withvalues"]).await,
+            Ok(Value::Array(x)) if x.len() == 6
+        ));
+    }
+
     #[tokio::test]
     async fn hmget() {
         let c = create_connection();
@@ -525,6 +574,123 @@ mod test {
         assert_eq!(Ok(Value::Integer(5)), r);
     }
 
+    #[tokio::test]
+    async fn hdel_multiple_fields() {
+        let c = create_connection();
+        let r = run_command(&c, &["hset", "foo", "f1", "1", "f2", "2", "f3", "3"]).await;
+        assert_eq!(Ok(Value::Integer(3)), r);
+
+        let r = run_command(&c, &["hdel", "foo", "f1", "f1", "f2", "f4"]).await;
+        assert_eq!(Ok(Value::Integer(2)), r);
+
+        let r = run_command(&c, &["hdel", "foo", "f3", "f4"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+
+        let r = run_command(&c, &["hdel", "foo", "f3"]).await;
+        assert_eq!(Ok(Value::Integer(0)), r);
+    }
+
+    #[tokio::test]
+    async fn no_op_writes_keep_watched_keys() {
+        let c = create_connection();
+        let r = run_command(&c, &["hset", "foo", "f1", "1", "f2", "2"]).await;
+        assert_eq!(Ok(Value::Integer(2)), r);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["watch", "foo"]).await);
+        let r = run_command(&c, &["hsetnx", "foo", "f1", "2"]).await;
+        assert_eq!(Ok(Value::Integer(0)), r);
+        let r = run_command(&c, &["hdel", "foo", "f3"]).await;
+        assert_eq!(Ok(Value::Integer(0)), r);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["hget", "foo", "f1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![Value::Blob("1".into())])),
+            run_command(&c, &["exec"]).await
+        );
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["watch", "foo"]).await);
+        let r = run_command(&c, &["hsetnx", "foo", "f3", "3"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["hget", "foo", "f1"]).await
+        );
+        assert_eq!(Ok(Value::Null), run_command(&c, &["exec"]).await);
+    }
+
+    #[tokio::test]
+    async fn type_exists_and_ttl() {
+        let c = create_connection();
+        let r = run_command(&c, &["hset", "foo", "f1", "1", "f2", "2"]).await;
+        assert_eq!(Ok(Value::Integer(2)), r);
+        assert_eq!(
+            Ok(Value::Blob("hash".into())),
+            run_command(&c, &["type", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["expire", "foo", "100"]).await
+        );
+
+        // changing the fields keeps the expiration
+        let r = run_command(&c, &["hset", "foo", "f3", "3"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+        let r = run_command(&c, &["hsetnx", "foo", "f4", "4"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+        let r = run_command(&c, &["hincrby", "foo", "f1", "1"]).await;
+        assert_eq!(Ok(Value::Integer(2)), r);
+        let r = run_command(&c, &["hdel", "foo", "f1", "f2", "f3"]).await;
+        assert_eq!(Ok(Value::Integer(3)), r);
+        assert_eq!(
+            Ok(Value::Integer(100)),
+            run_command(&c, &["ttl", "foo"]).await
+        );
+
+        // deleting the last field removes the key
+        let r = run_command(&c, &["hdel", "foo", "f4"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("none".into())),
+            run_command(&c, &["type", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(-2)),
+            run_command(&c, &["ttl", "foo"]).await
+        );
+
+        // a new hash does not inherit the expiration
+        let r = run_command(&c, &["hsetnx", "foo", "f1", "1"]).await;
+        assert_eq!(Ok(Value::Integer(1)), r);
+        assert_eq!(
+            Ok(Value::Integer(-1)),
+            run_command(&c, &["ttl", "foo"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn hmset() {
+        let c = create_connection();
+        let r = run_command(&c, &["hmset", "foo", "f1", "1", "f2", "2"]).await;
+        assert_eq!(Ok(Value::Ok), r);
+
+        let r = run_command(&c, &["hmset", "foo", "f1", "2", "f3", "3"]).await;
+        assert_eq!(Ok(Value::Ok), r);
+
+        let r = run_command(&c, &["hmset", "foo", "f1", "2", "f3"]).await;
+        assert_eq!(Err(Error::InvalidArgsCount("hmset".to_owned())), r);
+
+        let r = run_command(&c, &["hlen", "foo"]).await;
+        assert_eq!(Ok(Value::Integer(3)), r);
+    }
+
     #[tokio::test]
     async fn hkeys() {
         let c = create_connection();
@@ -587,6 +753,28 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn hincrbyfloat() {
+        let c = create_connection();
+
+        assert_eq!(
+            Ok(Value::Blob("10.5".into())),
+            run_command(&c, &["hincrbyfloat", "foo", "f1", "10.5"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("10.6".into())),
+            run_command(&c, &["hincrbyfloat", "foo", "f1", "0.1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("5011".into())),
+            run_command(&c, &["hincrbyfloat", "foo", "f1", "5000.4"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("5011".into())),
+            run_command(&c, &["hget", "foo", "f1"]).await
+        );
+    }
+
     #[tokio::test]
     async fn hsetnx() {
         let c = create_connection();
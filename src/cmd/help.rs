@@ -38,8 +38,12 @@ pub fn command() -> Result<Value, Error> {
         "\tReturn details about all Redis commands",
         "COUNT",
         "\tReturn the total number of commands in this Redis server.",
+        "DOCS [<command-name> ...]",
+        "\tReturn documentation details about multiple Redis commands.",
         "GETKEYS <full-command>",
         "\tReturn the keys from a full Redis command.",
+        "GETKEYSANDFLAGS <full-command>",
+        "\tReturn the keys and the access flags from a full Redis command.",
         "INFO [<command-name> ...]",
         "Return details about multiple Redis commands.",
         "HELP",
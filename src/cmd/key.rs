@@ -1,9 +1,8 @@
 //! # Key-related command handlers
-use super::now;
+use super::{is_option, now, parse_option};
 use crate::{
-    check_arg,
     connection::Connection,
-    db::scan::Scan,
+    db::{scan::Scan, utils::ExpirationOpts},
     error::Error,
     value::{
         bytes_to_int, bytes_to_number, cursor::Cursor, expiration::Expiration, typ::Typ, Value,
@@ -11,7 +10,7 @@ use crate::{
 };
 use bytes::Bytes;
 use std::{collections::VecDeque, convert::TryInto, str::FromStr};
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 
 /// This command copies the value stored at the source key to the destination
 /// key.
@@ -25,7 +24,7 @@ use tokio::time::Instant;
 pub async fn copy(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let source = args.pop_front().ok_or(Error::Syntax)?;
     let destination = args.pop_front().ok_or(Error::Syntax)?;
-    let target_db = if !args.is_empty() && check_arg!(args, 0, "DB") {
+    let target_db = if is_option(&args, 0, "DB") {
         let _ = args.pop_front();
         let db = args.pop_front().ok_or(Error::Syntax)?;
         Some(
@@ -37,17 +36,8 @@ pub async fn copy(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value,
     } else {
         None
     };
-    let replace = match args
-        .pop_front()
-        .map(|m| String::from_utf8_lossy(&m).to_uppercase())
-    {
-        Some(value) => {
-            if value == "REPLACE" {
-                true
-            } else {
-                return Err(Error::Syntax);
-            }
-        }
+    let replace = match args.pop_front() {
+        Some(arg) => parse_option(&arg, &[("REPLACE", true)])?,
         None => false,
     };
     let result = if conn
@@ -74,26 +64,35 @@ pub async fn exists(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
     Ok(conn.db().exists(&keys).into())
 }
 
+/// Shared implementation of EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT.
+///
+/// The NX, XX, GT and LT options are validated before the expiration time, as Redis does. An
+/// expiration in the past deletes the key, but only if the options allow to change its TTL.
 async fn expire_ex(
     command: &[u8],
     is_milliseconds: bool,
+    is_absolute: bool,
     conn: &Connection,
     mut args: VecDeque<Bytes>,
 ) -> Result<Value, Error> {
     let key = args.pop_front().ok_or(Error::Syntax)?;
     let expiration = args.pop_front().ok_or(Error::Syntax)?;
+    let opts: ExpirationOpts = args.into_iter().collect::<Vec<_>>().try_into()?;
 
-    let expires_at = Expiration::new(&expiration, is_milliseconds, false, command)?;
+    let expires_at = Expiration::new(&expiration, is_milliseconds, is_absolute, command)?;
+    let is_negative = expires_at.is_negative;
+    let expires_in = if is_negative {
+        Duration::ZERO
+    } else {
+        expires_at.try_into()?
+    };
 
-    if expires_at.is_negative {
+    let result = conn.db().set_ttl(&key, expires_in, opts)?;
+    if is_negative && result == Value::Integer(1) {
         // Delete key right away
-        return Ok(conn.db().del(&[key]));
+        conn.db().del(&[key]);
     }
-
-    let opts = args.into_iter().collect::<Vec<_>>();
-
-    conn.db()
-        .set_ttl(&key, expires_at.try_into()?, opts.try_into()?)
+    Ok(result)
 }
 
 /// Set a timeout on key. After the timeout has expired, the key will
@@ -123,13 +122,13 @@ async fn expire_ex(
 /// EXPIREAT/PEXPIREAT with a time in the past will result in the key being
 /// deleted rather than expired
 pub async fn expire(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    expire_ex(b"EXPIRE", false, conn, args).await
+    expire_ex(b"EXPIRE", false, false, conn, args).await
 }
 
 /// This command works exactly like EXPIRE but the time to live of the key is
 /// specified in milliseconds instead of seconds.
 pub async fn pexpire(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    expire_ex(b"PEXPIRE", true, conn, args).await
+    expire_ex(b"PEXPIRE", true, false, conn, args).await
 }
 
 /// Returns the string representation of the type of the value stored at key.
@@ -142,72 +141,40 @@ pub async fn data_type(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value
 /// EXPIREAT has the same effect and semantic as EXPIRE, but instead of specifying the number of
 /// seconds representing the TTL (time to live), it takes an absolute Unix timestamp (seconds since
 /// January 1, 1970). A timestamp in the past will delete the key immediately.
-pub async fn expire_at(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let key = args.pop_front().ok_or(Error::Syntax)?;
-    let expiration = args.pop_front().ok_or(Error::Syntax)?;
-    let expires_at = Expiration::new(&expiration, false, true, b"EXPIREAT")?;
-
-    if expires_at.is_negative {
-        // Delete key right away
-        return Ok(conn.db().del(&[key]));
-    }
-
-    conn.db().set_ttl(
-        &key,
-        expires_at.try_into()?,
-        args.into_iter().collect::<Vec<_>>().try_into()?,
-    )
+pub async fn expire_at(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    expire_ex(b"EXPIREAT", false, true, conn, args).await
 }
 
 /// PEXPIREAT has the same effect and semantic as EXPIREAT, but the Unix time at
 /// which the key will expire is specified in milliseconds instead of seconds.
-pub async fn pexpire_at(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let key = args.pop_front().ok_or(Error::Syntax)?;
-    let expiration = args.pop_front().ok_or(Error::Syntax)?;
-    let expires_at = Expiration::new(&expiration, true, true, b"PEXPIREAT")?;
-
-    if expires_at.is_negative {
-        // Delete key right away
-        return Ok(conn.db().del(&[key]));
-    }
-
-    conn.db().set_ttl(
-        &key,
-        expires_at.try_into()?,
-        args.into_iter().collect::<Vec<_>>().try_into()?,
-    )
+pub async fn pexpire_at(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    expire_ex(b"PEXPIREAT", true, true, conn, args).await
 }
 
 /// PEXPIRETIME has the same semantic as EXPIRETIME, but returns the absolute
 /// Unix expiration timestamp in milliseconds instead of seconds.
 pub async fn p_expire_time(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let ttl = match conn.db().ttl(&args[0]) {
-        Some(Some(ttl)) => {
-            // Is there a better way? There should be!
-            let secs: i64 = (ttl - Instant::now()).as_millis() as i64;
-            secs + 1 + (now().as_millis() as i64)
-        }
-        Some(None) => -1,
-        None => -2,
-    };
-
-    Ok(ttl.into())
+    Ok(expiration_timestamp(conn, &args[0], |t| t.as_millis() as i64).into())
 }
 
 /// Returns the absolute Unix timestamp (since January 1, 1970) in seconds at which the given key
 /// will expire.
 pub async fn expire_time(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let ttl = match conn.db().ttl(&args[0]) {
-        Some(Some(ttl)) => {
-            // Is there a better way? There should be!
-            let secs: i64 = (ttl - Instant::now()).as_secs() as i64;
-            secs + 1 + (now().as_secs() as i64)
-        }
+    Ok(expiration_timestamp(conn, &args[0], |t| t.as_secs() as i64).into())
+}
+
+/// Converts the expiration of a key into an absolute Unix timestamp, using `unit` to pick the
+/// resolution. Returns -1 if the key has no associated expiration and -2 if the key does not
+/// exist.
+///
+/// Expirations are stored as monotonic instants, so the absolute time is derived from the
+/// remaining time to live and the current wall clock.
+fn expiration_timestamp(conn: &Connection, key: &Bytes, unit: impl Fn(Duration) -> i64) -> i64 {
+    match conn.db().ttl(key) {
+        Some(Some(ttl)) => unit(now() + ttl.saturating_duration_since(Instant::now())),
         Some(None) => -1,
         None => -2,
-    };
-
-    Ok(ttl.into())
+    }
 }
 
 /// Returns all keys that matches a given pattern
@@ -238,32 +205,23 @@ pub async fn move_key(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Va
 pub async fn object(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let subcommand = String::from_utf8_lossy(&args[0]).to_lowercase();
 
-    let expected_args = if subcommand == "help" { 1 } else { 2 };
-
-    if expected_args != args.len() {
-        return Err(Error::SubCommandNotFound(
-            subcommand,
-            String::from_utf8_lossy(&args[0]).into(),
-        ));
-    }
-
     match subcommand.as_str() {
         "help" => super::help::object(),
-        "refcount" => Ok(if conn.db().exists(&[args[1].clone()]) == 1 {
-            1.into()
-        } else {
-            Value::Null
-        }),
-        _ => Err(Error::SubCommandNotFound(
-            subcommand,
-            String::from_utf8_lossy(&args[0]).into(),
-        )),
+        "encoding" => Ok(conn
+            .db()
+            .get_encoding(&args[1])
+            .map_or(Value::Null, |encoding| encoding.into())),
+        "refcount" => Ok(conn
+            .db()
+            .get_refcount(&args[1])
+            .map_or(Value::Null, |refcount| refcount.into())),
+        _ => Err(Error::SubCommandNotFound(subcommand, "object".into())),
     }
 }
 
 /// Return a random key from the currently selected database.
 pub async fn randomkey(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
-    conn.db().randomkey()
+    conn.db().randomkey(&mut conn.all_connections().rng())
 }
 
 /// Renames key to newkey. It returns an error when key does not exist. If
@@ -369,7 +327,11 @@ mod test {
     use std::convert::TryInto;
 
     use crate::{
-        cmd::test::{create_connection, run_command},
+        cmd::{
+            now,
+            test::{create_connection, run_command},
+        },
+        connection::Connection,
         error::Error,
         value::Value,
     };
@@ -398,6 +360,82 @@ mod test {
             run_command(&c, &["exists", "foo"]).await
         );
     }
+    #[tokio::test]
+    async fn del_multiple_keys() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key1", "Hello"]).await
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key2", "World"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["del", "key1", "key2", "key3"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn del_duplicated_keys() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key1", "Hello"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["del", "key1", "key1"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn unlink() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key1", "Hello"]).await
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key2", "World"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["unlink", "key1", "key2", "key3"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn exists() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key1", "Hello"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["exists", "key1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "nosuchkey"]).await
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "key2", "World"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["exists", "key1", "key2", "nosuchkey"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["exists", "key1", "key1"]).await
+        );
+    }
+
     #[tokio::test]
     async fn _type() {
         let c = create_connection();
@@ -468,6 +506,125 @@ mod test {
         );
     }
 
+    /// Runs an EXPIRE like command on `foo`, for the key to expire in `secs`
+    /// seconds
+    async fn expire_foo(
+        c: &Connection,
+        command: &str,
+        opts: &[&str],
+        secs: i64,
+    ) -> Result<Value, Error> {
+        let base = match command {
+            "expireat" => now().as_secs() as i64,
+            "pexpireat" => now().as_millis() as i64,
+            _ => 0,
+        };
+        let expiration = if command.starts_with('p') {
+            base + secs * 1000
+        } else {
+            base + secs
+        }
+        .to_string();
+        let mut args = vec![command, "foo", &expiration];
+        args.extend_from_slice(opts);
+        // Boxed, otherwise the test future overflows the stack on debug builds
+        Box::pin(run_command(c, &args)).await
+    }
+
+    #[tokio::test]
+    async fn expire_options() {
+        for command in ["expire", "pexpire", "expireat", "pexpireat"] {
+            let c = create_connection();
+            let _ = run_command(&c, &["set", "foo", "bar"]).await;
+
+            // Persistent key
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["xx"], 100).await);
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["gt"], 100).await);
+            assert_eq!(Ok(1.into()), expire_foo(&c, command, &["nx"], 100).await);
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["NX"], 200).await);
+
+            // Volatile key
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["gt"], 50).await);
+            assert_eq!(Ok(1.into()), expire_foo(&c, command, &["gt"], 200).await);
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["lt"], 300).await);
+            assert_eq!(
+                Ok(1.into()),
+                expire_foo(&c, command, &["LT", "xx"], 150).await
+            );
+            assert_eq!(Ok(1.into()), expire_foo(&c, command, &["xx"], 120).await);
+
+            // LT on a persistent key
+            let _ = run_command(&c, &["persist", "foo"]).await;
+            assert_eq!(Ok(1.into()), expire_foo(&c, command, &["lt"], 100).await);
+
+            // An expiration in the past only deletes the key if the options allow it
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["nx"], -10).await);
+            assert_eq!(Ok(1.into()), run_command(&c, &["exists", "foo"]).await);
+            assert_eq!(Ok(1.into()), expire_foo(&c, command, &["xx"], -10).await);
+            assert_eq!(Ok(0.into()), run_command(&c, &["exists", "foo"]).await);
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &[], -10).await);
+
+            // Missing key
+            assert_eq!(Ok(0.into()), expire_foo(&c, command, &["lt"], 100).await);
+
+            // Options are validated before the expiration time
+            assert_eq!(
+                Err(Error::OptsNotCompatible("NX and XX, GT or LT".to_owned())),
+                run_command(&c, &[command, "foo", "x", "nx", "gt"]).await
+            );
+            assert_eq!(
+                Err(Error::OptsNotCompatible("GT and LT".to_owned())),
+                expire_foo(&c, command, &["gt", "lt"], 100).await
+            );
+            assert_eq!(
+                Err(Error::UnsupportedOption("foo".to_owned())),
+                expire_foo(&c, command, &["nx", "foo"], 100).await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn expire_at_in_the_past() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        let _ = run_command(&c, &["set", "bar", "foo"]).await;
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c, &["expireat", "foo", "-1"]).await
+        );
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c, &["pexpireat", "bar", "1"]).await
+        );
+        assert_eq!(Ok(0.into()), run_command(&c, &["dbsize"]).await);
+    }
+
+    #[tokio::test]
+    async fn expire_time() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(
+            Ok((-2).into()),
+            run_command(&c, &["expiretime", "bar"]).await
+        );
+        assert_eq!(
+            Ok((-1).into()),
+            run_command(&c, &["pexpiretime", "foo"]).await
+        );
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c, &["pexpireat", "foo", "4102444800000"]).await
+        );
+        match run_command(&c, &["expiretime", "foo"]).await {
+            Ok(Value::Integer(n)) => assert!(n == 4102444800 || n == 4102444799),
+            _ => unreachable!(),
+        };
+        match run_command(&c, &["pexpiretime", "foo"]).await {
+            Ok(Value::Integer(n)) => assert!((n - 4102444800000).abs() < 100),
+            _ => unreachable!(),
+        };
+    }
+
     #[tokio::test]
     async fn copy() {
         let c = create_connection();
@@ -643,14 +800,29 @@ mod test {
             );
         }
 
-        let r: Vec<Value> = run_command(&c, &["scan", "0", "match", "foo-1*", "count", "50"])
-            .await
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let values: Vec<Value> = r[1].clone().try_into().unwrap();
+        // COUNT bounds the examined keys, not the matching ones
+        let mut cursor = "0".to_owned();
+        let mut values = vec![];
+        loop {
+            let r: Vec<Value> =
+                run_command(&c, &["scan", &cursor, "match", "foo-1*", "count", "50"])
+                    .await
+                    .unwrap()
+                    .try_into()
+                    .unwrap();
+            assert_eq!(2, r.len());
+            let batch: Vec<Value> = r[1].clone().try_into().unwrap();
+            assert!(batch.len() <= 50);
+            values.extend(batch);
+            cursor = match &r[0] {
+                Value::Blob(cursor) => String::from_utf8_lossy(cursor).to_string(),
+                _ => unreachable!(),
+            };
+            if cursor == "0" {
+                break;
+            }
+        }
 
-        assert_eq!(2, r.len());
         assert_eq!(11, values.len());
     }
 
@@ -696,6 +868,77 @@ mod test {
         assert_eq!(10, values.len());
     }
 
+    #[tokio::test]
+    async fn scan_with_type_3() {
+        let c = create_connection();
+        assert_eq!(Ok(1.into()), run_command(&c, &["incr", "foo"]).await);
+
+        let r: Vec<Value> = run_command(&c, &["scan", "0", "type", "ZSET"])
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let values: Vec<Value> = r[1].clone().try_into().unwrap();
+        assert_eq!(0, values.len());
+
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["scan", "0", "type", "foo"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn object_encoding() {
+        let c = create_connection();
+        assert_eq!(Ok(1.into()), run_command(&c, &["incr", "foo"]).await);
+        assert_eq!(Ok(1.into()), run_command(&c, &["sadd", "set", "a"]).await);
+        assert_eq!(
+            Ok("int".into()),
+            run_command(&c, &["object", "encoding", "foo"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "str", "bar"]).await);
+        assert_eq!(
+            Ok("embstr".into()),
+            run_command(&c, &["object", "encoding", "str"]).await
+        );
+        let long = "x".repeat(45);
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "long", &long]).await
+        );
+        assert_eq!(
+            Ok("raw".into()),
+            run_command(&c, &["object", "encoding", "long"]).await
+        );
+        assert_eq!(
+            Ok("hashtable".into()),
+            run_command(&c, &["object", "encoding", "set"]).await
+        );
+        assert_eq!(
+            Ok(Value::Null),
+            run_command(&c, &["object", "encoding", "bar"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn object_refcount() {
+        let c = create_connection();
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "int", "100"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "str", "bar"]).await);
+        assert_eq!(
+            Ok(Value::Integer(i32::MAX as i64)),
+            run_command(&c, &["object", "refcount", "int"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["object", "refcount", "str"]).await
+        );
+        assert_eq!(
+            Ok(Value::Null),
+            run_command(&c, &["object", "refcount", "bar"]).await
+        );
+    }
+
     #[tokio::test]
     async fn scan_with_count() {
         let c = create_connection();
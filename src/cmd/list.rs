@@ -1,13 +1,14 @@
 //! # List command handlers
 use crate::{
-    check_arg,
+    cmd::parse_option,
     connection::{Connection, UnblockReason},
-    db::utils::far_future,
+    db::{utils::far_future, KeyChangeListener},
     error::Error,
-    try_get_arg, try_get_arg_str,
-    value::bytes_to_number,
+    try_get_arg,
     value::checksum,
-    value::Value,
+    value::range::{resolve_index, resolve_range},
+    value::{bytes_to_count, bytes_to_number},
+    value::{typ::ValueTyp, Value},
 };
 use bytes::Bytes;
 use futures::{stream::FuturesUnordered, Future, StreamExt};
@@ -17,7 +18,6 @@ use tokio::{
     time::{sleep, Duration, Instant},
 };
 
-#[allow(clippy::needless_range_loop)]
 /// Removes an element from a list
 #[inline]
 fn remove_element(
@@ -42,20 +42,16 @@ fn remove_element(
                     return ret;
                 };
 
-                let mut ret = vec![None; limit];
-
-                for i in 0..limit {
-                    if front {
-                        ret[i] = x.pop_front();
-                    } else {
-                        ret[i] = x.pop_back();
-                    }
-                }
+                let limit = limit.min(x.len());
+                let ret = if front {
+                    x.drain(..limit).collect::<Vec<_>>()
+                } else {
+                    x.drain(x.len() - limit..).rev().collect::<Vec<_>>()
+                };
                 new_len = x.len();
 
                 Ok(ret
                     .iter()
-                    .flatten()
                     .map(|m| m.clone_value())
                     .collect::<Vec<Value>>()
                     .into())
@@ -65,7 +61,7 @@ fn remove_element(
         .unwrap_or(Ok(Value::Null))?;
 
     if new_len == 0 {
-        let _ = db.del(&[key.clone()]);
+        let _ = db.del(std::slice::from_ref(key));
     } else {
         db.bump_version(key);
     }
@@ -78,6 +74,13 @@ async fn wait_for_event(receiver: &mut Receiver<()>) {
     let _ = receiver.recv().await;
 }
 
+/// Waits until a watched key holds a list after a change. Any other change (a SET on a watched
+/// key, for instance) cannot unblock a list command, so it is ignored.
+#[inline]
+async fn wait_for_list_change(listener: &KeyChangeListener) {
+    while !listener.changes().await.contains(&ValueTyp::List) {}
+}
+
 #[inline]
 async fn schedule_blocking_task<F, T>(
     conn: Arc<Connection>,
@@ -89,15 +92,25 @@ async fn schedule_blocking_task<F, T>(
     F: Fn(Arc<Connection>, VecDeque<Bytes>, usize) -> T + Send + Sync + 'static,
     T: Future<Output = Result<Value, Error>> + Send + Sync + 'static,
 {
+    // The server may cap how long a client is blocked, even if it asked to wait forever
+    let timeout = match conn.all_connections().max_blocking_time() {
+        Some(max_blocking_time) => {
+            let cap = Instant::now()
+                .checked_add(max_blocking_time)
+                .unwrap_or_else(far_future);
+            Some(timeout.map_or(cap, |timeout| timeout.min(cap)))
+        }
+        None => timeout,
+    };
     conn.block();
 
+    let mut timeout_task = None;
     let mut timeout_rx = if let Some(timeout) = timeout {
         let (timeout_sx, timeout_rx) = broadcast::channel::<()>(1);
         // setup timeout triggering event
         let conn_for_timeout = conn.clone();
-        let _keys_to_watch_for_timeout = keys_to_watch.clone();
         let block_id = conn.get_block_id();
-        tokio::spawn(async move {
+        timeout_task = Some(tokio::spawn(async move {
             sleep(timeout - Instant::now()).await;
             if conn_for_timeout.get_block_id() != block_id {
                 // Timeout trigger event is not longer relevant
@@ -107,16 +120,17 @@ async fn schedule_blocking_task<F, T>(
             conn_for_timeout.append_response(Value::Null);
             // Notify timeout event to the worker thread
             let _ = timeout_sx.send(());
-        });
+        }));
         Some(timeout_rx)
     } else {
         None
     };
 
-    tokio::spawn(async move {
+    let supervisor = conn.all_connections().supervisor();
+    supervisor.spawn("blocking", async move {
         let db = conn.db();
 
-        let mut changes_watchers = db.subscribe_to_key_changes(&keys_to_watch);
+        let subscription = db.watch_key_changes(keys_to_watch);
         let mut externally_unblock_watcher = conn.get_unblocked_subscription();
 
         let mut attempt = 1;
@@ -141,10 +155,7 @@ async fn schedule_blocking_task<F, T>(
                 break;
             }
 
-            let mut futures = changes_watchers
-                .iter_mut()
-                .map(wait_for_event)
-                .collect::<FuturesUnordered<_>>();
+            let mut futures = FuturesUnordered::new();
 
             if let Some(ref mut timeout_rx) = &mut timeout_rx {
                 futures.push(wait_for_event(timeout_rx));
@@ -153,8 +164,21 @@ async fn schedule_blocking_task<F, T>(
                 futures.push(wait_for_event(externally));
             }
 
-            // wait until a key changes or a timeout event occurs
-            let _ = futures.next().await;
+            // wait until a list changes (and this connection is the first in line) or a timeout
+            // event occurs
+            tokio::select! {
+                _ = wait_for_list_change(subscription.listener()) => {},
+                _ = futures.next(), if !futures.is_empty() => {},
+            }
+
+            if !conn.is_blocked() {
+                break;
+            }
+        }
+
+        // The timer is no longer needed, it must not linger until the timeout
+        if let Some(timeout_task) = timeout_task {
+            timeout_task.abort();
         }
     });
 }
@@ -187,6 +211,9 @@ fn parse_timeout(arg: &Bytes) -> Result<Option<Instant>, Error> {
 pub async fn blpop(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let blpop_task = |conn: Arc<Connection>, args: VecDeque<Bytes>, attempt| async move {
         for key in args.iter() {
+            if !conn.db().is_first_subscriber(key) {
+                continue;
+            }
             match remove_element(&conn, key, None, true) {
                 Ok(Value::Null) => (),
                 Ok(n) => return Ok(vec![Value::Blob(key.clone()), n].into()),
@@ -232,12 +259,17 @@ pub async fn blmove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Valu
     }
 
     let timeout = parse_timeout(&args.pop_back().ok_or(Error::Syntax)?)?;
-    let keys_to_watch = vec![args[0].clone(), args[1].clone()];
+    let keys_to_watch = vec![args[0].clone()];
 
     schedule_blocking_task(
         conn.get_connection(),
         keys_to_watch,
-        |conn, args, _| async move { lmove(&conn, args).await },
+        |conn, args, _| async move {
+            if !conn.db().is_first_subscriber(&args[0]) {
+                return Ok(Value::Null);
+            }
+            lmove(&conn, args).await
+        },
         args,
         timeout,
     )
@@ -273,6 +305,9 @@ pub async fn brpoplpush(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<
 pub async fn brpop(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let brpop_task = |conn: Arc<Connection>, args: VecDeque<Bytes>, attempt| async move {
         for key in args.iter() {
+            if !conn.db().is_first_subscriber(key) {
+                continue;
+            }
             match remove_element(&conn, key, None, false) {
                 Ok(Value::Null) => (),
                 Ok(n) => return Ok(vec![Value::Blob(key.clone()), n].into()),
@@ -316,13 +351,9 @@ pub async fn lindex(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
             Value::List(x) => {
                 let index: i64 = bytes_to_number(&args[1])?;
 
-                let index = if index < 0 {
-                    x.len().checked_sub(-index as usize).unwrap_or(x.len())
-                } else {
-                    index as usize
-                };
-
-                Ok(x.get(index).map_or(Value::Null, |x| x.clone_value()))
+                Ok(resolve_index(index, x.len())
+                    .and_then(|index| x.get(index))
+                    .map_or(Value::Null, |x| x.clone_value()))
             }
             _ => Err(Error::WrongType),
         })
@@ -338,14 +369,7 @@ pub async fn linsert(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Val
     let pivot = args.pop_front().ok_or(Error::Syntax)?;
     let value = args.pop_front().ok_or(Error::Syntax)?;
 
-    let is_before = match String::from_utf8_lossy(&direction)
-        .to_ascii_uppercase()
-        .as_str()
-    {
-        "BEFORE" => true,
-        "AFTER" => false,
-        _ => return Err(Error::Syntax),
-    };
+    let is_before = parse_option(&direction, &[("BEFORE", true), ("AFTER", false)])?;
 
     let result = conn
         .db()
@@ -399,25 +423,15 @@ pub async fn llen(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
         .unwrap_or(Ok(0.into()))
 }
 
+/// Directions of LMOVE, true for the head of the list
+const DIRECTIONS: &[(&str, bool)] = &[("LEFT", true), ("RIGHT", false)];
+
 /// Atomically returns and removes the first/last element (head/tail depending on the wherefrom
 /// argument) of the list stored at source, and pushes the element at the first/last element
 /// (head/tail depending on the whereto argument) of the list stored at destination.
 pub async fn lmove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let source_is_left = if check_arg!(args, 2, "LEFT") {
-        true
-    } else if check_arg!(args, 2, "RIGHT") {
-        false
-    } else {
-        return Err(Error::Syntax);
-    };
-
-    let target_is_left = if check_arg!(args, 3, "LEFT") {
-        true
-    } else if check_arg!(args, 3, "RIGHT") {
-        false
-    } else {
-        return Err(Error::Syntax);
-    };
+    let source_is_left = parse_option(try_get_arg!(args, 2), DIRECTIONS)?;
+    let target_is_left = parse_option(try_get_arg!(args, 3), DIRECTIONS)?;
 
     let source = args.pop_front().ok_or(Error::Syntax)?;
     let destination = args.pop_front().ok_or(Error::Syntax)?;
@@ -504,6 +518,9 @@ pub async fn lmove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
     if let Some(to_create) = to_create {
         conn.db().set(destination.clone(), to_create.into(), None);
     }
+    if result != Ok(Value::Null) {
+        db.remove_if_empty(&source);
+    }
 
     // release the lock on keys
     db.unlock_keys(&to_lock);
@@ -523,13 +540,21 @@ pub async fn lmove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
 /// the list's length.
 pub async fn lpop(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let count = match args.get(1) {
-        Some(v) => Some(bytes_to_number(v)?),
+        Some(v) => Some(bytes_to_count(v)?),
         None => None,
     };
 
     remove_element(conn, &args[0], count, true)
 }
 
+/// Options of LPOS
+#[derive(Clone, Copy)]
+enum LposOption {
+    Rank,
+    Count,
+    MaxLen,
+}
+
 /// The command returns the index of matching elements inside a Redis list. By default, when no
 /// options are given, it will scan the list from head to tail, looking for the first match of
 /// "element". If the element is found, its index (the zero-based position in the list) is
@@ -547,11 +572,17 @@ pub async fn lpos(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
         }
 
         let next = try_get_arg!(args, index + 1);
-        match try_get_arg_str!(args, index).to_uppercase().as_str() {
-            "RANK" => rank = Some(bytes_to_number::<i64>(next)?),
-            "COUNT" => count = Some(bytes_to_number::<usize>(next)?),
-            "MAXLEN" => max_len = Some(bytes_to_number::<usize>(next)?),
-            _ => return Err(Error::Syntax),
+        match parse_option(
+            try_get_arg!(args, index),
+            &[
+                ("RANK", LposOption::Rank),
+                ("COUNT", LposOption::Count),
+                ("MAXLEN", LposOption::MaxLen),
+            ],
+        )? {
+            LposOption::Rank => rank = Some(bytes_to_number::<i64>(next)?),
+            LposOption::Count => count = Some(bytes_to_number::<usize>(next)?),
+            LposOption::MaxLen => max_len = Some(bytes_to_number::<usize>(next)?),
         }
 
         index += 2;
@@ -571,6 +602,9 @@ pub async fn lpos(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
     };
 
     let max_len = max_len.unwrap_or_default();
+    // Matches before RANK are skipped, they are not part of the reply
+    let skipped = rank.map_or(0, |rank| rank - 1);
+    let all_connections = conn.all_connections();
 
     conn.db()
         .get(&args[0])
@@ -587,13 +621,19 @@ pub async fn lpos(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
                     values.reverse();
                 }
 
-                let mut checks = 1;
-
-                for (id, val) in values.iter() {
+                for (checks, (id, val)) in (1..).zip(values.iter()) {
                     if **val == element {
                         // Match!
                         if let Some(count) = count {
                             result.push((*id).into());
+                            // The number of matches is not known in advance, the reply is checked
+                            // against max-reply-elements as it grows
+                            let replied = result.len().saturating_sub(skipped);
+                            all_connections.check_reply_elements(if count == 0 {
+                                replied
+                            } else {
+                                replied.min(count)
+                            })?;
                             if result.len() == count && count != 0 && rank.is_none() {
                                 // There is no point in keep looping. No RANK provided, COUNT is not 0
                                 // therefore we can return the vector of result as IS
@@ -612,7 +652,6 @@ pub async fn lpos(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
                     if checks == max_len {
                         break;
                     }
-                    checks += 1;
                 }
 
                 if let Some(rank) = rank {
@@ -723,31 +762,11 @@ pub async fn lrange(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
             Value::List(x) => {
                 let start: i64 = bytes_to_number(&args[1])?;
                 let end: i64 = bytes_to_number(&args[2])?;
-                let mut ret = vec![];
-
-                let start = if start < 0 {
-                    x.len().checked_sub(-start as usize).unwrap_or_default()
-                } else {
-                    start as usize
-                };
-
-                let end = if end < 0 {
-                    if let Some(x) = x.len().checked_sub(-end as usize) {
-                        x
-                    } else {
-                        return Ok(Value::Array(vec![]));
-                    }
-                } else {
-                    end as usize
-                };
 
-                for (i, val) in x.iter().enumerate().skip(start) {
-                    if i > end {
-                        break;
-                    }
-                    ret.push(val.clone_value());
-                }
-                Ok(ret.into())
+                Ok(resolve_range(start, end, x.len())
+                    .map(|range| x.range(range).map(|val| val.clone_value()).collect())
+                    .unwrap_or_default()
+                    .into())
             }
             _ => Err(Error::WrongType),
         })
@@ -800,6 +819,7 @@ pub async fn lrem(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
         })
         .unwrap_or(Ok(0.into()))?;
 
+    conn.db().remove_if_empty(&args[0]);
     conn.db().bump_version(&args[0]);
 
     Ok(result)
@@ -819,13 +839,9 @@ pub async fn lset(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value,
         .get(&key)
         .map_mut(|v| match v {
             Value::List(x) => {
-                let mut index: i64 = bytes_to_number(&index)?;
+                let index: i64 = bytes_to_number(&index)?;
 
-                if index < 0 {
-                    index += x.len() as i64;
-                }
-
-                if let Some(x) = x.get_mut(index as usize) {
+                if let Some(x) = resolve_index(index, x.len()).and_then(|index| x.get_mut(index)) {
                     *x = checksum::Value::new(value);
                     Ok(Value::Ok)
                 } else {
@@ -850,31 +866,25 @@ pub async fn ltrim(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Er
         .get(&args[0])
         .map_mut(|v| match v {
             Value::List(x) => {
-                let mut start: i64 = bytes_to_number(&args[1])?;
-                let mut end: i64 = bytes_to_number(&args[2])?;
-
-                if start < 0 {
-                    start += x.len() as i64;
-                }
+                let start: i64 = bytes_to_number(&args[1])?;
+                let end: i64 = bytes_to_number(&args[2])?;
 
-                if end < 0 {
-                    end += x.len() as i64;
+                match resolve_range(start, end, x.len()) {
+                    Some(range) => {
+                        x.truncate(range.end() + 1);
+                        x.drain(..range.start());
+                    }
+                    None => x.clear(),
                 }
 
-                let mut i = 0;
-                x.retain(|_| {
-                    let retain = i >= start && i <= end;
-                    i += 1;
-                    retain
-                });
-
                 Ok(Value::Ok)
             }
             _ => Err(Error::WrongType),
         })
         .unwrap_or(Ok(Value::Ok))?;
 
-    conn.db().bump_version(&args[1]);
+    conn.db().remove_if_empty(&args[0]);
+    conn.db().bump_version(&args[0]);
 
     Ok(result)
 }
@@ -886,7 +896,7 @@ pub async fn ltrim(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Er
 /// list's length.
 pub async fn rpop(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let count = match args.get(1) {
-        Some(v) => Some(bytes_to_number(v)?),
+        Some(v) => Some(bytes_to_count(v)?),
         None => None,
     };
 
@@ -967,10 +977,14 @@ pub async fn rpush(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
 #[cfg(test)]
 mod test {
     use crate::{
-        cmd::test::{create_connection, create_connection_and_pubsub, run_command},
+        cmd::test::{
+            create_connection, create_connection_and_pubsub, create_new_connection_from_connection,
+            run_command,
+        },
         error::Error,
         value::Value,
     };
+    use std::collections::HashSet;
     use tokio::time::{sleep, Duration, Instant};
 
     #[tokio::test]
@@ -1011,6 +1025,28 @@ mod test {
         assert!(Instant::now() - x >= Duration::from_millis(1000));
     }
 
+    #[tokio::test]
+    async fn blpop_max_blocking_time() {
+        let (mut recv, c) = create_connection_and_pubsub();
+        c.all_connections().set_max_blocking_time(200);
+        let x = Instant::now();
+
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c, &["blpop", "foobar", "0"]).await
+        );
+        assert_eq!(Some(Value::Null), recv.recv().await);
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c, &["blmove", "foobar", "bar", "left", "left", "10"]).await
+        );
+        assert_eq!(Some(Value::Null), recv.recv().await);
+
+        let elapsed = Instant::now() - x;
+        assert!(elapsed >= Duration::from_millis(400));
+        assert!(elapsed < Duration::from_millis(5000));
+    }
+
     #[tokio::test]
     async fn blpop_wait_insert() {
         let (mut recv, c) = create_connection_and_pubsub();
@@ -1047,6 +1083,137 @@ mod test {
         assert!(Instant::now() - x < Duration::from_millis(5000));
     }
 
+    #[tokio::test]
+    async fn blpop_fifo_order() {
+        let c = create_connection();
+        let mut clients = vec![];
+
+        for _ in 0..3 {
+            let (recv, client) = create_new_connection_from_connection(&c);
+            assert_eq!(
+                Ok(Value::Ignore),
+                run_command(&client, &["blpop", "queue", "5"]).await
+            );
+            // let the blocking task queue itself before the next client
+            sleep(Duration::from_millis(50)).await;
+            clients.push((recv, client));
+        }
+
+        assert_eq!(
+            Ok(Value::Integer(3)),
+            run_command(&c, &["rpush", "queue", "1", "2", "3"]).await,
+        );
+
+        for (i, (recv, _)) in clients.iter_mut().enumerate() {
+            assert_eq!(
+                Some(Value::Array(vec![
+                    Value::Blob("queue".into()),
+                    Value::Blob((i + 1).to_string().into()),
+                ])),
+                recv.recv().await,
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn blpop_burst_of_producers() {
+        const CLIENTS: usize = 2_000;
+        let c = create_connection();
+        let mut consumers = vec![];
+
+        for _ in 0..CLIENTS {
+            let (recv, client) = create_new_connection_from_connection(&c);
+            assert_eq!(
+                Ok(Value::Ignore),
+                Box::pin(run_command(&client, &["blpop", "queue", "0"])).await
+            );
+            consumers.push((recv, client));
+        }
+
+        let producers = (0..CLIENTS)
+            .map(|i| {
+                let (_, producer) = create_new_connection_from_connection(&c);
+                tokio::spawn(async move {
+                    let value = i.to_string();
+                    Box::pin(run_command(&producer, &["rpush", "queue", &value])).await
+                })
+            })
+            .collect::<Vec<_>>();
+        for producer in producers {
+            assert!(producer.await.expect("producer").is_ok());
+        }
+
+        // every consumer gets exactly one element, none of the wakeups is lost
+        let mut received = HashSet::new();
+        for (recv, _) in consumers.iter_mut() {
+            match tokio::time::timeout(Duration::from_secs(10), recv.recv()).await {
+                Ok(Some(Value::Array(x))) => assert!(received.insert(format!("{:?}", x[1]))),
+                x => panic!("unexpected response {:?}", x),
+            }
+        }
+        assert_eq!(CLIENTS, received.len());
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["llen", "queue"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn blocking_commands_release_their_subscriptions() {
+        let (mut recv, c) = create_connection_and_pubsub();
+
+        for i in 0..100 {
+            let key = format!("unique-{}", i);
+            assert_eq!(
+                Ok(Value::Ignore),
+                run_command(&c, &["blpop", &key, "0.01"]).await
+            );
+            assert_eq!(Some(Value::Null), recv.recv().await);
+        }
+
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c, &["blpop", "served", "10"]).await
+        );
+        let (_, c2) = create_new_connection_from_connection(&c);
+        let _ = run_command(&c2, &["rpush", "served", "1"]).await;
+        assert_eq!(
+            Some(Value::Array(vec!["served".into(), "1".into()])),
+            recv.recv().await
+        );
+
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(0, c.db().waited_keys());
+    }
+
+    #[tokio::test]
+    async fn blpop_timeout_leaves_queue() {
+        let (mut recv1, c1) = create_connection_and_pubsub();
+        let (mut recv2, c2) = create_new_connection_from_connection(&c1);
+
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c1, &["blpop", "queue", "0.1"]).await
+        );
+        assert_eq!(Some(Value::Null), recv1.recv().await);
+
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c2, &["blpop", "queue", "0"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c1, &["rpush", "queue", "1"]).await,
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                Value::Blob("queue".into()),
+                Value::Blob("1".into()),
+            ])),
+            recv2.recv().await,
+        );
+    }
+
     #[tokio::test]
     async fn lrem_1() {
         let c = create_connection();
@@ -1278,6 +1445,46 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn range_commands_extreme_indexes() {
+        let c = create_connection();
+        let (min, max) = ("-9223372036854775808", "9223372036854775807");
+        let _ = run_command(&c, &["rpush", "foo", "a", "b", "c"]).await;
+
+        assert_eq!(
+            Ok(Value::Null),
+            run_command(&c, &["lindex", "foo", min]).await
+        );
+        assert_eq!(
+            Err(Error::OutOfRange),
+            run_command(&c, &["lset", "foo", min, "x"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["lrange", "foo", min, min]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec!["a".into(), "b".into(), "c".into()])),
+            run_command(&c, &["lrange", "foo", min, max]).await
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["ltrim", "foo", "1", max]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec!["b".into(), "c".into()])),
+            run_command(&c, &["lrange", "foo", "0", "-1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["ltrim", "foo", max, min]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["llen", "foo"]).await
+        );
+    }
+
     #[tokio::test]
     async fn linsert_syntax_err() {
         let c = create_connection();
@@ -1406,6 +1613,40 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn pop_count() {
+        let c = create_connection();
+
+        assert_eq!(
+            Ok(Value::Integer(3)),
+            run_command(&c, &["rpush", "foo", "1", "2", "3"]).await,
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["lpop", "foo", "0"]).await
+        );
+        assert_eq!(
+            Err(Error::NotPositive),
+            run_command(&c, &["rpop", "foo", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumberType("an integer".to_owned())),
+            run_command(&c, &["lpop", "foo", "x"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                Value::Blob("3".into()),
+                Value::Blob("2".into())
+            ])),
+            run_command(&c, &["rpop", "foo", "2"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![Value::Blob("1".into())])),
+            run_command(&c, &["rpop", "foo", "9223372036854775807"]).await
+        );
+        assert_eq!(Ok(0.into()), run_command(&c, &["exists", "foo"]).await);
+    }
+
     #[tokio::test]
     async fn lmove_1() {
         let c = create_connection();
@@ -1498,6 +1739,26 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn lpos_max_reply_elements() {
+        let c = create_connection();
+        c.all_connections().set_max_reply_elements(2);
+        let _ = run_command(&c, &["RPUSH", "mylist", "3", "a", "3", "3"]).await;
+
+        assert_eq!(
+            Err(Error::ReplyTooLarge(2)),
+            run_command(&c, &["lpos", "mylist", "3", "count", "0"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![Value::Integer(0), Value::Integer(2)])),
+            run_command(&c, &["lpos", "mylist", "3", "count", "2"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![Value::Integer(2), Value::Integer(3)])),
+            run_command(&c, &["lpos", "mylist", "3", "count", "0", "rank", "2"]).await
+        );
+    }
+
     #[tokio::test]
     async fn lpos_with_negative_rank_with_count() {
         let c = create_connection();
@@ -1807,6 +2068,45 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn emptied_lists_are_removed() {
+        let c = create_connection();
+
+        let _ = run_command(&c, &["rpush", "foo", "1", "1"]).await;
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["lrem", "foo", "0", "1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "foo"]).await
+        );
+
+        let _ = run_command(&c, &["rpush", "foo", "1", "2"]).await;
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["ltrim", "foo", "5", "10"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "foo"]).await
+        );
+
+        let _ = run_command(&c, &["rpush", "foo", "1"]).await;
+        assert_eq!(
+            Ok(Value::Blob("1".into())),
+            run_command(&c, &["lmove", "foo", "bar", "left", "left"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["llen", "bar"]).await
+        );
+    }
+
     #[tokio::test]
     async fn rpop() {
         let c = create_connection();
@@ -1,16 +1,28 @@
 //! # Metrics command handlers
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 
 use crate::{connection::Connection, error::Error, value::Value};
 use bytes::Bytes;
 
-/// Dumps metrics from commands. If no argument is passed all commands' metrics are dump.
+/// Dumps metrics from commands. If no argument is passed all commands' metrics are dump, along
+/// with the number of connections and the number of keys of each type.
 ///
-/// The metrics are serialized as JSON.
-pub async fn metrics(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+/// The metrics are serialized as JSON. Command latencies are reported in microseconds, as
+/// percentiles.
+///
+/// `METRICS RESET [command ...]` forgets the metrics of the given commands, or of every command
+/// if none is given.
+pub async fn metrics(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let dispatcher = conn.all_connections().get_dispatcher();
-    let mut result: Vec<Value> = vec![];
-    let commands = if args.len() == 1 {
+    let is_reset = args
+        .front()
+        .map_or(false, |arg| arg.eq_ignore_ascii_case(b"reset"));
+    if is_reset {
+        args.pop_front();
+    }
+
+    let dump_all = args.is_empty();
+    let commands = if dump_all {
         dispatcher.get_all_commands()
     } else {
         let mut commands = vec![];
@@ -21,14 +33,98 @@ pub async fn metrics(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value,
         commands
     };
 
+    if is_reset {
+        for command in commands.iter() {
+            command.metrics().reset();
+        }
+        return Ok(Value::Ok);
+    }
+
+    let mut result: Vec<Value> = vec![];
     for command in commands.iter() {
         result.push(command.name().into());
-        result.push(
-            serde_json::to_string(command.metrics())
-                .map_err(|_| Error::Internal)?
-                .into(),
-        );
+        result.push(to_json(command.metrics())?);
+    }
+
+    if dump_all {
+        let connections = conn.all_connections();
+        let mut clients = BTreeMap::new();
+        clients.insert("total", connections.total_connections());
+        clients.insert("blocked", connections.total_blocked_connections());
+        result.push("connections".into());
+        result.push(to_json(&clients)?);
+
+        let mut keys: BTreeMap<&'static str, usize> = BTreeMap::new();
+        for db in connections.get_databases().into_iter() {
+            for (typ, count) in db.keys_by_type() {
+                *keys.entry(typ).or_insert(0) += count;
+            }
+        }
+        result.push("keys".into());
+        result.push(to_json(&keys)?);
     }
 
     Ok(result.into())
 }
+
+/// Serializes a metric as JSON
+fn to_json<T: serde::Serialize>(metric: &T) -> Result<Value, Error> {
+    Ok(serde_json::to_string(metric)
+        .map_err(|_| Error::Internal)?
+        .into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        cmd::test::{create_connection, run_command},
+        connection::Connection,
+        value::Value,
+    };
+    use std::convert::TryInto;
+
+    /// Returns the metrics of a single command, or of anything else METRICS reports, as JSON
+    async fn metric(conn: &Connection, name: &str) -> serde_json::Value {
+        let reply: Vec<Value> = run_command(conn, &["metrics"])
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let position = reply
+            .iter()
+            .position(|value| *value == Value::from(name))
+            .unwrap();
+        match &reply[position + 1] {
+            Value::Blob(json) => serde_json::from_slice(json).unwrap(),
+            value => panic!("Unexpected value {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn latency_and_reset() {
+        let c = create_connection();
+        let _ = run_command(&c, &["get", "foo"]).await;
+        let _ = run_command(&c, &["get", "foo"]).await;
+        assert_eq!(2, metric(&c, "GET").await["latency"]["samples"]);
+
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["metrics", "reset", "get"]).await
+        );
+        assert_eq!(0, metric(&c, "GET").await["latency"]["samples"]);
+        assert_eq!(0, metric(&c, "GET").await["hit_count"]);
+    }
+
+    #[tokio::test]
+    async fn keys_and_connections() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        let _ = run_command(&c, &["set", "bar", "foo"]).await;
+        let _ = run_command(&c, &["sadd", "set", "foo"]).await;
+
+        let keys = metric(&c, "keys").await;
+        assert_eq!(2, keys["string"]);
+        assert_eq!(1, keys["set"]);
+        assert_eq!(1, metric(&c, "connections").await["total"]);
+    }
+}
@@ -1,17 +1,30 @@
 //! # All commands handlers
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{error::Error, value::bytes_to_int};
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::time::Duration;
 
 pub mod client;
+#[cfg(all(test, feature = "differential-testing"))]
+mod differential;
+#[cfg(feature = "hashes")]
 pub mod hash;
 pub mod help;
 pub mod key;
+#[cfg(feature = "lists")]
 pub mod list;
 pub mod metrics;
+#[cfg(feature = "pubsub")]
 pub mod pubsub;
 pub mod server;
+#[cfg(feature = "sets")]
 pub mod set;
+#[cfg(feature = "strings")]
 pub mod string;
+#[cfg(feature = "transactions")]
 pub mod transaction;
 
 /// Returns the current time
@@ -22,40 +35,48 @@ pub fn now() -> Duration {
         .expect("Time went backwards")
 }
 
-#[cfg(test)]
-mod test {
-    use crate::{
-        connection::{connections::Connections, Connection},
-        db::pool::Databases,
-        dispatcher::Dispatcher,
-        error::Error,
-        value::Value,
+/// Takes the `numkeys key [key ...]` prefix of commands like SINTERCARD, returning the keys and
+/// leaving any trailing option in `args`.
+pub fn take_numkeys(args: &mut VecDeque<Bytes>) -> Result<VecDeque<Bytes>, Error> {
+    let numkeys = args.pop_front().ok_or(Error::Syntax)?;
+    let numkeys = match bytes_to_int::<i64>(&numkeys) {
+        Ok(n) if n > 0 => n as usize,
+        _ => return Err(Error::NumKeysNotPositive),
     };
+    if numkeys > args.len() {
+        return Err(Error::NumKeysTooBig);
+    }
+    let options = args.split_off(numkeys);
+    Ok(std::mem::replace(args, options))
+}
+
+/// Returns whether the argument at `index` is `option`. Options are compared byte-wise, ignoring
+/// the ASCII case, so `ex`, `Ex` and `EX` are the same option.
+pub fn is_option(args: &VecDeque<Bytes>, index: usize, option: &str) -> bool {
+    args.get(index)
+        .map_or(false, |arg| arg.eq_ignore_ascii_case(option.as_bytes()))
+}
+
+/// Parses an option, comparing it with the name of each one of `options` like `is_option` does,
+/// and returns the value of the matching one. Unknown options are a syntax error.
+pub fn parse_option<T: Copy>(arg: &[u8], options: &[(&str, T)]) -> Result<T, Error> {
+    options
+        .iter()
+        .find(|(name, _)| arg.eq_ignore_ascii_case(name.as_bytes()))
+        .map(|(_, value)| *value)
+        .ok_or(Error::Syntax)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{is_option, parse_option};
+    pub use crate::testing::*;
+    use crate::{error::Error, value::Value};
     use bytes::Bytes;
     use std::{
         collections::VecDeque,
         net::{IpAddr, Ipv4Addr, SocketAddr},
-        sync::Arc,
     };
-    use tokio::sync::mpsc::Receiver;
-
-    pub fn create_connection() -> Arc<Connection> {
-        let (default_db, all_dbs) = Databases::new(16, 1000);
-        let all_connections = Arc::new(Connections::new(all_dbs));
-
-        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        all_connections.new_connection(default_db, client).1
-    }
-
-    pub fn create_connection_and_pubsub() -> (Receiver<Value>, Arc<Connection>) {
-        let (default_db, all_dbs) = Databases::new(16, 1000);
-        let all_connections = Arc::new(Connections::new(all_dbs));
-
-        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        all_connections.new_connection(default_db, client)
-    }
 
     pub async fn invalid_type(cmd: &[&str]) {
         let c = create_connection();
@@ -63,24 +84,24 @@ mod test {
         assert_eq!(Err(Error::WrongType), run_command(&c, cmd).await);
     }
 
-    pub fn create_new_connection_from_connection(
-        conn: &Connection,
-    ) -> (Receiver<Value>, Arc<Connection>) {
-        let all_connections = conn.all_connections();
-
-        let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-
-        all_connections.new_connection(
-            all_connections.get_databases().get(0).expect("DB(0)"),
-            client,
-        )
-    }
-
-    pub async fn run_command(conn: &Connection, cmd: &[&str]) -> Result<Value, Error> {
-        let args: VecDeque<Bytes> = cmd.iter().map(|s| Bytes::from(s.to_string())).collect();
-
-        let dispatcher = Dispatcher::new();
-        dispatcher.execute(conn, args).await
+    #[test]
+    fn options() {
+        let args: VecDeque<Bytes> = vec![Bytes::from("foo"), Bytes::from("WithScores")].into();
+        assert!(is_option(&args, 1, "WITHSCORES"));
+        assert!(is_option(&args, 1, "withscores"));
+        assert!(!is_option(&args, 0, "WITHSCORES"));
+        assert!(!is_option(&args, 2, "WITHSCORES"));
+
+        let options = [("LEFT", true), ("RIGHT", false)];
+        assert_eq!(Ok(true), parse_option(b"left", &options));
+        assert_eq!(Ok(false), parse_option(b"RiGhT", &options));
+        assert_eq!(Err(Error::Syntax), parse_option(b"RIGHTS", &options));
+        assert_eq!(Err(Error::Syntax), parse_option(b"", &options));
+        // only ASCII letters are case-insensitive
+        assert_eq!(
+            Err(Error::Syntax),
+            parse_option("lefť".as_bytes(), &options)
+        );
     }
 
     #[tokio::test]
@@ -97,4 +118,103 @@ mod test {
         assert_eq!(1, all_connections.total_connections());
         assert_eq!(0, all_connections.total_blocked_connections());
     }
+
+    #[tokio::test]
+    async fn read_only() {
+        let c = create_connection();
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "bar"]).await);
+        c.all_connections().set_read_only(true);
+        assert_eq!(
+            Err(Error::ReadOnly),
+            run_command(&c, &["set", "foo", "xxx"]).await
+        );
+        assert_eq!(Err(Error::ReadOnly), run_command(&c, &["del", "foo"]).await);
+        assert_eq!(Ok("bar".into()), run_command(&c, &["get", "foo"]).await);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Err(Error::ReadOnly),
+            run_command(&c, &["set", "foo", "xxx"]).await
+        );
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+
+        c.all_connections().set_read_only(false);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "xxx"]).await);
+        assert_eq!(Ok("xxx".into()), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn incr_and_hincrby_share_errors() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "str", "foo"]).await;
+        let _ = run_command(&c, &["hset", "hash", "str", "foo"]).await;
+        let not_an_integer = Err(Error::NotANumberType("an integer".to_owned()));
+        let not_a_float = Err(Error::NotANumberType("a valid float".to_owned()));
+
+        assert_eq!(not_an_integer, run_command(&c, &["incr", "str"]).await);
+        assert_eq!(
+            not_an_integer,
+            run_command(&c, &["hincrby", "hash", "str", "1"]).await
+        );
+        assert_eq!(
+            not_an_integer,
+            run_command(&c, &["incrby", "num", "foo"]).await
+        );
+        assert_eq!(
+            not_an_integer,
+            run_command(&c, &["hincrby", "hash", "num", "foo"]).await
+        );
+        assert_eq!(
+            not_a_float,
+            run_command(&c, &["incrbyfloat", "str", "1"]).await
+        );
+        assert_eq!(
+            not_a_float,
+            run_command(&c, &["hincrbyfloat", "hash", "str", "1"]).await
+        );
+
+        let max = i64::MAX.to_string();
+        let _ = run_command(&c, &["set", "max", &max]).await;
+        let _ = run_command(&c, &["hset", "hash", "max", &max]).await;
+        assert_eq!(
+            Err(Error::Overflow),
+            run_command(&c, &["incr", "max"]).await
+        );
+        assert_eq!(
+            Err(Error::Overflow),
+            run_command(&c, &["hincrby", "hash", "max", "1"]).await
+        );
+        assert_eq!(
+            Err(Error::Overflow),
+            run_command(&c, &["decrby", "num", &i64::MIN.to_string()]).await
+        );
+
+        assert_eq!(
+            Err(Error::IncrByInfOrNan),
+            run_command(&c, &["incrbyfloat", "num", "inf"]).await
+        );
+        assert_eq!(
+            Err(Error::IncrByInfOrNan),
+            run_command(&c, &["hincrbyfloat", "hash", "num", "inf"]).await
+        );
+
+        assert_eq!(
+            Ok(Value::Blob("3".into())),
+            run_command(&c, &["incrbyfloat", "num", "3.0"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("3".into())),
+            run_command(&c, &["hincrbyfloat", "hash", "num", "3.0"]).await
+        );
+        assert_eq!(Ok(4.into()), run_command(&c, &["incr", "num"]).await);
+        assert_eq!(
+            Ok(4.into()),
+            run_command(&c, &["hincrby", "hash", "num", "1"]).await
+        );
+        assert_eq!(Ok("4".into()), run_command(&c, &["get", "num"]).await);
+        assert_eq!(
+            Ok("4".into()),
+            run_command(&c, &["hget", "hash", "num"]).await
+        );
+    }
 }
@@ -3,13 +3,52 @@ use std::collections::VecDeque;
 
 use crate::{connection::Connection, error::Error, value::Value};
 use bytes::Bytes;
-use glob::Pattern;
 
 /// Posts a message to the given channel.
+///
+/// The message is forwarded to the peers of the federation, if any. The reply counts the local
+/// subscribers only.
 pub async fn publish(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    if let Some(federation) = conn.all_connections().federation() {
+        federation.forward(&args[0], &args[1]);
+    }
     Ok(conn.pubsub().publish(&args[0], &args[1]).await.into())
 }
 
+/// Pubsub federation commands
+///
+/// `FEDERATION PUBLISH <channel> <message>` is sent by the peers to deliver their published
+/// messages, which are never forwarded again. `FEDERATION PEERS` describes the link with each
+/// peer, as `[address, connected, forwarded messages, dropped messages]`.
+pub async fn federation(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    match String::from_utf8_lossy(&(args.pop_front().ok_or(Error::Syntax)?))
+        .to_lowercase()
+        .as_str()
+    {
+        "publish" => Ok(conn.pubsub().publish(&args[0], &args[1]).await.into()),
+        "peers" => Ok(Value::Array(
+            conn.all_connections()
+                .federation()
+                .map(|federation| {
+                    federation
+                        .peers()
+                        .iter()
+                        .map(|peer| {
+                            Value::Array(vec![
+                                peer.addr().into(),
+                                (peer.status().is_connected() as i64).into(),
+                                (peer.status().forwarded() as i64).into(),
+                                (peer.status().dropped() as i64).into(),
+                            ])
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        )),
+        cmd => Err(Error::SubCommandNotFound(cmd.into(), "federation".into())),
+    }
+}
+
 /// All pubsub commands
 pub async fn pubsub(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     match String::from_utf8_lossy(&(args.pop_front().ok_or(Error::Syntax)?))
@@ -46,7 +85,7 @@ pub async fn subscribe(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value
 /// Subscribes with a pattern the client to the specified channels.
 pub async fn psubscribe(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     let pubsub = conn.pubsub();
-    pubsub.psubscribe(args, conn)?;
+    pubsub.psubscribe(args, conn);
     conn.start_pubsub()
 }
 
@@ -55,12 +94,7 @@ pub async fn punsubscribe(conn: &Connection, args: VecDeque<Bytes>) -> Result<Va
     let channels = if args.is_empty() {
         conn.pubsub_client().psubscriptions()
     } else {
-        args.into_iter()
-            .map(|channel| {
-                let channel = String::from_utf8_lossy(&channel);
-                Pattern::new(&channel).map_err(|_| Error::InvalidPattern(channel.to_string()))
-            })
-            .collect::<Result<Vec<Pattern>, Error>>()?
+        args.into_iter().collect()
     };
 
     conn.pubsub_client().punsubscribe(&channels, conn);
@@ -90,6 +124,7 @@ mod test {
         error::Error,
         value::Value,
     };
+    use bytes::Bytes;
     use std::convert::TryInto;
     use tokio::sync::mpsc::Receiver;
 
@@ -347,4 +382,84 @@ mod test {
             run_command(&c2, &["pubsub", "numpat"]).await
         );
     }
+
+    #[tokio::test]
+    async fn pubsub_pattern_matching() {
+        let (mut sub1, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+
+        assert_eq!(
+            Ok(Value::Ignore),
+            run_command(&c1, &["psubscribe", "news.[ae]*"]).await
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                "psubscribe".into(),
+                "news.[ae]*".into(),
+                1.into()
+            ])),
+            sub1.recv().await
+        );
+
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c2, &["publish", "news.sport", "x"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c2, &["publish", "news.art", "y"]).await
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "news.[ae]*".into(),
+                "news.art".into(),
+                "y".into(),
+            ])),
+            sub1.recv().await
+        );
+    }
+
+    #[tokio::test]
+    async fn pubsub_binary_pattern() {
+        let (mut sub1, c1) = create_connection_and_pubsub();
+        let pattern = Bytes::from_static(b"\x00\xff*");
+        let channel = Bytes::from_static(b"\x00\xff\x01");
+        let message = Bytes::from_static(b"msg");
+
+        c1.pubsub().psubscribe(vec![pattern.clone()].into(), &c1);
+        assert_eq!(
+            Some(Value::Array(vec![
+                "psubscribe".into(),
+                Value::Blob(pattern.clone()),
+                1.into()
+            ])),
+            sub1.recv().await
+        );
+
+        assert_eq!(1, c1.pubsub().publish(&channel, &message).await);
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                Value::Blob(pattern),
+                Value::Blob(channel),
+                Value::Blob(message),
+            ])),
+            sub1.recv().await
+        );
+    }
+
+    #[tokio::test]
+    async fn pubsub_numpat_distinct_patterns() {
+        let (_, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+
+        let _ = run_command(&c1, &["psubscribe", "foo*", "bar*"]).await;
+        let _ = run_command(&c2, &["psubscribe", "foo*", "foo*"]).await;
+
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c1, &["pubsub", "numpat"]).await
+        );
+    }
 }
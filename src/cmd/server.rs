@@ -1,11 +1,23 @@
 //! # Server command handlers
-use crate::{connection::Connection, error::Error, value::Value};
+use crate::{
+    connection::{connections::ReplicationState, Connection},
+    db::{snapshot, KeySample},
+    error::Error,
+    value::{bytes_to_count, bytes_to_number, Value},
+};
 use bytes::Bytes;
 use git_version::git_version;
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     time::{SystemTime, UNIX_EPOCH},
 };
+use tokio::time::{sleep, Duration};
+
+/// Number of keys sampled by MEMORY SAMPLE when no count is given
+const DEFAULT_MEMORY_SAMPLES: usize = 1000;
+
+/// Maximum number of keys sampled by MEMORY SAMPLE, bigger counts are lowered to it
+const MAX_MEMORY_SAMPLES: usize = 100_000;
 
 /// Returns Array reply of details about all Redis commands.
 pub async fn command(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
@@ -40,13 +52,31 @@ pub async fn command(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Val
             }
             Ok(Value::Array(result))
         }
+        "docs" => {
+            let commands = if args.is_empty() {
+                dispatcher.get_all_commands()
+            } else {
+                args.iter()
+                    .filter_map(|command| {
+                        dispatcher
+                            .get_handler_for_command(&String::from_utf8_lossy(command))
+                            .ok()
+                    })
+                    .collect()
+            };
+            Ok(Value::Array(
+                commands
+                    .iter()
+                    .flat_map(|command| {
+                        vec![
+                            command.name().to_lowercase().into(),
+                            command.get_command_docs(),
+                        ]
+                    })
+                    .collect(),
+            ))
+        }
         "getkeys" => {
-            if args.is_empty() {
-                return Err(Error::SubCommandNotFound(
-                    String::from_utf8_lossy(&sub_command).into(),
-                    "command".into(),
-                ));
-            }
             let command = dispatcher.get_handler(&args)?;
             let _ = args.pop_front(); // drop the function name from the list of arguments.
             Ok(Value::Array(
@@ -57,6 +87,22 @@ pub async fn command(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Val
                     .collect(),
             ))
         }
+        "getkeysandflags" => {
+            let command = dispatcher.get_handler(&args)?;
+            let _ = args.pop_front(); // drop the function name from the list of arguments.
+            Ok(Value::Array(
+                command
+                    .get_keys_and_flags(&args, false)
+                    .into_iter()
+                    .map(|(key, flags)| {
+                        Value::Array(vec![
+                            Value::Blob(key),
+                            Value::Array(flags.iter().map(|flag| (*flag).into()).collect()),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
         "help" => super::help::command(),
         cmd => Err(Error::SubCommandNotFound(cmd.into(), "command".into())),
     }
@@ -74,25 +120,189 @@ pub async fn debug(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
             .db()
             .debug(&(args.pop_front().ok_or(Error::Syntax)?))?
             .into()),
+        // There are no compact encodings, every value is stored in its full encoding, so no key
+        // is ever a listpack
+        "listpack" => {
+            conn.db().debug(&(args.pop_front().ok_or(Error::Syntax)?))?;
+            Err(Error::NotListpack)
+        }
         "set-active-expire" => Ok(Value::Ok),
+        // Crashes the handler on purpose, the dispatcher replies with an internal error
+        "panic" => panic!("DEBUG PANIC called"),
+        "sleep" => {
+            let seconds = bytes_to_number::<f64>(&args.pop_front().ok_or(Error::Syntax)?)?;
+            if !(seconds >= 0.0 && seconds < u64::MAX as f64) {
+                return Err(Error::NotANumber);
+            }
+            sleep(Duration::from_secs_f64(seconds)).await;
+            Ok(Value::Ok)
+        }
+        // Every reply sent to this connection is delayed, to test client timeouts and retries.
+        // A delay of 0 disables it.
+        "delay-reply" => {
+            let ms = bytes_to_number::<u64>(&args.pop_front().ok_or(Error::Syntax)?)?;
+            conn.set_reply_delay(if ms == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(ms))
+            });
+            Ok(Value::Ok)
+        }
         "digest-value" => Ok(Value::Array(
             conn.db().digest(&(args.into_iter().collect::<Vec<_>>()))?,
         )),
-        _ => Err(Error::Syntax),
+        cmd => Err(Error::SubCommandNotFound(cmd.into(), "debug".into())),
+    }
+}
+
+/// MEMORY SAMPLE [count] samples random keys (1000 by default, 100000 at most) and reports the
+/// biggest key of each type by estimated memory usage, a server side `redis-cli --bigkeys`. Each
+/// type is reported as `[type, sampled keys, biggest key, length, memory usage]`.
+pub async fn memory(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let sub_command = args.pop_front().ok_or(Error::Syntax)?;
+    match String::from_utf8_lossy(&sub_command)
+        .to_lowercase()
+        .as_str()
+    {
+        "sample" => {
+            let count = match args.pop_front() {
+                Some(count) => bytes_to_count(&count)?.min(MAX_MEMORY_SAMPLES),
+                None => DEFAULT_MEMORY_SAMPLES,
+            };
+            if !args.is_empty() {
+                return Err(Error::Syntax);
+            }
+
+            // The samples are aggregated as they are taken, only the biggest key of each type is
+            // kept
+            let db = conn.db();
+            let mut rng = conn.all_connections().rng();
+            let mut biggest: BTreeMap<&'static str, (usize, KeySample)> = BTreeMap::new();
+            for sample in db.sample_keys(count, &mut rng) {
+                let (sampled, current) = biggest
+                    .entry(sample.typ.name())
+                    .or_insert_with(|| (0, sample.clone()));
+                *sampled += 1;
+                if sample.memory_usage > current.memory_usage {
+                    *current = sample;
+                }
+            }
+
+            Ok(Value::Array(
+                biggest
+                    .into_iter()
+                    .map(|(typ, (sampled, sample))| {
+                        Value::Array(vec![
+                            typ.into(),
+                            sampled.into(),
+                            (&sample.key).into(),
+                            sample.length.into(),
+                            sample.memory_usage.into(),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        cmd => Err(Error::SubCommandNotFound(cmd.into(), "memory".into())),
+    }
+}
+
+/// CONFIG RELOAD loads the config file again and applies the settings which can be changed at
+/// runtime, the same as sending SIGHUP to the server.
+pub async fn config(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let sub_command = args.pop_front().ok_or(Error::Syntax)?;
+    match String::from_utf8_lossy(&sub_command)
+        .to_lowercase()
+        .as_str()
+    {
+        "reload" => {
+            conn.all_connections().reload_config().await?;
+            Ok(Value::Ok)
+        }
+        cmd => Err(Error::SubCommandNotFound(cmd.into(), "config".into())),
     }
 }
 
+/// Returns the health of the server: `ready`, `loading` or `shutting-down`.
+///
+/// Unlike PING, which only tells the server is alive, this command is allowed while the dataset
+/// is being loaded and tells whether the server is ready to serve requests.
+pub async fn healthcheck(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
+    Ok(Value::String(conn.all_connections().health().to_string()))
+}
+
 /// The INFO command returns information and statistics about the server in a
 /// format that is simple to parse by computers and easy to read by humans.
 pub async fn info(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
     let connections = conn.all_connections();
+    let persistence = match connections.loading() {
+        Some(loading) => format!(
+            "loading:1\r\nloading_start_time:{}\r\nloading_total_bytes:{}\r\nloading_loaded_bytes:{}\r\nloading_loaded_perc:{:.2}\r\nloading_eta_seconds:{}\r\n",
+            loading.started_at.as_secs(),
+            loading.total_bytes,
+            loading.loaded_bytes,
+            loading.loaded_perc(),
+            loading.eta_seconds(),
+        ),
+        None => "loading:0\r\n".to_owned(),
+    };
+    let (input_kbps, output_kbps) = connections.instantaneous_kbps();
+    let replication = match connections.replication_state() {
+        ReplicationState::Master => "role:master\r\n".to_owned(),
+        state => format!(
+            "role:slave\r\nmaster_link_status:{}\r\n",
+            if state.is_link_down() { "down" } else { "up" }
+        ),
+    };
+    let tasks = connections
+        .supervisor()
+        .health()
+        .into_iter()
+        .map(|(name, health)| {
+            format!(
+                "task_{}:running={},panics={},restarts={}\r\n",
+                name, health.running, health.panics, health.restarts
+            )
+        })
+        .collect::<String>();
+    let keyspace = connections
+        .get_databases()
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, db)| {
+            let expired = db.expired_len();
+            let keys = db.len_with_expired().saturating_sub(expired);
+            if keys == 0 {
+                return None;
+            }
+            Some(format!(
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                i,
+                keys,
+                db.expires_len().saturating_sub(expired)
+            ))
+        })
+        .collect::<String>();
     Ok(Value::Blob(
         format!(
-            "redis_version: {}\r\nredis_git_sha1:{}\r\n\r\nconnected_clients:{}\r\nblocked_clients:{}\r\n",
+            "redis_version: {}\r\nredis_git_sha1:{}\r\n\r\nconnected_clients:{}\r\nblocked_clients:{}\r\n\r\n# Persistence\r\n{}\r\n# Stats\r\ntotal_net_input_bytes:{}\r\ntotal_net_output_bytes:{}\r\ninstantaneous_input_kbps:{:.2}\r\ninstantaneous_output_kbps:{:.2}\r\nactive_defrag_running:{}\r\nactive_defrag_reclaimed_bytes:{}\r\naudit_log_dropped_records:{}\r\n\r\n# Replication\r\n{}\r\n# Tasks\r\n{}\r\n# Keyspace\r\n{}",
             git_version!(),
             git_version!(),
             connections.total_connections(),
             connections.total_blocked_connections(),
+            persistence,
+            connections.total_net_input_bytes(),
+            connections.total_net_output_bytes(),
+            input_kbps,
+            output_kbps,
+            u8::from(connections.is_active_defrag()),
+            connections.defrag_reclaimed_bytes(),
+            connections
+                .audit_log()
+                .map_or(0, |audit_log| audit_log.dropped()),
+            replication,
+            tasks,
+            keyspace,
         )
         .into(),
     ))
@@ -115,6 +325,28 @@ pub async fn flushall(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Er
     Ok(Value::Ok)
 }
 
+/// Exports all the databases to JSON, with the format documented in [`crate::db::snapshot`].
+pub async fn export(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
+    Ok(Value::Blob(
+        snapshot::export(&conn.all_connections().get_databases()).into(),
+    ))
+}
+
+/// Imports a JSON snapshot generated by EXPORT, returning the number of imported keys. Existing
+/// keys are kept unless REPLACE is given.
+pub async fn import(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let replace = match args.get(1) {
+        Some(x) if x.eq_ignore_ascii_case(b"REPLACE") => true,
+        Some(_) => return Err(Error::Syntax),
+        None => false,
+    };
+    if args.len() > 2 {
+        return Err(Error::Syntax);
+    }
+    let imported = snapshot::import(&conn.all_connections().get_databases(), &args[0], replace)?;
+    Ok(imported.into())
+}
+
 /// Return the number of keys in the currently-selected database.
 pub async fn dbsize(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
     conn.db().len().map(|s| s.into())
@@ -143,9 +375,13 @@ pub async fn quit(_: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
 mod test {
     use crate::{
         cmd::test::{create_connection, run_command},
+        config::ConfigSource,
+        connection::connections::ReplicationState,
         error::Error,
         value::Value,
     };
+    use std::convert::TryInto;
+    use tokio::time::{sleep, Duration};
 
     #[tokio::test]
     async fn digest() {
@@ -169,6 +405,114 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn debug_sleep() {
+        let c = create_connection();
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["debug", "sleep", "0.1"]).await
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            Err(Error::NotANumber),
+            run_command(&c, &["debug", "sleep", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumber),
+            run_command(&c, &["debug", "sleep", "inf"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_delay_reply() {
+        let c = create_connection();
+        assert_eq!(None, c.reply_delay());
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["debug", "delay-reply", "250"]).await
+        );
+        assert_eq!(Some(Duration::from_millis(250)), c.reply_delay());
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["debug", "delay-reply", "0"]).await
+        );
+        assert_eq!(None, c.reply_delay());
+        assert_eq!(
+            Err(Error::NotANumber),
+            run_command(&c, &["debug", "delay-reply", "-1"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_sample() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["memory", "sample"]).await
+        );
+
+        let _ = run_command(&c, &["set", "small", "x"]).await;
+        let _ = run_command(&c, &["set", "big", &"x".repeat(1000)]).await;
+        let _ = run_command(&c, &["rpush", "list", "a", "b", "c"]).await;
+        let reply: Vec<Value> = run_command(&c, &["memory", "sample", "10000"])
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(2, reply.len());
+
+        let list: Vec<Value> = reply[0].clone().try_into().unwrap();
+        assert_eq!(Value::from("list"), list[0]);
+        assert_eq!(Value::from("list"), list[2]);
+        assert_eq!(Value::Integer(3), list[3]);
+
+        let string: Vec<Value> = reply[1].clone().try_into().unwrap();
+        assert_eq!(Value::from("string"), string[0]);
+        assert_eq!(Value::from("big"), string[2]);
+        assert_eq!(Value::Integer(1000), string[3]);
+
+        assert_eq!(
+            Err(Error::NotPositive),
+            run_command(&c, &["memory", "sample", "-1"]).await
+        );
+
+        // huge counts are capped
+        let reply: Vec<Value> = run_command(&c, &["memory", "sample", "9223372036854775807"])
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let string: Vec<Value> = reply[1].clone().try_into().unwrap();
+        assert!(matches!(string[1], Value::Integer(n) if n as usize <= super::MAX_MEMORY_SAMPLES));
+    }
+
+    #[tokio::test]
+    async fn config_reload() {
+        let c = create_connection();
+        assert_eq!(
+            Err(Error::NoConfigFile),
+            run_command(&c, &["config", "reload"]).await
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "microredis-config-reload-{}.conf",
+            std::process::id()
+        ));
+        std::fs::write(&path, "multi-max-commands 10\nport 7777\n").unwrap();
+        let all_connections = c.all_connections();
+        all_connections.set_config_source(ConfigSource {
+            path: Some(path.to_string_lossy().to_string()),
+            overrides: vec!["--multi-max-bytes".to_owned(), "100".to_owned()],
+        });
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["config", "reload"]).await);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((10, 100), all_connections.multi_limits());
+        assert_eq!(6379, all_connections.config().port);
+    }
+
     #[tokio::test]
     async fn debug() {
         let c = create_connection();
@@ -179,7 +523,8 @@ mod test {
         match run_command(&c, &["debug", "object", "foo0"]).await {
             Ok(Value::Blob(s)) => {
                 let s = String::from_utf8_lossy(&s);
-                assert!(s.contains("hashtable"))
+                assert!(s.contains("hashtable"));
+                assert!(s.contains("serializedlength:16"));
             }
             _ => panic!("Unxpected response"),
         };
@@ -187,6 +532,9 @@ mod test {
             Ok(Value::Blob(s)) => {
                 let s = String::from_utf8_lossy(&s);
                 assert!(s.contains("embstr"));
+                assert!(s.contains("refcount:1"));
+                assert!(s.contains("serializedlength:3"));
+                assert!(s.contains("lru_seconds_idle:0"));
             }
             _ => panic!("Unxpected response"),
         };
@@ -204,6 +552,29 @@ mod test {
             }
             _ => panic!("Unxpected response"),
         };
+
+        assert_eq!(
+            Err(Error::NotListpack),
+            run_command(&c, &["debug", "listpack", "foo0"]).await
+        );
+        assert_eq!(
+            Err(Error::NotFound),
+            run_command(&c, &["debug", "listpack", "bar"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn panics_are_replied_as_errors() {
+        let c = create_connection();
+        assert_eq!(
+            Err(Error::Internal),
+            run_command(&c, &["debug", "panic"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "bar"]).await);
+        assert_eq!(
+            Ok(Value::Blob("bar".into())),
+            run_command(&c, &["get", "foo"]).await
+        );
     }
 
     #[tokio::test]
@@ -244,6 +615,233 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn loading() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        all_connections.start_loading(1000);
+        all_connections.set_loading_progress(250);
+
+        assert_eq!(Err(Error::Loading), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Err(Error::Loading), run_command(&c, &["ping"]).await);
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("loading:1\r\n"));
+                assert!(s.contains("loading_total_bytes:1000\r\n"));
+                assert!(s.contains("loading_loaded_bytes:250\r\n"));
+                assert!(s.contains("loading_loaded_perc:25.00\r\n"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+
+        all_connections.stop_loading();
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("loading:0\r\n"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+    }
+
+    #[tokio::test]
+    async fn healthcheck() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        assert_eq!(
+            Ok(Value::String("ready".into())),
+            run_command(&c, &["healthcheck"]).await
+        );
+        all_connections.start_loading(1000);
+        assert_eq!(
+            Ok(Value::String("loading".into())),
+            run_command(&c, &["healthcheck"]).await
+        );
+        all_connections.stop_loading();
+        all_connections.start_shutdown();
+        assert_eq!(
+            Ok(Value::String("shutting-down".into())),
+            run_command(&c, &["healthcheck"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_data() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        all_connections.set_serve_stale_data(false);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+
+        assert!(all_connections.set_replication_state(ReplicationState::Connect));
+        assert!(!all_connections.set_replication_state(ReplicationState::Connected));
+        assert_eq!(
+            Err(Error::MasterDown),
+            run_command(&c, &["get", "foo"]).await
+        );
+        // INFO is flagged as stale
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("role:slave\r\nmaster_link_status:down\r\n"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+
+        assert!(all_connections.set_replication_state(ReplicationState::Connecting));
+        assert!(all_connections.set_replication_state(ReplicationState::Transfer));
+        assert!(all_connections.set_replication_state(ReplicationState::Connected));
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+
+        // Link lost, but stale data is allowed
+        assert!(all_connections.set_replication_state(ReplicationState::Connect));
+        all_connections.set_serve_stale_data(true);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn replica_read_only() {
+        let c = create_connection();
+        let path = std::env::temp_dir().join(format!(
+            "microredis-replica-read-only-{}.conf",
+            std::process::id()
+        ));
+        std::fs::write(&path, "replica-read-only yes\n").unwrap();
+        let all_connections = c.all_connections();
+        all_connections.set_config_source(ConfigSource {
+            path: Some(path.to_string_lossy().to_string()),
+            overrides: vec![],
+        });
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["config", "reload"]).await);
+        std::fs::remove_file(&path).unwrap();
+        assert!(all_connections.config().replica_read_only);
+
+        // a master accepts writes
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "bar"]).await);
+
+        assert!(all_connections.set_replication_state(ReplicationState::Connect));
+        assert_eq!(
+            Err(Error::ReadOnly),
+            run_command(&c, &["set", "foo", "xxx"]).await
+        );
+
+        assert!(all_connections.set_replication_state(ReplicationState::Master));
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "xxx"]).await);
+    }
+
+    #[tokio::test]
+    async fn net_stats() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        all_connections.sample_net_stats();
+        c.add_net_bytes(2048, 4096);
+        sleep(Duration::from_millis(100)).await;
+        all_connections.sample_net_stats();
+
+        assert_eq!(2048, c.net_input_bytes());
+        assert_eq!(4096, c.net_output_bytes());
+        let (input_kbps, output_kbps) = all_connections.instantaneous_kbps();
+        assert!(input_kbps > 0.0 && input_kbps <= 20.0);
+        assert!(output_kbps > input_kbps);
+
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("total_net_input_bytes:2048\r\n"));
+                assert!(s.contains("total_net_output_bytes:4096\r\n"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+    }
+
+    #[tokio::test]
+    async fn export_and_import() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "string", "foo", "ex", "100"]).await;
+        let _ = run_command(&c, &["rpush", "list", "a", "b", "c"]).await;
+        let _ = run_command(&c, &["sadd", "set", "x", "y"]).await;
+        let _ = run_command(&c, &["hset", "hash", "f", "v"]).await;
+        let digest = run_command(&c, &["debug", "digest-value", "list", "hash"]).await;
+        let _ = run_command(&c, &["select", "2"]).await;
+        let _ = run_command(&c, &["set", "other", "db"]).await;
+
+        let snapshot = match run_command(&c, &["export"]).await {
+            Ok(Value::Blob(snapshot)) => String::from_utf8(snapshot.to_vec()).unwrap(),
+            x => panic!("unexpected reply {:?}", x),
+        };
+        assert!(snapshot.contains(r#""2":[{"key":"other","type":"string","value":"db"}]"#));
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["flushall"]).await);
+        assert_eq!(Ok(5.into()), run_command(&c, &["import", &snapshot]).await);
+        assert_eq!(Ok(0.into()), run_command(&c, &["import", &snapshot]).await);
+        assert_eq!(
+            Ok(5.into()),
+            run_command(&c, &["import", &snapshot, "replace"]).await
+        );
+
+        assert_eq!(Ok("db".into()), run_command(&c, &["get", "other"]).await);
+        let _ = run_command(&c, &["select", "0"]).await;
+        assert_eq!(Ok("foo".into()), run_command(&c, &["get", "string"]).await);
+        assert!(matches!(
+            run_command(&c, &["ttl", "string"]).await,
+            Ok(Value::Integer(99..=100))
+        ));
+        assert_eq!(
+            Ok(Value::Integer(-1)),
+            run_command(&c, &["ttl", "list"]).await
+        );
+        assert_eq!(
+            digest,
+            run_command(&c, &["debug", "digest-value", "list", "hash"]).await
+        );
+        assert_eq!(Ok(2.into()), run_command(&c, &["scard", "set"]).await);
+
+        assert!(matches!(
+            run_command(&c, &["import", "{}"]).await,
+            Err(Error::InvalidSnapshot(_))
+        ));
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["import", &snapshot, "foo"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn info_keyspace() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar", "ex", "100"]).await;
+        let _ = run_command(&c, &["set", "bar", "foo"]).await;
+        let _ = run_command(&c, &["set", "expired", "foo", "px", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(Ok(Value::Integer(2)), run_command(&c, &["dbsize"]).await);
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("# Keyspace\r\ndb0:keys=2,expires=1,avg_ttl=0\r\n"));
+                assert!(!s.contains("db1:"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+    }
+
+    #[tokio::test]
+    async fn info_tasks() {
+        let c = create_connection();
+        let _ = c
+            .all_connections()
+            .supervisor()
+            .spawn("faulty", async { panic!("bug") })
+            .await;
+        match run_command(&c, &["info"]).await {
+            Ok(Value::Blob(s)) => {
+                let s = String::from_utf8_lossy(&s);
+                assert!(s.contains("# Tasks\r\ntask_faulty:running=0,panics=1,restarts=0\r\n"));
+            }
+            _ => panic!("Unxpected response"),
+        };
+    }
+
     #[tokio::test]
     async fn flush() {
         let c = create_connection();
@@ -270,6 +868,110 @@ mod test {
         assert_eq!(Ok(Value::Integer(0)), run_command(&c, &["dbsize"]).await);
     }
 
+    #[tokio::test]
+    async fn command_docs() {
+        let c = create_connection();
+        let docs = match run_command(&c, &["command", "docs", "object"]).await {
+            Ok(Value::Array(docs)) => docs,
+            _ => panic!("Unxpected response"),
+        };
+        assert_eq!(Value::from("object"), docs[0]);
+        let docs = match &docs[1] {
+            Value::Array(docs) => docs,
+            _ => panic!("Unxpected response"),
+        };
+        assert_eq!(Value::from("keys"), docs[1]);
+        assert_eq!(Value::from("subcommands"), docs[8]);
+        assert_eq!(
+            Value::Array(vec![
+                "object|encoding".into(),
+                Value::Array(vec![
+                    "group".into(),
+                    "keys".into(),
+                    "arity".into(),
+                    3.into(),
+                    "flags".into(),
+                    Value::Array(vec!["readonly".into(), "random".into()]),
+                    "acl_categories".into(),
+                    Value::Array(vec!["@read".into(), "@slow".into(), "@keyspace".into()]),
+                ]),
+                "object|help".into(),
+                Value::Array(vec![
+                    "group".into(),
+                    "keys".into(),
+                    "arity".into(),
+                    2.into(),
+                    "flags".into(),
+                    Value::Array(vec!["readonly".into(), "loading".into(), "stale".into()]),
+                    "acl_categories".into(),
+                    Value::Array(vec!["@read".into(), "@slow".into(), "@keyspace".into()]),
+                ]),
+                "object|refcount".into(),
+                Value::Array(vec![
+                    "group".into(),
+                    "keys".into(),
+                    "arity".into(),
+                    3.into(),
+                    "flags".into(),
+                    Value::Array(vec!["readonly".into(), "random".into()]),
+                    "acl_categories".into(),
+                    Value::Array(vec!["@read".into(), "@slow".into(), "@keyspace".into()]),
+                ]),
+            ]),
+            docs[9]
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["command", "docs", "not-found"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn command_docs_deprecated() {
+        let c = create_connection();
+        let docs = match run_command(&c, &["command", "docs", "hmset", "hset"]).await {
+            Ok(Value::Array(docs)) => docs,
+            _ => panic!("Unxpected response"),
+        };
+        assert_eq!(
+            Value::Array(vec![
+                "doc_flags".into(),
+                Value::Array(vec!["deprecated".into()]),
+                "deprecated_since".into(),
+                "4.0.0".into(),
+                "replaced_by".into(),
+                "`HSET` with multiple field-value pairs".into(),
+            ]),
+            match &docs[1] {
+                Value::Array(docs) => Value::Array(docs[8..].to_vec()),
+                _ => panic!("Unxpected response"),
+            }
+        );
+        assert!(matches!(&docs[3], Value::Array(docs) if docs.len() == 8));
+    }
+
+    #[tokio::test]
+    async fn sub_command_flags() {
+        let c = create_connection();
+        c.all_connections().start_loading(1000);
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["client", "id"]).await
+        );
+        assert_eq!(
+            Err(Error::Loading),
+            run_command(&c, &["object", "refcount", "foo"]).await
+        );
+        assert!(run_command(&c, &["object", "help"]).await.is_ok());
+        assert_eq!(
+            Err(Error::SubCommandNotFound(
+                "refcount".to_owned(),
+                "object".to_owned()
+            )),
+            run_command(&c, &["object", "refcount"]).await
+        );
+    }
+
     #[tokio::test]
     async fn get_keys_1() {
         let c = create_connection();
@@ -293,4 +995,81 @@ mod test {
             run_command(&c, &["command", "getkeys"]).await
         );
     }
+
+    #[tokio::test]
+    async fn get_keys_3() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Array(vec!["a".into(), "b".into(), "c".into()])),
+            run_command(&c, &["command", "getkeys", "del", "a", "b", "c"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec!["a".into(), "b".into()])),
+            run_command(&c, &["command", "getkeys", "mset", "a", "1", "b", "2"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn get_keys_and_flags() {
+        let c = create_connection();
+        let key = |name: &str, flags: &[&str]| {
+            Value::Array(vec![
+                name.into(),
+                Value::Array(flags.iter().map(|flag| (*flag).into()).collect()),
+            ])
+        };
+        assert_eq!(
+            Ok(Value::Array(vec![
+                key("src", &["RW", "access", "delete"]),
+                key("dst", &["RW", "insert"]),
+            ])),
+            run_command(
+                &c,
+                &[
+                    "command",
+                    "getkeysandflags",
+                    "lmove",
+                    "src",
+                    "dst",
+                    "left",
+                    "left"
+                ]
+            )
+            .await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                key("dst", &["OW", "update"]),
+                key("a", &["RO", "access"]),
+                key("b", &["RO", "access"]),
+            ])),
+            run_command(
+                &c,
+                &["command", "getkeysandflags", "sunionstore", "dst", "a", "b"]
+            )
+            .await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                key("a", &["RW", "access", "delete"]),
+                key("b", &["OW", "update"]),
+            ])),
+            run_command(&c, &["command", "getkeysandflags", "rename", "a", "b"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                key("a", &["RW", "access", "delete"]),
+                key("b", &["OW", "insert"]),
+            ])),
+            run_command(&c, &["command", "getkeysandflags", "renamenx", "a", "b"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![key("foo", &["RO", "access"])])),
+            run_command(&c, &["command", "getkeysandflags", "get", "foo"]).await
+        );
+        assert_eq!(
+            Err(Error::InvalidArgsCount("GET".to_owned())),
+            run_command(&c, &["command", "getkeysandflags", "get"]).await
+        );
+    }
 }
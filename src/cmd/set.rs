@@ -1,7 +1,13 @@
 //! # Set command handlers
-use crate::{connection::Connection, error::Error, value::bytes_to_number, value::Value};
+use super::take_numkeys;
+use crate::{
+    connection::{connections::Connections, Connection},
+    db::random::{sample, sample_len, Generator},
+    error::Error,
+    value::Value,
+    value::{bytes_to_count, bytes_to_int},
+};
 use bytes::Bytes;
-use rand::Rng;
 use std::{
     cmp::min,
     collections::{HashSet, VecDeque},
@@ -107,42 +113,16 @@ where
 /// specified members.
 pub async fn sadd(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let key = args.pop_front().ok_or(Error::Syntax)?;
-    let key_for_not_found = key.clone();
-    let result = conn
-        .db()
-        .get(&key)
-        .map_mut(|v| match v {
-            Value::Set(x) => {
-                let mut len = 0;
-
-                for val in args.clone().into_iter() {
-                    if x.insert(val) {
-                        len += 1;
-                    }
-                }
-
-                Ok(len.into())
-            }
-            _ => Err(Error::WrongType),
-        })
-        .unwrap_or_else(|| {
-            #[allow(clippy::mutable_key_type)]
-            let mut x = HashSet::new();
-            let mut len = 0;
-
-            for val in args.into_iter() {
-                if x.insert(val) {
-                    len += 1;
-                }
-            }
-
-            conn.db().set(key_for_not_found, x.into(), None);
-            Ok(len.into())
-        })?;
-
-    conn.db().bump_version(&key);
-
-    Ok(result)
+    let db = conn.db();
+    let mut entry = db
+        .entry(key)
+        .or_insert_with(|| HashSet::<Bytes>::new().into());
+    let added = entry.modify(|v| match v {
+        Value::Set(x) => Ok(args.into_iter().filter(|val| x.insert(val.clone())).count()),
+        _ => Err(Error::WrongType),
+    })?;
+
+    Ok(added.unwrap_or_default().into())
 }
 
 /// Returns the set cardinality (number of elements) of the set stored at key.
@@ -217,12 +197,32 @@ pub async fn sinter(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
 /// Keys that do not exist are considered to be empty sets. With one of the keys being an empty
 /// set, the resulting set is also empty (since set intersection with an empty set always results
 /// in an empty set).
-pub async fn sintercard(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    if let Ok(Value::Array(x)) = sinter(conn, args).await {
-        Ok(x.len().into())
+///
+/// By default the command calculates the cardinality of the intersection of all given sets. When
+/// provided with the optional LIMIT argument (which defaults to 0 and means unlimited), the
+/// reported cardinality is capped to the given limit.
+pub async fn sintercard(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let keys = take_numkeys(&mut args)?;
+    let mut limit = 0;
+    while let Some(option) = args.pop_front() {
+        if !option.eq_ignore_ascii_case(b"LIMIT") {
+            return Err(Error::Syntax);
+        }
+        let value = args.pop_front().ok_or(Error::Syntax)?;
+        limit = bytes_to_count(&value).map_err(|_| Error::NegativeLimit)?;
+    }
+
+    let cardinality = match sinter(conn, keys).await? {
+        Value::Array(x) => x.len(),
+        _ => 0,
+    };
+
+    Ok(if limit > 0 {
+        min(cardinality, limit)
     } else {
-        Ok(0.into())
+        cardinality
     }
+    .into())
 }
 
 /// This command is equal to SINTER, but instead of returning the resulting set, it is stored in
@@ -313,6 +313,7 @@ pub async fn smove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
     let destination = args.pop_front().ok_or(Error::Syntax)?;
     let member = args.pop_front().ok_or(Error::Syntax)?;
     let mut to_insert = None;
+    let mut source_changed = false;
     let result = conn
         .db()
         .get(&source)
@@ -334,7 +335,7 @@ pub async fn smove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
                             if set2.insert(member.clone()) {
                                 Ok(1.into())
                             } else {
-                                conn.db().bump_version(&source);
+                                source_changed = true;
                                 Ok(0.into())
                             }
                         }
@@ -355,10 +356,15 @@ pub async fn smove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
     if let Some(x) = to_insert {
         conn.db().set(destination.clone(), x.into(), None);
     }
+    if source_changed || result == Value::Integer(1) {
+        conn.db().remove_if_empty(&source);
+    }
 
     if let Value::Integer(1) = result {
         conn.db().bump_version(&source);
         conn.db().bump_version(&destination);
+    } else if source_changed {
+        conn.db().bump_version(&source);
     }
 
     Ok(result)
@@ -373,39 +379,46 @@ pub async fn smove(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value
 /// count argument, the reply will consist of up to count members, depending on the set's
 /// cardinality.
 pub async fn spop(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let rand = srandmember(conn, args.clone()).await?;
+    let empty = match args.get(1) {
+        Some(count) => {
+            bytes_to_count(count)?;
+            Value::Array(vec![])
+        }
+        None => Value::Null,
+    };
     let key = args.pop_front().ok_or(Error::Syntax)?;
-    let mut should_remove = false;
-    let result = conn
-        .db()
-        .get(&key)
-        .map_mut(|v| match v {
-            Value::Set(x) => {
-                match &rand {
-                    Value::Blob(value) => {
-                        x.remove(value.as_ref());
-                    }
-                    Value::Array(values) => {
-                        for value in values.iter() {
-                            if let Value::Blob(value) = value {
-                                x.remove(value.as_ref());
-                            }
+    let db = conn.db();
+    let all_connections = conn.all_connections();
+    let mut rng = all_connections.rng();
+    let mut entry = db.entry(key);
+    // The members are picked and removed while holding the key, so concurrent SPOPs never pop the
+    // same member
+    let (result, is_empty) = match entry.modify(|v| match v {
+        Value::Set(x) => {
+            let rand = pick_random_members(x, args.front(), &all_connections, &mut rng)?;
+            match &rand {
+                Value::Blob(value) => {
+                    x.remove(value.as_ref());
+                }
+                Value::Array(values) => {
+                    for value in values.iter() {
+                        if let Value::Blob(value) = value {
+                            x.remove(value.as_ref());
                         }
                     }
-                    _ => unreachable!(),
-                };
-
-                should_remove = x.is_empty();
-                Ok(rand)
-            }
-            _ => Err(Error::WrongType),
-        })
-        .unwrap_or(Ok(Value::Null))?;
+                }
+                _ => {}
+            };
+            Ok((rand, x.is_empty()))
+        }
+        _ => Err(Error::WrongType),
+    })? {
+        Some(result) => result,
+        None => return Ok(empty),
+    };
 
-    if should_remove {
-        let _ = conn.db().del(&[key]);
-    } else {
-        conn.db().bump_version(&key);
+    if is_empty {
+        entry.remove();
     }
 
     Ok(result)
@@ -425,56 +438,13 @@ pub async fn srandmember(conn: &Connection, args: VecDeque<Bytes>) -> Result<Val
         .get(&args[0])
         .map(|v| match v {
             Value::Set(set) => {
-                let mut rng = rand::thread_rng();
-
-                let mut items = set
-                    .iter()
-                    .map(|x| (x, rng.gen()))
-                    .collect::<Vec<(&Bytes, i128)>>();
-
-                items.sort_by(|a, b| a.1.cmp(&b.1));
-
-                if args.len() == 1 {
-                    // Two arguments provided, return the first element or null if the array is null
-                    if items.is_empty() {
-                        Ok(Value::Null)
-                    } else {
-                        let item = items[0].0.clone();
-                        Ok(Value::new(&item))
-                    }
-                } else {
-                    if items.is_empty() {
-                        return Ok(Value::Array(vec![]));
-                    }
-                    let len = bytes_to_number::<i64>(&args[1])?;
-
-                    if len > 0 {
-                        // required length is positive, return *up* to the requested number and no duplicated allowed
-                        let len: usize = min(items.len(), len as usize);
-                        Ok(items[0..len]
-                            .iter()
-                            .map(|item| Value::new(item.0))
-                            .collect::<Vec<Value>>()
-                            .into())
-                    } else {
-                        // duplicated results are allowed and the requested number must be returned
-                        let len = -len as usize;
-                        let total = items.len() - 1;
-                        let mut i = 0;
-                        let items = (0..len)
-                            .map(|_| {
-                                let r = (items[i].0, rng.gen());
-                                i = if i >= total { 0 } else { i + 1 };
-                                r
-                            })
-                            .collect::<Vec<(&Bytes, i128)>>();
-                        Ok(items
-                            .iter()
-                            .map(|item| Value::new(item.0))
-                            .collect::<Vec<Value>>()
-                            .into())
-                    }
-                }
+                let all_connections = conn.all_connections();
+                pick_random_members(
+                    set,
+                    args.get(1),
+                    &all_connections,
+                    &mut all_connections.rng(),
+                )
             }
             _ => Err(Error::WrongType),
         })
@@ -485,33 +455,62 @@ pub async fn srandmember(conn: &Connection, args: VecDeque<Bytes>) -> Result<Val
         }))
 }
 
+/// Picks random members of a set, following the `count` semantics of SRANDMEMBER. The number of
+/// members is checked against max-reply-elements before picking them.
+#[allow(clippy::mutable_key_type)]
+fn pick_random_members(
+    set: &HashSet<Bytes>,
+    count: Option<&Bytes>,
+    all_connections: &Connections,
+    rng: &mut Generator,
+) -> Result<Value, Error> {
+    let len = match count {
+        Some(count) => Some(bytes_to_int::<i64>(count)?),
+        None => None,
+    };
+    if len == Some(i64::MIN) {
+        return Err(Error::NotANumberType("an integer".to_owned()));
+    }
+    if let Some(len) = len {
+        all_connections.check_reply_elements(sample_len(set.len(), len))?;
+    }
+    let members = set.iter().collect::<Vec<_>>();
+    Ok(match len {
+        Some(len) => sample(members, len, rng)
+            .into_iter()
+            .map(|member| Value::new(member))
+            .collect::<Vec<Value>>()
+            .into(),
+        None => sample(members, 1, rng)
+            .first()
+            .map_or(Value::Null, |member| Value::new(member)),
+    })
+}
+
 /// Remove the specified members from the set stored at key. Specified members that are not a
 /// member of this set are ignored. If key does not exist, it is treated as an empty set and this
 /// command returns 0.
 pub async fn srem(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let key = args.pop_front().ok_or(Error::Syntax)?;
-    let result = conn
-        .db()
-        .get(&key)
-        .map_mut(|v| match v {
-            Value::Set(set) => {
-                let mut i = 0;
-
-                args.into_iter().for_each(|value| {
-                    if set.remove(&value) {
-                        i += 1;
-                    }
-                });
-
-                Ok(i.into())
+    let db = conn.db();
+    let mut entry = db.entry(key);
+    let removed = entry.modify(|v| match v {
+        Value::Set(set) => Ok((
+            args.into_iter().filter(|value| set.remove(value)).count(),
+            set.is_empty(),
+        )),
+        _ => Err(Error::WrongType),
+    })?;
+
+    Ok(match removed {
+        Some((removed, is_empty)) => {
+            if is_empty {
+                entry.remove();
             }
-            _ => Err(Error::WrongType),
-        })
-        .unwrap_or(Ok(0.into()))?;
-
-    conn.db().bump_version(&key);
-
-    Ok(result)
+            removed.into()
+        }
+        None => 0.into(),
+    })
 }
 
 /// Returns the members of the set resulting from the union of all the given sets.
@@ -704,7 +703,49 @@ mod test {
 
         assert_eq!(
             Ok(Value::Integer(1)),
-            run_command(&c, &["sintercard", "1", "2", "3"]).await
+            run_command(&c, &["sintercard", "3", "1", "2", "3"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["sintercard", "2", "1", "3", "limit", "0"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["sintercard", "2", "1", "3", "LIMIT", "1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["sintercard", "2", "1", "unknown"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn sintercard_syntax() {
+        let c = create_connection();
+
+        assert_eq!(
+            Err(Error::NumKeysNotPositive),
+            run_command(&c, &["sintercard", "0", "1"]).await
+        );
+        assert_eq!(
+            Err(Error::NumKeysNotPositive),
+            run_command(&c, &["sintercard", "foo", "1"]).await
+        );
+        assert_eq!(
+            Err(Error::NumKeysTooBig),
+            run_command(&c, &["sintercard", "3", "1", "2"]).await
+        );
+        assert_eq!(
+            Err(Error::NegativeLimit),
+            run_command(&c, &["sintercard", "1", "1", "limit", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["sintercard", "1", "1", "limit"]).await
+        );
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["sintercard", "1", "1", "2"]).await
         );
     }
 
@@ -810,6 +851,16 @@ mod test {
             Ok(Value::Integer(1)),
             run_command(&c, &["scard", "2"]).await
         );
+
+        let _ = run_command(&c, &["sadd", "3", "a"]).await;
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["smove", "3", "2", "a"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["exists", "3"]).await
+        );
     }
 
     #[tokio::test]
@@ -838,6 +889,89 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn pop_count() {
+        let c = create_connection();
+
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["spop", "1", "2"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["sadd", "1", "a", "b"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![])),
+            run_command(&c, &["spop", "1", "0"]).await
+        );
+        assert_eq!(
+            Err(Error::NotPositive),
+            run_command(&c, &["spop", "1", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumberType("an integer".to_owned())),
+            run_command(&c, &["srandmember", "unknown", "x"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumberType("an integer".to_owned())),
+            run_command(&c, &["srandmember", "1", "-9223372036854775808"]).await
+        );
+        assert!(matches!(
+            run_command(&c, &["srandmember", "1", "-3"]).await,
+            Ok(Value::Array(x)) if x.len() == 3
+        ));
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            run_command(&c, &["scard", "1"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn max_reply_elements() {
+        let c = create_connection();
+        let _ = run_command(&c, &["sadd", "set", "a", "b", "c", "d", "e"]).await;
+        // replies are capped by default
+        assert_eq!(
+            Err(Error::ReplyTooLarge(
+                crate::config::DEFAULT_MAX_REPLY_ELEMENTS
+            )),
+            run_command(&c, &["srandmember", "set", "-2000000"]).await
+        );
+
+        c.all_connections().set_max_reply_elements(3);
+
+        assert_eq!(
+            Err(Error::ReplyTooLarge(3)),
+            run_command(&c, &["srandmember", "set", "-4"]).await
+        );
+        assert_eq!(
+            Err(Error::ReplyTooLarge(3)),
+            run_command(&c, &["srandmember", "set", "10"]).await
+        );
+        assert_eq!(
+            Err(Error::ReplyTooLarge(3)),
+            run_command(&c, &["spop", "set", "4"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(5)),
+            run_command(&c, &["scard", "set"]).await
+        );
+        assert!(matches!(
+            run_command(&c, &["srandmember", "set", "-3"]).await,
+            Ok(Value::Array(x)) if x.len() == 3
+        ));
+        assert!(matches!(
+            run_command(&c, &["spop", "set", "3"]).await,
+            Ok(Value::Array(x)) if x.len() == 3
+        ));
+        // the reply is bounded by the cardinality of the set
+        assert!(matches!(
+            run_command(&c, &["srandmember", "set", "10"]).await,
+            Ok(Value::Array(x)) if x.len() == 2
+        ));
+    }
+
     #[tokio::test]
     async fn srem() {
         let c = create_connection();
@@ -925,4 +1059,24 @@ mod test {
             }
         );
     }
+
+    #[tokio::test]
+    async fn seeded_random_picks() {
+        let mut picks = vec![];
+        for members in [["a", "b", "c", "d", "e"], ["e", "d", "c", "b", "a"]] {
+            let c = create_connection();
+            c.all_connections().set_random_seed(42);
+            let mut args = vec!["sadd", "set"];
+            args.extend(members.iter());
+            let _ = run_command(&c, &args).await;
+            let _ = run_command(&c, &["set", "foo", "bar"]).await;
+
+            picks.push(vec![
+                run_command(&c, &["srandmember", "set", "-10"]).await,
+                run_command(&c, &["spop", "set", "2"]).await,
+                run_command(&c, &["randomkey"]).await,
+            ]);
+        }
+        assert_eq!(picks[0], picks[1]);
+    }
 }
@@ -1,19 +1,15 @@
 //! # String command handlers
 
 use crate::{
-    check_arg,
+    cmd::parse_option,
     connection::Connection,
-    db::utils::Override,
+    db::utils::{Number, Override},
     error::Error,
-    value::{bytes_to_number, expiration::Expiration, float::Float, Value},
+    protocol::PROTO_MAX_BULK_LEN,
+    value::{bytes_to_number, expiration::Expiration, float::Float, range::resolve_range, Value},
 };
 use bytes::Bytes;
-use std::{
-    cmp::min,
-    collections::VecDeque,
-    convert::TryInto,
-    ops::{Bound, Deref, Neg},
-};
+use std::{collections::VecDeque, convert::TryInto, mem::size_of, time::Duration};
 
 /// If key already exists and is a string, this command appends the value at the
 /// end of the string. If key does not exist it is created and set as an empty
@@ -35,26 +31,20 @@ pub async fn incr(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
 /// type or contains a string that can not be represented as integer. This operation is limited to
 /// 64 bit signed integers.
 pub async fn incr_by(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let by: i64 = bytes_to_number(&args[1])?;
+    let by = i64::parse(&args[1])?;
     conn.db().incr(&args[0], by).map(|n| n.into())
 }
 
 /// Increment the string representing a floating point number stored at key by the specified
 /// increment. By using a negative increment value, the result is that the value stored at the key
 /// is decremented (by the obvious properties of addition). If the key does not exist, it is set to
-/// 0 before performing the operation.
+/// 0 before performing the operation. The maths are done with 64 bits floats, so integers bigger
+/// than 2^53 are not exact, see [`Float`].
 pub async fn incr_by_float(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let by = bytes_to_number::<Float>(&args[1])?;
-    if by.is_infinite() || by.is_nan() {
-        return Err(Error::IncrByInfOrNan);
-    }
-    conn.db().incr(&args[0], by).map(|f| {
-        if f.fract() == 0.0 {
-            (*f as i64).into()
-        } else {
-            f.to_string().into()
-        }
-    })
+    let by = Float::parse(&args[1])?;
+    conn.db()
+        .incr(&args[0], by)
+        .map(|f| Value::Blob(f.to_string().into()))
 }
 
 /// Decrements the number stored at key by one. If the key does not exist, it is set to 0 before
@@ -70,114 +60,107 @@ pub async fn decr(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
 /// type or contains a string that can not be represented as integer. This operation is limited to
 /// 64 bit signed integers.
 pub async fn decr_by(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let by: i64 = (&Value::new(&args[1])).try_into()?;
-    conn.db().incr(&args[0], by.neg()).map(|n| n.into())
+    let by = i64::parse(&args[1])?;
+    conn.db()
+        .incr(&args[0], by.checked_neg().ok_or(Error::Overflow)?)
+        .map(|n| n.into())
 }
 
 /// Get the value of key. If the key does not exist the special value nil is returned. An error is
 /// returned if the value stored at key is not a string, because GET only handles string values.
 pub async fn get(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    Ok(conn.db().get(&args[0]).into_inner())
+    let db = conn.db();
+    db.load_missing(&args[0]).await;
+    db.get(&args[0]).into_inner()
 }
 
 /// Get the value of key and optionally set its expiration. GETEX is similar to
 /// GET, but is a write command with additional options.
-pub async fn getex(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let (expires_in, persist) = match args.len() {
-        1 => (None, false),
-        2 => {
-            if check_arg!(args, 1, "PERSIST") {
-                (None, true)
-            } else {
-                return Err(Error::Syntax);
+///
+/// The EX, PX, EXAT, PXAT and PERSIST options are mutually exclusive. A
+/// timestamp in the past expires the key right after returning its value.
+pub async fn getex(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let command = b"GETEX";
+    let key = args.pop_front().ok_or(Error::Syntax)?;
+    let mut expiration = None;
+    let mut persist = false;
+
+    while let Some(arg) = args.pop_front() {
+        if expiration.is_some() || persist {
+            return Err(Error::Syntax);
+        }
+        let (is_milliseconds, is_absolute) = match parse_option(
+            &arg,
+            &[
+                ("PERSIST", None),
+                ("EX", Some((false, false))),
+                ("PX", Some((true, false))),
+                ("EXAT", Some((false, true))),
+                ("PXAT", Some((true, true))),
+            ],
+        )? {
+            Some(expiration) => expiration,
+            None => {
+                persist = true;
+                continue;
             }
+        };
+        let time = args.pop_front().ok_or(Error::Syntax)?;
+        if bytes_to_number::<i64>(&time)? <= 0 {
+            return Err(Error::InvalidExpire("getex".to_owned()));
         }
-        3 => match String::from_utf8_lossy(&args[1]).to_uppercase().as_str() {
-            "EX" => (
-                Some(Expiration::new(&args[2], false, false, b"GETEX")?),
-                false,
-            ),
-            "PX" => (
-                Some(Expiration::new(&args[2], true, false, b"GETEX")?),
-                false,
-            ),
-            "EXAT" => (
-                Some(Expiration::new(&args[2], false, true, b"GETEX")?),
-                false,
-            ),
-            "PXAT" => (
-                Some(Expiration::new(&args[2], true, true, b"GETEX")?),
-                false,
-            ),
-            "PERSIST" => (None, Default::default()),
-            _ => return Err(Error::Syntax),
-        },
-        _ => return Err(Error::Syntax),
+        expiration = Some(Expiration::new(
+            &time,
+            is_milliseconds,
+            is_absolute,
+            command,
+        )?);
+    }
+
+    let expires_in = match expiration {
+        Some(expiration) if expiration.is_negative => Some(Duration::ZERO),
+        Some(expiration) => Some(expiration.try_into()?),
+        None => None,
     };
-    Ok(conn.db().getex(
-        &args[0],
-        expires_in.map(|t| t.try_into()).transpose()?,
-        persist,
-    ))
+
+    conn.db().getex(&key, expires_in, persist)
 }
 
 /// Get the value of key. If the key does not exist the special value nil is returned. An error is
 /// returned if the value stored at key is not a string, because GET only handles string values.
 pub async fn getrange(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    let bytes = if let Some(value) = conn.db().get(&args[0]).inner() {
-        match value.deref() {
-            Value::Blob(binary) => binary.clone(),
-            Value::BlobRw(binary) => binary.clone().freeze(),
-            Value::Null => return Ok("".into()),
-            _ => return Err(Error::WrongType),
-        }
-    } else {
-        return Ok("".into());
+    let db = conn.db();
+    let value = db.get(&args[0]);
+    let value = match value.inner() {
+        Some(value) => value,
+        None => return Ok("".into()),
+    };
+    let bytes = match value.as_bytes() {
+        Some(bytes) => bytes,
+        None if *value == Value::Null => return Ok("".into()),
+        None => return Err(Error::WrongType),
     };
 
     let start = bytes_to_number::<i64>(&args[1])?;
     let end = bytes_to_number::<i64>(&args[2])?;
-    let len = bytes.len();
 
-    // resolve negative positions
-    let start: usize = if start < 0 {
-        (start + len as i64).try_into().unwrap_or(0)
-    } else {
-        start.try_into().expect("Positive number")
-    };
-
-    // resolve negative positions
-    let end: usize = if end < 0 {
-        if let Ok(val) = (end + len as i64).try_into() {
-            val
-        } else {
-            return Ok("".into());
-        }
-    } else {
-        end.try_into().expect("Positive number")
-    };
-    let end = min(end, len.checked_sub(1).unwrap_or_default());
-
-    if end < start {
-        return Ok("".into());
-    }
-
-    Ok(Value::Blob(
-        bytes.slice((Bound::Included(start), Bound::Included(end))),
-    ))
+    Ok(match resolve_range(start, end, bytes.len()) {
+        Some(range) => Value::new(&bytes[range]),
+        None => "".into(),
+    })
 }
 
 /// Get the value of key and delete the key. This command is similar to GET, except for the fact
 /// that it also deletes the key on success (if and only if the key's value type is a string).
 pub async fn getdel(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    Ok(conn.db().getdel(&args[0]))
+    conn.db().getdel(&args[0])
 }
 
 /// Atomically sets key to value and returns the old value stored at key. Returns an error when key
 /// exists but does not hold a string value. Any previous time to live associated with the key is
 /// discarded on successful SET operation.
 pub async fn getset(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
-    Ok(conn.db().getset(&args[0], Value::new(&args[1])))
+    conn.db().getset(&args[0], Value::Blob(args[1].clone()))
 }
 
 /// Returns the values of all specified keys. For every key that does not hold a string value or
@@ -186,6 +169,29 @@ pub async fn mget(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Err
     Ok(conn.db().get_multi(args))
 }
 
+/// Options of SET
+#[derive(Clone, Copy)]
+enum SetOption {
+    /// An expiration time, flagged as (in milliseconds, absolute)
+    Expire(bool, bool),
+    KeepTtl,
+    Nx,
+    Xx,
+    Get,
+}
+
+/// Names of the SET options
+const SET_OPTIONS: &[(&str, SetOption)] = &[
+    ("EX", SetOption::Expire(false, false)),
+    ("PX", SetOption::Expire(true, false)),
+    ("EXAT", SetOption::Expire(false, true)),
+    ("PXAT", SetOption::Expire(true, true)),
+    ("KEEPTTL", SetOption::KeepTtl),
+    ("NX", SetOption::Nx),
+    ("XX", SetOption::Xx),
+    ("GET", SetOption::Get),
+];
+
 /// Set key to hold the string value. If key already holds a value, it is overwritten, regardless
 /// of its type. Any previous time to live associated with the key is discarded on successful SET
 /// operation.
@@ -199,63 +205,23 @@ pub async fn set(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value,
     let key = args.pop_front().ok_or(Error::Syntax)?;
     let value = args.pop_front().ok_or(Error::Syntax)?;
 
-    loop {
-        let arg = if let Some(arg) = args.pop_front() {
-            String::from_utf8_lossy(&arg).to_uppercase()
-        } else {
-            break;
-        };
-
-        match arg.as_str() {
-            "EX" => {
-                if expiration.is_some() {
-                    return Err(Error::Syntax);
-                }
-                expiration = Some(Expiration::new(
-                    &args.pop_front().ok_or(Error::Syntax)?,
-                    false,
-                    false,
-                    command,
-                )?);
-            }
-            "PX" => {
-                if expiration.is_some() {
-                    return Err(Error::Syntax);
-                }
-                expiration = Some(Expiration::new(
-                    &args.pop_front().ok_or(Error::Syntax)?,
-                    true,
-                    false,
-                    command,
-                )?);
-            }
-            "EXAT" => {
+    while let Some(arg) = args.pop_front() {
+        match parse_option(&arg, SET_OPTIONS)? {
+            SetOption::Expire(is_milliseconds, is_absolute) => {
                 if expiration.is_some() {
                     return Err(Error::Syntax);
                 }
                 expiration = Some(Expiration::new(
                     &args.pop_front().ok_or(Error::Syntax)?,
-                    false,
-                    true,
+                    is_milliseconds,
+                    is_absolute,
                     command,
                 )?);
             }
-            "PXAT" => {
-                if expiration.is_some() {
-                    return Err(Error::Syntax);
-                }
-                expiration = Some(Expiration::new(
-                    &args.pop_front().ok_or(Error::Syntax)?,
-                    true,
-                    true,
-                    command,
-                )?);
-            }
-            "KEEPTTL" => keep_ttl = true,
-            "NX" => override_value = Override::No,
-            "XX" => override_value = Override::Only,
-            "GET" => return_previous = true,
-            _ => return Err(Error::Syntax),
+            SetOption::KeepTtl => keep_ttl = true,
+            SetOption::Nx => override_value = Override::No,
+            SetOption::Xx => override_value = Override::Only,
+            SetOption::Get => return_previous = true,
         }
     }
     Ok(
@@ -266,7 +232,7 @@ pub async fn set(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value,
             override_value,
             keep_ttl,
             return_previous,
-        ) {
+        )? {
             Value::Integer(1) => Value::Ok,
             Value::Integer(0) => Value::Null,
             any_return => any_return,
@@ -346,20 +312,18 @@ pub async fn psetex(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
 pub async fn setnx(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
     let key = args.pop_front().ok_or(Error::Syntax)?;
     let value = args.pop_front().ok_or(Error::Syntax)?;
-    Ok(conn
-        .db()
-        .set_advanced(key, Value::Blob(value), None, Override::No, false, false))
+    conn.db()
+        .set_advanced(key, Value::Blob(value), None, Override::No, false, false)
 }
 
 /// Returns the length of the string value stored at key. An error is returned when key holds a
 /// non-string value.
 pub async fn strlen(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
     if let Some(value) = conn.db().get(&args[0]).inner() {
-        match value.deref() {
-            Value::Blob(x) => Ok(x.len().into()),
-            Value::String(x) => Ok(x.len().into()),
-            Value::Null => Ok(0.into()),
-            _ => Ok(Error::WrongType.into()),
+        match value.as_bytes() {
+            Some(x) => Ok(x.len().into()),
+            None if *value == Value::Null => Ok(0.into()),
+            None => Err(Error::WrongType),
         }
     } else {
         Ok(0.into())
@@ -372,9 +336,197 @@ pub async fn strlen(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, E
 /// make offset fit. Non-existing keys are considered as empty strings, so this
 /// command will make sure it holds a string large enough to be able to set
 /// value at offset.
+///
+/// The growth of the string is checked against maxmemory before allocating it.
 pub async fn setrange(conn: &Connection, args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let offset: i128 = bytes_to_number(&args[1])?;
+    conn.db().set_range(
+        &args[0],
+        offset,
+        &args[2],
+        conn.all_connections().memory_budget(),
+    )
+}
+
+/// Options of LCS
+#[derive(Clone, Copy)]
+enum LcsOption {
+    Len,
+    Idx,
+    MinMatchLen,
+    WithMatchLen,
+}
+
+const LCS_OPTIONS: &[(&str, LcsOption)] = &[
+    ("LEN", LcsOption::Len),
+    ("IDX", LcsOption::Idx),
+    ("MINMATCHLEN", LcsOption::MinMatchLen),
+    ("WITHMATCHLEN", LcsOption::WithMatchLen),
+];
+
+/// Returns a copy of the string stored at key. Keys that do not exist are empty strings.
+fn get_string(conn: &Connection, key: &Bytes) -> Result<Vec<u8>, Error> {
     conn.db()
-        .set_range(&args[0], bytes_to_number(&args[1])?, &args[2])
+        .get(key)
+        .map(|value| match value.as_bytes() {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None if *value == Value::Null => Ok(vec![]),
+            None => Err(Error::WrongType),
+        })
+        .unwrap_or_else(|| Ok(vec![]))
+}
+
+/// Returns the length of the longest common subsequence of `a` and `b`. Only two rows of the
+/// table are kept, so the memory used grows with the length of `b` alone.
+fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+    let mut previous = vec![0; b.len() + 1];
+    let mut current = vec![0; b.len() + 1];
+
+    for x in a.iter() {
+        for (j, y) in b.iter().enumerate() {
+            current[j + 1] = if x == y {
+                previous[j] + 1
+            } else {
+                previous[j + 1].max(current[j])
+            };
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Returns the length of the longest common subsequence of every pair of prefixes of `a` and
+/// `b`, as a table of `a.len() + 1` rows of `b.len() + 1` cells. The table grows with the product
+/// of both lengths, so it is refused if it would take more than proto-max-bulk-len bytes.
+fn lcs_table(a: &[u8], b: &[u8]) -> Result<Vec<u32>, Error> {
+    let cells = (a.len() + 1)
+        .checked_mul(b.len() + 1)
+        .filter(|cells| {
+            (*cells as u64).saturating_mul(size_of::<u32>() as u64) <= PROTO_MAX_BULK_LEN as u64
+        })
+        .ok_or(Error::LcsTooLarge)?;
+    let width = b.len() + 1;
+    let mut table = vec![0; cells];
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    Ok(table)
+}
+
+/// Returns the longest common subsequence of `a` and `b`, or the matched ranges of both strings
+/// with `get_idx`. See [`lcs`].
+fn lcs_matches(
+    a: &[u8],
+    b: &[u8],
+    get_idx: bool,
+    min_match_len: usize,
+    with_match_len: bool,
+) -> Result<Value, Error> {
+    let table = lcs_table(a, b)?;
+    let width = b.len() + 1;
+    let len = table[table.len() - 1] as usize;
+
+    // The table is walked back from the end of both strings, collecting the LCS from its last
+    // byte and the ranges of contiguous matches, as (start in a, start in b, length)
+    let mut lcs = vec![0; len];
+    let mut matches = vec![];
+    let mut range: Option<(usize, usize, usize)> = None;
+    let mut emit_range = |range: Option<(usize, usize, usize)>| {
+        if let Some((a_start, b_start, len)) = range.filter(|(_, _, len)| *len >= min_match_len) {
+            let mut matched = vec![
+                Value::Array(vec![a_start.into(), (a_start + len - 1).into()]),
+                Value::Array(vec![b_start.into(), (b_start + len - 1).into()]),
+            ];
+            if with_match_len {
+                matched.push(len.into());
+            }
+            matches.push(Value::Array(matched));
+        }
+    };
+    let (mut i, mut j, mut left) = (a.len(), b.len(), len);
+
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            i -= 1;
+            j -= 1;
+            left -= 1;
+            lcs[left] = a[i];
+            range = Some((i, j, range.map_or(1, |(_, _, len)| len + 1)));
+            continue;
+        }
+
+        if table[(i - 1) * width + j] > table[i * width + j - 1] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+        emit_range(range.take());
+    }
+    emit_range(range);
+
+    Ok(if get_idx {
+        Value::Array(vec![
+            "matches".into(),
+            Value::Array(matches),
+            "len".into(),
+            len.into(),
+        ])
+    } else {
+        Value::new(&lcs)
+    })
+}
+
+/// Implements the longest common subsequence algorithm over the strings stored at two keys. Keys
+/// that do not exist are considered empty strings.
+///
+/// The LCS itself is returned, or its length with LEN. IDX returns the matched ranges of both
+/// strings instead, from the last to the first, along with the length of the LCS. Ranges shorter
+/// than MINMATCHLEN are omitted, and WITHMATCHLEN adds the length of each range.
+///
+/// The work grows with the product of the lengths of both strings, so it runs on the blocking
+/// thread pool instead of stalling the other connections. LEN only needs the length, which is
+/// computed without the full table, so it is not bound by proto-max-bulk-len.
+pub async fn lcs(conn: &Connection, mut args: VecDeque<Bytes>) -> Result<Value, Error> {
+    let key1 = args.pop_front().ok_or(Error::Syntax)?;
+    let key2 = args.pop_front().ok_or(Error::Syntax)?;
+    let (mut get_len, mut get_idx, mut with_match_len, mut min_match_len) =
+        (false, false, false, 0);
+
+    while let Some(arg) = args.pop_front() {
+        match parse_option(&arg, LCS_OPTIONS)? {
+            LcsOption::Len => get_len = true,
+            LcsOption::Idx => get_idx = true,
+            LcsOption::WithMatchLen => with_match_len = true,
+            LcsOption::MinMatchLen => {
+                let len = bytes_to_number::<i64>(&args.pop_front().ok_or(Error::Syntax)?)?;
+                min_match_len = len.max(0) as usize;
+            }
+        }
+    }
+    if get_len && get_idx {
+        return Err(Error::OptsNotCompatible("LEN and IDX".to_owned()));
+    }
+
+    let a = get_string(conn, &key1)?;
+    let b = get_string(conn, &key2)?;
+    tokio::task::spawn_blocking(move || {
+        if get_len {
+            Ok(lcs_len(&a, &b).into())
+        } else {
+            lcs_matches(&a, &b, get_idx, min_match_len, with_match_len)
+        }
+    })
+    .await
+    .map_err(|_| Error::Internal)?
 }
 
 #[cfg(test)]
@@ -382,8 +534,9 @@ mod test {
     use crate::{
         cmd::test::{create_connection, run_command},
         error::Error,
-        value::Value,
+        value::{Protocol, Value},
     };
+    use tokio::time::{sleep, Duration};
 
     #[tokio::test]
     async fn append() {
@@ -416,6 +569,31 @@ mod test {
         assert_eq!(Ok(Value::Blob("2".into())), x);
     }
 
+    #[tokio::test]
+    async fn incr_rejects_non_canonical_integers() {
+        let c = create_connection();
+        let not_an_integer = Err(Error::NotANumberType("an integer".to_owned()));
+        for value in [" 11", "11 ", "+10", "010", "-0"].iter() {
+            let _ = run_command(&c, &["set", "foo", value]).await;
+            assert_eq!(not_an_integer, run_command(&c, &["incr", "foo"]).await);
+            assert_eq!(not_an_integer, run_command(&c, &["decr", "foo"]).await);
+            assert_eq!(
+                Ok(Value::Blob(value.to_string().into())),
+                run_command(&c, &["get", "foo"]).await
+            );
+        }
+
+        let _ = run_command(&c, &["set", "foo", "1"]).await;
+        assert_eq!(
+            not_an_integer,
+            run_command(&c, &["incrby", "foo", "+10"]).await
+        );
+        assert_eq!(
+            Err(Error::NotANumberType("an integer".to_owned())),
+            run_command(&c, &["expire", "foo", " 10"]).await
+        );
+    }
+
     #[tokio::test]
     async fn incr_do_not_affect_ttl() {
         let c = create_connection();
@@ -435,6 +613,35 @@ mod test {
         assert_eq!(Ok(Value::Integer(60)), r);
     }
 
+    #[tokio::test]
+    async fn incr_by_float() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Blob("10.5".into())),
+            run_command(&c, &["incrbyfloat", "foo", "10.5"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("10.6".into())),
+            run_command(&c, &["incrbyfloat", "foo", "0.1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("11".into())),
+            run_command(&c, &["incrbyfloat", "foo", "0.4"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("5011".into())),
+            run_command(&c, &["incrbyfloat", "foo", "5.0e3"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("5011".into())),
+            run_command(&c, &["get", "foo"]).await
+        );
+        assert_eq!(
+            Err(Error::IncrByInfOrNan),
+            run_command(&c, &["incrbyfloat", "foo", "inf"]).await
+        );
+    }
+
     #[tokio::test]
     async fn decr() {
         let c = create_connection();
@@ -661,9 +868,218 @@ mod test {
             run_command(&c, &["getrange", "foo", "0", "0"]).await
         );
 
+        assert_eq!(
+            Ok("this is a long string".into()),
+            run_command(
+                &c,
+                &[
+                    "getrange",
+                    "foo",
+                    "-9223372036854775808",
+                    "9223372036854775807"
+                ]
+            )
+            .await
+        );
+
         assert_eq!(Ok(Value::Null), run_command(&c, &["get", "fox"]).await);
     }
 
+    #[tokio::test]
+    async fn getrange_empty_string() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", ""]).await;
+
+        assert_eq!(
+            Ok("".into()),
+            run_command(&c, &["getrange", "foo", "0", "0"]).await
+        );
+        assert_eq!(
+            Ok("".into()),
+            run_command(&c, &["getrange", "foo", "-1", "-1"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn substr() {
+        let c = create_connection();
+        let x = run_command(&c, &["set", "foo", "this is a long string"]).await;
+        assert_eq!(Ok(Value::Ok), x);
+        let _ = run_command(&c, &["rpush", "list", "a"]).await;
+
+        for (key, start, end) in [
+            ("foo", "0", "-4"),
+            ("foo", "-4", "-1"),
+            ("foo", "-4", "1"),
+            ("foo", "-400", "1000000"),
+            ("foo", "0", "0"),
+            ("foo", "a", "0"),
+            ("missing", "0", "-1"),
+            ("list", "0", "-1"),
+        ] {
+            assert_eq!(
+                run_command(&c, &["getrange", key, start, end]).await,
+                run_command(&c, &["substr", key, start, end]).await,
+            );
+        }
+
+        assert_eq!(
+            Ok("ring".into()),
+            run_command(&c, &["substr", "foo", "-4", "-1"]).await
+        );
+        assert_eq!(
+            Err(Error::InvalidArgsCount("SUBSTR".to_owned())),
+            run_command(&c, &["substr", "foo", "0"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn getex_ex_and_px() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "ex", "10"]).await
+        );
+        assert_eq!(Ok(10.into()), run_command(&c, &["ttl", "foo"]).await);
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "px", "20000"]).await
+        );
+        assert_eq!(Ok(20.into()), run_command(&c, &["ttl", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn getex_exat_and_pxat() {
+        let c = create_connection();
+        let now = crate::cmd::now().as_secs();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "exat", &(now + 11).to_string()]).await
+        );
+        match run_command(&c, &["ttl", "foo"]).await {
+            Ok(Value::Integer(ttl)) => assert!((9..=11).contains(&ttl)),
+            r => panic!("Unexpected response {:?}", r),
+        };
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(
+                &c,
+                &["getex", "foo", "pxat", &((now + 21) * 1000).to_string()]
+            )
+            .await
+        );
+        match run_command(&c, &["ttl", "foo"]).await {
+            Ok(Value::Integer(ttl)) => assert!((19..=21).contains(&ttl)),
+            r => panic!("Unexpected response {:?}", r),
+        };
+    }
+
+    #[tokio::test]
+    async fn getex_timestamp_in_the_past() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "exat", "1"]).await
+        );
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn getdel_wrong_type() {
+        let c = create_connection();
+        let _ = run_command(&c, &["hset", "foo", "f1", "1"]).await;
+        let _ = run_command(&c, &["expire", "foo", "100"]).await;
+
+        assert_eq!(
+            Err(Error::WrongType),
+            run_command(&c, &["getdel", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Blob("1".into())),
+            run_command(&c, &["hget", "foo", "f1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(100)),
+            run_command(&c, &["ttl", "foo"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn getex_persist() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar", "ex", "10"]).await;
+        assert_eq!(Ok(10.into()), run_command(&c, &["ttl", "foo"]).await);
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "persist"]).await
+        );
+        assert_eq!(Ok((-1).into()), run_command(&c, &["ttl", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn getex_no_option() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(Ok("bar".into()), run_command(&c, &["getex", "foo"]).await);
+        assert_eq!(Ok((-1).into()), run_command(&c, &["ttl", "foo"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["getex", "bar"]).await);
+    }
+
+    #[tokio::test]
+    async fn getex_syntax_errors() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        for args in [
+            vec!["getex", "foo", "non-existent-option"],
+            vec!["getex", "foo", "ex"],
+            vec!["getex", "foo", "ex", "10", "persist"],
+            vec!["getex", "foo", "persist", "ex", "10"],
+            vec!["getex", "foo", "ex", "10", "px", "10000"],
+            vec!["getex", "foo", "ex", "10", "ex", "10"],
+            vec!["getex", "foo", "persist", "persist"],
+        ] {
+            assert_eq!(Err(Error::Syntax), run_command(&c, &args).await);
+        }
+        assert_eq!(Ok((-1).into()), run_command(&c, &["ttl", "foo"]).await);
+        assert_eq!(
+            Err(Error::InvalidArgsCount("GETEX".to_owned())),
+            run_command(&c, &["getex"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn getex_invalid_expire() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        for args in [
+            vec!["getex", "foo", "ex", "-9223372036854775808"],
+            vec!["getex", "foo", "ex", "9223372036854775807"],
+            vec!["getex", "foo", "px", "0"],
+            vec!["getex", "foo", "exat", "-1"],
+        ] {
+            assert_eq!(
+                Err(Error::InvalidExpire("getex".to_owned())),
+                run_command(&c, &args).await
+            );
+        }
+        assert_eq!(Ok((-1).into()), run_command(&c, &["ttl", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn getex_expired_key() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        assert_eq!(
+            Ok("bar".into()),
+            run_command(&c, &["getex", "foo", "px", "1"]).await
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        assert_eq!(Ok(Value::Null), run_command(&c, &["getex", "foo"]).await);
+    }
+
     #[tokio::test]
     async fn getdel() {
         let c = create_connection();
@@ -695,6 +1111,82 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn getset_and_set_get_parity() {
+        let commands: [fn(&'static str, &'static str) -> Vec<&'static str>; 2] = [
+            |key, value| vec!["getset", key, value],
+            |key, value| vec!["set", key, value, "get"],
+        ];
+
+        for cmd in commands.iter() {
+            let c = create_connection();
+
+            // missing key
+            assert_eq!(Ok(Value::Null), run_command(&c, &cmd("foo", "1")).await);
+            assert_eq!(Ok("1".into()), run_command(&c, &["get", "foo"]).await);
+
+            // the TTL is discarded
+            let _ = run_command(&c, &["expire", "foo", "60"]).await;
+            assert_eq!(Ok("1".into()), run_command(&c, &cmd("foo", "2")).await);
+            assert_eq!(Ok("2".into()), run_command(&c, &["get", "foo"]).await);
+            assert_eq!(
+                Ok(Value::Integer(-1)),
+                run_command(&c, &["ttl", "foo"]).await
+            );
+
+            // expired keys are missing
+            let _ = run_command(&c, &["pexpire", "foo", "1"]).await;
+            sleep(Duration::from_millis(10)).await;
+            assert_eq!(Ok(Value::Null), run_command(&c, &cmd("foo", "3")).await);
+            assert_eq!(Ok("3".into()), run_command(&c, &["get", "foo"]).await);
+
+            // wrong type, nothing is written
+            let _ = run_command(&c, &["hset", "hash", "f", "v"]).await;
+            let _ = run_command(&c, &["expire", "hash", "60"]).await;
+            assert_eq!(
+                Err(Error::WrongType),
+                run_command(&c, &cmd("hash", "1")).await
+            );
+            assert_eq!(
+                Ok(Value::Array(vec!["f".into(), "v".into()])),
+                run_command(&c, &["hgetall", "hash"]).await
+            );
+            assert_eq!(
+                Ok(Value::Integer(60)),
+                run_command(&c, &["ttl", "hash"]).await
+            );
+
+            let _ = run_command(&c, &["rpush", "list", "a", "b"]).await;
+            let _ = run_command(&c, &["expire", "list", "60"]).await;
+            assert_eq!(
+                Err(Error::WrongType),
+                run_command(&c, &cmd("list", "1")).await
+            );
+            assert_eq!(
+                Ok(Value::Array(vec!["a".into(), "b".into()])),
+                run_command(&c, &["lrange", "list", "0", "-1"]).await
+            );
+            assert_eq!(
+                Ok(Value::Integer(60)),
+                run_command(&c, &["ttl", "list"]).await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn set_get_keepttl() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "1", "ex", "60"]).await;
+        assert_eq!(
+            Ok("1".into()),
+            run_command(&c, &["set", "foo", "2", "get", "keepttl"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(60)),
+            run_command(&c, &["ttl", "foo"]).await
+        );
+    }
+
     #[tokio::test]
     async fn strlen() {
         let c = create_connection();
@@ -708,6 +1200,50 @@ mod test {
         assert_eq!(Ok(Value::Integer(0)), x);
     }
 
+    #[tokio::test]
+    async fn string_values() {
+        let c = create_connection();
+        let set_string = |key: &str, value: &str| {
+            c.db()
+                .set(key.to_owned().into(), Value::String(value.to_owned()), None);
+        };
+
+        set_string("foo", "hello world");
+        assert_eq!(Ok(11.into()), run_command(&c, &["strlen", "foo"]).await);
+        assert_eq!(
+            Ok("world".into()),
+            run_command(&c, &["getrange", "foo", "6", "-1"]).await
+        );
+        assert_eq!(
+            Ok(11.into()),
+            run_command(&c, &["setrange", "foo", "0", "HELLO"]).await
+        );
+        assert_eq!(
+            Ok(12.into()),
+            run_command(&c, &["append", "foo", "!"]).await
+        );
+        assert_eq!(
+            Ok("HELLO world!".into()),
+            run_command(&c, &["getrange", "foo", "0", "-1"]).await
+        );
+
+        set_string("num", "41");
+        assert_eq!(Ok(42.into()), run_command(&c, &["incr", "num"]).await);
+        assert_eq!(Ok(2.into()), run_command(&c, &["strlen", "num"]).await);
+    }
+
+    #[tokio::test]
+    async fn strlen_after_setrange() {
+        let c = create_connection();
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+        let _ = run_command(&c, &["setrange", "foo", "3", "foo"]).await;
+        assert_eq!(Ok(6.into()), run_command(&c, &["strlen", "foo"]).await);
+        assert_eq!(
+            Ok("rfo".into()),
+            run_command(&c, &["getrange", "foo", "2", "4"]).await
+        );
+    }
+
     #[tokio::test]
     async fn setex() {
         let c = create_connection();
@@ -726,19 +1262,40 @@ mod test {
         let _ = run_command(&c, &["incr", "foo"]).await;
 
         let x = run_command(&c, &["strlen", "xxx"]).await;
-        assert_eq!(Ok(Error::WrongType.into()), x);
+        assert_eq!(Err(Error::WrongType), x);
 
         let x = run_command(&c, &["get", "xxx"]).await;
-        assert_eq!(Ok(Error::WrongType.into()), x);
+        assert_eq!(Err(Error::WrongType), x);
 
-        let x = run_command(&c, &["get", "xxx"]).await;
-        assert_eq!(Ok(Error::WrongType.into()), x);
+        let x = run_command(&c, &["getex", "xxx", "ex", "10"]).await;
+        assert_eq!(Err(Error::WrongType), x);
+        assert_eq!(Ok((-1).into()), run_command(&c, &["ttl", "xxx"]).await);
 
         let x = run_command(&c, &["mget", "xxx", "foo"]).await;
         assert_eq!(
             Ok(Value::Array(vec![Value::Null, Value::Blob("1".into()),])),
             x
         );
+
+        let x = run_command(&c, &["getdel", "xxx"]).await;
+        assert_eq!(Err(Error::WrongType), x);
+    }
+
+    #[tokio::test]
+    async fn wrong_type_in_transaction() {
+        let c = create_connection();
+        let _ = run_command(&c, &["hset", "xxx", "key", "foo"]).await;
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "xxx"]).await);
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["strlen", "xxx"]).await);
+        assert_eq!(
+            Ok(Value::Array(vec![
+                Error::WrongType.into(),
+                Error::WrongType.into()
+            ])),
+            run_command(&c, &["exec"]).await
+        );
     }
 
     #[tokio::test]
@@ -764,6 +1321,53 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn set_range_maxmemory() {
+        let c = create_connection();
+        c.all_connections().set_maxmemory(10 * 1024 * 1024);
+        let _ = run_command(&c, &["set", "foo", "bar"]).await;
+
+        assert_eq!(
+            Err(Error::OutOfMemory),
+            run_command(&c, &["setrange", "foo", "20000000", "x"]).await,
+        );
+        assert_eq!(
+            Err(Error::OutOfMemory),
+            run_command(&c, &["setrange", "bar", "20000000", "x"]).await,
+        );
+        assert_eq!(
+            Ok(Value::Blob("bar".into())),
+            run_command(&c, &["get", "foo"]).await,
+        );
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "bar"]).await);
+
+        assert_eq!(
+            Ok(2_000_001.into()),
+            run_command(&c, &["setrange", "foo", "2000000", "x"]).await,
+        );
+        assert_eq!(
+            Ok(2_000_001.into()),
+            run_command(&c, &["strlen", "foo"]).await,
+        );
+
+        // once maxmemory is reached, every command which may grow the data is rejected
+        c.all_connections().set_maxmemory(1024 * 1024);
+        assert_eq!(
+            Err(Error::OutOfMemory),
+            run_command(&c, &["set", "bar", "x"]).await,
+        );
+        assert_eq!(
+            Err(Error::OutOfMemory),
+            run_command(&c, &["append", "foo", "x"]).await,
+        );
+        assert_eq!(
+            Ok(2_000_001.into()),
+            run_command(&c, &["strlen", "foo"]).await,
+        );
+        assert_eq!(Ok(1.into()), run_command(&c, &["del", "foo"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "bar", "x"]).await);
+    }
+
     #[tokio::test]
     async fn test_set_px() {
         let c = create_connection();
@@ -781,4 +1385,133 @@ mod test {
             run_command(&c, &["set", "foo", "bar", "EX", "10000000000000000"]).await
         );
     }
+
+    #[tokio::test]
+    async fn lcs() {
+        let c = create_connection();
+        let _ = run_command(&c, &["mset", "key1", "ohmytext", "key2", "mynewtext"]).await;
+
+        assert_eq!(
+            Ok(Value::Blob("mytext".into())),
+            run_command(&c, &["lcs", "key1", "key2"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(6)),
+            run_command(&c, &["lcs", "key1", "key2", "len"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                "matches".into(),
+                Value::Array(vec![
+                    Value::Array(vec![
+                        Value::Array(vec![4.into(), 7.into()]),
+                        Value::Array(vec![5.into(), 8.into()]),
+                    ]),
+                    Value::Array(vec![
+                        Value::Array(vec![2.into(), 3.into()]),
+                        Value::Array(vec![0.into(), 1.into()]),
+                    ]),
+                ]),
+                "len".into(),
+                6.into(),
+            ])),
+            run_command(&c, &["lcs", "key1", "key2", "idx"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                "matches".into(),
+                Value::Array(vec![Value::Array(vec![
+                    Value::Array(vec![4.into(), 7.into()]),
+                    Value::Array(vec![5.into(), 8.into()]),
+                    4.into(),
+                ])]),
+                "len".into(),
+                6.into(),
+            ])),
+            run_command(
+                &c,
+                &[
+                    "lcs",
+                    "key1",
+                    "key2",
+                    "idx",
+                    "minmatchlen",
+                    "4",
+                    "withmatchlen"
+                ]
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn lcs_resp3() {
+        let c = create_connection();
+        c.set_protocol(Protocol::Resp3);
+        let _ = run_command(&c, &["mset", "idx", "ohmytext", "key2", "mynewtext"]).await;
+
+        assert_eq!(
+            Ok(Value::Blob("mytext".into())),
+            run_command(&c, &["lcs", "idx", "key2"]).await
+        );
+        assert!(matches!(
+            run_command(&c, &["lcs", "idx", "key2", "idx"]).await,
+            Ok(Value::MapReply(x)) if x.len() == 4
+        ));
+    }
+
+    #[tokio::test]
+    async fn lcs_errors() {
+        let c = create_connection();
+        assert_eq!(
+            Ok(Value::Blob("".into())),
+            run_command(&c, &["lcs", "key1", "key2"]).await
+        );
+        assert_eq!(
+            Err(Error::OptsNotCompatible("LEN and IDX".to_owned())),
+            run_command(&c, &["lcs", "key1", "key2", "len", "idx"]).await
+        );
+        assert_eq!(
+            Err(Error::Syntax),
+            run_command(&c, &["lcs", "key1", "key2", "minmatchlen"]).await
+        );
+
+        let _ = run_command(&c, &["sadd", "set", "foo"]).await;
+        assert_eq!(
+            Err(Error::WrongType),
+            run_command(&c, &["lcs", "key1", "set"]).await
+        );
+
+        // the table would take more than proto-max-bulk-len bytes, but LEN does not need it
+        let _ = run_command(&c, &["setrange", "key1", "11599", "a"]).await;
+        let _ = run_command(&c, &["setrange", "key2", "11599", "a"]).await;
+        assert_eq!(
+            Err(Error::LcsTooLarge),
+            run_command(&c, &["lcs", "key1", "key2"]).await
+        );
+        assert_eq!(
+            Err(Error::LcsTooLarge),
+            run_command(&c, &["lcs", "key1", "key2", "idx"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(11600)),
+            run_command(&c, &["lcs", "key1", "key2", "len"]).await
+        );
+    }
+
+    #[test]
+    fn lcs_len() {
+        for (a, b) in [
+            ("ohmytext", "mynewtext"),
+            ("", "foo"),
+            ("abc", "abc"),
+            ("abcbdab", "bdcaba"),
+        ] {
+            let table = super::lcs_table(a.as_bytes(), b.as_bytes()).unwrap();
+            assert_eq!(
+                table[table.len() - 1] as usize,
+                super::lcs_len(a.as_bytes(), b.as_bytes())
+            );
+        }
+    }
 }
@@ -27,6 +27,10 @@ pub async fn multi(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error
 ///
 /// When using WATCH, EXEC will execute commands only if the watched keys were not modified,
 /// allowing for a check-and-set mechanism.
+///
+/// If any command failed to be queued (unknown command, wrong number of arguments, etc) the
+/// transaction is discarded and an EXECABORT error is returned. Errors while executing the queued
+/// commands do not stop the transaction, they are returned as error elements of the reply array.
 pub async fn exec(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error> {
     match conn.status() {
         ConnectionStatus::Multi => Ok(()),
@@ -42,16 +46,32 @@ pub async fn exec(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error>
         return Ok(Value::Null);
     }
 
-    let db = conn.db();
-    let locked_keys = conn.get_tx_keys();
-
-    db.lock_keys(&locked_keys);
+    // Keys are locked in every involved database, always in the same order (sorted by the database
+    // index) to avoid deadlocks between concurrent transactions.
+    let databases = conn.all_connections().get_databases();
+    let locked_keys = conn
+        .get_tx_keys()
+        .into_iter()
+        .map(|(db, keys)| Ok((databases.get(db)?.set_conn_id(conn.id()), keys)))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    for (db, keys) in locked_keys.iter() {
+        db.lock_keys(keys);
+    }
 
     let mut results = vec![];
 
     if let Some(commands) = conn.get_queue_commands() {
         let dispatcher = conn.all_connections().get_dispatcher();
-        for args in commands.into_iter() {
+        for (db, args) in commands.into_iter() {
+            if conn.current_db() != db {
+                // The command was queued after a SELECT, it must be executed against the database
+                // selected at queue time.
+                if let Err(err) = conn.selectdb(db) {
+                    results.push(err.into());
+                    continue;
+                }
+            }
             let result = dispatcher
                 .execute(conn, args)
                 .await
@@ -60,7 +80,9 @@ pub async fn exec(conn: &Connection, _: VecDeque<Bytes>) -> Result<Value, Error>
         }
     }
 
-    db.unlock_keys(&locked_keys);
+    for (db, keys) in locked_keys.iter() {
+        db.unlock_keys(keys);
+    }
     let _ = conn.stop_transaction();
 
     Ok(results.into())
@@ -97,6 +119,7 @@ mod test {
     use crate::dispatcher::Dispatcher;
     use crate::{
         cmd::test::{create_connection, run_command},
+        connection::ConnectionStatus,
         error::Error,
         value::Value,
     };
@@ -179,6 +202,51 @@ mod test {
         assert_eq!(Ok(Value::Null), run_command(&c, &["exec"]).await);
     }
 
+    #[tokio::test]
+    async fn test_exec_select() {
+        let c = create_connection();
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "db0"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["select", "1"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "db1"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["select", "99"]).await);
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(
+            Ok(Value::Array(vec![
+                Value::Ok,
+                Value::Ok,
+                Value::Ok,
+                Error::NotSuchDatabase.into(),
+                Value::Blob("db1".into()),
+            ])),
+            run_command(&c, &["exec"]).await
+        );
+
+        // The connection keeps the database selected inside the transaction
+        assert_eq!(1, c.current_db());
+        assert_eq!(Ok("db1".into()), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["select", "0"]).await);
+        assert_eq!(Ok("bar".into()), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn test_discard_select() {
+        let c = create_connection();
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["select", "1"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["discard"]).await);
+        assert_eq!(0, c.current_db());
+    }
+
     #[test]
     fn test_extract_keys() {
         assert_eq!(vec!["foo"], get_keys(&["get", "foo"]));
@@ -188,6 +256,15 @@ mod test {
             vec!["key", "key1", "key2"],
             get_keys(&["SINTERSTORE", "key", "key1", "key2"])
         );
+        assert_eq!(vec!["a", "b"], get_keys(&["mset", "a", "1", "b", "2"]));
+        assert_eq!(vec!["foo"], get_keys(&["lpop", "foo"]));
+        assert_eq!(vec!["foo"], get_keys(&["lpop", "foo", "2"]));
+        assert_eq!(
+            vec!["a", "b"],
+            get_keys(&["sintercard", "2", "a", "b", "LIMIT", "1"])
+        );
+        assert_eq!(vec!["a"], get_keys(&["sintercard", "10", "a"]));
+        assert!(get_keys(&["sintercard", "x", "a"]).is_empty());
     }
 
     #[tokio::test]
@@ -292,6 +369,193 @@ mod test {
         assert_eq!(Err(Error::NotInTx), run_command(&c, &["exec"]).await,);
     }
 
+    #[tokio::test]
+    async fn test_exec_queue_time_errors_abort() {
+        let c = create_connection();
+
+        // MULTI; BADCMD; EXEC
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(
+            Err(Error::CommandNotFound("BADCMD".to_owned())),
+            run_command(&c, &["badcmd"]).await
+        );
+        // commands are still validated once the transaction failed
+        assert_eq!(
+            Err(Error::CommandNotFound("BADCMD".to_owned())),
+            run_command(&c, &["badcmd"]).await
+        );
+        assert_eq!(
+            Err(Error::InvalidArgsCount("GET".to_owned())),
+            run_command(&c, &["get"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+
+        // unknown sub-commands are detected at queue time too
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Err(Error::SubCommandNotFound(
+                "foo".to_owned(),
+                "config".to_owned()
+            )),
+            run_command(&c, &["config", "foo"]).await
+        );
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+        assert_eq!(ConnectionStatus::Normal, c.status());
+    }
+
+    #[tokio::test]
+    async fn test_exec_wrong_arity_abort() {
+        let c = create_connection();
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "foo"]).await
+        );
+        assert_eq!(
+            Err(Error::InvalidArgsCount("GET".to_owned())),
+            run_command(&c, &["get", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn test_exec_runtime_errors() {
+        let c = create_connection();
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["incr", "foo"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["lpush", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["incr", "bar"]).await);
+        assert_eq!(
+            Ok(Value::Array(vec![
+                Value::Ok,
+                Error::NotANumberType("an integer".to_owned()).into(),
+                Error::WrongType.into(),
+                1.into(),
+            ])),
+            run_command(&c, &["exec"]).await
+        );
+        assert_eq!(Ok("bar".into()), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Ok("1".into()), run_command(&c, &["get", "bar"]).await);
+    }
+
+    #[tokio::test]
+    async fn test_multi_max_commands() {
+        let c = create_connection();
+        c.all_connections().set_multi_limits(2, 0);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(
+            Err(Error::TxTooLarge("multi-max-commands".to_owned())),
+            run_command(&c, &["get", "foo"]).await
+        );
+        assert_eq!(Ok(Value::Queued), run_command(&c, &["get", "foo"]).await);
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+
+        // the limit is per transaction
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![Value::Ok])),
+            run_command(&c, &["exec"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_max_bytes() {
+        let c = create_connection();
+        c.all_connections().set_multi_limits(0, 16);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(
+            Err(Error::TxTooLarge("multi-max-bytes".to_owned())),
+            run_command(&c, &["set", "foo", "foobar"]).await
+        );
+        assert_eq!(Err(Error::TxAborted), run_command(&c, &["exec"]).await);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["discard"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "foo", "bar"]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_destroy_releases_tx_locks() {
+        let c1 = create_connection();
+        let all_connections = c1.all_connections();
+        let (_, c2) = all_connections.new_connection(c1.db(), "127.0.0.1:8081");
+
+        assert_eq!(Ok(Value::Ok), run_command(&c1, &["watch", "bar"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c1, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c1, &["set", "foo", "bar"]).await
+        );
+        // the connection dies while executing the transaction, holding the locks
+        c1.db().lock_keys(&["foo".into(), "bar".into()]);
+        assert_eq!(2, c2.db().locked_keys());
+
+        c1.clone().destroy();
+
+        assert_eq!(0, c2.db().locked_keys());
+        assert_eq!(ConnectionStatus::Normal, c1.status());
+        assert!(!c1.did_keys_change());
+        assert_eq!(Ok(Value::Null), run_command(&c2, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn test_unlock_orphan_keys() {
+        let c1 = create_connection();
+        let all_connections = c1.all_connections();
+        let (_, c2) = all_connections.new_connection(c1.db(), "127.0.0.1:8081");
+
+        c1.db().lock_keys(&["foo".into()]);
+        c2.db().lock_keys(&["bar".into()]);
+        assert_eq!(0, all_connections.unlock_orphan_keys());
+
+        // abrupt disconnection, the connection is gone without releasing its locks
+        all_connections.remove(c1.clone());
+        assert_eq!(1, all_connections.unlock_orphan_keys());
+        assert_eq!(1, c2.db().locked_keys());
+        assert_eq!(Ok(Value::Null), run_command(&c2, &["get", "foo"]).await);
+    }
+
     fn get_keys(args: &[&str]) -> Vec<Bytes> {
         let args: VecDeque<Bytes> = args.iter().map(|s| Bytes::from(s.to_string())).collect();
         let d = Dispatcher::new();
@@ -1,16 +1,55 @@
 //! # Redis Config parsing
 //!
 //! This module loads and parses the config, compatible with Redis format, to run the service
-use crate::error::Error;
-use redis_config_parser::de::from_slice;
-use serde::Deserialize;
+use crate::{error::Error, quota::QuotaRule};
+use redis_config_parser::{de::from_slice, parser};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
 use serde_enum_str::Deserialize_enum_str;
+use std::{convert::TryFrom, fmt, net::IpAddr, path::Path};
 use strum_macros::Display;
 
+/// All the config keys, including aliases, that are understood. Any other key is ignored.
+const KNOWN_KEYS: &[&str] = &[
+    "daemonize",
+    "port",
+    "bind",
+    "loglevel",
+    "logfile",
+    "databases",
+    "unixsocket",
+    "replica-read-only",
+    "slave-read-only",
+    "replica-serve-stale-data",
+    "slave-serve-stale-data",
+    "pipeline-yield-threshold",
+    "tcp-backlog",
+    "tcp-nodelay",
+    "multi-max-commands",
+    "multi-max-bytes",
+    "activedefrag",
+    "client-query-buffer-limit",
+    "maxmemory",
+    "max-blocking-time",
+    "max-reply-elements",
+    "random-seed",
+    "federation-peers",
+    "namespace-quotas",
+    "preload",
+    "audit-log",
+    "audit-log-max-size",
+    "always-show-logo",
+    "notify-keyspace-events",
+];
+
 /// Config
 ///
-/// Holds the parsed configuration to start the service
-#[derive(Deserialize, Debug, Clone)]
+/// Holds the parsed configuration to start the service. Any missing setting takes its default
+/// value, which is the same as in the redis.conf shipped with Redis.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct Config {
     /// Run the server as a deamon
     pub daemonize: bool,
@@ -25,16 +64,519 @@ pub struct Config {
     pub databases: u8,
     /// Unix socket
     pub unixsocket: Option<String>,
+    /// Reject all commands that may modify the database while the server is a
+    /// replica. A master always accepts them.
+    #[serde(rename = "replica-read-only", alias = "slave-read-only", default)]
+    pub replica_read_only: bool,
+    /// Whether a replica serves data while the link with its master is down
+    #[serde(
+        rename = "replica-serve-stale-data",
+        alias = "slave-serve-stale-data",
+        default = "default_true"
+    )]
+    pub replica_serve_stale_data: bool,
+    /// How many pipelined commands a connection executes before yielding to
+    /// other connections. Zero disables it.
+    #[serde(
+        rename = "pipeline-yield-threshold",
+        default = "default_pipeline_yield_threshold"
+    )]
+    pub pipeline_yield_threshold: usize,
+    /// Size of the TCP listen backlog
+    #[serde(rename = "tcp-backlog", default = "default_tcp_backlog")]
+    pub tcp_backlog: u32,
+    /// Disable Nagle's algorithm (TCP_NODELAY) on the accepted sockets
+    #[serde(rename = "tcp-nodelay", default = "default_true")]
+    pub tcp_nodelay: bool,
+    /// Maximum number of commands a transaction may queue. Zero means no limit.
+    #[serde(rename = "multi-max-commands", default)]
+    pub multi_max_commands: usize,
+    /// Maximum number of bytes, counting all arguments, a transaction may
+    /// queue. Zero means no limit.
+    #[serde(rename = "multi-max-bytes", default)]
+    pub multi_max_bytes: usize,
+    /// Release the unused memory of the databases in the background, while the
+    /// server is idle
+    #[serde(default)]
+    pub activedefrag: bool,
+    /// Maximum size of the unparsed input of a client, the client is disconnected when it is
+    /// reached. Zero means no limit.
+    #[serde(
+        rename = "client-query-buffer-limit",
+        default = "default_client_query_buffer_limit",
+        deserialize_with = "deserialize_memory"
+    )]
+    pub client_query_buffer_limit: usize,
+    /// Maximum memory the databases may use. Once it is reached, commands which may grow the
+    /// data (those flagged `denyoom`) are rejected. Zero means no limit.
+    #[serde(default, deserialize_with = "deserialize_memory")]
+    pub maxmemory: usize,
+    /// Maximum time, in milliseconds, BLPOP, BRPOP, BLMOVE and BRPOPLPUSH may block a client,
+    /// including blocking forever with a zero timeout. Zero means no limit.
+    #[serde(rename = "max-blocking-time", default)]
+    pub max_blocking_time: u64,
+    /// Maximum number of elements of the replies of SRANDMEMBER, SPOP, HRANDFIELD and LPOS with
+    /// COUNT. Bigger replies are rejected with an error. Zero means no limit.
+    #[serde(rename = "max-reply-elements", default = "default_max_reply_elements")]
+    pub max_reply_elements: usize,
+    /// Seed of the random picks of RANDOMKEY, SRANDMEMBER, SPOP and HRANDFIELD, to make test
+    /// runs reproducible. Zero seeds them from the OS.
+    #[serde(rename = "random-seed", default)]
+    pub random_seed: u64,
+    /// Peers, as `host:port`, every PUBLISH is forwarded to. It is read at startup.
+    #[serde(rename = "federation-peers", default)]
+    pub federation_peers: Vec<String>,
+    /// Quotas of the namespaces, as `<prefix> <max-keys> <max-memory>` triplets, see
+    /// [`crate::quota`]
+    #[serde(
+        rename = "namespace-quotas",
+        default,
+        deserialize_with = "deserialize_quotas"
+    )]
+    pub namespace_quotas: Vec<QuotaRule>,
+    /// File of commands, RESP encoded or inline, replayed at startup before accepting connections
+    #[serde(default)]
+    pub preload: Option<String>,
+    /// Record every write command to this file, or to a pubsub channel with `pubsub:<channel>`
+    #[serde(rename = "audit-log", default)]
+    pub audit_log: Option<String>,
+    /// The audit log file is rotated once it is bigger than this. Zero means no rotation.
+    #[serde(
+        rename = "audit-log-max-size",
+        default = "default_audit_log_max_size",
+        deserialize_with = "deserialize_memory"
+    )]
+    pub audit_log_max_size: usize,
+    /// Print the ASCII logo at startup
+    #[serde(rename = "always-show-logo", default)]
+    pub always_show_logo: bool,
+    /// Keyspace notifications published through pubsub, see [`KeyspaceEvents`]. None are
+    /// published by default.
+    #[serde(rename = "notify-keyspace-events", default)]
+    pub notify_keyspace_events: KeyspaceEvents,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Default number of pipelined commands a connection may execute before yielding to other
+/// connections
+pub const DEFAULT_PIPELINE_YIELD_THRESHOLD: usize = 1000;
+
+fn default_pipeline_yield_threshold() -> usize {
+    DEFAULT_PIPELINE_YIELD_THRESHOLD
+}
+
+/// Default maximum number of elements of the replies of the commands picking random elements, and
+/// LPOS
+pub const DEFAULT_MAX_REPLY_ELEMENTS: usize = 1_000_000;
+
+fn default_max_reply_elements() -> usize {
+    DEFAULT_MAX_REPLY_ELEMENTS
+}
+
+fn default_tcp_backlog() -> u32 {
+    511
+}
+
+fn default_client_query_buffer_limit() -> usize {
+    1024 * 1024 * 1024
+}
+
+fn default_audit_log_max_size() -> usize {
+    64 * 1024 * 1024
+}
+
+/// Parses a memory size with the units understood by Redis: `1k` is 1000 bytes, `1kb` is 1024
+/// bytes and so on.
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit())?);
+    let multiplier = match unit {
+        "b" => 1,
+        "k" => 1_000,
+        "kb" => 1024,
+        "m" => 1_000_000,
+        "mb" => 1024 * 1024,
+        "g" => 1_000_000_000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Deserializes a memory size, either a plain number of bytes or a number with a unit
+fn deserialize_memory<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    struct MemoryVisitor;
+
+    impl Visitor<'_> for MemoryVisitor {
+        type Value = usize;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a memory size")
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<usize, E> {
+            usize::try_from(value).map_err(|_| E::custom("invalid memory size"))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<usize, E> {
+            usize::try_from(value).map_err(|_| E::custom("invalid memory size"))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<usize, E> {
+            parse_memory(value).ok_or_else(|| E::custom(format!("invalid memory size '{}'", value)))
+        }
+    }
+
+    deserializer.deserialize_any(MemoryVisitor)
+}
+
+/// A single argument of a setting, which the parser may have read as a number
+struct Arg(String);
+
+impl<'de> Deserialize<'de> for Arg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ArgVisitor;
+
+        impl Visitor<'_> for ArgVisitor {
+            type Value = Arg;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an argument")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Arg, E> {
+                Ok(Arg(value.to_string()))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Arg, E> {
+                Ok(Arg(value.to_string()))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Arg, E> {
+                Ok(Arg(value.to_owned()))
+            }
+        }
+
+        deserializer.deserialize_any(ArgVisitor)
+    }
+}
+
+/// Deserializes the namespace quotas, `<prefix> <max-keys> <max-memory>` triplets
+fn deserialize_quotas<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<QuotaRule>, D::Error> {
+    struct QuotasVisitor;
+
+    impl<'de> Visitor<'de> for QuotasVisitor {
+        type Value = Vec<QuotaRule>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "<prefix> <max-keys> <max-memory> triplets")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<QuotaRule>, A::Error> {
+            let mut args = vec![];
+            while let Some(Arg(arg)) = seq.next_element()? {
+                args.push(arg);
+            }
+            if args.len() % 3 != 0 {
+                return Err(de::Error::custom(
+                    "namespace quotas must be <prefix> <max-keys> <max-memory> triplets",
+                ));
+            }
+            args.chunks(3)
+                .map(|rule| {
+                    Ok(QuotaRule {
+                        prefix: rule[0].clone(),
+                        max_keys: rule[1].parse().map_err(|_| {
+                            de::Error::custom(format!("invalid number of keys '{}'", rule[1]))
+                        })?,
+                        max_memory: rule[2]
+                            .parse()
+                            .ok()
+                            .or_else(|| parse_memory(&rule[2]))
+                            .ok_or_else(|| {
+                                de::Error::custom(format!("invalid memory size '{}'", rule[2]))
+                            })?,
+                    })
+                })
+                .collect()
+        }
+    }
+
+    deserializer.deserialize_seq(QuotasVisitor)
+}
+
+/// Flags of `notify-keyspace-events`, in the order they are written back
+const KEYSPACE_EVENTS_FLAGS: &str = "g$lshzxetdmnKE";
+
+/// Classes of events the `A` flag stands for
+const KEYSPACE_EVENTS_ALL: &str = "g$lshzxetd";
+
+/// Keyspace notifications to publish, set with the Redis flags of `notify-keyspace-events`: `K`
+/// publishes to the `__keyspace@<db>__` channels and `E` to the `__keyevent@<db>__` channels,
+/// while the other flags select the classes of events, like `g` for generic commands (DEL,
+/// RENAME, MOVE...) and `x` for expired keys. `A` stands for every class but `m` and `n`.
+///
+/// Nothing is published unless `K` or `E` is given along with a class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    /// Parses the flags, None is returned if any flag is unknown
+    pub fn parse(flags: &str) -> Option<Self> {
+        let mut events = Self::default();
+        for flag in flags.chars() {
+            if flag == 'A' {
+                KEYSPACE_EVENTS_ALL
+                    .chars()
+                    .for_each(|flag| events.set(flag));
+            } else if KEYSPACE_EVENTS_FLAGS.contains(flag) {
+                events.set(flag);
+            } else {
+                return None;
+            }
+        }
+        Some(events)
+    }
+
+    fn set(&mut self, flag: char) {
+        if let Some(bit) = KEYSPACE_EVENTS_FLAGS.find(flag) {
+            self.0 |= 1 << bit;
+        }
+    }
+
+    /// Whether the flag is set
+    pub fn contains(&self, flag: char) -> bool {
+        KEYSPACE_EVENTS_FLAGS
+            .find(flag)
+            .is_some_and(|bit| self.0 & (1 << bit) != 0)
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let all = KEYSPACE_EVENTS_ALL.chars().all(|flag| self.contains(flag));
+        if all {
+            write!(f, "A")?;
+        }
+        for flag in KEYSPACE_EVENTS_FLAGS.chars() {
+            if self.contains(flag) && !(all && KEYSPACE_EVENTS_ALL.contains(flag)) {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyspaceEvents {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Arg(flags) = Arg::deserialize(deserializer)?;
+        Self::parse(&flags).ok_or_else(|| {
+            de::Error::custom(format!("invalid notify-keyspace-events flags '{}'", flags))
+        })
+    }
 }
 
 impl Config {
     /// Returns all addresses to bind
+    ///
+    /// Like redis, `*` binds all IPv4 addresses and `::*` all IPv6 addresses. IPv6 literals are
+    /// wrapped in brackets. The `-` prefix, which makes the address optional in redis, is ignored
+    /// since failing to bind an address is never fatal.
     pub fn get_tcp_hostnames(&self) -> Vec<String> {
         self.bind
             .iter()
-            .map(|host| format!("{}:{}", host, self.port))
+            .map(|host| {
+                let host = host.strip_prefix('-').unwrap_or(host);
+                let host = match host {
+                    "*" => "0.0.0.0",
+                    "::*" => "::",
+                    host => host,
+                };
+                if host.contains(':') && !host.starts_with('[') {
+                    format!("[{}]:{}", host, self.port)
+                } else {
+                    format!("{}:{}", host, self.port)
+                }
+            })
             .collect::<Vec<String>>()
     }
+
+    /// Merges a config loaded at runtime into the running config. Settings which cannot change
+    /// without a restart keep their running value, and their names are returned.
+    pub fn reload(&self, new: Config) -> (Config, Vec<&'static str>) {
+        let mut ignored = vec![];
+        let mut merged = new;
+
+        macro_rules! keep {
+            ($($($field:ident).+ => $name:expr),+ $(,)?) => {
+                $(
+                    if merged.$($field).+ != self.$($field).+ {
+                        merged.$($field).+ = self.$($field).+.clone();
+                        ignored.push($name);
+                    }
+                )+
+            };
+        }
+
+        keep!(
+            daemonize => "daemonize",
+            port => "port",
+            bind => "bind",
+            log.file => "logfile",
+            databases => "databases",
+            unixsocket => "unixsocket",
+            tcp_backlog => "tcp-backlog",
+            tcp_nodelay => "tcp-nodelay",
+            preload => "preload",
+            audit_log => "audit-log",
+            audit_log_max_size => "audit-log-max-size",
+            federation_peers => "federation-peers",
+            always_show_logo => "always-show-logo",
+        );
+
+        (merged, ignored)
+    }
+
+    /// Serializes the config in the redis.conf format, one setting per line, as CONFIG REWRITE
+    /// writes it. Parsing the output returns the same config.
+    pub fn to_conf_string(&self) -> String {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        // Values are written bare unless the parser would split them or read them as a quoted
+        // value or a comment, then they are double quoted with the escapes the parser understands
+        let string = |value: &str| {
+            let is_bare = !value.starts_with('#')
+                && value.chars().all(|c| {
+                    !c.is_whitespace() && !c.is_control() && !matches!(c, '"' | '\'' | '\\')
+                });
+            if value.is_empty() {
+                "''".to_owned()
+            } else if is_bare {
+                value.to_owned()
+            } else {
+                let mut quoted = String::with_capacity(value.len() + 2);
+                quoted.push('"');
+                for c in value.chars() {
+                    match c {
+                        '"' | '\\' => {
+                            quoted.push('\\');
+                            quoted.push(c);
+                        }
+                        '\n' => quoted.push_str("\\n"),
+                        '\r' => quoted.push_str("\\r"),
+                        '\t' => quoted.push_str("\\t"),
+                        c if c.is_ascii_control() => {
+                            quoted.push_str(&format!("\\x{:02x}", c as u8))
+                        }
+                        c => quoted.push(c),
+                    }
+                }
+                quoted.push('"');
+                quoted
+            }
+        };
+        let strings = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| string(value))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let mut lines = vec![
+            format!("daemonize {}", yes_no(self.daemonize)),
+            format!("port {}", self.port),
+        ];
+        if !self.bind.is_empty() {
+            lines.push(format!("bind {}", strings(&self.bind)));
+        }
+        lines.push(format!("loglevel {}", self.log.level.conf_name()));
+        if let Some(file) = self.log.file.as_ref() {
+            lines.push(format!("logfile {}", string(file)));
+        }
+        lines.push(format!("databases {}", self.databases));
+        if let Some(file) = self.unixsocket.as_ref() {
+            lines.push(format!("unixsocket {}", string(file)));
+        }
+        lines.extend([
+            format!("replica-read-only {}", yes_no(self.replica_read_only)),
+            format!(
+                "replica-serve-stale-data {}",
+                yes_no(self.replica_serve_stale_data)
+            ),
+            format!("pipeline-yield-threshold {}", self.pipeline_yield_threshold),
+            format!("tcp-backlog {}", self.tcp_backlog),
+            format!("tcp-nodelay {}", yes_no(self.tcp_nodelay)),
+            format!("multi-max-commands {}", self.multi_max_commands),
+            format!("multi-max-bytes {}", self.multi_max_bytes),
+            format!("activedefrag {}", yes_no(self.activedefrag)),
+            format!(
+                "client-query-buffer-limit {}",
+                self.client_query_buffer_limit
+            ),
+            format!("maxmemory {}", self.maxmemory),
+            format!("max-blocking-time {}", self.max_blocking_time),
+            format!("max-reply-elements {}", self.max_reply_elements),
+            format!("random-seed {}", self.random_seed),
+        ]);
+        if !self.federation_peers.is_empty() {
+            lines.push(format!(
+                "federation-peers {}",
+                strings(&self.federation_peers)
+            ));
+        }
+        if !self.namespace_quotas.is_empty() {
+            lines.push(format!(
+                "namespace-quotas {}",
+                self.namespace_quotas
+                    .iter()
+                    .map(|rule| format!(
+                        "{} {} {}",
+                        string(&rule.prefix),
+                        rule.max_keys,
+                        rule.max_memory
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ));
+        }
+        if let Some(file) = self.preload.as_ref() {
+            lines.push(format!("preload {}", string(file)));
+        }
+        if let Some(file) = self.audit_log.as_ref() {
+            lines.push(format!("audit-log {}", string(file)));
+        }
+        lines.extend([
+            format!("audit-log-max-size {}", self.audit_log_max_size),
+            format!("always-show-logo {}", yes_no(self.always_show_logo)),
+            format!(
+                "notify-keyspace-events {}",
+                string(&self.notify_keyspace_events.to_string())
+            ),
+        ]);
+
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Where the config is loaded from. It is kept to reload the config at runtime.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigSource {
+    /// Config file
+    pub path: Option<String>,
+    /// Command line overrides, see [`parse_with_overrides`]
+    pub overrides: Vec<String>,
+}
+
+impl ConfigSource {
+    /// Loads and parses the config
+    pub async fn load(&self) -> Result<Config, Error> {
+        parse_with_overrides(self.path.clone(), &self.overrides).await
+    }
 }
 
 impl Default for Config {
@@ -46,35 +588,74 @@ impl Default for Config {
             log: Log::default(),
             databases: 16,
             unixsocket: None,
+            replica_read_only: false,
+            replica_serve_stale_data: true,
+            pipeline_yield_threshold: default_pipeline_yield_threshold(),
+            tcp_backlog: default_tcp_backlog(),
+            tcp_nodelay: true,
+            multi_max_commands: 0,
+            multi_max_bytes: 0,
+            activedefrag: false,
+            client_query_buffer_limit: default_client_query_buffer_limit(),
+            maxmemory: 0,
+            max_blocking_time: 0,
+            max_reply_elements: default_max_reply_elements(),
+            random_seed: 0,
+            federation_peers: vec![],
+            namespace_quotas: vec![],
+            preload: None,
+            audit_log: None,
+            audit_log_max_size: default_audit_log_max_size(),
+            always_show_logo: false,
+            notify_keyspace_events: KeyspaceEvents::default(),
         }
     }
 }
 
 /// Log levels
-#[derive(Deserialize_enum_str, Debug, PartialEq, Clone, Display)]
+#[derive(Deserialize_enum_str, Debug, PartialEq, Clone, Display, Default)]
 pub enum LogLevel {
     /// Trace
-    #[serde(rename = "trace")]
+    #[serde(rename = "trace", alias = "debug")]
     Trace,
     /// Debug
     #[serde(rename = "verbose")]
     Debug,
     /// Notice
     #[serde(rename = "notice")]
+    #[default]
     Notice,
     /// Warning
     #[serde(rename = "warning")]
     Warning,
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Debug
+impl LogLevel {
+    /// Returns the name of the level in the redis.conf format
+    pub fn conf_name(&self) -> &'static str {
+        match self {
+            Self::Trace => "debug",
+            Self::Debug => "verbose",
+            Self::Notice => "notice",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+impl From<&LogLevel> for log::LevelFilter {
+    fn from(level: &LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => Self::Trace,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Notice => Self::Info,
+            LogLevel::Warning => Self::Warn,
+        }
     }
 }
 
 /// Logging settings
-#[derive(Deserialize, Debug, Default, Clone)]
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
 pub struct Log {
     /// Log level
     #[serde(rename = "loglevel")]
@@ -90,6 +671,171 @@ pub async fn parse(path: String) -> Result<Config, Error> {
     Ok(from_slice(&content)?)
 }
 
+/// Loads the config, from a file path if given, and applies command line overrides on top of it.
+///
+/// Like redis-server, overrides are `--name value [value ...]` pairs, e.g. `microredis redis.conf
+/// --port 7777 --loglevel debug`, and they replace the values of the file.
+pub async fn parse_with_overrides(
+    path: Option<String>,
+    overrides: &[String],
+) -> Result<Config, Error> {
+    let content = if let Some(path) = path {
+        tokio::fs::read(path).await?
+    } else {
+        vec![]
+    };
+    let content = merge_overrides(&content, overrides)?;
+    Ok(from_slice(&content)?)
+}
+
+/// Merges command line overrides into the config content. Lines of the config setting an
+/// overridden key are blanked, not removed, so errors still point to the right line of the file.
+fn merge_overrides(content: &[u8], overrides: &[String]) -> Result<Vec<u8>, Error> {
+    let mut lines: Vec<(String, Vec<String>)> = vec![];
+
+    for arg in overrides.iter() {
+        if let Some(name) = arg.strip_prefix("--") {
+            if name.is_empty() {
+                return Err(Error::CliArgument(arg.to_owned()));
+            }
+            lines.push((name.to_lowercase(), vec![]));
+        } else if let Some((_, args)) = lines.last_mut() {
+            args.push(if arg.is_empty() {
+                "''".to_owned()
+            } else {
+                arg.to_owned()
+            });
+        } else {
+            return Err(Error::CliArgument(arg.to_owned()));
+        }
+    }
+
+    let mut merged = content
+        .split(|byte| *byte == b'\n')
+        .map(|line| {
+            let text = String::from_utf8_lossy(line);
+            let name = text.split_whitespace().next().unwrap_or_default();
+            if lines
+                .iter()
+                .any(|(overridden, _)| overridden.eq_ignore_ascii_case(name))
+            {
+                &b""[..]
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<&[u8]>>()
+        .join(&b'\n');
+
+    merged.push(b'\n');
+    for (name, args) in lines.into_iter() {
+        merged.extend_from_slice(format!("{} {}\n", name, args.join(" ")).as_bytes());
+    }
+
+    Ok(merged)
+}
+
+/// Outcome of validating a config without starting the server
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Issues that do not prevent the server from starting, like unknown keys
+    pub warnings: Vec<String>,
+    /// Issues that would make the server fail or misbehave
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    /// Returns true if no errors were found
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Loads a config from a file path and validates it
+pub async fn check_file(path: String) -> Result<Report, Error> {
+    let content = tokio::fs::read(path).await?;
+    Ok(check(&content))
+}
+
+/// Validates a config, reporting unknown keys, invalid values and incompatible settings
+pub fn check(content: &[u8]) -> Report {
+    let mut report = Report::default();
+    let mut input = content;
+
+    while let Ok((rest, value)) = parser::parse(input) {
+        let name = value.name.to_string();
+        if !KNOWN_KEYS.contains(&name.as_str()) {
+            report
+                .warnings
+                .push(format!("unknown config key '{}', it will be ignored", name));
+        }
+        input = rest;
+    }
+
+    let config: Config = match from_slice(content) {
+        Ok(config) => config,
+        Err(err) => {
+            report
+                .errors
+                .push(format!("cannot parse the config: {}", err));
+            return report;
+        }
+    };
+
+    if config.databases == 0 {
+        report
+            .errors
+            .push("databases must be greater than zero".to_owned());
+    }
+
+    for host in config.bind.iter() {
+        let host = host.strip_prefix('-').unwrap_or(host);
+        if !matches!(host, "*" | "::*" | "localhost") && host.parse::<IpAddr>().is_err() {
+            report
+                .errors
+                .push(format!("bind address '{}' is not a valid IP address", host));
+        }
+    }
+
+    if let Some(file) = config.unixsocket.as_ref() {
+        if !is_writable_dir(Path::new(file).parent()) {
+            report.errors.push(format!(
+                "unixsocket '{}' cannot be created, its directory is not writable",
+                file
+            ));
+        }
+    }
+
+    if let Some(file) = config.log.file.as_ref().filter(|file| !file.is_empty()) {
+        if !is_writable_dir(Path::new(file).parent()) {
+            report.errors.push(format!(
+                "logfile '{}' cannot be created, its directory is not writable",
+                file
+            ));
+        }
+    }
+
+    if config.daemonize {
+        report
+            .warnings
+            .push("daemonize is not supported, the server runs in the foreground".to_owned());
+    }
+
+    report
+}
+
+/// Checks whether a directory exists and is writable. A path without a directory refers to the
+/// current directory.
+fn is_writable_dir(dir: Option<&Path>) -> bool {
+    let dir = match dir {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::metadata(dir)
+        .map(|meta| meta.is_dir() && !meta.permissions().readonly())
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -122,12 +868,18 @@ unixsocket /Users/crodas/projects/rust/microredis/tests/tmp/server.43948.1/socke
 
         let config: Config = from_str(config).unwrap();
         assert!(!config.daemonize);
+        assert!(config.always_show_logo);
+        assert_eq!(
+            KeyspaceEvents::parse("KEA"),
+            Some(config.notify_keyspace_events)
+        );
         assert_eq!(21111, config.port);
         assert_eq!(vec!["127.0.0.1"], config.bind);
         assert_eq!(vec!["127.0.0.1:21111"], config.get_tcp_hostnames());
         assert_eq!(LogLevel::Debug, config.log.level);
         assert_eq!(Some("".to_owned()), config.log.file);
         assert_eq!(16, config.databases);
+        assert!(config.replica_serve_stale_data);
         assert_eq!(
             Some(
                 "/Users/crodas/projects/rust/microredis/tests/tmp/server.43948.1/socket".to_owned()
@@ -143,9 +895,433 @@ unixsocket /Users/crodas/projects/rust/microredis/tests/tmp/server.43948.1/socke
         assert_eq!(6379, config.port);
         assert_eq!(vec!["127.0.0.1"], config.bind);
         assert_eq!(vec!["127.0.0.1:6379"], config.get_tcp_hostnames());
-        assert_eq!(LogLevel::Debug, config.log.level);
+        assert_eq!(LogLevel::Notice, config.log.level);
         assert_eq!(None, config.log.file);
         assert_eq!(16, config.databases);
         assert_eq!(None, config.unixsocket);
+        assert!(!config.replica_read_only);
+        assert!(config.replica_serve_stale_data);
+        assert_eq!(1000, config.pipeline_yield_threshold);
+        assert_eq!(511, config.tcp_backlog);
+        assert!(config.tcp_nodelay);
+    }
+
+    #[test]
+    fn default_config_round_trip() {
+        let config = Config::default();
+        let parsed: Config = from_str(&config.to_conf_string()).unwrap();
+        assert_eq!(config, parsed);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config: Config = from_str(
+            "port 7777
+bind 127.0.0.1 ::1
+loglevel debug
+logfile ''
+databases 4
+unixsocket /tmp/redis.sock
+replica-read-only yes
+tcp-nodelay no
+multi-max-commands 10
+client-query-buffer-limit 1mb
+maxmemory 100mb
+max-blocking-time 30000
+max-reply-elements 100000
+random-seed 42
+federation-peers 10.0.0.2:6379 10.0.0.3:6379
+namespace-quotas tenant1: 1000 10mb tenant2: 0 1024
+preload /tmp/fixtures.resp
+audit-log /tmp/audit.log
+always-show-logo yes
+notify-keyspace-events xEg
+",
+        )
+        .unwrap();
+        let conf = config.to_conf_string();
+        assert!(conf.contains("notify-keyspace-events gxE\n"));
+        assert!(conf.contains("loglevel debug\n"));
+        assert!(conf.contains("logfile ''\n"));
+        assert!(conf.contains("client-query-buffer-limit 1048576\n"));
+        assert!(conf.contains("maxmemory 104857600\n"));
+        assert_eq!(config, from_str::<Config>(&conf).unwrap());
+        assert!(check(conf.as_bytes()).warnings.is_empty());
+
+        for level in ["debug", "verbose", "notice", "warning"] {
+            let config: Config = from_str(&format!("loglevel {}\n", level)).unwrap();
+            assert_eq!(level, config.log.level.conf_name());
+        }
+    }
+
+    #[test]
+    fn quoted_values_round_trip() {
+        let config = Config {
+            log: Log {
+                file: Some("/var/log/micro redis.log".to_owned()),
+                ..Log::default()
+            },
+            unixsocket: Some("/tmp/it's \"here\"\\\n\x01".to_owned()),
+            namespace_quotas: vec![QuotaRule {
+                prefix: "#tenant 1:".to_owned(),
+                max_keys: 10,
+                max_memory: 0,
+            }],
+            preload: Some("".to_owned()),
+            ..Config::default()
+        };
+        let conf = config.to_conf_string();
+        assert!(conf.contains("logfile \"/var/log/micro redis.log\"\n"));
+        assert!(conf.contains("unixsocket \"/tmp/it's \\\"here\\\"\\\\\\n\\x01\"\n"));
+        assert!(conf.contains("namespace-quotas \"#tenant 1:\" 10 0\n"));
+        assert!(conf.contains("preload ''\n"));
+        assert_eq!(config, from_str::<Config>(&conf).unwrap());
+    }
+
+    #[test]
+    fn keyspace_events_flags() {
+        let events = KeyspaceEvents::parse("Ex").unwrap();
+        assert!(events.contains('E') && events.contains('x'));
+        assert!(!events.contains('K') && !events.contains('g'));
+        assert_eq!("xE", events.to_string());
+
+        let events = KeyspaceEvents::parse("KEAm").unwrap();
+        assert!(events.contains('g') && events.contains('d') && events.contains('m'));
+        assert!(!events.contains('n'));
+        assert_eq!("AmKE", events.to_string());
+
+        assert_eq!(Some(KeyspaceEvents::default()), KeyspaceEvents::parse(""));
+        assert_eq!(None, KeyspaceEvents::parse("KEq"));
+        assert!(from_str::<Config>("notify-keyspace-events KEq\n").is_err());
+    }
+
+    #[test]
+    fn reload_keeps_immutable_settings() {
+        let running = Config::default();
+        let new: Config = from_str(
+            "port 7777
+databases 4
+multi-max-commands 10
+loglevel warning
+federation-peers 10.0.0.2:6379
+",
+        )
+        .unwrap();
+
+        let (merged, ignored) = running.reload(new);
+        assert_eq!(vec!["port", "databases", "federation-peers"], ignored);
+        assert_eq!(6379, merged.port);
+        assert_eq!(16, merged.databases);
+        assert!(merged.federation_peers.is_empty());
+        assert_eq!(10, merged.multi_max_commands);
+        assert_eq!(LogLevel::Warning, merged.log.level);
+
+        let (_, ignored) = running.reload(Config::default());
+        assert!(ignored.is_empty());
+    }
+
+    #[test]
+    fn parse_read_only() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert!(!parsed.replica_read_only);
+        let parsed: Config = from_str(&format!("{}replica-read-only yes\n", config)).unwrap();
+        assert!(parsed.replica_read_only);
+        let parsed: Config = from_str(&format!("{}slave-read-only yes\n", config)).unwrap();
+        assert!(parsed.replica_read_only);
+    }
+
+    #[test]
+    fn parse_serve_stale_data() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert!(parsed.replica_serve_stale_data);
+        let parsed: Config = from_str(&format!("{}replica-serve-stale-data no\n", config)).unwrap();
+        assert!(!parsed.replica_serve_stale_data);
+    }
+
+    #[test]
+    fn parse_pipeline_yield_threshold() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(1000, parsed.pipeline_yield_threshold);
+        let parsed: Config = from_str(&format!("{}pipeline-yield-threshold 50\n", config)).unwrap();
+        assert_eq!(50, parsed.pipeline_yield_threshold);
+    }
+
+    #[test]
+    fn parse_activedefrag() {
+        let config = "port 21111\n";
+        let parsed: Config = from_str(config).unwrap();
+        assert!(!parsed.activedefrag);
+        let parsed: Config = from_str(&format!("{}activedefrag yes\n", config)).unwrap();
+        assert!(parsed.activedefrag);
+    }
+
+    #[test]
+    fn parse_multi_limits() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(0, parsed.multi_max_commands);
+        assert_eq!(0, parsed.multi_max_bytes);
+        let parsed: Config = from_str(&format!(
+            "{}multi-max-commands 100\nmulti-max-bytes 1024\n",
+            config
+        ))
+        .unwrap();
+        assert_eq!(100, parsed.multi_max_commands);
+        assert_eq!(1024, parsed.multi_max_bytes);
+    }
+
+    #[test]
+    fn parse_client_query_buffer_limit() {
+        let config = "port 21111\n";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(1024 * 1024 * 1024, parsed.client_query_buffer_limit);
+        for (value, expected) in [
+            ("2048", 2048),
+            ("2k", 2000),
+            ("2kb", 2048),
+            ("1MB", 1024 * 1024),
+            ("1g", 1_000_000_000),
+        ] {
+            let parsed: Config =
+                from_str(&format!("{}client-query-buffer-limit {}\n", config, value)).unwrap();
+            assert_eq!(expected, parsed.client_query_buffer_limit);
+        }
+        assert!(from_str::<Config>(&format!("{}client-query-buffer-limit 1tb\n", config)).is_err());
+    }
+
+    #[test]
+    fn parse_audit_log() {
+        let config = "port 21111\n";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(None, parsed.audit_log);
+        assert_eq!(64 * 1024 * 1024, parsed.audit_log_max_size);
+        let parsed: Config = from_str(&format!(
+            "{}audit-log /var/log/microredis-audit.log\naudit-log-max-size 1mb\n",
+            config
+        ))
+        .unwrap();
+        assert_eq!(
+            Some("/var/log/microredis-audit.log".to_owned()),
+            parsed.audit_log
+        );
+        assert_eq!(1024 * 1024, parsed.audit_log_max_size);
+    }
+
+    #[test]
+    fn parse_namespace_quotas() {
+        let parsed: Config = from_str("namespace-quotas t1: 100 1mb 42 0 512\n").unwrap();
+        assert_eq!(
+            vec![
+                QuotaRule {
+                    prefix: "t1:".to_owned(),
+                    max_keys: 100,
+                    max_memory: 1024 * 1024,
+                },
+                QuotaRule {
+                    prefix: "42".to_owned(),
+                    max_keys: 0,
+                    max_memory: 512,
+                },
+            ],
+            parsed.namespace_quotas
+        );
+        assert!(from_str::<Config>("namespace-quotas t1: 100\n").is_err());
+        assert!(from_str::<Config>("namespace-quotas t1: many 1mb\n").is_err());
+    }
+
+    #[test]
+    fn parse_tcp_options() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(511, parsed.tcp_backlog);
+        assert!(parsed.tcp_nodelay);
+        let parsed: Config =
+            from_str(&format!("{}tcp-backlog 1024\ntcp-nodelay no\n", config)).unwrap();
+        assert_eq!(1024, parsed.tcp_backlog);
+        assert!(!parsed.tcp_nodelay);
+    }
+
+    #[test]
+    fn ipv6_bind() {
+        let config = "daemonize no
+port 21111
+bind 127.0.0.1 ::1 * -::* [::1]
+loglevel verbose
+databases 16
+";
+        let parsed: Config = from_str(config).unwrap();
+        assert_eq!(
+            vec![
+                "127.0.0.1:21111",
+                "[::1]:21111",
+                "0.0.0.0:21111",
+                "[::]:21111",
+                "[::1]:21111"
+            ],
+            parsed.get_tcp_hostnames()
+        );
+    }
+
+    const CHECK_BASE: &str = "daemonize no
+port 21111
+loglevel verbose
+";
+
+    #[test]
+    fn check_valid_config() {
+        let report =
+            check(format!("{}databases 16\nbind 127.0.0.1 ::1 -*\n", CHECK_BASE).as_bytes());
+        assert_eq!(Report::default(), report);
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn check_missing_keys() {
+        let report = check(b"port 21111\n");
+        assert_eq!(Report::default(), report);
+    }
+
+    #[test]
+    fn missing_keys_use_defaults() {
+        let config: Config = from_str("port 21111\n").unwrap();
+        let default = Config::default();
+        assert_eq!(21111, config.port);
+        assert_eq!(default.bind, config.bind);
+        assert_eq!(default.databases, config.databases);
+        assert_eq!(default.log.level, config.log.level);
+    }
+
+    #[test]
+    fn cli_overrides() {
+        let content = b"port 21111
+bind 127.0.0.1
+loglevel verbose
+databases 16
+";
+        let overrides = [
+            "--port",
+            "7777",
+            "--bind",
+            "127.0.0.1",
+            "::1",
+            "--LogLevel",
+            "debug",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>();
+        let merged = merge_overrides(content, &overrides).unwrap();
+        let config: Config = from_slice(&merged).unwrap();
+        assert_eq!(7777, config.port);
+        assert_eq!(vec!["127.0.0.1", "::1"], config.bind);
+        assert_eq!(LogLevel::Trace, config.log.level);
+        assert_eq!(16, config.databases);
+
+        // overridden lines are blanked, errors report the line of the file
+        let overrides = vec!["--port".to_owned(), "7777".to_owned()];
+        let merged = merge_overrides(b"port 1\nbind 127.0.0.1\ndatabases foo\n", &overrides);
+        let err = from_slice::<Config>(&merged.unwrap()).unwrap_err();
+        assert!(err.to_string().starts_with("line 3, column 1:"), "{}", err);
+
+        let overrides = vec!["--logfile".to_owned(), "".to_owned()];
+        let config: Config = from_slice(&merge_overrides(b"", &overrides).unwrap()).unwrap();
+        assert_eq!(Some("".to_owned()), config.log.file);
+        assert_eq!(6379, config.port);
+    }
+
+    #[test]
+    fn cli_overrides_invalid() {
+        assert_eq!(
+            Err(Error::CliArgument("7777".to_owned())),
+            merge_overrides(b"", &["7777".to_owned()])
+        );
+        assert_eq!(
+            Err(Error::CliArgument("--".to_owned())),
+            merge_overrides(b"", &["--".to_owned()])
+        );
+    }
+
+    #[test]
+    fn check_unknown_keys() {
+        let report = check(
+            format!(
+                "{}databases 16\nbind 127.0.0.1\nappendonly no\n",
+                CHECK_BASE
+            )
+            .as_bytes(),
+        );
+        assert!(report.is_ok());
+        assert_eq!(
+            vec!["unknown config key 'appendonly', it will be ignored".to_owned()],
+            report.warnings
+        );
+
+        let report =
+            check(format!("{}databases 16\nbind 127.0.0.1\nport 1\n", CHECK_BASE).as_bytes());
+        assert_eq!(
+            vec!["cannot parse the config: duplicate field `port`".to_owned()],
+            report.errors
+        );
+    }
+
+    #[test]
+    fn check_invalid_values() {
+        let report = check(format!("{}databases foo\nbind 127.0.0.1\n", CHECK_BASE).as_bytes());
+        assert!(!report.is_ok());
+        assert_eq!(1, report.errors.len());
+        assert!(report.errors[0].starts_with(
+            "cannot parse the config: line 4, column 1: invalid value for 'databases': "
+        ));
+
+        let report = check(format!("{}databases 0\nbind 127.0.0.1 foo\n", CHECK_BASE).as_bytes());
+        assert_eq!(
+            vec![
+                "databases must be greater than zero".to_owned(),
+                "bind address 'foo' is not a valid IP address".to_owned(),
+            ],
+            report.errors
+        );
+    }
+
+    #[test]
+    fn check_incompatible_paths() {
+        let report = check(
+            format!(
+                "{}databases 16\nbind 127.0.0.1\nunixsocket /does/not/exist/redis.sock\nlogfile /does/not/exist/redis.log\n",
+                CHECK_BASE
+            )
+            .as_bytes(),
+        );
+        assert_eq!(2, report.errors.len());
+        assert!(report.errors[0].starts_with("unixsocket"));
+        assert!(report.errors[1].starts_with("logfile"));
     }
 }
@@ -3,10 +3,173 @@
 //! This mod keeps track of all active conections. There is one instance of this mod per running
 //! server.
 use super::{pubsub_connection::PubsubClient, pubsub_server::Pubsub, Connection, ConnectionInfo};
-use crate::{db::pool::Databases, db::Db, dispatcher::Dispatcher, value::Value};
+use crate::{
+    audit::AuditLog,
+    cmd::now,
+    config::{
+        Config, ConfigSource, KeyspaceEvents, DEFAULT_MAX_REPLY_ELEMENTS,
+        DEFAULT_PIPELINE_YIELD_THRESHOLD,
+    },
+    db::{
+        pool::Databases,
+        random::{Generator, RngProvider},
+        utils::far_future,
+        Db,
+    },
+    dispatcher::Dispatcher,
+    error::Error,
+    federation::Federation,
+    quota::{QuotaRule, Quotas},
+    supervisor::Supervisor,
+    value::Value,
+};
 use parking_lot::RwLock;
-use std::{collections::BTreeMap, sync::Arc};
-use tokio::sync::mpsc;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{Duration, Instant},
+};
+
+/// Replication state
+///
+/// The replica goes through these states while it connects and synchronizes
+/// with its master. The link with the master is only considered up in the
+/// `Connected` state.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ReplicationState {
+    /// The server is not a replica
+    #[default]
+    Master,
+    /// The replica must connect to the master
+    Connect,
+    /// The replica is connecting and doing the handshake with the master
+    Connecting,
+    /// The replica is receiving the dataset from the master
+    Transfer,
+    /// The link with the master is up
+    Connected,
+}
+
+impl ReplicationState {
+    /// Checks if the state machine can move to a given state.
+    ///
+    /// A replica can always go back to `Connect` (the link was lost) and any
+    /// server can be promoted to `Master`.
+    pub fn can_transition_to(&self, next: ReplicationState) -> bool {
+        matches!(
+            (self, next),
+            (_, Self::Master)
+                | (_, Self::Connect)
+                | (Self::Connect, Self::Connecting)
+                | (Self::Connecting, Self::Transfer)
+                | (Self::Transfer, Self::Connected)
+        )
+    }
+
+    /// Is the server a replica with its link to the master down?
+    pub fn is_link_down(&self) -> bool {
+        !matches!(self, Self::Master | Self::Connected)
+    }
+}
+
+/// Health of the server, used by orchestration systems to tell liveness from readiness
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Health {
+    /// The server is ready to serve requests
+    Ready,
+    /// The server is alive but loading the dataset
+    Loading,
+    /// The server is alive but shutting down
+    ShuttingDown,
+}
+
+impl Health {
+    /// Is the server ready to serve requests?
+    pub fn is_ready(&self) -> bool {
+        *self == Self::Ready
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ready => "ready",
+            Self::Loading => "loading",
+            Self::ShuttingDown => "shutting-down",
+        })
+    }
+}
+
+/// Administrative events, broadcast to every connection through the server-wide event bus
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ServerEvent {
+    /// The connection with the given ID must be closed
+    Kill(u128),
+    /// Clients are paused, no command is read from them until the given instant
+    Pause(Instant),
+    /// Paused clients are served again
+    Unpause,
+    /// The server is shutting down, every connection must be closed
+    Shutdown,
+}
+
+impl ServerEvent {
+    /// Whether the event is addressed to a given connection
+    pub fn applies_to(&self, conn_id: u128) -> bool {
+        match self {
+            Self::Kill(id) => *id == conn_id,
+            _ => true,
+        }
+    }
+}
+
+/// Number of events that can be buffered before slow connections start losing them
+const EVENTS_CAPACITY: usize = 64;
+
+/// Dataset loading progress
+#[derive(Debug, Clone, Copy)]
+pub struct Loading {
+    /// Unix time when the loading started
+    pub started_at: Duration,
+    /// Total bytes to load
+    pub total_bytes: u64,
+    /// Bytes loaded so far
+    pub loaded_bytes: u64,
+    started: Instant,
+}
+
+impl Loading {
+    /// Percentage of the dataset already loaded
+    pub fn loaded_perc(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            (self.loaded_bytes as f64 * 100.0) / self.total_bytes as f64
+        }
+    }
+
+    /// Estimated number of seconds to finish the loading, based on the loading
+    /// speed so far
+    pub fn eta_seconds(&self) -> u64 {
+        if self.loaded_bytes == 0 {
+            return 1;
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let remaining = self.total_bytes.saturating_sub(self.loaded_bytes) as f64;
+        (elapsed * remaining / self.loaded_bytes as f64) as u64
+    }
+}
+
+/// How many samples of the network traffic are kept to calculate the
+/// instantaneous input and output kbps
+pub const NET_STATS_SAMPLES: usize = 16;
 
 /// Connections struct
 #[derive(Debug)]
@@ -16,6 +179,34 @@ pub struct Connections {
     pubsub: Arc<Pubsub>,
     dispatcher: Arc<Dispatcher>,
     counter: RwLock<u128>,
+    read_only: AtomicBool,
+    replica_read_only: AtomicBool,
+    loading: RwLock<Option<Loading>>,
+    replication_state: RwLock<ReplicationState>,
+    serve_stale_data: AtomicBool,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    net_samples: RwLock<VecDeque<(Instant, u64, u64)>>,
+    pipeline_yield_threshold: AtomicUsize,
+    multi_max_commands: AtomicUsize,
+    multi_max_bytes: AtomicUsize,
+    client_query_buffer_limit: AtomicUsize,
+    maxmemory: AtomicUsize,
+    max_blocking_time: AtomicU64,
+    max_reply_elements: AtomicUsize,
+    keyspace_events: RwLock<KeyspaceEvents>,
+    rng: RngProvider,
+    audit_log: RwLock<Option<AuditLog>>,
+    federation: RwLock<Option<Federation>>,
+    quotas: RwLock<Option<Arc<Quotas>>>,
+    active_defrag: AtomicBool,
+    defrag_reclaimed_bytes: AtomicU64,
+    shutting_down: AtomicBool,
+    paused_until: RwLock<Option<Instant>>,
+    events: broadcast::Sender<ServerEvent>,
+    supervisor: Arc<Supervisor>,
+    config: RwLock<Config>,
+    config_source: RwLock<Option<ConfigSource>>,
 }
 
 impl Connections {
@@ -27,7 +218,489 @@ impl Connections {
             pubsub: Arc::new(Pubsub::new()),
             dispatcher: Arc::new(Dispatcher::new()),
             connections: RwLock::new(BTreeMap::new()),
+            read_only: AtomicBool::new(false),
+            replica_read_only: AtomicBool::new(false),
+            loading: RwLock::new(None),
+            replication_state: RwLock::new(ReplicationState::default()),
+            serve_stale_data: AtomicBool::new(true),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            net_samples: RwLock::new(VecDeque::with_capacity(NET_STATS_SAMPLES)),
+            pipeline_yield_threshold: AtomicUsize::new(DEFAULT_PIPELINE_YIELD_THRESHOLD),
+            multi_max_commands: AtomicUsize::new(0),
+            multi_max_bytes: AtomicUsize::new(0),
+            client_query_buffer_limit: AtomicUsize::new(0),
+            maxmemory: AtomicUsize::new(0),
+            max_blocking_time: AtomicU64::new(0),
+            max_reply_elements: AtomicUsize::new(DEFAULT_MAX_REPLY_ELEMENTS),
+            keyspace_events: RwLock::new(KeyspaceEvents::default()),
+            rng: RngProvider::default(),
+            audit_log: RwLock::new(None),
+            federation: RwLock::new(None),
+            quotas: RwLock::new(None),
+            active_defrag: AtomicBool::new(false),
+            defrag_reclaimed_bytes: AtomicU64::new(0),
+            shutting_down: AtomicBool::new(false),
+            paused_until: RwLock::new(None),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            supervisor: Arc::new(Supervisor::new()),
+            config: RwLock::new(Config::default()),
+            config_source: RwLock::new(None),
+        }
+    }
+
+    /// Applies the settings of a config which can be changed at runtime, and keeps the config as
+    /// the running one.
+    pub fn apply_config(&self, config: Config) {
+        log::set_max_level((&config.log.level).into());
+        self.set_replica_read_only(config.replica_read_only);
+        self.set_serve_stale_data(config.replica_serve_stale_data);
+        self.set_pipeline_yield_threshold(config.pipeline_yield_threshold);
+        self.set_multi_limits(config.multi_max_commands, config.multi_max_bytes);
+        self.set_active_defrag(config.activedefrag);
+        self.set_client_query_buffer_limit(config.client_query_buffer_limit);
+        self.set_maxmemory(config.maxmemory);
+        self.set_max_blocking_time(config.max_blocking_time);
+        self.set_max_reply_elements(config.max_reply_elements);
+        self.set_keyspace_events(config.notify_keyspace_events);
+        // reloading the same seed would restart the sequence of the random picks
+        if self.config.read().random_seed != config.random_seed {
+            self.set_random_seed(config.random_seed);
+        }
+        if self.config.read().namespace_quotas != config.namespace_quotas {
+            self.set_namespace_quotas(config.namespace_quotas.clone());
+        }
+        *self.config.write() = config;
+    }
+
+    /// Returns the running config
+    pub fn config(&self) -> Config {
+        self.config.read().clone()
+    }
+
+    /// Sets where the config was loaded from, so it can be reloaded
+    pub fn set_config_source(&self, source: ConfigSource) {
+        *self.config_source.write() = Some(source);
+    }
+
+    /// Loads the config again and applies the settings which can be changed at runtime. Changes to
+    /// any other setting are ignored with a warning.
+    pub async fn reload_config(&self) -> Result<(), Error> {
+        let source = self
+            .config_source
+            .read()
+            .clone()
+            .ok_or(Error::NoConfigFile)?;
+        let (config, ignored) = self.config().reload(source.load().await?);
+        for name in ignored.iter() {
+            log::warn!(
+                "Ignoring the new value of '{}', it cannot be changed without a restart",
+                name
+            );
+        }
+        self.apply_config(config);
+        log::info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Moves the replication state machine to a new state. If the transition
+    /// is not valid the state is not changed and false is returned.
+    pub fn set_replication_state(&self, next: ReplicationState) -> bool {
+        let mut state = self.replication_state.write();
+        if state.can_transition_to(next) {
+            *state = next;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the current replication state
+    pub fn replication_state(&self) -> ReplicationState {
+        *self.replication_state.read()
+    }
+
+    /// Whether a replica rejects the commands that may modify the database.
+    /// Masters always accept them.
+    pub fn set_replica_read_only(&self, replica_read_only: bool) {
+        self.replica_read_only
+            .store(replica_read_only, Ordering::Relaxed);
+    }
+
+    /// Whether a replica should serve (possibly stale) data while its link to
+    /// the master is down
+    pub fn set_serve_stale_data(&self, serve_stale_data: bool) {
+        self.serve_stale_data
+            .store(serve_stale_data, Ordering::Relaxed);
+    }
+
+    /// Is the server a replica with stale data that must not be served? In
+    /// that case only commands flagged as `Stale` are allowed.
+    #[inline]
+    pub fn is_stale(&self) -> bool {
+        !self.serve_stale_data.load(Ordering::Relaxed)
+            && self.replication_state.read().is_link_down()
+    }
+
+    /// Flags the server as loading the dataset.
+    ///
+    /// While loading, every command not flagged as `Loading` is rejected with
+    /// a LOADING error.
+    pub fn start_loading(&self, total_bytes: u64) {
+        *self.loading.write() = Some(Loading {
+            started_at: now(),
+            total_bytes,
+            loaded_bytes: 0,
+            started: Instant::now(),
+        });
+    }
+
+    /// Updates the number of bytes loaded so far
+    pub fn set_loading_progress(&self, loaded_bytes: u64) {
+        if let Some(loading) = self.loading.write().as_mut() {
+            loading.loaded_bytes = loaded_bytes;
+        }
+    }
+
+    /// Flags the server as done loading the dataset
+    pub fn stop_loading(&self) {
+        *self.loading.write() = None;
+    }
+
+    /// Is the server loading the dataset?
+    #[inline]
+    pub fn is_loading(&self) -> bool {
+        self.loading.read().is_some()
+    }
+
+    /// Returns the loading progress, if the server is loading the dataset
+    pub fn loading(&self) -> Option<Loading> {
+        *self.loading.read()
+    }
+
+    /// Switches the server to (or out of) read-only mode.
+    ///
+    /// While in read-only mode every command flagged as `Write` is rejected
+    /// with a READONLY error.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Is the server in read-only mode? Either because it was switched to it,
+    /// or because it is a read-only replica.
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+            || (self.replica_read_only.load(Ordering::Relaxed)
+                && self.replication_state() != ReplicationState::Master)
+    }
+
+    /// Flags the server as shutting down, every connection is closed
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.broadcast(ServerEvent::Shutdown);
+    }
+
+    /// Returns a receiver of the administrative events. Each connection subscribes to it
+    pub fn subscribe_to_events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an administrative event to all connections. Returns the number of connections
+    /// listening to events.
+    pub fn broadcast(&self, event: ServerEvent) -> usize {
+        // Nobody may be listening, which is fine
+        self.events.send(event).unwrap_or_default()
+    }
+
+    /// Requests a connection to be closed, even if it is blocked. Returns false if the
+    /// connection does not exist.
+    ///
+    /// The connection is flagged as killed before the event is sent, so a connection lagging
+    /// behind the events still finds out.
+    pub fn kill(&self, conn_id: u128) -> bool {
+        match self.get_by_conn_id(conn_id) {
+            Some(conn) => conn.kill(),
+            None => return false,
+        }
+        self.broadcast(ServerEvent::Kill(conn_id));
+        true
+    }
+
+    /// Pauses all clients for a given time
+    pub fn pause(&self, timeout: Duration) {
+        let until = Instant::now()
+            .checked_add(timeout)
+            .unwrap_or_else(far_future);
+        *self.paused_until.write() = Some(until);
+        self.broadcast(ServerEvent::Pause(until));
+    }
+
+    /// Resumes all paused clients
+    pub fn unpause(&self) {
+        *self.paused_until.write() = None;
+        self.broadcast(ServerEvent::Unpause);
+    }
+
+    /// Returns until when clients are paused, if they are
+    pub fn paused_until(&self) -> Option<Instant> {
+        self.paused_until
+            .read()
+            .filter(|until| *until > Instant::now())
+    }
+
+    /// Is the server shutting down?
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Returns the health of the server
+    pub fn health(&self) -> Health {
+        if self.is_shutting_down() {
+            Health::ShuttingDown
+        } else if self.is_loading() {
+            Health::Loading
+        } else {
+            Health::Ready
+        }
+    }
+
+    /// Sets how many pipelined commands a connection may execute before
+    /// yielding to other connections. Zero disables it.
+    pub fn set_pipeline_yield_threshold(&self, threshold: usize) {
+        self.pipeline_yield_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+
+    /// Returns how many pipelined commands a connection may execute before
+    /// yielding to other connections.
+    pub fn pipeline_yield_threshold(&self) -> usize {
+        self.pipeline_yield_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets how many commands and bytes a transaction may queue. Zero means
+    /// no limit.
+    pub fn set_multi_limits(&self, max_commands: usize, max_bytes: usize) {
+        self.multi_max_commands
+            .store(max_commands, Ordering::Relaxed);
+        self.multi_max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    /// Returns how many commands and bytes a transaction may queue
+    pub fn multi_limits(&self) -> (usize, usize) {
+        (
+            self.multi_max_commands.load(Ordering::Relaxed),
+            self.multi_max_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Sets the maximum size of the unparsed input of a client. Zero means no limit.
+    pub fn set_client_query_buffer_limit(&self, limit: usize) {
+        self.client_query_buffer_limit
+            .store(limit, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum size of the unparsed input of a client
+    pub fn client_query_buffer_limit(&self) -> usize {
+        self.client_query_buffer_limit.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum memory the databases may use. Zero means no limit.
+    pub fn set_maxmemory(&self, maxmemory: usize) {
+        self.maxmemory.store(maxmemory, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum memory the databases may use
+    pub fn maxmemory(&self) -> usize {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum time, in milliseconds, a blocking command may wait. Zero means no limit.
+    pub fn set_max_blocking_time(&self, millis: u64) {
+        self.max_blocking_time.store(millis, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum time a blocking command may wait, if it is limited
+    pub fn max_blocking_time(&self) -> Option<Duration> {
+        match self.max_blocking_time.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+
+    /// Sets the maximum number of elements of the replies of SRANDMEMBER, SPOP, HRANDFIELD and
+    /// LPOS. Zero means no limit.
+    pub fn set_max_reply_elements(&self, max: usize) {
+        self.max_reply_elements.store(max, Ordering::Relaxed);
+    }
+
+    /// Sets the keyspace notifications to publish
+    pub fn set_keyspace_events(&self, events: KeyspaceEvents) {
+        *self.keyspace_events.write() = events;
+    }
+
+    /// Returns the keyspace notifications to publish, set with notify-keyspace-events
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        *self.keyspace_events.read()
+    }
+
+    /// Checks that a reply of `elements` elements is allowed by max-reply-elements. It is checked
+    /// before building the reply whenever the number of elements is known in advance.
+    pub fn check_reply_elements(&self, elements: usize) -> Result<(), Error> {
+        match self.max_reply_elements.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            max if elements > max => Err(Error::ReplyTooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Seeds the generators of the commands picking random elements, so their picks are
+    /// reproducible. Zero seeds them from the OS.
+    pub fn set_random_seed(&self, seed: u64) {
+        self.rng.set_seed(if seed == 0 { None } else { Some(seed) });
+    }
+
+    /// Returns a random generator for a command
+    pub fn rng(&self) -> Generator {
+        self.rng.rng()
+    }
+
+    /// Returns the memory used by the keys and values of every database, in bytes. The usage is
+    /// kept up to date on every write, so this is cheap.
+    pub fn used_memory(&self) -> usize {
+        self.dbs.into_iter().map(|db| db.memory_usage()).sum()
+    }
+
+    /// Checks that the used memory is below maxmemory, before a command which may grow the data
+    /// runs
+    pub fn check_maxmemory(&self) -> Result<(), Error> {
+        let maxmemory = self.maxmemory();
+        if maxmemory > 0 && self.used_memory() >= maxmemory {
+            return Err(Error::OutOfMemory);
         }
+        Ok(())
+    }
+
+    /// Returns how many bytes the data may still grow before reaching maxmemory, None if there is
+    /// no limit
+    pub fn memory_budget(&self) -> Option<usize> {
+        match self.maxmemory() {
+            0 => None,
+            maxmemory => Some(maxmemory.saturating_sub(self.used_memory())),
+        }
+    }
+
+    /// Sets the namespace quotas, attaching them to every database so the usage of each
+    /// namespace is accounted from now on. No rules disables the quotas.
+    pub fn set_namespace_quotas(&self, rules: Vec<QuotaRule>) {
+        let quotas = if rules.is_empty() {
+            None
+        } else {
+            Some(Arc::new(Quotas::new(rules)))
+        };
+        for db in self.dbs.into_iter() {
+            db.set_quotas(quotas.clone());
+        }
+        *self.quotas.write() = quotas;
+    }
+
+    /// Returns the namespace quotas, if any
+    pub fn quotas(&self) -> Option<Arc<Quotas>> {
+        self.quotas.read().clone()
+    }
+
+    /// Sets the audit log where write commands are recorded, None disables it
+    pub fn set_audit_log(&self, audit_log: Option<AuditLog>) {
+        *self.audit_log.write() = audit_log;
+    }
+
+    /// Returns the audit log, if write commands are being recorded
+    pub fn audit_log(&self) -> Option<AuditLog> {
+        self.audit_log.read().clone()
+    }
+
+    /// Sets the federation the published messages are forwarded to, None disables it
+    pub fn set_federation(&self, federation: Option<Federation>) {
+        *self.federation.write() = federation;
+    }
+
+    /// Returns the federation, if the published messages are forwarded to peers
+    pub fn federation(&self) -> Option<Federation> {
+        self.federation.read().clone()
+    }
+
+    /// Returns the supervisor of the background tasks
+    pub fn supervisor(&self) -> Arc<Supervisor> {
+        self.supervisor.clone()
+    }
+
+    /// Enables or disables the background release of unused memory
+    pub fn set_active_defrag(&self, enabled: bool) {
+        self.active_defrag.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether the background release of unused memory is enabled
+    pub fn is_active_defrag(&self) -> bool {
+        self.active_defrag.load(Ordering::Relaxed)
+    }
+
+    /// Accounts bytes released by the background defrag task
+    pub fn add_defrag_reclaimed_bytes(&self, bytes: usize) {
+        self.defrag_reclaimed_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Total bytes released by the background defrag task
+    pub fn defrag_reclaimed_bytes(&self) -> u64 {
+        self.defrag_reclaimed_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Accounts bytes read from and written to any connection
+    pub fn add_net_bytes(&self, input: u64, output: u64) {
+        self.net_input_bytes.fetch_add(input, Ordering::Relaxed);
+        self.net_output_bytes.fetch_add(output, Ordering::Relaxed);
+    }
+
+    /// Total bytes read from all connections
+    pub fn total_net_input_bytes(&self) -> u64 {
+        self.net_input_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes written to all connections
+    pub fn total_net_output_bytes(&self) -> u64 {
+        self.net_output_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Takes a sample of the network traffic. This function is expected to be
+    /// called periodically, only the last `NET_STATS_SAMPLES` samples are kept.
+    pub fn sample_net_stats(&self) {
+        let mut samples = self.net_samples.write();
+        if samples.len() == NET_STATS_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((
+            Instant::now(),
+            self.total_net_input_bytes(),
+            self.total_net_output_bytes(),
+        ));
+    }
+
+    /// Returns the input and output kbps calculated from the network traffic
+    /// samples.
+    pub fn instantaneous_kbps(&self) -> (f64, f64) {
+        let samples = self.net_samples.read();
+        let (first, last) = match (samples.front(), samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return (0.0, 0.0),
+        };
+        let elapsed = last.0.duration_since(first.0).as_secs_f64();
+        if elapsed == 0.0 {
+            return (0.0, 0.0);
+        }
+        (
+            (last.1 - first.1) as f64 / elapsed / 1024.0,
+            (last.2 - first.2) as f64 / elapsed / 1024.0,
+        )
     }
 
     /// Returns all databases
@@ -51,6 +724,18 @@ impl Connections {
         self.connections.write().remove(&id);
     }
 
+    /// Releases the transaction locks held by connections that are no longer
+    /// registered. Returns the number of released locks.
+    pub fn unlock_orphan_keys(&self) -> usize {
+        // The connections stay locked for the whole sweep, otherwise the locks of a connection
+        // created in the meantime would be taken as orphaned
+        let alive = self.connections.read();
+        self.dbs
+            .into_iter()
+            .map(|db| db.unlock_orphan_keys(|conn_id| alive.contains_key(&conn_id)))
+            .sum()
+    }
+
     /// Creates a new connection
     pub fn new_connection<T: ToString>(
         self: &Arc<Connections>,
@@ -68,12 +753,22 @@ impl Connections {
             all_connections: self.clone(),
             info: RwLock::new(ConnectionInfo::new(db.set_conn_id(*id))),
             pubsub_client: PubsubClient::new(pubsub_sender),
+            net_input_bytes: AtomicU64::new(0),
+            net_output_bytes: AtomicU64::new(0),
+            query_buffer: AtomicUsize::new(0),
+            query_buffer_peak: AtomicUsize::new(0),
+            killed: AtomicBool::new(false),
         });
 
         self.connections.write().insert(*id, conn.clone());
         (pubsub_receiver, conn)
     }
 
+    /// Get a connection by the client ID, the one exposed by CLIENT ID
+    pub fn get_by_client_id(&self, client_id: u64) -> Option<Arc<Connection>> {
+        self.get_by_conn_id(client_id.into())
+    }
+
     /// Get a connection by their connection id
     pub fn get_by_conn_id(&self, conn_id: u128) -> Option<Arc<Connection>> {
         self.connections.read().get(&conn_id).cloned()
@@ -88,8 +783,8 @@ impl Connections {
     pub fn total_blocked_connections(&self) -> usize {
         self.connections
             .read()
-            .iter()
-            .map(|(_, conn)| if conn.is_blocked() { 1 } else { 0 })
+            .values()
+            .map(|conn| if conn.is_blocked() { 1 } else { 0 })
             .sum()
     }
 
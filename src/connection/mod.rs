@@ -1,11 +1,20 @@
 //! # Connection module
 use self::pubsub_server::Pubsub;
-use crate::{db::Db, error::Error, value::Value};
+use crate::{
+    db::Db,
+    error::Error,
+    value::{bytes_to_number, Protocol, Value},
+};
 use bytes::Bytes;
 use parking_lot::RwLock;
 use std::{
-    collections::{HashSet, VecDeque},
-    sync::Arc,
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::sync::broadcast::{self, Receiver, Sender};
 
@@ -46,14 +55,18 @@ pub struct ConnectionInfo {
     current_db: usize,
     db: Arc<Db>,
     name: Option<String>,
+    protocol: Protocol,
     watch_keys: Vec<(Bytes, usize)>,
-    tx_keys: HashSet<Bytes>,
+    tx_keys: BTreeMap<usize, HashSet<Bytes>>,
+    tx_db: usize,
     status: ConnectionStatus,
-    commands: Option<Vec<VecDeque<Bytes>>>,
+    commands: Option<Vec<(usize, VecDeque<Bytes>)>>,
+    queued_bytes: usize,
     is_blocked: bool,
     blocked_notification: Option<Sender<()>>,
     block_id: usize,
     unblock_reason: Option<UnblockReason>,
+    reply_delay: Option<Duration>,
 }
 
 /// Connection
@@ -64,6 +77,11 @@ pub struct Connection {
     addr: String,
     info: RwLock<ConnectionInfo>,
     pubsub_client: pubsub_connection::PubsubClient,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    query_buffer: AtomicUsize,
+    query_buffer_peak: AtomicUsize,
+    killed: AtomicBool,
 }
 
 impl ConnectionInfo {
@@ -71,16 +89,20 @@ impl ConnectionInfo {
     fn new(db: Arc<Db>) -> Self {
         Self {
             name: None,
+            protocol: Protocol::default(),
             watch_keys: vec![],
             db,
             current_db: 0,
-            tx_keys: HashSet::new(),
+            tx_keys: BTreeMap::new(),
+            tx_db: 0,
             commands: None,
+            queued_bytes: 0,
             status: ConnectionStatus::default(),
             blocked_notification: None,
             is_blocked: false,
             block_id: 0,
             unblock_reason: None,
+            reply_delay: None,
         }
     }
 }
@@ -194,6 +216,66 @@ impl Connection {
         self.id
     }
 
+    /// Client ID, the connection ID as exposed to the clients, which only understand 64 bit
+    /// integers. Connection IDs are monotonically increasing and never reused, so they stay well
+    /// below 2^64.
+    #[inline]
+    pub fn client_id(&self) -> u64 {
+        self.id as u64
+    }
+
+    /// Address of the client
+    #[inline]
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Accounts the bytes read from and written to this connection
+    pub fn add_net_bytes(&self, input: u64, output: u64) {
+        if input == 0 && output == 0 {
+            return;
+        }
+        self.net_input_bytes.fetch_add(input, Ordering::Relaxed);
+        self.net_output_bytes.fetch_add(output, Ordering::Relaxed);
+        self.all_connections.add_net_bytes(input, output);
+    }
+
+    /// Total bytes read from this connection
+    pub fn net_input_bytes(&self) -> u64 {
+        self.net_input_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes written to this connection
+    pub fn net_output_bytes(&self) -> u64 {
+        self.net_output_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Records the size of the input read from the client that is not parsed yet
+    pub fn set_query_buffer(&self, len: usize) {
+        self.query_buffer.store(len, Ordering::Relaxed);
+        self.query_buffer_peak.fetch_max(len, Ordering::Relaxed);
+    }
+
+    /// Size of the input read from the client that is not parsed yet
+    pub fn query_buffer(&self) -> usize {
+        self.query_buffer.load(Ordering::Relaxed)
+    }
+
+    /// Biggest size the unparsed input of the client ever had
+    pub fn query_buffer_peak(&self) -> usize {
+        self.query_buffer_peak.load(Ordering::Relaxed)
+    }
+
+    /// Marks the connection to be closed, see [`connections::Connections::kill`]
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the connection was killed. The connection task checks it if it missed events.
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
     /// Drops a multi/transaction and reset the connection
     ///
     /// If the connection was not in a MULTI stage an error is thrown.
@@ -204,6 +286,7 @@ impl Connection {
             | ConnectionStatus::FailedTx
             | ConnectionStatus::ExecutingTx => {
                 info.commands = None;
+                info.queued_bytes = 0;
                 info.watch_keys.clear();
                 info.tx_keys.clear();
                 info.status = ConnectionStatus::default();
@@ -227,6 +310,7 @@ impl Connection {
         let mut info = self.info.write();
         if info.status == ConnectionStatus::Normal {
             info.status = ConnectionStatus::Multi;
+            info.tx_db = info.current_db;
             Ok(Value::Ok)
         } else {
             Err(Error::NestedTx)
@@ -238,9 +322,11 @@ impl Connection {
         let mut info = self.info.write();
         info.status = ConnectionStatus::default();
         info.name = None;
+        info.protocol = Protocol::default();
         info.watch_keys = vec![];
         info.commands = None;
-        info.tx_keys = HashSet::new();
+        info.queued_bytes = 0;
+        info.tx_keys = BTreeMap::new();
         drop(info);
 
         let pubsub = self.pubsub();
@@ -294,43 +380,89 @@ impl Connection {
         self.info.write().watch_keys.clear()
     }
 
-    /// Returns a list of key that are involved in a transaction. These keys will be locked as
-    /// exclusive, even if they don't exists, during the execution of a transction.
+    /// Returns a list of key that are involved in a transaction, grouped by database index and
+    /// sorted by it. These keys will be locked as exclusive, even if they don't exists, during the
+    /// execution of a transction.
     ///
     /// The original implementation of Redis does not need this promise because only one
     /// transaction is executed at a time, in microredis transactions reserve their keys and do not
     /// prevent other connections to continue modifying the database.
-    pub fn get_tx_keys(&self) -> Vec<Bytes> {
+    pub fn get_tx_keys(&self) -> Vec<(usize, Vec<Bytes>)> {
         self.info
             .read()
             .tx_keys
             .iter()
-            .cloned()
-            .collect::<Vec<Bytes>>()
+            .map(|(db, keys)| (*db, keys.iter().cloned().collect()))
+            .collect()
     }
 
-    /// Queues a command for later execution
-    pub fn queue_command(&self, args: VecDeque<Bytes>) {
+    /// Queues a command for later execution.
+    ///
+    /// The command is queued alongside the database index it must be executed against, which is
+    /// the database selected when the transaction started or by a previously queued SELECT.
+    ///
+    /// If queuing the command would exceed the configured limits of commands or bytes per
+    /// transaction, the queued commands are released, the transaction is flagged as failed and an
+    /// error is returned.
+    pub fn queue_command(&self, args: VecDeque<Bytes>) -> Result<(), Error> {
         let mut info = self.info.write();
+        let (max_commands, max_bytes) = self.all_connections.multi_limits();
+        let queued = info.commands.as_ref().map(|c| c.len()).unwrap_or(0);
+        let bytes = info.queued_bytes + args.iter().map(|arg| arg.len()).sum::<usize>();
+        let exceeded = if max_commands > 0 && queued >= max_commands {
+            Some("multi-max-commands")
+        } else if max_bytes > 0 && bytes > max_bytes {
+            Some("multi-max-bytes")
+        } else {
+            None
+        };
+        if let Some(limit) = exceeded {
+            info.commands = None;
+            info.queued_bytes = 0;
+            info.status = ConnectionStatus::FailedTx;
+            return Err(Error::TxTooLarge(limit.to_owned()));
+        }
+        info.queued_bytes = bytes;
+        let db = info.tx_db;
+        if args.len() == 2 && args[0].eq_ignore_ascii_case(b"SELECT") {
+            // Only a valid SELECT changes the database of the next queued commands, an invalid
+            // one would fail at EXEC time.
+            if let Ok(new_db) = bytes_to_number::<usize>(&args[1]) {
+                if self.all_connections.get_databases().get(new_db).is_ok() {
+                    info.tx_db = new_db;
+                }
+            }
+        }
         let commands = info.commands.get_or_insert(vec![]);
-        commands.push(args);
+        commands.push((db, args));
+        Ok(())
     }
 
-    /// Returns a list of queued commands.
-    pub fn get_queue_commands(&self) -> Option<Vec<VecDeque<Bytes>>> {
+    /// Returns a list of queued commands alongside the database index they must be executed
+    /// against.
+    pub fn get_queue_commands(&self) -> Option<Vec<(usize, VecDeque<Bytes>)>> {
         let mut info = self.info.write();
         info.watch_keys = vec![];
         info.status = ConnectionStatus::ExecutingTx;
+        info.queued_bytes = 0;
         info.commands.take()
     }
 
-    /// Returns a list of transaction keys
+    /// Returns the index of the currently selected database
+    pub fn current_db(&self) -> usize {
+        self.info.read().current_db
+    }
+
+    /// Adds keys to the list of keys involved in the transaction. The keys belong to the
+    /// database the next queued command will be executed against.
     pub fn tx_keys<T>(&self, keys: T)
     where
         T: IntoIterator<Item = Bytes>,
     {
+        let mut info = self.info.write();
+        let db = info.tx_db;
         #[allow(clippy::mutable_key_type)]
-        let tx_keys = &mut self.info.write().tx_keys;
+        let tx_keys = info.tx_keys.entry(db).or_default();
         keys.into_iter()
             .map(|k| {
                 tx_keys.insert(k);
@@ -340,9 +472,16 @@ impl Connection {
 
     /// Disconnects from the server, disconnect from all pubsub channels and remove itself from the
     /// all_connection lists.
+    ///
+    /// Any pending transaction is discarded and the keys it may still have locked are released.
     pub fn destroy(self: Arc<Connection>) {
         let pubsub = self.pubsub();
         self.clone().unblock(UnblockReason::Timeout);
+        let _ = self.stop_transaction();
+        self.discard_watched_keys();
+        for db in self.all_connections.get_databases().into_iter() {
+            db.set_conn_id(self.id).unlock_all_keys();
+        }
         pubsub.unsubscribe(&self.pubsub_client.subscriptions(), &self, false);
         pubsub.punsubscribe(&self.pubsub_client.psubscriptions(), &self, false);
         self.all_connections.clone().remove(self);
@@ -364,6 +503,28 @@ impl Connection {
         r.name = Some(name);
     }
 
+    /// Returns the protocol spoken by the client
+    #[inline]
+    pub fn protocol(&self) -> Protocol {
+        self.info.read().protocol
+    }
+
+    /// Sets the protocol spoken by the client
+    pub fn set_protocol(&self, protocol: Protocol) {
+        self.info.write().protocol = protocol;
+    }
+
+    /// Returns the artificial latency added to every reply, set by DEBUG DELAY-REPLY
+    #[inline]
+    pub fn reply_delay(&self) -> Option<Duration> {
+        self.info.read().reply_delay
+    }
+
+    /// Sets the artificial latency added to every reply. None disables it.
+    pub fn set_reply_delay(&self, delay: Option<Duration>) {
+        self.info.write().reply_delay = delay;
+    }
+
     /// Changes the current db for the current connection
     pub fn selectdb(&self, db: usize) -> Result<Value, Error> {
         let mut info = self.info.write();
@@ -377,13 +538,19 @@ impl Connection {
     }
 }
 
-impl ToString for Connection {
+impl fmt::Display for Connection {
     /// Returns a string representation of this connection
-    fn to_string(&self) -> String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let info = self.info.read();
-        format!(
-            "id={} addr={} name={:?} db={}\r\n",
-            self.id, self.addr, info.name, info.current_db
+        write!(
+            f,
+            "id={} addr={} name={:?} db={} qbuf={} qbuf-peak={}\r\n",
+            self.client_id(),
+            self.addr,
+            info.name,
+            info.current_db,
+            self.query_buffer(),
+            self.query_buffer_peak(),
         )
     }
 }
@@ -4,7 +4,6 @@
 use super::Connection;
 use crate::value::Value;
 use bytes::Bytes;
-use glob::Pattern;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use tokio::sync::mpsc;
@@ -20,7 +19,7 @@ pub struct PubsubClient {
 #[derive(Debug)]
 struct MetaData {
     subscriptions: HashMap<Bytes, bool>,
-    psubscriptions: HashMap<Pattern, bool>,
+    psubscriptions: HashMap<Bytes, bool>,
     is_psubcribed: bool,
 }
 
@@ -38,7 +37,7 @@ impl PubsubClient {
     }
 
     /// Unsubscribe from pattern subscriptions
-    pub fn punsubscribe(&self, channels: &[Pattern], conn: &Connection) {
+    pub fn punsubscribe(&self, channels: &[Bytes], conn: &Connection) {
         let mut meta = self.meta.write();
         channels
             .iter()
@@ -78,13 +77,13 @@ impl PubsubClient {
     }
 
     /// Return list of pattern subscriptions
-    pub fn psubscriptions(&self) -> Vec<Pattern> {
+    pub fn psubscriptions(&self) -> Vec<Bytes> {
         self.meta
             .read()
             .psubscriptions
             .keys()
             .cloned()
-            .collect::<Vec<Pattern>>()
+            .collect::<Vec<Bytes>>()
     }
 
     /// Return total number of subscriptions + psubscription
@@ -100,7 +99,7 @@ impl PubsubClient {
     }
 
     /// Creates a new pattern subscription
-    pub fn new_psubscription(&self, channel: &Pattern) {
+    pub fn new_psubscription(&self, channel: &Bytes) {
         let mut meta = self.meta.write();
         meta.is_psubcribed = true;
         meta.psubscriptions.insert(channel.clone(), true);
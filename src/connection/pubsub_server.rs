@@ -1,9 +1,11 @@
 //! # Pubsub server
 //!
 //! There is one instance of this mod active per server instance.
-use crate::{connection::Connection, error::Error, value::Value};
+use crate::{
+    connection::Connection,
+    value::{glob::matches, Value},
+};
 use bytes::Bytes;
-use glob::Pattern;
 use parking_lot::RwLock;
 use std::collections::{HashMap, VecDeque};
 use tokio::sync::mpsc;
@@ -15,7 +17,7 @@ type Subscription = HashMap<u128, Sender>;
 #[derive(Debug)]
 pub struct Pubsub {
     subscriptions: RwLock<HashMap<Bytes, Subscription>>,
-    psubscriptions: RwLock<HashMap<Pattern, Subscription>>,
+    psubscriptions: RwLock<HashMap<Bytes, Subscription>>,
 }
 
 impl Default for Pubsub {
@@ -59,14 +61,12 @@ impl Pubsub {
     }
 
     /// Subscribe to patterns
-    pub fn psubscribe(&self, channels: VecDeque<Bytes>, conn: &Connection) -> Result<(), Error> {
+    ///
+    /// Patterns are glob-style patterns and, like in redis, any pattern is valid.
+    pub fn psubscribe(&self, channels: VecDeque<Bytes>, conn: &Connection) {
         let mut subscriptions = self.psubscriptions.write();
 
-        for bytes_channel in channels.into_iter() {
-            let channel = String::from_utf8_lossy(&bytes_channel);
-            let channel =
-                Pattern::new(&channel).map_err(|_| Error::InvalidPattern(channel.to_string()))?;
-
+        for channel in channels.into_iter() {
             if let Some(subs) = subscriptions.get_mut(&channel) {
                 subs.insert(conn.id(), conn.pubsub_client().sender());
             } else {
@@ -79,14 +79,12 @@ impl Pubsub {
             conn.append_response(
                 vec![
                     "psubscribe".into(),
-                    Value::Blob(bytes_channel),
+                    Value::Blob(channel),
                     conn.pubsub_client().total_subs().into(),
                 ]
                 .into(),
             );
         }
-
-        Ok(())
     }
 
     /// Publishes a new message. This broadcast to channels subscribers and pattern-subscription
@@ -109,17 +107,15 @@ impl Pubsub {
             }
         }
 
-        let str_channel = String::from_utf8_lossy(channel);
-
         for (pattern, subs) in self.psubscriptions.read().iter() {
-            if !pattern.matches(&str_channel) {
+            if !matches(pattern, channel) {
                 continue;
             }
 
             for sub in subs.values() {
                 let _ = sub.try_send(Value::Array(vec![
                     "pmessage".into(),
-                    pattern.as_str().into(),
+                    Value::new(pattern),
                     Value::new(channel),
                     Value::new(message),
                 ]));
@@ -131,7 +127,7 @@ impl Pubsub {
     }
 
     /// Unsubscribe from a pattern subscription
-    pub fn punsubscribe(&self, channels: &[Pattern], conn: &Connection, notify: bool) {
+    pub fn punsubscribe(&self, channels: &[Bytes], conn: &Connection, notify: bool) {
         if channels.is_empty() {
             return conn.append_response(Value::Array(vec![
                 "punsubscribe".into(),
@@ -154,7 +150,7 @@ impl Pubsub {
                 if notify {
                     conn.append_response(Value::Array(vec![
                         "punsubscribe".into(),
-                        channel.as_str().into(),
+                        Value::new(channel),
                         conn.pubsub_client().total_subs().into(),
                     ]));
                 }
@@ -1,18 +1,57 @@
-use crate::{error::Error, value::Value};
-use bytes::BytesMut;
+use super::KeySample;
+use crate::{
+    error::Error,
+    value::{typ::ValueTyp, VDebug, Value},
+};
+use bytes::Bytes;
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::time::Instant;
+use std::{
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Entry {
     value: RwLock<Value>,
     version: AtomicUsize,
     expires_at: Mutex<Option<Instant>>,
+    /// LRU clock of the last access by a command
+    last_access: AtomicU32,
+    /// Estimated memory used by the value, and the number of elements it had when it was
+    /// estimated
+    measured: Mutex<(usize, usize)>,
 }
 
+/// Collections with up to this many elements are measured again after every change. The estimation
+/// of bigger collections is scaled by the change of their number of elements instead, so a write
+/// never walks a big collection.
+const MEASURE_ELEMENTS_UP_TO: usize = 128;
+
 static LAST_VERSION: AtomicUsize = AtomicUsize::new(0);
 
+/// Coarse clock, in seconds since the UNIX epoch, the accesses to the entries are recorded with. It
+/// is advanced by [`update_lru_clock`], so recording an access never reads the system clock.
+static LRU_CLOCK: AtomicU32 = AtomicU32::new(0);
+
+/// Advances the LRU clock to the current time, returning it. The server calls it periodically.
+pub fn update_lru_clock() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+    LRU_CLOCK.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// Returns the LRU clock
+#[inline]
+fn lru_clock() -> u32 {
+    match LRU_CLOCK.load(Ordering::Relaxed) {
+        0 => update_lru_clock(),
+        clock => clock,
+    }
+}
+
 /// Returns a new version
 pub fn unique_id() -> usize {
     LAST_VERSION.fetch_add(1, Ordering::Relaxed)
@@ -28,8 +67,10 @@ pub fn unique_id() -> usize {
 impl Entry {
     pub fn new(value: Value, expires_at: Option<Instant>) -> Self {
         Self {
+            measured: Mutex::new((value.memory_usage(), value.length())),
             value: RwLock::new(value),
             expires_at: Mutex::new(expires_at),
+            last_access: AtomicU32::new(lru_clock()),
             version: AtomicUsize::new(LAST_VERSION.fetch_add(1, Ordering::Relaxed)),
         }
     }
@@ -85,17 +126,78 @@ impl Entry {
         self.value.write()
     }
 
-    pub fn ensure_blob_is_mutable(&self) -> Result<(), Error> {
-        self.bump_version();
-        let mut val = self.inner_mut();
-        match *val {
-            Value::Blob(ref mut data) => {
-                let rw_data = BytesMut::from(&data[..]);
-                *val = Value::BlobRw(rw_data);
-                Ok(())
+    /// Records an access to the value by a command. Internal reads, like expiring or persisting
+    /// keys, must not call it so they do not reset the idle time.
+    #[inline(always)]
+    pub fn touch(&self) {
+        self.last_access.store(lru_clock(), Ordering::Relaxed);
+    }
+
+    /// Returns the time elapsed since the last access, with the resolution of the LRU clock
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(
+            lru_clock().saturating_sub(self.last_access.load(Ordering::Relaxed)) as u64,
+        )
+    }
+
+    /// Returns the debug information of the value, without counting it as an access
+    pub fn debug(&self) -> VDebug {
+        self.value.read().debug().idle(self.idle())
+    }
+
+    /// Returns the reference count of the value, without counting it as an access
+    pub fn refcount(&self) -> usize {
+        self.value.read().refcount()
+    }
+
+    /// Returns the type of the value, without counting it as an access
+    pub fn typ(&self) -> ValueTyp {
+        self.value.read().typ()
+    }
+
+    /// Returns a copy of the value, without counting it as an access
+    pub fn snapshot(&self) -> Value {
+        self.value.read().clone()
+    }
+
+    /// Returns an estimation of the memory used by the value, without counting it as an access
+    pub fn memory_usage(&self) -> usize {
+        self.value.read().memory_usage()
+    }
+
+    /// Returns the memory used by the value, as estimated when it was last measured
+    pub fn size(&self) -> usize {
+        self.measured.lock().0
+    }
+
+    /// Estimates again the memory used by the value, after a change. Returns the previous and the
+    /// new estimation.
+    pub fn measure(&self) -> (usize, usize) {
+        let value = self.value.read();
+        let length = value.length();
+        let mut measured = self.measured.lock();
+        let (previous, previous_length) = *measured;
+        let size = match *value {
+            Value::Hash(_) | Value::List(_) | Value::Set(_)
+                if length > MEASURE_ELEMENTS_UP_TO && previous_length > 0 =>
+            {
+                (previous as u128 * length as u128 / previous_length as u128) as usize
             }
-            Value::BlobRw(_) => Ok(()),
-            _ => Err(Error::WrongType),
+            _ => value.memory_usage(),
+        };
+        *measured = (size, length);
+        (previous, size)
+    }
+
+    /// Describes the value stored at `key` for MEMORY SAMPLE. Like `debug`, the last access time
+    /// is not updated.
+    pub fn sample(&self, key: Bytes) -> KeySample {
+        let value = self.value.read();
+        KeySample {
+            key,
+            typ: value.typ(),
+            length: value.length(),
+            memory_usage: value.memory_usage(),
         }
     }
 
@@ -104,7 +206,7 @@ impl Entry {
     /// behaviour we can schedule the purge thread to run every few seconds or
     /// even minutes instead of once every second.
     pub fn is_valid(&self) -> bool {
-        self.expires_at.lock().map_or(true, |x| x > Instant::now())
+        self.expires_at.lock().is_none_or(|x| x > Instant::now())
     }
 
     /// Whether or not the value is scalar
@@ -123,13 +225,13 @@ impl Entry {
         )
     }
 
-    /// Clone a value. If the value is not clonable an error is Value::Error is
-    /// returned instead
-    pub fn clone_value(&self) -> Value {
+    /// Clone a value. If the value is not scalar a WrongType error is returned
+    /// instead
+    pub fn clone_value(&self) -> Result<Value, Error> {
         if self.is_scalar() {
-            self.value.read().clone()
+            Ok(self.inner().clone())
         } else {
-            Error::WrongType.into()
+            Err(Error::WrongType)
         }
     }
 }
@@ -137,7 +239,6 @@ impl Entry {
 #[cfg(test)]
 mod test {
     use super::*;
-    use tokio::time::Duration;
 
     #[test]
     fn is_valid_without_expiration() {
@@ -174,4 +275,20 @@ mod test {
         e.set_ttl(Instant::now());
         assert!(!e.is_valid());
     }
+
+    #[test]
+    fn idle_time() {
+        let e = Entry::new(Value::Blob("foo".into()), None);
+        e.last_access
+            .store(update_lru_clock() - 10, Ordering::Relaxed);
+        // the clock may tick in between
+        let idle = e.debug().lru_seconds_idle;
+        assert!((10..=11).contains(&idle), "{}", idle);
+        // only accesses by commands count
+        let _ = e.inner();
+        let _ = e.inner_mut();
+        assert!(e.debug().lru_seconds_idle >= 10);
+        e.touch();
+        assert!(e.debug().lru_seconds_idle <= 1);
+    }
 }
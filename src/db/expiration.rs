@@ -58,7 +58,7 @@ impl ExpirationDb {
     }
 
     pub fn has(&self, key: &Bytes) -> bool {
-        self.keys.get(key).is_some()
+        self.keys.contains_key(key)
     }
 
     pub fn flush(&mut self) -> bool {
@@ -78,10 +78,23 @@ impl ExpirationDb {
         }
     }
 
+    /// Returns the instance of a key stored in the expiration table
+    #[cfg(test)]
+    pub fn get_key(&self, key: &Bytes) -> Option<&Bytes> {
+        self.keys.get_key_value(key).map(|(key, _)| key)
+    }
+
     pub fn len(&self) -> usize {
         self.expiring_keys.len()
     }
 
+    /// Returns the number of keys that are already expired at `now`
+    pub fn expired_len(&self, now: Instant) -> usize {
+        self.expiring_keys
+            .range(..=ExpirationId((now, u64::MAX)))
+            .count()
+    }
+
     /// Returns a list of expired keys, these keys are removed from the internal
     /// data structure which is keeping track of expiring keys.
     pub fn get_expired_keys(&mut self, now: Option<Instant>) -> Vec<Bytes> {
@@ -146,7 +159,7 @@ mod test {
     #[test]
     fn get_expiration() {
         let mut db = ExpirationDb::new();
-        let keys = vec![
+        let keys = [
             ("hix".into(), Instant::now() + Duration::from_secs(15)),
             ("key".into(), Instant::now() + Duration::from_secs(2)),
             ("bar".into(), Instant::now() + Duration::from_secs(3)),
@@ -180,7 +193,7 @@ mod test {
     #[test]
     pub fn remove() {
         let mut db = ExpirationDb::new();
-        let keys = vec![
+        let keys = [
             ("hix".into(), Instant::now() + Duration::from_secs(15)),
             ("key".into(), Instant::now() + Duration::from_secs(2)),
             ("bar".into(), Instant::now() + Duration::from_secs(3)),
@@ -2,68 +2,138 @@
 //!
 //! This database module is the core of the miniredis project. All other modules around this
 //! database module.
-use self::utils::{far_future, ExpirationOpts, Override};
+use self::utils::{far_future, ExpirationOpts, Number, Override};
 use crate::{
     error::Error,
-    value::{bytes_to_number, cursor::Cursor, typ::Typ, VDebug, Value},
+    quota::Quotas,
+    value::{
+        cursor::Cursor,
+        glob,
+        typ::{Typ, ValueTyp},
+        VDebug, Value,
+    },
 };
 use bytes::{BufMut, Bytes, BytesMut};
+pub use entry::update_lru_clock;
 use entry::{unique_id, Entry};
 use expiration::ExpirationDb;
-use glob::Pattern;
 use log::trace;
-use num_traits::CheckedAdd;
-use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use rand::{prelude::SliceRandom, Rng};
+use random::Generator;
+use read_view::ReadView;
 use seahash::hash;
+use slot::Slot;
 use std::{
-    collections::{HashMap, VecDeque},
-    convert::{TryFrom, TryInto},
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryFrom,
     ops::{Deref, DerefMut},
-    str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     thread,
 };
+use store::{BackingStore, StoreHandle};
 use tokio::{
-    sync::broadcast::{self, Receiver, Sender},
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        Notify,
+    },
     time::{Duration, Instant},
 };
 
 mod entry;
 mod expiration;
 pub mod pool;
+pub mod random;
+pub mod read_view;
 pub mod scan;
+mod slot;
+pub mod snapshot;
+pub mod store;
 pub(crate) mod utils;
 
+/// Number of removed keys events that can be buffered before slow subscribers start losing them
+const REMOVED_KEYS_CAPACITY: usize = 1024;
+
+/// Why a key was removed from the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// The key expired, it was removed either lazily (on access) or by the purge process
+    Expired,
+    /// The key was deleted by a command, like DEL or GETDEL, or its collection was emptied
+    Deleted,
+    /// The key was renamed, it was removed from its old name
+    Renamed,
+    /// The key was moved to another database
+    Moved,
+    /// The database was flushed
+    Flushed,
+}
+
+impl RemovalReason {
+    /// Name of the keyspace notification event, like Redis none is published for flushed keys
+    pub fn event(&self) -> Option<&'static str> {
+        match self {
+            Self::Expired => Some("expired"),
+            Self::Deleted => Some("del"),
+            Self::Renamed => Some("rename_from"),
+            Self::Moved => Some("move_from"),
+            Self::Flushed => None,
+        }
+    }
+
+    /// Class of the keyspace notification event, the notify-keyspace-events flag enabling it
+    pub fn class(&self) -> char {
+        match self {
+            Self::Expired => 'x',
+            _ => 'g',
+        }
+    }
+}
+
+/// A key removed from the database, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedKey {
+    /// The key
+    pub key: Bytes,
+    /// Why it was removed
+    pub reason: RemovalReason,
+}
+
+/// Whether an entry found by a command is valid, recording the access if it is
+#[inline]
+fn accessed(entry: &Entry) -> bool {
+    let is_valid = entry.is_valid();
+    if is_valid {
+        entry.touch();
+    }
+    is_valid
+}
+
 /// Read only reference
 pub struct RefValue<'a> {
     key: &'a Bytes,
-    slot: RwLockReadGuard<'a, HashMap<Bytes, Entry>>,
+    slot: RwLockReadGuard<'a, Slot>,
 }
 
 impl<'a> RefValue<'a> {
-    /// Consumes the RefValue and a new cloned Value, only if the Value is
-    /// scalar, otherwise a WrongType error is returned (casted as a Value)
+    /// Consumes the RefValue and returns a cloned Value, only if the Value is
+    /// scalar, otherwise a WrongType error is returned
     #[inline(always)]
-    pub fn into_inner(self) -> Value {
+    pub fn into_inner(self) -> Result<Value, Error> {
         self.slot
             .get(self.key)
-            .filter(|x| x.is_valid())
-            .map(|x| {
-                if x.is_scalar() {
-                    x.inner().clone()
-                } else {
-                    Error::WrongType.into()
-                }
-            })
-            .unwrap_or_default()
+            .filter(|x| accessed(x))
+            .map_or(Ok(Value::Null), |x| x.clone_value())
     }
 
     /// Gets an optional reference to the read guarded value
     pub fn inner(&self) -> Option<RwLockReadGuard<'_, Value>> {
         self.slot
             .get(self.key)
-            .filter(|x| x.is_valid())
+            .filter(|x| accessed(x))
             .map(|x| x.inner())
     }
 
@@ -71,7 +141,7 @@ impl<'a> RefValue<'a> {
     pub fn inner_mut(&self) -> Option<RwLockWriteGuard<'_, Value>> {
         self.slot
             .get(self.key)
-            .filter(|x| x.is_valid())
+            .filter(|x| accessed(x))
             .map(|x| x.inner_mut())
     }
 
@@ -86,7 +156,7 @@ impl<'a> RefValue<'a> {
     where
         F: FnOnce(&Value) -> T,
     {
-        self.slot.get(self.key).filter(|x| x.is_valid()).map(|x| {
+        self.slot.get(self.key).filter(|x| accessed(x)).map(|x| {
             let value = x.inner();
             f(value.deref())
         })
@@ -103,7 +173,7 @@ impl<'a> RefValue<'a> {
     where
         F: FnOnce(&mut Value) -> T,
     {
-        self.slot.get(self.key).filter(|x| x.is_valid()).map(|x| {
+        self.slot.get(self.key).filter(|x| accessed(x)).map(|x| {
             let mut value = x.inner_mut();
             f(value.deref_mut())
         })
@@ -120,6 +190,200 @@ impl<'a> RefValue<'a> {
     }
 }
 
+/// Exclusive access to a key, see `Db::entry`.
+///
+/// The slot of the key is write locked until the entry is dropped, so a command can read, insert,
+/// modify and remove a key acquiring the slot lock only once. If the value was inserted or
+/// modified its version is bumped on drop, and the first connection waiting for the key is woken
+/// up.
+pub struct DbEntry<'a> {
+    db: &'a Db,
+    key: Bytes,
+    slot: Option<RwLockWriteGuard<'a, Slot>>,
+    modified: bool,
+}
+
+impl<'a> DbEntry<'a> {
+    #[inline]
+    fn slot(&self) -> &Slot {
+        self.slot
+            .as_ref()
+            .expect("the slot is locked until the entry is dropped")
+    }
+
+    /// Returns the key
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    /// Whether the key exists
+    pub fn exists(&self) -> bool {
+        self.slot().contains_key(&self.key)
+    }
+
+    /// Maps the value, if the key exists
+    pub fn map<T, F>(&self, f: F) -> Option<T>
+    where
+        F: FnOnce(&Value) -> T,
+    {
+        self.slot().get(&self.key).map(|x| {
+            x.touch();
+            f(x.inner().deref())
+        })
+    }
+
+    /// Modifies the value, if the key exists, returning the result of `f`. The value is only
+    /// considered modified if `f` succeeds.
+    pub fn modify<T, F>(&mut self, f: F) -> Result<Option<T>, Error>
+    where
+        F: FnOnce(&mut Value) -> Result<T, Error>,
+    {
+        let result = self
+            .slot()
+            .get(&self.key)
+            .map(|x| {
+                x.touch();
+                f(x.inner_mut().deref_mut())
+            })
+            .transpose()?;
+        self.modified |= result.is_some();
+        Ok(result)
+    }
+
+    /// Modifies the value if the key exists, like `and_modify` of the entry API of `HashMap`.
+    pub fn and_modify<F>(mut self, f: F) -> Result<Self, Error>
+    where
+        F: FnOnce(&mut Value) -> Result<(), Error>,
+    {
+        self.modify(f)?;
+        Ok(self)
+    }
+
+    /// Inserts the value returned by `f`, without expiration, if the key does not exist.
+    pub fn or_insert_with<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() -> Value,
+    {
+        if !self.exists() {
+            let slot = self
+                .slot
+                .as_mut()
+                .expect("the slot is locked until the entry is dropped");
+            self.db
+                .insert_entry(slot, self.key.clone(), Entry::new(f(), None));
+            self.modified = true;
+        }
+        self
+    }
+
+    /// Removes the key, returning its value
+    pub fn remove(&mut self) -> Option<Value> {
+        let slot = self
+            .slot
+            .as_mut()
+            .expect("the slot is locked until the entry is dropped");
+        let entry = self
+            .db
+            .remove_entry(slot, &self.key, RemovalReason::Deleted)?;
+        self.modified = false;
+        Some(entry.take_value())
+    }
+}
+
+impl Drop for DbEntry<'_> {
+    fn drop(&mut self) {
+        let slot = match self.slot.take() {
+            Some(slot) => slot,
+            None => return,
+        };
+        let typ = if self.modified {
+            slot.get(&self.key).map(|entry| {
+                entry.bump_version();
+                self.db.changed(&slot, &self.key, entry);
+                entry.typ()
+            })
+        } else {
+            None
+        };
+        drop(slot);
+        if let Some(typ) = typ {
+            self.db.wake_first_subscriber(&self.key, typ);
+        }
+    }
+}
+
+/// Subscription to key changes, see `Db::watch_key_changes`
+pub struct KeyChangeSubscription {
+    db: Arc<Db>,
+    keys: Vec<Bytes>,
+    listener: Arc<KeyChangeListener>,
+}
+
+impl KeyChangeSubscription {
+    /// Returns the listener of the key changes
+    pub fn listener(&self) -> &KeyChangeListener {
+        &self.listener
+    }
+}
+
+impl Drop for KeyChangeSubscription {
+    fn drop(&mut self) {
+        // Leave the waiting queues, handing over any pending element to the next in line
+        self.db.unsubscribe_from_key_changes(&self.keys);
+    }
+}
+
+/// Connections waiting for a key to change, in the order they started waiting
+type WaitQueue = VecDeque<(u128, Arc<KeyChangeListener>)>;
+
+/// Receives the changes of the keys a connection is waiting for.
+///
+/// Each notification carries the type of the value the key holds after the change, so a blocked
+/// command can ignore changes it cannot act on (a SET on a key BLPOP is watching) instead of
+/// running its whole worker again.
+#[derive(Debug, Default)]
+pub struct KeyChangeListener {
+    notify: Notify,
+    changes: Mutex<Vec<ValueTyp>>,
+}
+
+impl KeyChangeListener {
+    /// Records a change and wakes up the listener
+    fn push(&self, typ: ValueTyp) {
+        let mut changes = self.changes.lock();
+        if !changes.contains(&typ) {
+            changes.push(typ);
+        }
+        drop(changes);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next changes, returning the types of the changed values. Changes that
+    /// happened while the listener was not waiting are returned right away.
+    pub async fn changes(&self) -> Vec<ValueTyp> {
+        loop {
+            let changes = std::mem::take(&mut *self.changes.lock());
+            if !changes.is_empty() {
+                return changes;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// A key picked by `Db::sample_keys`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySample {
+    /// Key name
+    pub key: Bytes,
+    /// Type of the value
+    pub typ: ValueTyp,
+    /// Number of elements of a collection, or length in bytes of a string
+    pub length: usize,
+    /// Estimated memory used by the value, in bytes
+    pub memory_usage: usize,
+}
+
 /// Database structure
 ///
 /// Each connection has their own clone of the database and the conn_id is stored in each instance.
@@ -146,15 +410,33 @@ pub struct Db {
     ///
     /// Because all operations are always key specific, the key is used to hash
     /// and select to which HashMap the data might be stored.
-    slots: Arc<Vec<RwLock<HashMap<Bytes, Entry>>>>,
+    slots: Arc<Vec<RwLock<Slot>>>,
+
+    /// Number of entries of each slot, readable without locking the slots.
+    ///
+    /// Expired entries are counted until they are purged.
+    slot_lens: Arc<Vec<Arc<AtomicUsize>>>,
+
+    /// Estimated memory used by the keys and their values of all the slots, readable without
+    /// locking the slots.
+    used_memory: Arc<AtomicUsize>,
 
     /// Data structure to store all expiring keys
     expirations: Arc<Mutex<ExpirationDb>>,
 
-    /// Key changes subscriptions hash. This hash contains all the senders to
-    /// key subscriptions. If a key does not exists here it means that no-one
-    /// wants to be notified of the current key changes.
-    change_subscriptions: Arc<RwLock<HashMap<Bytes, Sender<()>>>>,
+    /// Key changes subscriptions hash. Connections waiting for a key to change (blocked in
+    /// BLPOP and friends) are queued in the order they subscribed, and each change wakes only
+    /// the first one, so blocked clients are served in FIFO order. If a key does not exists here
+    /// it means that no-one wants to be notified of the current key changes.
+    ///
+    /// The subscriptions are sharded like the data, a key's queue lives in the shard of the key's
+    /// slot, so waiting for and notifying changes scales like the slots do.
+    change_subscriptions: Arc<Vec<Mutex<HashMap<Bytes, WaitQueue>>>>,
+
+    /// Removed keys are announced through this channel, once per removal, along with the reason
+    /// of the removal. Expired keys are announced when they are removed either lazily (on access)
+    /// or by the purge process.
+    removed_keys: Sender<RemovedKey>,
 
     /// Number of HashMaps that are available.
     number_of_slots: usize,
@@ -176,41 +458,136 @@ pub struct Db {
     /// here and it is not being hold by the current connection, current
     /// connection must wait.
     tx_key_locks: Arc<RwLock<HashMap<Bytes, u128>>>,
+
+    /// External store the database is a cache of, if any
+    backing_store: Arc<RwLock<Option<StoreHandle>>>,
 }
 
 impl Db {
     /// Creates a new database instance
     pub fn new(number_of_slots: usize) -> Self {
-        let slots = (0..number_of_slots)
-            .map(|_| RwLock::new(HashMap::new()))
+        let slot_lens: Vec<Arc<AtomicUsize>> = (0..number_of_slots)
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect();
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        let slots = slot_lens
+            .iter()
+            .map(|len| RwLock::new(Slot::new(len.clone(), used_memory.clone())))
             .collect();
 
         Self {
             slots: Arc::new(slots),
+            slot_lens: Arc::new(slot_lens),
+            used_memory,
             expirations: Arc::new(Mutex::new(ExpirationDb::new())),
-            change_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            change_subscriptions: Arc::new(
+                (0..number_of_slots)
+                    .map(|_| Mutex::new(HashMap::new()))
+                    .collect(),
+            ),
+            removed_keys: broadcast::channel(REMOVED_KEYS_CAPACITY).0,
             conn_id: 0,
             db_id: unique_id(),
             tx_key_locks: Arc::new(RwLock::new(HashMap::new())),
+            backing_store: Arc::new(RwLock::new(None)),
             number_of_slots,
         }
     }
 
+    /// Returns a read-only handle to the database, which can be shared with other threads. Only
+    /// read operations are available through the handle.
+    pub fn read_view(self: &Arc<Db>) -> ReadView {
+        ReadView::new(self.clone())
+    }
+
     /// Attaches a conn_id to a database instance.
     ///
     /// This conn_id is used to lock entries for a given conn_id.
     pub fn set_conn_id(self: Arc<Db>, conn_id: u128) -> Arc<Db> {
         Arc::new(Self {
             slots: self.slots.clone(),
+            slot_lens: self.slot_lens.clone(),
+            used_memory: self.used_memory.clone(),
             tx_key_locks: self.tx_key_locks.clone(),
+            backing_store: self.backing_store.clone(),
             expirations: self.expirations.clone(),
             change_subscriptions: self.change_subscriptions.clone(),
+            removed_keys: self.removed_keys.clone(),
             conn_id,
             db_id: self.db_id,
             number_of_slots: self.number_of_slots,
         })
     }
 
+    /// Puts the database in front of an external store. Missing keys are loaded from the store
+    /// with [`Db::load_missing`] and changes are propagated to it.
+    ///
+    /// It must be called from within a tokio runtime.
+    pub fn set_backing_store(&self, store: Arc<dyn BackingStore>) {
+        let slots = Arc::downgrade(&self.slots);
+        let number_of_slots = self.number_of_slots;
+        // Changes are propagated after the fact, so the locks of transactions are not waited for
+        let read = move |key: &Bytes| {
+            let slots = slots.upgrade()?;
+            let slot = slots[(hash(key) as usize) % number_of_slots].read();
+            slot.get(key)
+                .filter(|entry| entry.is_valid())
+                .map(|entry| entry.snapshot())
+        };
+        *self.backing_store.write() = Some(StoreHandle::new(store, read));
+    }
+
+    #[inline]
+    fn backing_store(&self) -> Option<StoreHandle> {
+        self.backing_store.read().clone()
+    }
+
+    /// Queues a created or changed key to be written to the backing store, if any
+    #[inline]
+    fn write_through(&self, key: &Bytes) {
+        if let Some(store) = self.backing_store() {
+            store.set(key.clone());
+        }
+    }
+
+    /// Records a change made in place to the value of a key: the memory used by the value is
+    /// estimated again, and the key is written to the backing store. The value must not be
+    /// locked.
+    fn changed(&self, slot: &Slot, key: &Bytes, entry: &Entry) {
+        slot.measure(key, entry);
+        self.write_through(key);
+    }
+
+    /// Loads a key from the backing store if the database does not have it
+    pub async fn load_missing(&self, key: &Bytes) {
+        let store = match self.backing_store() {
+            Some(store) => store,
+            None => return,
+        };
+        // A key with a pending change is newer in the database than in the store, even if it was
+        // removed
+        if store.is_pending(key) || self.exists(std::slice::from_ref(key)) > 0 {
+            return;
+        }
+        if let Some((value, expires_in)) = store.get_missing(key).await {
+            let mut slot = self.slots[self.get_slot(key)].write();
+            // The key may have been written or removed while the store was being queried
+            if slot.get(key).filter(|x| x.is_valid()).is_none() && !store.is_pending(key) {
+                let expires_at = expires_in.map(|duration| {
+                    Instant::now()
+                        .checked_add(duration)
+                        .unwrap_or_else(far_future)
+                });
+                self.insert_entry(&mut slot, key.clone(), Entry::new(value, expires_at));
+            }
+        }
+    }
+
+    /// Returns the number of slots (internal shards) of the database
+    pub fn number_of_slots(&self) -> usize {
+        self.number_of_slots
+    }
+
     #[inline]
     /// Returns a slot where a key may be hosted.
     ///
@@ -239,6 +616,65 @@ impl Db {
         id
     }
 
+    /// Returns the slot where a key may be hosted, read locked.
+    ///
+    /// If the key is expired it is removed before returning the slot, instead of waiting for the
+    /// purge process to reclaim its memory.
+    fn read_slot(&self, key: &Bytes) -> RwLockReadGuard<'_, Slot> {
+        let slot_id = self.get_slot(key);
+        let slot = self.slots[slot_id].read();
+        if slot.get(key).filter(|x| !x.is_valid()).is_none() {
+            return slot;
+        }
+        drop(slot);
+        self.remove_expired(slot_id, key);
+        self.slots[slot_id].read()
+    }
+
+    /// Returns the slot where a key may be hosted, write locked.
+    ///
+    /// If the key is expired it is removed first, announcing its expiration, so a write never
+    /// replaces or reuses an expired key silently.
+    fn write_slot(&self, key: &Bytes) -> RwLockWriteGuard<'_, Slot> {
+        let mut slot = self.slots[self.get_slot(key)].write();
+        if slot.get(key).filter(|x| !x.is_valid()).is_some() {
+            self.remove_entry(&mut slot, key, RemovalReason::Expired);
+        }
+        slot
+    }
+
+    /// Removes a key only if it is expired, announcing its expiration. Because the key is removed
+    /// while holding the write lock only one caller can succeed, so each expiration is announced
+    /// exactly once.
+    fn remove_expired(&self, slot_id: usize, key: &Bytes) -> bool {
+        let mut slot = self.slots[slot_id].write();
+        // The key may have been replaced (or got a new TTL) in the meantime, only remove it if it
+        // is still expired.
+        if slot.get(key).filter(|x| !x.is_valid()).is_none() {
+            return false;
+        }
+        self.remove_entry(&mut slot, key, RemovalReason::Expired);
+        drop(slot);
+
+        trace!("Removed key {:?} due timeout", key);
+        true
+    }
+
+    /// Announces the removal of a key
+    fn notify_removal(&self, key: &Bytes, reason: RemovalReason) {
+        // Nobody may be listening, which is fine
+        let _ = self.removed_keys.send(RemovedKey {
+            key: key.clone(),
+            reason,
+        });
+    }
+
+    /// Subscribe to removed keys. Each removal is sent once, when the key is removed from the
+    /// database, telling whether the key expired or was deleted.
+    pub fn subscribe_to_removals(&self) -> Receiver<RemovedKey> {
+        self.removed_keys.subscribe()
+    }
+
     /// Locks keys exclusively
     ///
     /// The locked keys are only accessible (read or write) by the connection
@@ -290,12 +726,60 @@ impl Db {
         }
     }
 
+    /// Releases all the locks held by the connection attached to this database
+    /// instance
+    pub fn unlock_all_keys(&self) {
+        self.tx_key_locks
+            .write()
+            .retain(|_, blocker| *blocker != self.conn_id);
+    }
+
+    /// Releases the locks held by connections that are no longer alive, for
+    /// instance a connection that died abruptly in the middle of a transaction.
+    ///
+    /// Returns the number of released locks.
+    pub fn unlock_orphan_keys<F: Fn(u128) -> bool>(&self, is_alive: F) -> usize {
+        let mut lock = self.tx_key_locks.write();
+        let before = lock.len();
+        lock.retain(|_, blocker| is_alive(*blocker));
+        before - lock.len()
+    }
+
+    /// Returns the number of keys locked by transactions
+    pub fn locked_keys(&self) -> usize {
+        self.tx_key_locks.read().len()
+    }
+
+    /// Returns the internal encoding of the value stored at key, if any
+    pub fn get_encoding(&self, key: &Bytes) -> Option<&'static str> {
+        let slot = self.read_slot(key);
+        slot.get(key)
+            .filter(|x| x.is_valid())
+            .map(|x| x.inner().encoding())
+    }
+
+    /// Returns a copy of the value stored at key, or Null. Unlike reads through [`Db::get`], the
+    /// access is not recorded, so the idle time of the key is left untouched.
+    pub fn peek(&self, key: &Bytes) -> Value {
+        let slot = self.read_slot(key);
+        slot.get(key)
+            .filter(|x| x.is_valid())
+            .map(|x| x.inner().clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the reference count of the value stored at key, if any
+    pub fn get_refcount(&self, key: &Bytes) -> Option<usize> {
+        let slot = self.read_slot(key);
+        slot.get(key).filter(|x| x.is_valid()).map(|x| x.refcount())
+    }
+
     /// Return debug info for a key
     pub fn debug(&self, key: &Bytes) -> Result<VDebug, Error> {
-        let slot = self.slots[self.get_slot(key)].read();
+        let slot = self.read_slot(key);
         slot.get(key)
             .filter(|x| x.is_valid())
-            .map(|x| x.inner().debug())
+            .map(|x| x.debug())
             .ok_or(Error::NotFound)
     }
 
@@ -304,7 +788,7 @@ impl Db {
         Ok(keys
             .iter()
             .map(|key| {
-                let slot = self.slots[self.get_slot(key)].read();
+                let slot = self.read_slot(key);
                 Value::new(
                     slot.get(key)
                         .filter(|v| v.is_valid())
@@ -316,13 +800,38 @@ impl Db {
             .collect::<Vec<Value>>())
     }
 
+    /// Returns a copy of every key, along with its value and time to live
+    pub fn entries(&self) -> Vec<(Bytes, Value, Option<Duration>)> {
+        let now = Instant::now();
+        self.slots
+            .iter()
+            .flat_map(|slot| {
+                slot.read()
+                    .iter()
+                    .filter(|(_, entry)| entry.is_valid())
+                    .map(|(key, entry)| {
+                        let ttl = entry.get_ttl().map(|at| at.saturating_duration_since(now));
+                        (key.clone(), entry.inner().clone(), ttl)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     /// Flushes the entire database
     pub fn flushdb(&self) -> Result<Value, Error> {
+        let store = self.backing_store();
         self.expirations.lock().flush();
         self.slots
             .iter()
             .map(|s| {
                 let mut s = s.write();
+                for (key, entry) in s.iter() {
+                    if let Some(store) = store.as_ref().filter(|_| entry.is_valid()) {
+                        store.delete(key.clone());
+                    }
+                    self.notify_removal(key, RemovalReason::Flushed);
+                }
                 s.clear();
             })
             .for_each(drop);
@@ -331,19 +840,69 @@ impl Db {
 
     /// Checks if the database is empty
     pub fn is_empty(&self) -> bool {
+        self.len_with_expired() == 0
+    }
+
+    /// Returns the number of elements in the database.
+    ///
+    /// Expired keys that were not purged yet are not counted.
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self.len_with_expired().saturating_sub(self.expired_len()))
+    }
+
+    /// Returns the number of entries stored in the database, including expired keys that were
+    /// not purged yet. No slot is locked.
+    pub fn len_with_expired(&self) -> usize {
+        self.slot_lens
+            .iter()
+            .map(|len| len.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the number of entries of each slot, including expired keys that were not purged
+    /// yet. No slot is locked.
+    pub fn slot_lens(&self) -> Vec<usize> {
+        self.slot_lens
+            .iter()
+            .map(|len| len.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Counts the keys of each type, by type name. Each slot is read-locked in turn, and the last
+    /// access time of the keys is not updated.
+    pub fn keys_by_type(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
         for slot in self.slots.iter() {
-            if slot.read().len() > 0 {
-                return false;
+            for entry in slot.read().values().filter(|entry| entry.is_valid()) {
+                *counts.entry(entry.typ().name()).or_insert(0) += 1;
             }
         }
+        counts
+    }
+
+    /// Returns an estimation of the memory used by the keys and their values, in bytes. The
+    /// estimation is kept up to date as the data changes, no slot is locked.
+    pub fn memory_usage(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// Sets the namespace quotas the keys of the database are accounted to. The keys the database
+    /// already has are accounted right away, each slot is write-locked in turn.
+    pub fn set_quotas(&self, quotas: Option<Arc<Quotas>>) {
+        for slot in self.slots.iter() {
+            slot.write().set_quotas(quotas.clone());
+        }
+    }
 
-        true
+    /// Returns the number of expired keys that were not purged yet
+    pub fn expired_len(&self) -> usize {
+        self.expirations.lock().expired_len(Instant::now())
     }
 
-    /// Returns the number of elements in the database
-    pub fn len(&self) -> Result<usize, Error> {
-        self.purge();
-        Ok(self.slots.iter().map(|s| s.read().len()).sum())
+    /// Returns the number of keys with an expiration time, including expired keys that were not
+    /// purged yet
+    pub fn expires_len(&self) -> usize {
+        self.expirations.lock().len()
     }
 
     /// Round numbers to store efficiently, specially float numbers. For instance `1.00` will be converted to `1`.
@@ -357,9 +916,7 @@ impl Db {
             return Bytes::copy_from_slice(number_to_str.as_bytes());
         }
 
-        let number_to_str = number_to_str
-            .trim_end_matches(|c| c == '0' || c == '.')
-            .to_string();
+        let number_to_str = number_to_str.trim_end_matches(['0', '.']).to_string();
 
         Bytes::copy_from_slice(if number_to_str.is_empty() {
             b"0"
@@ -368,146 +925,203 @@ impl Db {
         })
     }
 
-    // Converts a given number to a correct Value, it should be used with Self::round_numbers()
-    fn number_to_value(number: &[u8]) -> Result<Value, Error> {
-        if number.iter().any(|x| *x == b'.') {
-            Ok(Value::new(number))
-        } else {
-            Ok(Value::Integer(bytes_to_number(number)?))
+    /// Adds `incr_by` to the `current` number, if any, and returns the result alongside with its
+    /// representation to be stored.
+    ///
+    /// This is shared by every INCR* and HINCRBY* command, so they all report the same errors
+    /// and store numbers the same way.
+    fn incr_number<T: Number>(current: Option<&[u8]>, incr_by: T) -> Result<(T, Bytes), Error> {
+        let current = match current {
+            Some(current) => T::parse(current)?,
+            None => T::zero(),
+        };
+        let number = current.checked_add(&incr_by).ok_or_else(T::overflow)?;
+        Ok((number, Self::round_numbers(number)))
+    }
+
+    /// Returns the bytes of a string to modify them in place, turning the value into a mutable
+    /// blob first if needed. Any other type is a WrongType error.
+    fn mutable_blob(value: &mut Value) -> Result<&mut BytesMut, Error> {
+        if !matches!(value, Value::BlobRw(_)) {
+            let bytes = BytesMut::from(value.as_bytes().ok_or(Error::WrongType)?);
+            *value = Value::BlobRw(bytes);
+        }
+        match value {
+            Value::BlobRw(bytes) => Ok(bytes),
+            _ => Err(Error::WrongType),
         }
     }
 
     /// Increment a sub-key in a hash
     ///
     /// If the stored value cannot be converted into a number an error will be thrown
-    pub fn hincrby<T>(
-        &self,
-        key: &Bytes,
-        sub_key: &Bytes,
-        incr_by: &Bytes,
-        typ: &str,
-    ) -> Result<Value, Error>
-    where
-        T: ToString
-            + FromStr
-            + CheckedAdd
-            + for<'a> TryFrom<&'a Value, Error = Error>
-            + Into<Value>
-            + Copy,
-    {
-        let slot_id = self.get_slot(key);
-        let slot = self.slots[slot_id].read();
-        let mut incr_by: T =
-            bytes_to_number(incr_by).map_err(|_| Error::NotANumberType(typ.to_owned()))?;
-
-        if let Some(x) = slot
-            .get(key)
-            .filter(|x| x.is_valid())
-            .map(|x| x.inner_mut())
-            .map(|mut x| match x.deref_mut() {
-                Value::Hash(ref mut h) => {
-                    if let Some(n) = h.get(sub_key) {
-                        incr_by = incr_by
-                            .checked_add(
-                                &bytes_to_number(n)
-                                    .map_err(|_| Error::NotANumberType(typ.to_owned()))?,
-                            )
-                            .ok_or(Error::Overflow)?;
-                    }
-                    let incr_by_bytes = Self::round_numbers(incr_by);
-                    h.insert(sub_key.clone(), incr_by_bytes.clone());
-
-                    Self::number_to_value(&incr_by_bytes)
-                }
-                _ => Err(Error::WrongType),
-            })
-        {
-            return x;
+    pub fn hincrby<T: Number>(&self, key: &Bytes, sub_key: &Bytes, incr_by: T) -> Result<T, Error> {
+        // The key is looked up and created under the same lock, so concurrent increments of a
+        // missing key are never lost
+        let mut entry = self.entry(key.clone());
+        let incremented = entry.modify(|value| match value {
+            Value::Hash(ref mut h) => {
+                let (number, bytes) =
+                    Self::incr_number(h.get(sub_key).map(|n| n.as_ref()), incr_by)?;
+                h.insert(sub_key.clone(), bytes);
+                Ok(number)
+            }
+            _ => Err(Error::WrongType),
+        })?;
+        if let Some(number) = incremented {
+            return Ok(number);
         }
 
-        drop(slot);
+        let (number, bytes) = Self::incr_number(None, incr_by)?;
         #[allow(clippy::mutable_key_type)]
         let mut h = HashMap::new();
-        let incr_by_bytes = Self::round_numbers(incr_by);
-        h.insert(sub_key.clone(), incr_by_bytes.clone());
-        let _ = self.slots[slot_id]
-            .write()
-            .insert(key.clone(), Entry::new(h.into(), None));
-        Self::number_to_value(&incr_by_bytes)
+        h.insert(sub_key.clone(), bytes);
+        entry.or_insert_with(|| h.into());
+        Ok(number)
     }
 
     /// Increments a key's value by a given number
     ///
     /// If the stored value cannot be converted into a number an error will be
     /// thrown.
-    pub fn incr<T>(&self, key: &Bytes, incr_by: T) -> Result<T, Error>
-    where
-        T: ToString + CheckedAdd + for<'a> TryFrom<&'a Value, Error = Error> + Into<Value> + Copy,
-    {
-        let slot_id = self.get_slot(key);
-        let slot = self.slots[slot_id].read();
-
-        if let Some(entry) = slot.get(key).filter(|x| x.is_valid()) {
-            if !entry.is_scalar() {
+    pub fn incr<T: Number>(&self, key: &Bytes, incr_by: T) -> Result<T, Error> {
+        let mut entry = self.entry(key.clone());
+        let incremented = entry.modify(|value| {
+            if value.typ() != ValueTyp::String {
                 return Err(Error::WrongType);
             }
-            let mut value = entry.inner_mut();
-            let mut number: T = (&*value).try_into()?;
-
-            number = incr_by.checked_add(&number).ok_or(Error::Overflow)?;
-            *value = Value::Blob(Self::round_numbers(number));
-            entry.bump_version();
+            let current = value.as_bytes().ok_or_else(T::not_a_number)?;
+            let (number, bytes) = Self::incr_number(Some(current), incr_by)?;
+            *value = Value::Blob(bytes);
             Ok(number)
-        } else {
-            drop(slot);
-            self.slots[slot_id].write().insert(
-                key.clone(),
-                Entry::new(Value::Blob(Self::round_numbers(incr_by)), None),
-            );
-            Ok(incr_by)
+        })?;
+        if let Some(number) = incremented {
+            return Ok(number);
         }
+
+        let (number, bytes) = Self::incr_number(None, incr_by)?;
+        entry.or_insert_with(|| Value::Blob(bytes));
+        Ok(number)
     }
 
-    /// Removes any expiration associated with a given key
-    pub fn persist(&self, key: &Bytes) -> Value {
-        let slot = self.slots[self.get_slot(key)].read();
-        slot.get(key)
-            .filter(|x| x.is_valid())
-            .map_or(0.into(), |x| {
-                if x.has_ttl() {
-                    self.expirations.lock().remove(key);
-                    x.persist();
-                    1.into()
-                } else {
-                    0.into()
-                }
-            })
+    /// Inserts an entry into an exclusively locked slot, keeping the expiration table in sync.
+    ///
+    /// If the slot already holds the key its stored instance is kept, and that instance is the
+    /// one shared with the expiration table, so the bytes of each key are stored only once.
+    fn insert_entry(&self, slot: &mut Slot, key: Bytes, entry: Entry) -> Option<Entry> {
+        let expires_at = entry.get_ttl();
+        let previous = slot.insert(key.clone(), entry);
+        let mut expirations = self.expirations.lock();
+        match (expires_at, slot.get_key_value(&key)) {
+            (Some(expires_at), Some((key, _))) => expirations.add(key, expires_at),
+            // Make sure to remove the new key (or replaced) from the
+            // expiration table (from any possible past value).
+            _ => {
+                expirations.remove(&key);
+            }
+        }
+        previous
     }
 
-    /// Set time to live for a given key
-    pub fn set_ttl(
-        &self,
-        key: &Bytes,
-        expires_in: Duration,
-        opts: ExpirationOpts,
-    ) -> Result<Value, Error> {
-        if opts.if_none && (opts.replace_only || opts.greater_than || opts.lower_than) {
-            return Err(Error::OptsNotCompatible("NX and XX, GT or LT".to_owned()));
+    /// Removes a key from an exclusively locked slot, keeping the expiration table in sync.
+    ///
+    /// Every removal but FLUSHDB goes through here, so removed keys are also deleted from the
+    /// backing store, and the removal is announced with `reason`, or as an expiration if the key
+    /// had expired. Expired keys are not deleted from the store, expiring is how the cache forgets
+    /// about a key.
+    fn remove_entry(&self, slot: &mut Slot, key: &Bytes, reason: RemovalReason) -> Option<Entry> {
+        let entry = slot.remove(key)?;
+        self.expirations.lock().remove(key);
+        if entry.is_valid() {
+            if let Some(store) = self.backing_store() {
+                store.delete(key.clone());
+            }
+            self.notify_removal(key, reason);
+        } else {
+            self.notify_removal(key, RemovalReason::Expired);
         }
+        Some(entry)
+    }
 
-        if opts.greater_than && opts.lower_than {
-            return Err(Error::OptsNotCompatible("GT and LT".to_owned()));
+    /// Removes a key holding an empty list, set or hash, announcing it as deleted. The commands
+    /// removing elements call it, so emptied collections do not linger. Returns whether the key
+    /// was removed.
+    pub fn remove_if_empty(&self, key: &Bytes) -> bool {
+        let mut slot = self.slots[self.get_slot(key)].write();
+        let is_empty = slot.get(key).filter(|x| x.is_valid()).map_or(false, |x| {
+            let value = x.inner();
+            matches!(*value, Value::List(_) | Value::Set(_) | Value::Hash(_)) && value.length() == 0
+        });
+        if is_empty {
+            self.remove_entry(&mut slot, key, RemovalReason::Deleted);
         }
+        is_empty
+    }
 
-        let slot = self.slots[self.get_slot(key)].read();
+    /// Updates the TTL of an entry and the expiration table.
+    ///
+    /// The entry's TTL is mutated through interior mutability while the slot is only held in
+    /// shared mode, so concurrent TTL updates to the same key are serialized by holding the
+    /// expiration table lock, that way the entry and the expiration table always agree.
+    ///
+    /// The lock order is always slot first, then the expiration table. The key should be the
+    /// instance stored in the slot, so the expiration table shares it instead of holding a copy.
+    fn update_ttl(&self, key: &Bytes, entry: &Entry, expires_at: Option<Instant>) {
+        let mut expirations = self.expirations.lock();
+        Self::update_ttl_locked(&mut expirations, key, entry, expires_at);
+    }
+
+    /// Same as `update_ttl` but with an already locked expiration table.
+    fn update_ttl_locked(
+        expirations: &mut ExpirationDb,
+        key: &Bytes,
+        entry: &Entry,
+        expires_at: Option<Instant>,
+    ) {
+        if let Some(expires_at) = expires_at {
+            expirations.add(key, expires_at);
+            entry.set_ttl(expires_at);
+        } else {
+            expirations.remove(key);
+            entry.persist();
+        }
+    }
+
+    /// Removes any expiration associated with a given key
+    pub fn persist(&self, key: &Bytes) -> Value {
+        let slot = self.read_slot(key);
+        slot.get(key)
+            .filter(|x| x.is_valid())
+            .map_or(0.into(), |x| {
+                let mut expirations = self.expirations.lock();
+                if x.has_ttl() {
+                    Self::update_ttl_locked(&mut expirations, key, x, None);
+                    1.into()
+                } else {
+                    0.into()
+                }
+            })
+    }
+
+    /// Set time to live for a given key
+    pub fn set_ttl(
+        &self,
+        key: &Bytes,
+        expires_in: Duration,
+        opts: ExpirationOpts,
+    ) -> Result<Value, Error> {
+        let slot = self.read_slot(key);
         let expires_at = Instant::now()
             .checked_add(expires_in)
             .unwrap_or_else(far_future);
 
         Ok(slot
-            .get(key)
-            .filter(|x| x.is_valid())
-            .map_or(0.into(), |x| {
+            .get_key_value(key)
+            .filter(|(_, x)| x.is_valid())
+            .map_or(0.into(), |(key, x)| {
+                // The expiration table is locked before reading the current TTL, so the
+                // conditions are evaluated and applied atomically.
+                let mut expirations = self.expirations.lock();
                 let current_expire = x.get_ttl();
                 if opts.if_none && current_expire.is_some() {
                     return 0.into();
@@ -533,8 +1147,7 @@ impl Db {
                     }
                 }
 
-                self.expirations.lock().add(key, expires_at);
-                x.set_ttl(expires_at);
+                Self::update_ttl_locked(&mut expirations, key, x, Some(expires_at));
                 1.into()
             }))
     }
@@ -545,58 +1158,58 @@ impl Db {
     /// make offset fit. Non-existing keys are considered as empty strings, so this
     /// command will make sure it holds a string large enough to be able to set
     /// value at offset.
-    pub fn set_range(&self, key: &Bytes, offset: i128, data: &[u8]) -> Result<Value, Error> {
-        let slot_id = self.get_slot(key);
-        let slot = self.slots[slot_id].read();
-
-        let mut value = slot
-            .get(key)
-            .map(|value| {
-                value.ensure_blob_is_mutable()?;
-                if !value.is_valid() {
-                    self.expirations.lock().remove(key);
-                    value.persist();
-                }
-                Ok::<_, Error>(value.inner_mut())
-            })
-            .transpose()?;
+    ///
+    /// `budget` is the number of bytes the data may still grow, if limited. The growth of the
+    /// string is checked against it before allocating anything, while the key is locked.
+    pub fn set_range(
+        &self,
+        key: &Bytes,
+        offset: i128,
+        data: &[u8],
+        budget: Option<usize>,
+    ) -> Result<Value, Error> {
+        let length = usize::try_from(offset).map_or(0, |offset| offset.saturating_add(data.len()));
+        let fits =
+            |current: usize| budget.map_or(true, |budget| length.saturating_sub(current) <= budget);
+        let check_offset = || {
+            if offset < 0 {
+                Err(Error::OutOfRange)
+            } else if offset >= 512 * 1024 * 1024 - 4 {
+                Err(Error::MaxAllowedSize)
+            } else {
+                Ok(())
+            }
+        };
 
-        if offset < 0 {
-            return Err(Error::OutOfRange);
+        let mut entry = self.entry(key.clone());
+        let written = entry.modify(|value| {
+            let current = value.as_bytes().ok_or(Error::WrongType)?.len();
+            if !fits(current) {
+                return Err(Error::OutOfMemory);
+            }
+            check_offset()?;
+            let bytes = Self::mutable_blob(value)?;
+            if bytes.len() < length {
+                grow_zeroed(bytes, length);
+            }
+            bytes[offset as usize..length].copy_from_slice(data);
+            Ok(bytes.len())
+        })?;
+        if let Some(len) = written {
+            return Ok(len.into());
         }
 
-        if offset >= 512 * 1024 * 1024 - 4 {
-            return Err(Error::MaxAllowedSize);
+        check_offset()?;
+        if data.is_empty() {
+            return Ok(0.into());
         }
-
-        let length = offset as usize + data.len();
-        if let Some(value) = value.as_mut() {
-            match value.deref_mut() {
-                Value::BlobRw(ref mut bytes) => {
-                    if bytes.capacity() < length {
-                        bytes.resize(length, 0);
-                    }
-                    let writer = &mut bytes[offset as usize..length];
-                    writer.copy_from_slice(data);
-                    Ok(bytes.len().into())
-                }
-                _ => Err(Error::WrongType),
-            }
-        } else {
-            drop(value);
-            drop(slot);
-            if data.is_empty() {
-                return Ok(0.into());
-            }
-            let mut bytes = BytesMut::new();
-            bytes.resize(length, 0);
-            let writer = &mut bytes[offset as usize..];
-            writer.copy_from_slice(data);
-            self.slots[slot_id]
-                .write()
-                .insert(key.clone(), Entry::new(Value::new(&bytes), None));
-            Ok(bytes.len().into())
+        if !fits(0) {
+            return Err(Error::OutOfMemory);
         }
+        let mut bytes = BytesMut::zeroed(length);
+        bytes[offset as usize..].copy_from_slice(data);
+        entry.or_insert_with(|| Value::new(&bytes));
+        Ok(bytes.len().into())
     }
 
     /// Copies a key
@@ -607,7 +1220,7 @@ impl Db {
         replace: Override,
         target_db: Option<Arc<Db>>,
     ) -> Result<bool, Error> {
-        let slot = self.slots[self.get_slot(&source)].read();
+        let slot = self.read_slot(&source);
         let value = if let Some(value) = slot.get(&source).filter(|x| x.is_valid()) {
             value.clone()
         } else {
@@ -619,7 +1232,7 @@ impl Db {
             if db.db_id == self.db_id && source == target {
                 return Err(Error::SameEntry);
             }
-            if replace == Override::No && db.exists(&[target.clone()]) > 0 {
+            if replace == Override::No && db.exists(std::slice::from_ref(&target)) > 0 {
                 return Ok(false);
             }
 
@@ -631,11 +1244,12 @@ impl Db {
                 return Err(Error::SameEntry);
             }
 
-            if replace == Override::No && self.exists(&[target.clone()]) > 0 {
+            if replace == Override::No && self.exists(std::slice::from_ref(&target)) > 0 {
                 return Ok(false);
             }
-            let mut slot = self.slots[self.get_slot(&target)].write();
-            slot.insert(target, value);
+            let mut slot = self.write_slot(&target);
+            self.write_through(&target);
+            self.insert_entry(&mut slot, target, value);
 
             Ok(true)
         }
@@ -656,7 +1270,7 @@ impl Db {
             return Ok(false);
         };
 
-        if Value::Integer(1)
+        if Ok(Value::Integer(1))
             == target_db.set_advanced(
                 source.clone(),
                 value,
@@ -666,35 +1280,71 @@ impl Db {
                 false,
             )
         {
-            slot.remove(&source);
+            self.remove_entry(&mut slot, &source, RemovalReason::Moved);
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    /// Samples up to `count` random keys. Expired keys are skipped and a key may be sampled more
+    /// than once. The last access time of the sampled keys is not updated.
+    ///
+    /// The keys are sampled lazily, locking a single slot at a time, as the iterator is consumed.
+    pub fn sample_keys<'a>(
+        &'a self,
+        count: usize,
+        rng: &'a mut Generator,
+    ) -> impl Iterator<Item = KeySample> + 'a {
+        let slots = self
+            .slot_lens
+            .iter()
+            .enumerate()
+            .filter(|(_, len)| len.load(Ordering::Relaxed) > 0)
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        let count = if slots.is_empty() { 0 } else { count };
+
+        (0..count).filter_map(move |_| {
+            let slot = self.slots[slots[rng.gen_range(0..slots.len())]].read();
+            random_entry(&slot, rng)
+                .filter(|(_, entry)| entry.is_valid())
+                .map(|(key, entry)| entry.sample(key.clone()))
+        })
+    }
+
     /// Return a random key from the database
-    pub fn randomkey(&self) -> Result<Value, Error> {
-        let mut rng = rand::thread_rng();
+    pub fn randomkey(&self, rng: &mut Generator) -> Result<Value, Error> {
         let mut candidates = self
             .slots
             .iter()
-            .filter_map(|slot| {
-                let slot = slot.read();
-                if slot.is_empty() {
-                    None
-                } else {
-                    slot.iter()
-                        .nth(rng.gen_range(0..slot.len()))
-                        .map(|(k, _v)| k.clone())
-                }
-            })
+            .filter_map(|slot| random_entry(&slot.read(), rng).map(|(k, _v)| k.clone()))
             .collect::<Vec<Bytes>>();
-        candidates.shuffle(&mut rng);
+        candidates.shuffle(rng);
         Ok(candidates.first().into())
     }
 
+    /// Checks whether a key can be renamed. An error is returned if the source does not exist,
+    /// and false if the target exists and it must not be overridden. Expired keys are treated as
+    /// missing.
+    fn can_rename(
+        source_slot: &Slot,
+        target_slot: &Slot,
+        source: &Bytes,
+        target: &Bytes,
+        override_value: Override,
+    ) -> Result<bool, Error> {
+        if source_slot.get(source).filter(|x| x.is_valid()).is_none() {
+            return Err(Error::NotFound);
+        }
+        Ok(override_value == Override::Yes
+            || target_slot.get(target).filter(|x| x.is_valid()).is_none())
+    }
+
     /// Renames a key
+    ///
+    /// Returns false, without renaming the key, when the target exists and `override_value` is
+    /// not set.
     pub fn rename(
         &self,
         source: &Bytes,
@@ -704,70 +1354,64 @@ impl Db {
         let slot1 = self.get_slot(source);
         let slot2 = self.get_slot(target);
 
-        let result = if slot1 == slot2 {
+        if slot1 == slot2 {
             let mut slot = self.slots[slot1].write();
-
-            if override_value == Override::No && slot.get(target).is_some() {
+            if !Self::can_rename(&slot, &slot, source, target, override_value)? {
                 return Ok(false);
             }
-
-            if let Some(value) = slot.remove(source) {
-                slot.insert(target.clone(), value);
-                Ok(true)
-            } else {
-                Err(Error::NotFound)
+            if let Some(value) = self.remove_entry(&mut slot, source, RemovalReason::Renamed) {
+                self.insert_entry(&mut slot, target.clone(), value);
             }
         } else {
-            let mut slot1 = self.slots[slot1].write();
-            let mut slot2 = self.slots[slot2].write();
-            if override_value == Override::No && slot2.get(target).is_some() {
+            // The slots are always locked in the same order, otherwise two renames in opposite
+            // directions could deadlock
+            let (mut source_slot, mut target_slot) = if slot1 < slot2 {
+                let source_slot = self.slots[slot1].write();
+                (source_slot, self.slots[slot2].write())
+            } else {
+                let target_slot = self.slots[slot2].write();
+                (self.slots[slot1].write(), target_slot)
+            };
+            if !Self::can_rename(&source_slot, &target_slot, source, target, override_value)? {
                 return Ok(false);
             }
-            if let Some(value) = slot1.remove(source) {
-                slot2.insert(target.clone(), value);
-                Ok(true)
-            } else {
-                Err(Error::NotFound)
+            if let Some(value) = self.remove_entry(&mut source_slot, source, RemovalReason::Renamed)
+            {
+                self.insert_entry(&mut target_slot, target.clone(), value);
             }
-        };
-
-        if result.is_ok() {
-            self.bump_version(source);
-            self.bump_version(target);
         }
 
-        result
+        self.bump_version(source);
+        self.bump_version(target);
+
+        Ok(true)
     }
 
     /// Removes keys from the database
+    ///
+    /// Returns the number of keys that were actually removed. A key given more than once is only
+    /// counted once, because it no longer exists after the first removal. Expired keys are
+    /// removed, and announced as expired, but not counted.
     pub fn del(&self, keys: &[Bytes]) -> Value {
-        let mut expirations = self.expirations.lock();
-
         keys.iter()
             .filter_map(|key| {
-                expirations.remove(key);
-                self.slots[self.get_slot(key)].write().remove(key)
+                let mut slot = self.slots[self.get_slot(key)].write();
+                self.remove_entry(&mut slot, key, RemovalReason::Deleted)
             })
-            .filter(|key| key.is_valid())
+            .filter(|entry| entry.is_valid())
             .count()
             .into()
     }
 
     /// Returns all keys that matches a given pattern. This is a very expensive command.
     pub fn get_all_keys(&self, pattern: &Bytes) -> Result<Vec<Value>, Error> {
-        let pattern = String::from_utf8_lossy(pattern);
-        let pattern =
-            Pattern::new(&pattern).map_err(|_| Error::InvalidPattern(pattern.to_string()))?;
         Ok(self
             .slots
             .iter()
             .flat_map(|slot| {
                 slot.read()
                     .keys()
-                    .filter(|key| {
-                        let str_key = String::from_utf8_lossy(key);
-                        pattern.matches(&str_key)
-                    })
+                    .filter(|key| glob::matches(pattern, key))
                     .map(|key| Value::new(key))
                     .collect::<Vec<Value>>()
             })
@@ -775,152 +1419,223 @@ impl Db {
     }
 
     /// Check if keys exists in the database
+    ///
+    /// Keys are counted each time they appear in the list, so a key given twice is counted twice
+    /// (`EXISTS foo foo` returns 2 if `foo` exists).
     pub fn exists(&self, keys: &[Bytes]) -> usize {
-        let mut matches = 0;
         keys.iter()
-            .map(|key| {
-                let slot = self.slots[self.get_slot(key)].read();
-                if let Some(key) = slot.get(key) {
-                    matches += if key.is_valid() { 1 } else { 0 };
-                }
+            .filter(|key| {
+                self.slots[self.get_slot(key)]
+                    .read()
+                    .get(*key)
+                    .filter(|x| x.is_valid())
+                    .is_some()
             })
-            .for_each(drop);
-
-        matches
+            .count()
     }
 
     /// Updates the entry version of a given key
     pub fn bump_version(&self, key: &Bytes) -> bool {
-        let slot = self.slots[self.get_slot(key)].read();
-        let to_return = slot
-            .get(key)
-            .filter(|x| x.is_valid())
-            .map(|entry| {
-                entry.bump_version();
-            })
-            .is_some();
+        let slot = self.read_slot(key);
+        let typ = slot.get(key).filter(|x| x.is_valid()).map(|entry| {
+            entry.bump_version();
+            self.changed(&slot, key, entry);
+            entry.typ()
+        });
+        let to_return = typ.is_some();
         drop(slot);
-        if to_return {
-            let senders = self.change_subscriptions.read();
-            if let Some(sender) = senders.get(key) {
-                if sender.receiver_count() == 0 {
-                    // Garbage collection
-                    drop(senders);
-                    self.change_subscriptions.write().remove(key);
-                } else {
-                    // Notify
-                    let _ = sender.send(());
-                }
-            }
+        if let Some(typ) = typ {
+            self.wake_first_subscriber(key, typ);
         }
         to_return
     }
 
-    /// Subscribe to key changes.
-    pub fn subscribe_to_key_changes(&self, keys: &[Bytes]) -> Vec<Receiver<()>> {
-        let mut subscriptions = self.change_subscriptions.write();
-        keys.iter()
-            .map(|key| {
-                if let Some(sender) = subscriptions.get(key) {
-                    sender.subscribe()
-                } else {
-                    let (sender, receiver) = broadcast::channel(1);
-                    subscriptions.insert(key.clone(), sender);
-                    receiver
+    /// Returns the shard of the key changes subscriptions where the queue of a key lives
+    #[inline]
+    fn subscriptions_for(&self, key: &Bytes) -> MutexGuard<'_, HashMap<Bytes, WaitQueue>> {
+        self.change_subscriptions[self.get_slot(key)].lock()
+    }
+
+    /// Wakes the first connection waiting for a key to change
+    fn wake_first_subscriber(&self, key: &Bytes, typ: ValueTyp) {
+        if let Some((_, listener)) = self
+            .subscriptions_for(key)
+            .get(key)
+            .and_then(|queue| queue.front())
+        {
+            listener.push(typ);
+        }
+    }
+
+    /// Subscribe to key changes. The current connection is queued after any other connection
+    /// already waiting for these keys, and the returned listener only receives the changes made
+    /// while the connection is the first in the queue of a key.
+    pub fn subscribe_to_key_changes(&self, keys: &[Bytes]) -> Arc<KeyChangeListener> {
+        let listener = Arc::new(KeyChangeListener::default());
+        for key in keys.iter() {
+            let mut subscriptions = self.subscriptions_for(key);
+            let queue = subscriptions.entry(key.clone()).or_default();
+            if !queue.iter().any(|(conn_id, _)| *conn_id == self.conn_id) {
+                queue.push_back((self.conn_id, listener.clone()));
+            }
+        }
+        listener
+    }
+
+    /// Subscribe to key changes, like `subscribe_to_key_changes`. The connection leaves the
+    /// waiting queues when the returned subscription is dropped, even if the task waiting for the
+    /// changes panics, so no queue outlives its waiter.
+    pub fn watch_key_changes(self: &Arc<Db>, keys: Vec<Bytes>) -> KeyChangeSubscription {
+        KeyChangeSubscription {
+            listener: self.subscribe_to_key_changes(&keys),
+            db: self.clone(),
+            keys,
+        }
+    }
+
+    /// Returns the number of keys at least one connection is waiting for
+    pub fn waited_keys(&self) -> usize {
+        self.change_subscriptions
+            .iter()
+            .map(|subscriptions| subscriptions.lock().len())
+            .sum()
+    }
+
+    /// Removes the current connection from the key changes queues. The next connection in line
+    /// is woken up for any key that still exists, as it may be able to consume it.
+    pub fn unsubscribe_from_key_changes(&self, keys: &[Bytes]) {
+        for key in keys.iter() {
+            let mut subscriptions = self.subscriptions_for(key);
+            if let Some(queue) = subscriptions.get_mut(key) {
+                queue.retain(|(conn_id, _)| *conn_id != self.conn_id);
+                if queue.is_empty() {
+                    subscriptions.remove(key);
                 }
-            })
-            .collect()
+            }
+        }
+
+        for key in keys.iter() {
+            let typ = self
+                .read_slot(key)
+                .get(key)
+                .filter(|x| x.is_valid())
+                .map(|x| x.inner().typ());
+            if let Some(typ) = typ {
+                self.wake_first_subscriber(key, typ);
+            }
+        }
+    }
+
+    /// Whether the current connection may consume a key: nobody else is waiting for it, or the
+    /// current connection is the first in line.
+    pub fn is_first_subscriber(&self, key: &Bytes) -> bool {
+        self.subscriptions_for(key)
+            .get(key)
+            .and_then(|queue| queue.front())
+            .is_none_or(|(conn_id, _)| *conn_id == self.conn_id)
     }
 
     /// Returns the name of the value type
     pub fn get_data_type(&self, key: &Bytes) -> String {
-        let slot = self.slots[self.get_slot(key)].read();
+        let slot = self.read_slot(key);
         slot.get(key)
             .filter(|x| x.is_valid())
-            .map_or("none".to_owned(), |x| {
-                x.inner().typ().to_string().to_lowercase()
-            })
+            .map_or("none".to_owned(), |x| x.inner().typ().to_string())
+    }
+
+    /// Returns exclusive access to a key, see `DbEntry`. An expired key is removed first, so it is
+    /// seen as missing.
+    pub fn entry(&self, key: Bytes) -> DbEntry<'_> {
+        DbEntry {
+            db: self,
+            slot: Some(self.write_slot(&key)),
+            key,
+            modified: false,
+        }
     }
 
     /// Get a ref value
     pub fn get<'a>(&'a self, key: &'a Bytes) -> RefValue<'a> {
         RefValue {
-            slot: self.slots[self.get_slot(key)].read(),
+            slot: self.read_slot(key),
             key,
         }
     }
 
     /// Get a copy of an entry and modifies the expiration of the key
-    pub fn getex(&self, key: &Bytes, expires_in: Option<Duration>, make_persistent: bool) -> Value {
-        let slot = self.slots[self.get_slot(key)].read();
-        slot.get(key)
-            .filter(|x| x.is_valid())
-            .map(|value| {
-                if make_persistent {
-                    self.expirations.lock().remove(key);
-                    value.persist();
-                } else if let Some(expires_in) = expires_in {
-                    let expires_at = Instant::now()
-                        .checked_add(expires_in)
-                        .unwrap_or_else(far_future);
-                    self.expirations.lock().add(key, expires_at);
-                    value.set_ttl(expires_at);
-                }
-                value
-            })
-            .map_or(Value::Null, |x| x.clone_value())
+    pub fn getex(
+        &self,
+        key: &Bytes,
+        expires_in: Option<Duration>,
+        make_persistent: bool,
+    ) -> Result<Value, Error> {
+        let slot = self.read_slot(key);
+        let (key, value) = match slot.get_key_value(key).filter(|(_, x)| accessed(x)) {
+            Some(entry) => entry,
+            None => return Ok(Value::Null),
+        };
+        // The expiration is not changed if the value is not a string
+        let cloned = value.clone_value()?;
+        if make_persistent {
+            self.update_ttl(key, value, None);
+        } else if let Some(expires_in) = expires_in {
+            let expires_at = Instant::now()
+                .checked_add(expires_in)
+                .unwrap_or_else(far_future);
+            self.update_ttl(key, value, Some(expires_at));
+        }
+        Ok(cloned)
     }
 
     /// Get multiple copies of entries
     pub fn get_multi(&self, keys: VecDeque<Bytes>) -> Value {
         keys.iter()
             .map(|key| {
-                let slot = self.slots[self.get_slot(key)].read();
+                let slot = self.read_slot(key);
                 slot.get(key)
-                    .filter(|x| x.is_valid() && x.is_scalar())
-                    .map_or(Value::Null, |x| x.clone_value())
+                    .filter(|x| accessed(x))
+                    .and_then(|x| x.clone_value().ok())
+                    .unwrap_or_default()
             })
             .collect::<Vec<Value>>()
             .into()
     }
 
-    /// Get a key or set a new value for the given key.
-    pub fn getset(&self, key: &Bytes, value: Value) -> Value {
-        let mut slot = self.slots[self.get_slot(key)].write();
-        self.expirations.lock().remove(key);
-        slot.insert(key.clone(), Entry::new(value, None))
-            .filter(|x| x.is_valid())
-            .map_or(Value::Null, |x| x.clone_value())
+    /// Sets a new value for the given key, discarding its TTL, and returns the previous value. It
+    /// is the same as `SET key value GET`, nothing is written if the previous value is not a
+    /// string.
+    pub fn getset(&self, key: &Bytes, value: Value) -> Result<Value, Error> {
+        self.set_advanced(key.clone(), value, None, Override::Yes, false, true)
     }
 
-    /// Takes an entry from the database.
-    pub fn getdel(&self, key: &Bytes) -> Value {
+    /// Takes an entry from the database. An expired entry is removed as expired, and Null is
+    /// returned. If the value is not a string a WrongType error is returned and the key is left
+    /// untouched.
+    pub fn getdel(&self, key: &Bytes) -> Result<Value, Error> {
         let mut slot = self.slots[self.get_slot(key)].write();
-        slot.remove(key).map_or(Value::Null, |x| {
-            self.expirations.lock().remove(key);
-            x.clone_value()
-        })
+        let value = match slot.get(key) {
+            Some(entry) if entry.is_valid() => entry.clone_value()?,
+            Some(_) => Value::Null,
+            None => return Ok(Value::Null),
+        };
+        self.remove_entry(&mut slot, key, RemovalReason::Deleted);
+        Ok(value)
     }
 
-    /// Set a key, value with an optional expiration time
+    /// Appends a value to the string stored at key, creating it if the key does not exist
     pub fn append(&self, key: &Bytes, value_to_append: &Bytes) -> Result<Value, Error> {
-        let slot = self.slots[self.get_slot(key)].read();
-
-        if let Some(entry) = slot.get(key).filter(|x| x.is_valid()) {
-            entry.ensure_blob_is_mutable()?;
-            match *entry.inner_mut() {
-                Value::BlobRw(ref mut value) => {
-                    value.put(value_to_append.as_ref());
-                    Ok(value.len().into())
-                }
-                _ => Err(Error::WrongType),
-            }
-        } else {
-            drop(slot);
-            let mut slot = self.slots[self.get_slot(key)].write();
-            slot.insert(key.clone(), Entry::new(Value::new(value_to_append), None));
-            Ok(value_to_append.len().into())
+        let mut entry = self.entry(key.clone());
+        let appended = entry.modify(|value| {
+            let bytes = Self::mutable_blob(value)?;
+            bytes.put(value_to_append.as_ref());
+            Ok(bytes.len())
+        })?;
+        if let Some(len) = appended {
+            return Ok(len.into());
         }
+
+        entry.or_insert_with(|| Value::new(value_to_append));
+        Ok(value_to_append.len().into())
     }
 
     /// Set multiple key/value pairs. Are involved keys are locked exclusively
@@ -955,7 +1670,7 @@ impl Db {
 
         if !override_all {
             for key in keys.iter() {
-                let slot = self.slots[self.get_slot(key)].read();
+                let slot = self.read_slot(key);
                 if slot.get(key).is_some() {
                     self.unlock_keys(&keys);
                     return Ok(0.into());
@@ -966,9 +1681,10 @@ impl Db {
         let mut values = values.into_iter();
 
         for key in keys.into_iter() {
-            let mut slot = self.slots[self.get_slot(&key)].write();
+            let mut slot = self.write_slot(&key);
             if let Some(value) = values.next() {
-                slot.insert(key, Entry::new(Value::Blob(value), None));
+                self.write_through(&key);
+                self.insert_entry(&mut slot, key, Entry::new(Value::Blob(value), None));
             }
         }
 
@@ -984,9 +1700,11 @@ impl Db {
     /// Set a key, value with an optional expiration time
     pub fn set(&self, key: Bytes, value: Value, expires_in: Option<Duration>) -> Value {
         self.set_advanced(key, value, expires_in, Default::default(), false, false)
+            .expect("setting a key without returning the previous value never fails")
     }
 
-    /// Set a value in the database with various settings
+    /// Set a value in the database with various settings. An error is returned, and nothing is
+    /// written, if the previous value must be returned but it is not a string.
     pub fn set_advanced(
         &self,
         key: Bytes,
@@ -995,8 +1713,8 @@ impl Db {
         override_value: Override,
         keep_ttl: bool,
         return_previous: bool,
-    ) -> Value {
-        let mut slot = self.slots[self.get_slot(&key)].write();
+    ) -> Result<Value, Error> {
+        let mut slot = self.write_slot(&key);
         let expires_at = expires_in.map(|duration| {
             Instant::now()
                 .checked_add(duration)
@@ -1015,61 +1733,46 @@ impl Db {
         };
 
         let to_return = if return_previous {
-            let previous = previous.map_or(Value::Null, |v| v.clone_value());
-            if previous.is_err() {
-                // Error while trying to clone the previous value to return, we
-                // must halt and return immediately.
-                return previous;
-            }
-            Some(previous)
+            // Error while trying to clone the previous value to return, we
+            // must halt and return immediately.
+            Some(previous.map_or(Ok(Value::Null), |v| v.clone_value())?)
         } else {
             None
         };
 
         match override_value {
-            Override::No => {
-                if previous.is_some() {
-                    return if let Some(to_return) = to_return {
-                        to_return
-                    } else {
-                        0.into()
-                    };
-                }
+            Override::No if previous.is_some() => {
+                return Ok(if let Some(to_return) = to_return {
+                    to_return
+                } else {
+                    0.into()
+                });
             }
-            Override::Only => {
-                if previous.is_none() {
-                    return if let Some(to_return) = to_return {
-                        to_return
-                    } else {
-                        0.into()
-                    };
-                }
+            Override::Only if previous.is_none() => {
+                return Ok(if let Some(to_return) = to_return {
+                    to_return
+                } else {
+                    0.into()
+                });
             }
             _ => {}
         };
 
-        if let Some(expires_at) = expires_at {
-            self.expirations.lock().add(&key, expires_at);
-        } else {
-            // Make sure to remove the new key (or replaced) from the
-            // expiration table (from any possible past value).
-            self.expirations.lock().remove(&key);
-        }
-
-        slot.insert(key, Entry::new(value, expires_at));
+        self.write_through(&key);
+        self.insert_entry(&mut slot, key, Entry::new(value, expires_at));
 
-        if let Some(to_return) = to_return {
+        Ok(if let Some(to_return) = to_return {
             to_return
         } else if override_value == Override::Yes {
             Value::Ok
         } else {
             1.into()
-        }
+        })
     }
 
     /// Returns the TTL of a given key
     pub fn ttl(&self, key: &Bytes) -> Option<Option<Instant>> {
-        let slot = self.slots[self.get_slot(key)].read();
+        let slot = self.read_slot(key);
         slot.get(key).filter(|x| x.is_valid()).map(|x| x.get_ttl())
     }
 
@@ -1079,6 +1782,25 @@ impl Db {
         self.expirations.lock().has(key)
     }
 
+    /// Releases the unused capacity of a slot and of the values it holds, after mass deletions.
+    ///
+    /// The slot is locked exclusively only while its own HashMap is shrunk, values are shrunk
+    /// one at a time. Returns an estimation of the reclaimed bytes.
+    pub fn defrag_slot(&self, slot_id: usize) -> usize {
+        let mut slot = match self.slots.get(slot_id) {
+            Some(slot) => slot.write(),
+            None => return 0,
+        };
+        let reclaimed = slot.shrink_to_fit();
+        let slot = RwLockWriteGuard::downgrade(slot);
+
+        reclaimed
+            + slot
+                .values()
+                .map(|entry| entry.inner_mut().shrink_to_fit())
+                .sum::<usize>()
+    }
+
     /// Remove expired entries from the database.
     ///
     /// This function should be called from a background thread every few seconds. Calling it more
@@ -1088,7 +1810,6 @@ impl Db {
     /// the memory from those expired keys.
     pub fn purge(&self) -> u64 {
         let mut expirations = self.expirations.lock();
-        let mut removed = 0;
 
         trace!("Watching {} keys for expirations", expirations.len());
 
@@ -1096,20 +1817,45 @@ impl Db {
         drop(expirations);
 
         keys.iter()
-            .map(|key| {
-                let mut slot = self.slots[self.get_slot(key)].write();
-                if slot.remove(key).is_some() {
-                    trace!("Removed key {:?} due timeout", key);
-                    removed += 1;
-                }
-            })
-            .for_each(drop);
+            .filter(|key| self.remove_expired(self.get_slot(key), key))
+            .count() as u64
+    }
+}
+
+/// Picks a random entry of a slot. With a seeded generator the pick is made in the order of the
+/// keys, so it does not depend on the iteration order of the slot, which changes on every run.
+fn random_entry<'a>(slot: &'a Slot, rng: &mut Generator) -> Option<(&'a Bytes, &'a Entry)> {
+    if slot.is_empty() {
+        return None;
+    }
+    let index = rng.gen_range(0..slot.len());
+    if rng.is_seeded() {
+        slot.nth_sorted(index)
+    } else {
+        slot.iter().nth(index)
+    }
+}
 
-        removed
+/// Grows a buffer to `length` bytes, padding it with zeros. Rather than writing the zeros, a new
+/// zeroed buffer is requested, and big allocations get zeroed pages from the OS lazily, as they
+/// are touched, so padding a string with hundreds of megabytes does not stall the server.
+fn grow_zeroed(bytes: &mut BytesMut, length: usize) {
+    if bytes.capacity() >= length {
+        bytes.resize(length, 0);
+        return;
     }
+    let mut grown = BytesMut::zeroed(length);
+    grown[..bytes.len()].copy_from_slice(bytes);
+    *bytes = grown;
 }
 
+/// Number of entries examined by SCAN when no COUNT is given
+const SCAN_DEFAULT_COUNT: usize = 10;
+
 impl scan::Scan for Db {
+    /// Scans the database. Like Redis, COUNT bounds the work done rather than the number of
+    /// keys returned: at most `count` entries are examined, matching or not, so a selective
+    /// pattern never makes a single call walk the whole database.
     fn scan(
         &self,
         cursor: Cursor,
@@ -1120,12 +1866,8 @@ impl scan::Scan for Db {
         let mut keys = vec![];
         let mut slot_id = cursor.bucket as usize;
         let mut last_pos = cursor.last_position as usize;
-        let pattern = pattern
-            .map(|pattern| {
-                let pattern = String::from_utf8_lossy(&pattern);
-                Pattern::new(&pattern).map_err(|_| Error::InvalidPattern(pattern.to_string()))
-            })
-            .transpose()?;
+        let count = count.unwrap_or(SCAN_DEFAULT_COUNT).max(1);
+        let mut examined = 0;
 
         loop {
             let slot = if let Some(value) = self.slots.get(slot_id) {
@@ -1139,33 +1881,31 @@ impl scan::Scan for Db {
             };
 
             for (key, value) in slot.iter().skip(last_pos) {
+                if examined == count {
+                    break;
+                }
+                examined += 1;
+                last_pos += 1;
+
                 if !value.is_valid() {
                     // Entry still exists in memory but it is not longer valid
                     // and will soon be gargabe collected.
-                    last_pos += 1;
                     continue;
                 }
                 if let Some(pattern) = &pattern {
-                    let str_key = String::from_utf8_lossy(key);
-                    if !pattern.matches(&str_key) {
-                        last_pos += 1;
+                    if !glob::matches(pattern, key) {
                         continue;
                     }
                 }
                 if let Some(typ) = &typ {
                     if !typ.check_type(&value.inner()) {
-                        last_pos += 1;
                         continue;
                     }
                 }
                 keys.push(Value::new(key));
-                last_pos += 1;
-                if keys.len() == count.unwrap_or(10) {
-                    break;
-                }
             }
 
-            if keys.len() == count.unwrap_or(10) {
+            if examined == count {
                 break;
             }
 
@@ -1183,9 +1923,61 @@ impl scan::Scan for Db {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{bytes, db::scan::Scan, value::float::Float};
+    use crate::{
+        bytes,
+        db::scan::Scan,
+        value::{checksum, float::Float},
+    };
     use std::str::FromStr;
 
+    #[test]
+    fn getset_wrong_type() {
+        let db = Db::new(100);
+        let list: VecDeque<checksum::Value> = vec![checksum::Value::new(bytes!("a"))].into();
+        db.set(
+            bytes!(b"list"),
+            Value::List(list.clone()),
+            Some(Duration::from_secs(60)),
+        );
+        let ttl = db.ttl(&bytes!(b"list"));
+
+        assert_eq!(
+            Err(Error::WrongType),
+            db.getset(&bytes!(b"list"), Value::Blob(bytes!("1")))
+        );
+        assert_eq!(
+            Some(Value::List(list)),
+            db.get(&bytes!(b"list")).map(|v| v.clone())
+        );
+        assert_eq!(ttl, db.ttl(&bytes!(b"list")));
+    }
+
+    #[test]
+    fn concurrent_incr_of_missing_keys() {
+        let db = Arc::new(Db::new(100));
+        let threads = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = Bytes::from(format!("num-{}", i));
+                        db.incr(&key, 1i64).unwrap();
+                        db.hincrby(&bytes!("hash"), &key, 1i64).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .for_each(|thread| thread.join().unwrap());
+
+        for i in 0..100 {
+            let key = Bytes::from(format!("num-{}", i));
+            assert_eq!(Ok(Value::Blob(bytes!("8"))), db.get(&key).into_inner());
+            assert_eq!(Ok(8), db.hincrby(&bytes!("hash"), &key, 0i64));
+        }
+    }
+
     #[test]
     fn incr_wrong_type() {
         let db = Db::new(100);
@@ -1194,9 +1986,12 @@ mod test {
         let r = db.incr(&bytes!("num"), 1);
 
         assert!(r.is_err());
-        assert_eq!(Error::NotANumber, r.expect_err("should fail"));
         assert_eq!(
-            Value::Blob(bytes!("some string")),
+            Error::NotANumberType("an integer".to_owned()),
+            r.expect_err("should fail")
+        );
+        assert_eq!(
+            Ok(Value::Blob(bytes!("some string"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1208,7 +2003,7 @@ mod test {
 
         assert_eq!(Ok(2.2.into()), db.incr::<Float>(&bytes!("num"), 1.1.into()));
         assert_eq!(
-            Value::Blob(bytes!("2.2")),
+            Ok(Value::Blob(bytes!("2.2"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1220,7 +2015,7 @@ mod test {
 
         assert_eq!(Ok(2.1.into()), db.incr::<Float>(&bytes!("num"), 1.1.into()));
         assert_eq!(
-            Value::Blob(bytes!("2.1")),
+            Ok(Value::Blob(bytes!("2.1"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1232,7 +2027,7 @@ mod test {
 
         assert_eq!(Ok(2), db.incr(&bytes!("num"), 1));
         assert_eq!(
-            Value::Blob(bytes!("2")),
+            Ok(Value::Blob(bytes!("2"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1242,7 +2037,7 @@ mod test {
         let db = Db::new(100);
         assert_eq!(Ok(1), db.incr(&bytes!("num"), 1));
         assert_eq!(
-            Value::Blob(bytes!("1")),
+            Ok(Value::Blob(bytes!("1"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1252,7 +2047,7 @@ mod test {
         let db = Db::new(100);
         assert_eq!(Ok(1.1.into()), db.incr::<Float>(&bytes!("num"), 1.1.into()));
         assert_eq!(
-            Value::Blob(bytes!("1.1")),
+            Ok(Value::Blob(bytes!("1.1"))),
             db.get(&bytes!("num")).into_inner()
         );
     }
@@ -1275,6 +2070,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn del_duplicated_keys() {
+        let db = Db::new(100);
+        db.set(bytes!(b"valid"), Value::Ok, None);
+
+        assert_eq!(
+            Value::Integer(1),
+            db.del(&[bytes!(b"valid"), bytes!(b"valid"), bytes!(b"valid")])
+        );
+    }
+
+    #[test]
+    fn exists() {
+        let db = Db::new(100);
+        db.set(bytes!(b"expired"), Value::Ok, Some(Duration::from_secs(0)));
+        db.set(bytes!(b"valid"), Value::Ok, None);
+
+        assert_eq!(1, db.exists(&[bytes!(b"valid")]));
+        assert_eq!(0, db.exists(&[bytes!(b"expired")]));
+        assert_eq!(
+            2,
+            db.exists(&[bytes!(b"valid"), bytes!(b"expired"), bytes!(b"valid")])
+        );
+    }
+
     #[test]
     fn ttl() {
         let db = Db::new(100);
@@ -1292,25 +2112,566 @@ mod test {
     fn persist_bug() {
         let db = Db::new(100);
         db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(1)));
-        assert_eq!(Value::Ok, db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(Ok(Value::Ok), db.get(&bytes!(b"one")).into_inner());
         assert!(db.is_key_in_expiration_list(&bytes!(b"one")));
         db.persist(&bytes!(b"one"));
         assert!(!db.is_key_in_expiration_list(&bytes!(b"one")));
     }
 
+    #[test]
+    fn ttl_updates_race_with_purge() {
+        let db = Arc::new(Db::new(10));
+        let keys: Vec<Bytes> = (0..10).map(|i| format!("key-{}", i).into()).collect();
+
+        let workers = (0..4)
+            .map(|worker| {
+                let db = db.clone();
+                let keys = keys.clone();
+                thread::spawn(move || {
+                    for i in 0..2_000u64 {
+                        let key = &keys[(i as usize + worker) % keys.len()];
+                        match (i + worker as u64) % 5 {
+                            0 => {
+                                db.set(key.clone(), Value::Ok, Some(Duration::from_millis(i % 3)));
+                            }
+                            1 => {
+                                let _ = db.set_ttl(
+                                    key,
+                                    Duration::from_millis(i % 7),
+                                    ExpirationOpts::default(),
+                                );
+                            }
+                            2 => {
+                                db.persist(key);
+                            }
+                            3 => {
+                                let _ = db.getex(key, Some(Duration::from_millis(i % 5)), false);
+                            }
+                            _ => {
+                                db.purge();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for worker in workers {
+            worker.join().expect("worker thread panicked");
+        }
+
+        // The entries and the expiration table must agree on every key
+        for key in keys.iter() {
+            match db.ttl(key) {
+                Some(Some(_)) => assert!(db.is_key_in_expiration_list(key)),
+                Some(None) => assert!(!db.is_key_in_expiration_list(key)),
+                None => {}
+            }
+        }
+
+        thread::sleep(Duration::from_millis(10));
+        db.purge();
+
+        // Anything left must be a persistent key
+        for key in keys.iter() {
+            assert!(matches!(db.ttl(key), Some(None) | None));
+            assert!(!db.is_key_in_expiration_list(key));
+        }
+    }
+
+    #[test]
+    fn purge_keeps_replaced_keys() {
+        let db = Db::new(100);
+        db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(0)));
+        let expired = db.expirations.lock().get_expired_keys(None);
+        assert_eq!(vec![Bytes::from_static(b"one")], expired);
+
+        // The key is replaced while the purge process is about to remove it
+        db.set(bytes!(b"one"), Value::Ok, None);
+        assert_eq!(0, db.purge());
+        assert_eq!(Ok(Value::Ok), db.get(&bytes!(b"one")).into_inner());
+    }
+
     #[test]
     fn purge_keys() {
         let db = Db::new(100);
         db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(0)));
-        // Expired keys should not be returned, even if they are not yet
-        // removed by the purge process.
-        assert_eq!(Value::Null, db.get(&bytes!(b"one")).into_inner());
+        db.set(bytes!(b"two"), Value::Ok, Some(Duration::from_secs(0)));
+        // Expired keys should not be returned, reading them removes them
+        // before the purge process does.
+        assert_eq!(Ok(Value::Null), db.get(&bytes!(b"one")).into_inner());
 
         // Purge twice
         assert_eq!(1, db.purge());
         assert_eq!(0, db.purge());
 
-        assert_eq!(Value::Null, db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(Ok(Value::Null), db.get(&bytes!(b"one")).into_inner());
+    }
+
+    #[test]
+    fn lazy_delete_expired_keys() {
+        let db = Db::new(100);
+        let mut removed = db.subscribe_to_removals();
+        db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(0)));
+        db.set(bytes!(b"two"), Value::Ok, Some(Duration::from_secs(0)));
+        assert_eq!(2, db.len_with_expired());
+
+        // Reading an expired key removes it
+        assert_eq!(Ok(Value::Null), db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(Ok(Value::Null), db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(1, db.len_with_expired());
+        assert!(!db.is_key_in_expiration_list(&bytes!(b"one")));
+
+        // Only the keys not read yet are left to the purge process
+        assert_eq!(1, db.purge());
+        assert_eq!(0, db.len_with_expired());
+
+        // Each key is announced exactly once
+        let expired = |key: &'static [u8]| RemovedKey {
+            key: Bytes::from_static(key),
+            reason: RemovalReason::Expired,
+        };
+        assert_eq!(Ok(expired(b"one")), removed.try_recv());
+        assert_eq!(Ok(expired(b"two")), removed.try_recv());
+        assert!(removed.try_recv().is_err());
+    }
+
+    #[test]
+    fn write_to_expired_keys() {
+        let db = Db::new(100);
+        let mut removed = db.subscribe_to_removals();
+        let expire = |key: &'static [u8], value: Value| {
+            db.set(Bytes::from_static(key), value, Some(Duration::from_secs(0)));
+        };
+        #[allow(clippy::mutable_key_type)]
+        let mut hash = HashMap::new();
+        hash.insert(bytes!(b"old"), bytes!(b"1"));
+
+        expire(b"num", Value::Blob(bytes!(b"10")));
+        expire(b"hash", hash.into());
+        expire(b"append", Value::Blob(bytes!(b"hello")));
+        expire(b"setrange", Value::Blob(bytes!(b"hello")));
+        expire(b"mset", Value::Blob(bytes!(b"hello")));
+        expire(b"set", Value::Blob(bytes!(b"hello")));
+
+        assert_eq!(Ok(1), db.incr(&bytes!(b"num"), 1i64));
+        assert_eq!(Ok(1), db.hincrby(&bytes!(b"hash"), &bytes!(b"new"), 1i64));
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            db.append(&bytes!(b"append"), &bytes!(b"J"))
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            db.set_range(&bytes!(b"setrange"), 0, b"J", None)
+        );
+        assert_eq!(
+            Ok(Value::Ok),
+            db.multi_set(vec![bytes!(b"mset"), bytes!(b"J")].into(), true)
+        );
+        db.set(bytes!(b"set"), Value::Blob(bytes!(b"J")), None);
+
+        assert_eq!(
+            Ok(Value::Blob(bytes!(b"1"))),
+            db.get(&bytes!(b"num")).into_inner()
+        );
+        assert_eq!(Some(1), db.get(&bytes!(b"hash")).map(|v| v.length()));
+        for key in [&b"append"[..], b"setrange", b"mset", b"set"] {
+            let key = Bytes::from_static(key);
+            assert_eq!(
+                Some(b"J".to_vec()),
+                db.get(&key)
+                    .map(|v| v.as_bytes().unwrap_or_default().to_vec())
+            );
+        }
+
+        // Each expired key is announced once, and none is left in the expiration table
+        for key in [
+            &b"num"[..],
+            b"hash",
+            b"append",
+            b"setrange",
+            b"mset",
+            b"set",
+        ] {
+            assert_eq!(
+                Ok(RemovedKey {
+                    key: Bytes::from_static(key),
+                    reason: RemovalReason::Expired,
+                }),
+                removed.try_recv()
+            );
+            assert!(!db.is_key_in_expiration_list(&Bytes::from_static(key)));
+        }
+        assert!(removed.try_recv().is_err());
+    }
+
+    #[test]
+    fn entry() {
+        let db = Db::new(100);
+        let key = bytes!(b"set");
+        let version = |db: &Db| db.get(&key).version();
+
+        // missing keys are inserted
+        let entry = db
+            .entry(key.clone())
+            .and_modify(|_| panic!("the key does not exist"))
+            .unwrap()
+            .or_insert_with(|| Value::Blob(bytes!(b"1")));
+        assert!(entry.exists());
+        drop(entry);
+        assert_eq!(Ok(Value::Blob(bytes!(b"1"))), db.get(&key).into_inner());
+
+        // the version is bumped when the entry is modified
+        let before = version(&db);
+        let mut entry = db.entry(key.clone());
+        assert_eq!(
+            Ok(Some(2)),
+            entry.modify(|v| {
+                *v = Value::Blob(bytes!(b"2"));
+                Ok(2)
+            })
+        );
+        drop(entry);
+        assert!(version(&db) > before);
+        assert_eq!(Ok(Value::Blob(bytes!(b"2"))), db.get(&key).into_inner());
+
+        // but not when the modification fails
+        let before = version(&db);
+        assert_eq!(
+            Err(Error::WrongType),
+            db.entry(key.clone())
+                .modify::<(), _>(|_| Err(Error::WrongType))
+        );
+        assert_eq!(before, version(&db));
+
+        // existing keys are not replaced
+        let entry = db.entry(key.clone()).or_insert_with(|| Value::Ok);
+        assert_eq!(Some(Value::Blob(bytes!(b"2"))), entry.map(|v| v.clone()));
+        drop(entry);
+
+        // removed keys are announced
+        let mut removed = db.subscribe_to_removals();
+        assert_eq!(
+            Some(Value::Blob(bytes!(b"2"))),
+            db.entry(key.clone()).remove()
+        );
+        assert_eq!(Ok(Value::Null), db.get(&key).into_inner());
+        assert_eq!(
+            Ok(RemovedKey {
+                key: key.clone(),
+                reason: RemovalReason::Deleted
+            }),
+            removed.try_recv()
+        );
+
+        // expired keys are missing
+        db.set(key.clone(), Value::Ok, Some(Duration::from_secs(0)));
+        assert!(!db.entry(key.clone()).exists());
+        assert_eq!(
+            Ok(RemovedKey {
+                key,
+                reason: RemovalReason::Expired
+            }),
+            removed.try_recv()
+        );
+    }
+
+    #[test]
+    fn every_removal_is_announced() {
+        let db = Db::new(100);
+        let other = Arc::new(Db::new(100));
+        let mut removed = db.subscribe_to_removals();
+        let mut next = || {
+            removed
+                .try_recv()
+                .map(|removed| (removed.key, removed.reason))
+        };
+
+        db.set(bytes!(b"a"), Value::Blob(bytes!(b"1")), None);
+        assert_eq!(
+            Ok(true),
+            db.rename(&bytes!(b"a"), &bytes!(b"b"), Override::Yes)
+        );
+        assert_eq!(Ok((bytes!(b"a"), RemovalReason::Renamed)), next());
+
+        assert_eq!(Ok(true), db.move_key(bytes!(b"b"), other));
+        assert_eq!(Ok((bytes!(b"b"), RemovalReason::Moved)), next());
+
+        db.set(bytes!(b"list"), Value::List(Default::default()), None);
+        db.set(bytes!(b"str"), Value::Blob(bytes!(b"")), None);
+        assert!(db.remove_if_empty(&bytes!(b"list")));
+        assert!(!db.remove_if_empty(&bytes!(b"str")));
+        assert!(!db.remove_if_empty(&bytes!(b"missing")));
+        assert_eq!(Ok((bytes!(b"list"), RemovalReason::Deleted)), next());
+        assert!(next().is_err());
+
+        assert_eq!(Ok(Value::Ok), db.flushdb());
+        assert_eq!(Ok((bytes!(b"str"), RemovalReason::Flushed)), next());
+        assert!(next().is_err());
+        assert_eq!(None, RemovalReason::Flushed.event());
+    }
+
+    #[test]
+    fn removal_reasons() {
+        let db = Db::new(100);
+        let mut removed = db.subscribe_to_removals();
+        let removal = |key: &'static [u8], reason| RemovedKey {
+            key: Bytes::from_static(key),
+            reason,
+        };
+
+        db.set(bytes!(b"live"), Value::Blob(bytes!(b"1")), None);
+        db.set(
+            bytes!(b"gone"),
+            Value::Blob(bytes!(b"1")),
+            Some(Duration::from_secs(0)),
+        );
+        assert_eq!(
+            Value::Integer(1),
+            db.del(&[bytes!(b"live"), bytes!(b"gone")])
+        );
+        assert_eq!(
+            Ok(removal(b"live", RemovalReason::Deleted)),
+            removed.try_recv()
+        );
+        assert_eq!(
+            Ok(removal(b"gone", RemovalReason::Expired)),
+            removed.try_recv()
+        );
+
+        db.set(bytes!(b"live"), Value::Blob(bytes!(b"1")), None);
+        db.set(
+            bytes!(b"gone"),
+            Value::Blob(bytes!(b"1")),
+            Some(Duration::from_secs(0)),
+        );
+        assert_eq!(Ok(Value::Blob(bytes!(b"1"))), db.getdel(&bytes!(b"live")));
+        assert_eq!(Ok(Value::Null), db.getdel(&bytes!(b"gone")));
+        assert_eq!(
+            Ok(removal(b"live", RemovalReason::Deleted)),
+            removed.try_recv()
+        );
+        assert_eq!(
+            Ok(removal(b"gone", RemovalReason::Expired)),
+            removed.try_recv()
+        );
+
+        // Values which are not strings are neither removed nor announced
+        db.set(bytes!(b"hash"), Value::Hash(HashMap::new()), None);
+        assert_eq!(Err(Error::WrongType), db.getdel(&bytes!(b"hash")));
+        assert_eq!(1, db.exists(&[bytes!(b"hash")]));
+        assert!(removed.try_recv().is_err());
+
+        // Nothing is announced for missing keys
+        assert_eq!(Value::Integer(0), db.del(&[bytes!(b"live")]));
+        assert_eq!(Ok(Value::Null), db.getdel(&bytes!(b"live")));
+        assert!(removed.try_recv().is_err());
+    }
+
+    #[test]
+    fn rename_same_and_cross_slot() {
+        let db = Db::new(100);
+        let source: Bytes = bytes!(b"source");
+        let key_in_slot = |same: bool| {
+            (0..)
+                .map(|i| Bytes::from(format!("target-{}", i)))
+                .find(|key| (db.get_slot(key) == db.get_slot(&source)) == same)
+                .unwrap()
+        };
+
+        for target in vec![key_in_slot(true), key_in_slot(false)] {
+            db.set(source.clone(), Value::Ok, Some(Duration::from_secs(100)));
+            db.set(target.clone(), Value::Null, None);
+
+            assert_eq!(Ok(false), db.rename(&source, &target, Override::No));
+            assert_eq!(Ok(true), db.rename(&source, &target, Override::Yes));
+            assert_eq!(Ok(Value::Ok), db.get(&target).into_inner());
+            assert_eq!(Ok(Value::Null), db.get(&source).into_inner());
+            assert!(db.is_key_in_expiration_list(&target));
+            assert!(!db.is_key_in_expiration_list(&source));
+            assert_eq!(
+                Err(Error::NotFound),
+                db.rename(&source, &target, Override::Yes)
+            );
+
+            // expired keys do not exist
+            db.set(source.clone(), Value::Ok, Some(Duration::from_millis(1)));
+            db.set(target.clone(), Value::Ok, Some(Duration::from_millis(1)));
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(
+                Err(Error::NotFound),
+                db.rename(&source, &target, Override::Yes)
+            );
+            db.set(source.clone(), Value::Ok, None);
+            assert_eq!(Ok(true), db.rename(&source, &target, Override::No));
+            assert_eq!(Ok(Value::Ok), db.get(&target).into_inner());
+            assert!(!db.is_key_in_expiration_list(&target));
+            let _ = db.del(&[target]);
+        }
+    }
+
+    #[test]
+    fn rename_in_opposite_directions() {
+        let db = Arc::new(Db::new(100));
+        let (a, b): (Bytes, Bytes) = (bytes!(b"a"), bytes!(b"b"));
+        assert_ne!(db.get_slot(&a), db.get_slot(&b));
+        db.set(a.clone(), Value::Ok, None);
+        db.set(b.clone(), Value::Ok, None);
+
+        let threads = vec![(a.clone(), b.clone()), (b, a)]
+            .into_iter()
+            .map(|(source, target)| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let _ = db.rename(&source, &target, Override::Yes);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn keys_are_shared_with_expirations() {
+        let db = Db::new(100);
+        let key = Bytes::from("foo".to_owned());
+        db.set(key.clone(), Value::Ok, None);
+
+        // Another instance of the same key, as if it was parsed from another command
+        let other = Bytes::from("foo".to_owned());
+        assert_ne!(key.as_ptr(), other.as_ptr());
+        assert_eq!(
+            Ok(1.into()),
+            db.set_ttl(&other, Duration::from_secs(100), Default::default())
+        );
+        let stored = |key: &Bytes| db.expirations.lock().get_key(key).map(|k| k.as_ptr());
+        assert_eq!(Some(key.as_ptr()), stored(&other));
+
+        // Replacing the value keeps the stored key
+        db.set(other.clone(), Value::Ok, Some(Duration::from_secs(50)));
+        assert_eq!(Some(key.as_ptr()), stored(&other));
+
+        // The expiration follows the key when renamed
+        assert_eq!(Ok(true), db.rename(&other, &bytes!(b"bar"), Override::Yes));
+        assert!(!db.is_key_in_expiration_list(&key));
+        assert!(db.is_key_in_expiration_list(&bytes!(b"bar")));
+        assert!(db.ttl(&bytes!(b"bar")).flatten().is_some());
+    }
+
+    #[tokio::test]
+    async fn key_changes_carry_value_type() {
+        let db = Db::new(100);
+        let listener = db.subscribe_to_key_changes(&[bytes!(b"foo"), bytes!(b"bar")]);
+
+        db.set(bytes!(b"foo"), Value::Blob(bytes!(b"x")), None);
+        db.set(bytes!(b"bar"), Value::List(Default::default()), None);
+        assert!(db.bump_version(&bytes!(b"foo")));
+        assert!(db.bump_version(&bytes!(b"foo")));
+        assert!(db.bump_version(&bytes!(b"bar")));
+        assert_eq!(
+            vec![ValueTyp::String, ValueTyp::List],
+            listener.changes().await
+        );
+
+        // Changes to keys nobody is waiting for are not delivered
+        db.unsubscribe_from_key_changes(&[bytes!(b"foo"), bytes!(b"bar")]);
+        assert!(db.bump_version(&bytes!(b"bar")));
+        assert!(listener.changes.lock().is_empty());
+        assert_eq!(0, db.waited_keys());
+    }
+
+    #[test]
+    fn key_change_subscriptions_are_sharded_by_slot() {
+        let db = Db::new(100);
+        let keys = (0..10)
+            .map(|i| Bytes::from(format!("key-{}", i)))
+            .collect::<Vec<_>>();
+        let _listener = db.subscribe_to_key_changes(&keys);
+
+        for key in keys.iter() {
+            assert!(db.change_subscriptions[db.get_slot(key)]
+                .lock()
+                .contains_key(key));
+        }
+        let used_shards = db
+            .change_subscriptions
+            .iter()
+            .filter(|subscriptions| !subscriptions.lock().is_empty())
+            .count();
+        let used_slots = keys
+            .iter()
+            .map(|key| db.get_slot(key))
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        assert_eq!(used_slots, used_shards);
+        assert_eq!(10, db.waited_keys());
+
+        db.unsubscribe_from_key_changes(&keys);
+        assert_eq!(0, db.waited_keys());
+    }
+
+    #[test]
+    fn key_change_subscriptions_are_released_on_drop() {
+        let db = Arc::new(Db::new(100));
+        let subscription = db.watch_key_changes(vec![bytes!(b"foo"), bytes!(b"bar")]);
+        assert_eq!(2, db.waited_keys());
+
+        db.set(bytes!(b"foo"), Value::Blob(bytes!(b"x")), None);
+        assert!(db.bump_version(&bytes!(b"foo")));
+        assert_eq!(1, subscription.listener().changes.lock().len());
+
+        drop(subscription);
+        assert_eq!(0, db.waited_keys());
+    }
+
+    #[test]
+    fn defrag_slot() {
+        let db = Db::new(1);
+        for i in 0..1000 {
+            db.set(Bytes::from(i.to_string()), Value::Ok, None);
+        }
+        let list = (0..1000)
+            .map(|i| checksum::Value::new(Bytes::from(i.to_string())))
+            .collect::<VecDeque<_>>();
+        db.set(bytes!(b"list"), Value::List(list), None);
+        for i in 0..1000 {
+            db.del(&[Bytes::from(i.to_string())]);
+        }
+        db.get(&bytes!(b"list")).map_mut(|value| match value {
+            Value::List(x) => x.truncate(1),
+            _ => unreachable!(),
+        });
+
+        assert!(db.defrag_slot(0) > 0);
+        assert_eq!(0, db.defrag_slot(0));
+        assert_eq!(0, db.defrag_slot(1));
+        assert_eq!(1, db.len_with_expired());
+    }
+
+    #[test]
+    fn len_counters() {
+        let db = Db::new(4);
+        db.set(bytes!(b"one"), Value::Ok, None);
+        db.set(bytes!(b"two"), Value::Ok, Some(Duration::from_secs(0)));
+        db.set(bytes!(b"three"), Value::Ok, Some(Duration::from_secs(100)));
+
+        // Expired keys are accounted separately until they are purged
+        assert_eq!(3, db.len_with_expired());
+        assert_eq!(Ok(2), db.len());
+        assert_eq!(3, db.slot_lens().iter().sum::<usize>());
+        assert_eq!(4, db.slot_lens().len());
+
+        assert_eq!(1, db.purge());
+        assert_eq!(2, db.len_with_expired());
+        assert_eq!(Ok(2), db.len());
+        assert_eq!(1, db.expires_len());
+
+        db.flushdb().unwrap();
+        assert_eq!(0, db.len_with_expired());
+        assert!(db.is_empty());
     }
 
     #[test]
@@ -1319,10 +2680,10 @@ mod test {
         db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(0)));
         // Expired keys should not be returned, even if they are not yet
         // removed by the purge process.
-        assert_eq!(Value::Null, db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(Ok(Value::Null), db.get(&bytes!(b"one")).into_inner());
 
         db.set(bytes!(b"one"), Value::Ok, Some(Duration::from_secs(5)));
-        assert_eq!(Value::Ok, db.get(&bytes!(b"one")).into_inner());
+        assert_eq!(Ok(Value::Ok), db.get(&bytes!(b"one")).into_inner());
 
         // Purge should return 0 as the expired key has been removed already
         assert_eq!(0, db.purge());
@@ -1340,20 +2701,30 @@ mod test {
         let result = db
             .scan(Cursor::from_str("0").unwrap(), None, None, None)
             .unwrap();
-        // first 10 records
-        assert_eq!(10, result.result.len());
-        // make sure the cursor is valid
-        assert_ne!("0", result.cursor.to_string());
-        let result = db.scan(result.cursor, None, None, None).unwrap();
-        // 10 more records
-        assert_eq!(10, result.result.len());
+        // up to 10 records, expired entries are examined but not returned
+        assert!(result.result.len() <= 10);
         // make sure the cursor is valid
         assert_ne!("0", result.cursor.to_string());
 
-        let result = db.scan(result.cursor, None, None, None).unwrap();
-        // No more results!
-        assert_eq!(0, result.result.len());
-        assert_eq!("0", result.cursor.to_string());
+        // all the records, but the expired ones
+        let (keys, _) = scan_all(&db, None, None);
+        assert_eq!(20, keys.len());
+    }
+
+    /// Scans the whole database, returning all the keys and the number of calls
+    fn scan_all(db: &Db, pattern: Option<Bytes>, count: Option<usize>) -> (Vec<Value>, usize) {
+        let mut cursor = Cursor::from_str("0").unwrap();
+        let mut keys = vec![];
+        let mut calls = 0;
+        loop {
+            let result = db.scan(cursor, pattern.clone(), count, None).unwrap();
+            calls += 1;
+            keys.extend(result.result);
+            if result.cursor.to_string() == "0" {
+                return (keys, calls);
+            }
+            cursor = result.cursor;
+        }
     }
 
     #[test]
@@ -1368,8 +2739,33 @@ mod test {
         let result = db
             .scan(Cursor::from_str("0").unwrap(), None, Some(2), None)
             .unwrap();
-        assert_eq!(2, result.result.len());
+        assert!(result.result.len() <= 2);
+        assert_ne!("0", result.cursor.to_string());
+    }
+
+    #[test]
+    fn scan_count_bounds_the_work() {
+        let db = Db::new(10);
+        for i in 0u64..2000u64 {
+            let key: Bytes = i.to_string().into();
+            db.set(key, Value::Ok, None);
+        }
+
+        // a single call would examine the whole database if COUNT were the number of results
+        let result = db
+            .scan(
+                Cursor::from_str("0").unwrap(),
+                Some(bytes!(b"none-*")),
+                Some(100),
+                None,
+            )
+            .unwrap();
+        assert!(result.result.is_empty());
         assert_ne!("0", result.cursor.to_string());
+
+        let (keys, calls) = scan_all(&db, Some(bytes!(b"1?")), Some(100));
+        assert_eq!(10, keys.len());
+        assert!((20..=21).contains(&calls));
     }
 
     #[test]
@@ -1389,8 +2785,27 @@ mod test {
                 None,
             )
             .unwrap();
-        assert_eq!(2, result.result.len());
-        assert_eq!("0", result.cursor.to_string());
+        assert!(result.result.len() <= 2);
+
+        let (keys, _) = scan_all(&db, Some(bytes!(b"f*")), None);
+        assert_eq!(2, keys.len());
+    }
+
+    #[test]
+    fn binary_safe_patterns() {
+        let db = Db::new(100);
+        db.set(bytes!(b"\xffone"), Value::Ok, None);
+        db.set(bytes!(b"\xfetwo"), Value::Ok, None);
+        db.set(bytes!(b"three"), Value::Ok, None);
+
+        assert_eq!(
+            vec![Value::Blob(bytes!(b"\xffone"))],
+            db.get_all_keys(&bytes!(b"\xff*")).unwrap()
+        );
+        let (keys, _) = scan_all(&db, Some(bytes!(b"[\xfe\xff]*")), None);
+        assert_eq!(2, keys.len());
+        // patterns the glob crate rejected are valid, like in redis
+        assert_eq!(1, db.get_all_keys(&bytes!(b"thre[e")).unwrap().len());
     }
 
     #[tokio::test]
@@ -8,9 +8,34 @@
 //!
 //! This struct will hold an Arc for each database to share databases between
 //! connections.
+//!
+//! The pool can also be used without the server, to embed microredis as an
+//! in-process cache with multiple logical namespaces:
+//!
+//! ```
+//! use bytes::Bytes;
+//! use microredis::{db::pool::Databases, value::Value};
+//!
+//! let (default_db, dbs) = Databases::builder()
+//!     .databases(4)
+//!     .slots(128)
+//!     .slots_for(1, 16)
+//!     .build();
+//!
+//! default_db.set(Bytes::from("foo"), Value::Blob("bar".into()), None);
+//! assert_eq!(4, dbs.len());
+//! assert_eq!(1, dbs.get(0).unwrap().exists(&[Bytes::from("foo")]));
+//! assert_eq!(0, dbs.get(1).unwrap().exists(&[Bytes::from("foo")]));
+//! ```
 use super::Db;
 use crate::error::Error;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+
+/// Default number of databases
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Default number of slots per database
+pub const DEFAULT_SLOTS: usize = 1000;
 
 /// Databases
 #[derive(Debug)]
@@ -18,16 +43,90 @@ pub struct Databases {
     databases: Vec<Arc<Db>>,
 }
 
+/// Databases builder
+///
+/// Configures the number of databases and how many slots (internal shards)
+/// each database has. The number of slots can be overridden for specific
+/// databases.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    databases: usize,
+    slots: usize,
+    slots_per_db: HashMap<usize, usize>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            databases: DEFAULT_DATABASES,
+            slots: DEFAULT_SLOTS,
+            slots_per_db: HashMap::new(),
+        }
+    }
+}
+
+impl Builder {
+    /// Sets the number of databases. At least one database is always created.
+    pub fn databases(mut self, databases: usize) -> Self {
+        self.databases = databases.max(1);
+        self
+    }
+
+    /// Sets the default number of slots for each database. At least one slot
+    /// is always created.
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots.max(1);
+        self
+    }
+
+    /// Sets the number of slots for a given database, overriding the default
+    /// number of slots.
+    pub fn slots_for(mut self, db: usize, slots: usize) -> Self {
+        self.slots_per_db.insert(db, slots.max(1));
+        self
+    }
+
+    /// Creates the pool of databases.
+    ///
+    /// The default database is returned along side the pool
+    pub fn build(self) -> (Arc<Db>, Arc<Databases>) {
+        let databases = (0..self.databases)
+            .map(|db| {
+                Arc::new(Db::new(
+                    self.slots_per_db.get(&db).cloned().unwrap_or(self.slots),
+                ))
+            })
+            .collect::<Vec<Arc<Db>>>();
+
+        (databases[0].clone(), Arc::new(Databases { databases }))
+    }
+}
+
 impl Databases {
     /// Creates new pool of databases.
     ///
     /// The default database is returned along side the pool
     pub fn new(databases: usize, number_of_slots: usize) -> (Arc<Db>, Arc<Self>) {
-        let databases = (0..databases)
-            .map(|_| Arc::new(Db::new(number_of_slots)))
-            .collect::<Vec<Arc<Db>>>();
+        Self::builder()
+            .databases(databases)
+            .slots(number_of_slots)
+            .build()
+    }
 
-        (databases[0].clone(), Arc::new(Self { databases }))
+    /// Returns a builder to configure a new pool of databases
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Returns the number of databases
+    pub fn len(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// Whether the pool has no databases. It is always false, a pool has at
+    /// least one database.
+    pub fn is_empty(&self) -> bool {
+        self.databases.is_empty()
     }
 
     /// Returns a single database or None
@@ -64,3 +163,36 @@ impl<'a> Iterator for DatabasesIterator<'a> {
         self.databases.get(self.index - 1).ok()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builder_defaults() {
+        let (_, dbs) = Databases::builder().build();
+        assert_eq!(DEFAULT_DATABASES, dbs.len());
+        assert_eq!(DEFAULT_SLOTS, dbs.get(0).unwrap().number_of_slots());
+    }
+
+    #[test]
+    fn builder_per_db_slots() {
+        let (default_db, dbs) = Databases::builder()
+            .databases(3)
+            .slots(10)
+            .slots_for(2, 5)
+            .build();
+        assert_eq!(3, dbs.len());
+        assert_eq!(10, default_db.number_of_slots());
+        assert_eq!(10, dbs.get(1).unwrap().number_of_slots());
+        assert_eq!(5, dbs.get(2).unwrap().number_of_slots());
+        assert_eq!(Err(Error::NotSuchDatabase), dbs.get(3).map(|_| ()));
+    }
+
+    #[test]
+    fn builder_at_least_one_db() {
+        let (_, dbs) = Databases::builder().databases(0).slots(0).build();
+        assert_eq!(1, dbs.len());
+        assert_eq!(1, dbs.get(0).unwrap().number_of_slots());
+    }
+}
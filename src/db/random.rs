@@ -0,0 +1,160 @@
+//! # Random generators
+//!
+//! Commands picking random elements (RANDOMKEY, SRANDMEMBER, SPOP, HRANDFIELD, MEMORY SAMPLE) get
+//! their generator from the [`RngProvider`] of the server. By default the generators are seeded
+//! by the OS. Once a seed is set, every generator is derived from a single seeded generator, so
+//! the same commands sent in the same order pick the same elements, and test runs are
+//! reproducible.
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, RngCore, SeedableRng};
+use std::cmp::min;
+
+/// Random generator of a command
+#[derive(Debug)]
+pub enum Generator {
+    /// The thread local generator, seeded by the OS
+    Os,
+    /// Generator derived from the seeded generator of the provider
+    Seeded(StdRng),
+}
+
+impl Generator {
+    /// Whether the generator is deterministic. Hash maps and sets iterate in a different order on
+    /// every run, so deterministic picks must not depend on their iteration order.
+    pub fn is_seeded(&self) -> bool {
+        matches!(self, Self::Seeded(_))
+    }
+}
+
+impl RngCore for Generator {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Self::Os => rand::thread_rng().next_u32(),
+            Self::Seeded(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            Self::Os => rand::thread_rng().next_u64(),
+            Self::Seeded(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            Self::Os => rand::thread_rng().fill_bytes(dest),
+            Self::Seeded(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            Self::Os => rand::thread_rng().try_fill_bytes(dest),
+            Self::Seeded(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+/// Hands out the random generators of the commands
+#[derive(Debug, Default)]
+pub struct RngProvider {
+    seeded: Mutex<Option<StdRng>>,
+}
+
+impl RngProvider {
+    /// Makes the generators deterministic, derived from `seed`, or seeded by the OS again with
+    /// None. Setting the same seed again restarts the sequence.
+    pub fn set_seed(&self, seed: Option<u64>) {
+        *self.seeded.lock() = seed.map(StdRng::seed_from_u64);
+    }
+
+    /// Returns a generator for a command
+    pub fn rng(&self) -> Generator {
+        match self.seeded.lock().as_mut() {
+            Some(seeded) => Generator::Seeded(StdRng::seed_from_u64(seeded.gen())),
+            None => Generator::Os,
+        }
+    }
+}
+
+/// Returns how many elements [`sample`] picks out of `len` elements
+pub fn sample_len(len: usize, count: i64) -> usize {
+    match (len, count) {
+        (0, _) => 0,
+        (len, count) if count >= 0 => min(count as usize, len),
+        (_, count) => count.unsigned_abs() as usize,
+    }
+}
+
+/// Picks random elements, with the `count` semantics of SRANDMEMBER and HRANDFIELD: a positive
+/// count picks up to `count` distinct elements, and a negative count picks exactly `-count`
+/// elements, which may repeat.
+///
+/// With a seeded generator the elements are sorted first, so the picks do not depend on the
+/// iteration order of the hash map or set they come from.
+pub fn sample<T: Ord + Clone>(mut items: Vec<T>, count: i64, rng: &mut Generator) -> Vec<T> {
+    if items.is_empty() {
+        return vec![];
+    }
+    if rng.is_seeded() {
+        items.sort_unstable();
+    }
+    if count >= 0 {
+        let count = min(count as usize, items.len());
+        items.partial_shuffle(rng, count).0.to_vec()
+    } else {
+        (0..count.unsigned_abs())
+            .map(|_| items[rng.gen_range(0..items.len())].clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let provider = RngProvider::default();
+        assert!(!provider.rng().is_seeded());
+
+        provider.set_seed(Some(42));
+        let first = (0..10)
+            .map(|_| provider.rng().gen::<u64>())
+            .collect::<Vec<_>>();
+        provider.set_seed(Some(42));
+        let second = (0..10)
+            .map(|_| provider.rng().gen::<u64>())
+            .collect::<Vec<_>>();
+        assert_eq!(first, second);
+
+        provider.set_seed(None);
+        assert!(!provider.rng().is_seeded());
+    }
+
+    #[test]
+    fn sample_count() {
+        let mut rng = Generator::Os;
+        let items = vec![1, 2, 3, 4, 5];
+
+        let mut picked = sample(items.clone(), 3, &mut rng);
+        picked.sort_unstable();
+        picked.dedup();
+        assert_eq!(3, picked.len());
+
+        let mut picked = sample(items.clone(), 10, &mut rng);
+        picked.sort_unstable();
+        assert_eq!(items, picked);
+
+        let picked = sample(items.clone(), -20, &mut rng);
+        assert_eq!(20, picked.len());
+        assert_eq!(20, sample_len(items.len(), -20));
+        assert_eq!(5, sample_len(items.len(), 10));
+        assert_eq!(0, sample_len(0, -10));
+        assert!(picked.iter().all(|item| items.contains(item)));
+
+        assert!(sample(items, 0, &mut rng).is_empty());
+        assert!(sample(Vec::<u8>::new(), -3, &mut rng).is_empty());
+    }
+}
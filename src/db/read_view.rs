@@ -0,0 +1,144 @@
+//! # Read-only views
+//!
+//! A [`ReadView`] is a cheap handle to a live database that exposes only read operations, so it
+//! can be handed to other subsystems (reporting, metrics exporters) without giving them a way to
+//! modify the dataset. It is `Clone`, `Send` and `Sync`, and it reads the same slots the
+//! connections write to, so every read sees the current data.
+//!
+//! Expired keys are never returned. Like any other read, a read through a view may reclaim the
+//! memory of an expired key, which is announced as an expiration. Reads through a view are not
+//! recorded as accesses of the keys, so they do not reset the idle time OBJECT IDLETIME reports.
+use super::{
+    scan::{self, Scan},
+    Db,
+};
+use crate::{
+    error::Error,
+    value::{cursor::Cursor, typ::Typ, Value},
+};
+use bytes::Bytes;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::time::{Duration, Instant};
+
+/// Read-only handle to a database, see [`Db::read_view`]
+#[derive(Debug, Clone)]
+pub struct ReadView {
+    db: Arc<Db>,
+}
+
+impl ReadView {
+    /// Creates a view of a database
+    pub(super) fn new(db: Arc<Db>) -> Self {
+        Self { db }
+    }
+
+    /// Returns a copy of the value stored at key, of any type, or Null if the key does not exist
+    pub fn get(&self, key: &Bytes) -> Value {
+        self.db.peek(key)
+    }
+
+    /// Returns a copy of the values stored at each key, Null for missing keys
+    pub fn get_multi(&self, keys: &[Bytes]) -> Vec<Value> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Counts how many of the given keys exist
+    pub fn exists(&self, keys: &[Bytes]) -> usize {
+        self.db.exists(keys)
+    }
+
+    /// Returns the name of the type of the value stored at key, `none` if the key does not exist
+    pub fn get_data_type(&self, key: &Bytes) -> String {
+        self.db.get_data_type(key)
+    }
+
+    /// Returns the time to live of a key. None is returned if the key does not exist, and
+    /// Some(None) if the key does not expire.
+    pub fn ttl(&self, key: &Bytes) -> Option<Option<Duration>> {
+        let now = Instant::now();
+        self.db
+            .ttl(key)
+            .map(|ttl| ttl.map(|at| at.saturating_duration_since(now)))
+    }
+
+    /// Returns the number of keys
+    pub fn len(&self) -> usize {
+        self.db.len().unwrap_or_default()
+    }
+
+    /// Checks if the database is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the keys matching a glob-style pattern
+    pub fn keys(&self, pattern: &Bytes) -> Result<Vec<Value>, Error> {
+        self.db.get_all_keys(pattern)
+    }
+
+    /// Counts the keys of each type, by type name
+    pub fn keys_by_type(&self) -> BTreeMap<&'static str, usize> {
+        self.db.keys_by_type()
+    }
+
+    /// Returns an estimation of the memory used by the keys and their values, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.db.memory_usage()
+    }
+
+    /// Returns a copy of every key, along with its value and time to live. Each slot is read
+    /// locked in turn, so the copy is not a point-in-time snapshot of the whole database.
+    pub fn entries(&self) -> Vec<(Bytes, Value, Option<Duration>)> {
+        self.db.entries()
+    }
+}
+
+impl Scan for ReadView {
+    fn scan(
+        &self,
+        cursor: Cursor,
+        pattern: Option<Bytes>,
+        count: Option<usize>,
+        typ: Option<Typ>,
+    ) -> Result<scan::Result, Error> {
+        self.db.scan(cursor, pattern, count, typ)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bytes;
+
+    #[test]
+    fn reads_live_data() {
+        let db = Arc::new(Db::new(10));
+        let view = db.read_view();
+        assert!(view.is_empty());
+
+        db.set(bytes!("foo"), Value::Blob(bytes!("bar")), None);
+        db.set(
+            bytes!("tmp"),
+            Value::Blob(bytes!("x")),
+            Some(Duration::from_secs(60)),
+        );
+
+        let from_thread = view.clone();
+        let value = std::thread::spawn(move || from_thread.get(&bytes!("foo")))
+            .join()
+            .unwrap();
+        assert_eq!(Value::Blob(bytes!("bar")), value);
+        assert_eq!(Value::Null, view.get(&bytes!("missing")));
+        assert_eq!(2, view.len());
+        assert_eq!(1, view.exists(&[bytes!("foo"), bytes!("missing")]));
+        assert_eq!("string", view.get_data_type(&bytes!("foo")));
+        assert_eq!(Some(None), view.ttl(&bytes!("foo")));
+        assert!(view.ttl(&bytes!("tmp")).unwrap().is_some());
+        assert_eq!(None, view.ttl(&bytes!("missing")));
+        assert_eq!(Some(&2), view.keys_by_type().get("string"));
+
+        db.del(&[bytes!("foo")]);
+        assert_eq!(Value::Null, view.get(&bytes!("foo")));
+        assert_eq!(1, view.len());
+    }
+}
@@ -0,0 +1,245 @@
+//! # Slot
+//!
+//! A slot is one of the HashMaps (internal shards) a database is split into. Every change to a
+//! slot goes through this wrapper, which keeps the number of entries and the memory they use in
+//! atomic counters shared with the database, so the size of the database can be read without
+//! locking any slot. The same changes are accounted to the namespace quotas, if any.
+//!
+//! Seeded random picks must not depend on the iteration order of the HashMap, which changes on
+//! every run, so they go through a sorted index of the keys. The index is only built by the first
+//! seeded pick, and from then on it is updated as keys are inserted and removed.
+use super::entry::Entry;
+use crate::quota::Quotas;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    mem,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Database slot
+#[derive(Debug)]
+pub struct Slot {
+    entries: HashMap<Bytes, Entry>,
+    len: Arc<AtomicUsize>,
+    memory: Arc<AtomicUsize>,
+    quotas: Option<Arc<Quotas>>,
+    sorted_keys: Mutex<Option<Vec<Bytes>>>,
+}
+
+impl Slot {
+    /// Creates a new slot, its number of entries is kept in `len`, and the memory used by its
+    /// keys and values is added to `memory`, which may be shared by several slots.
+    pub fn new(len: Arc<AtomicUsize>, memory: Arc<AtomicUsize>) -> Self {
+        len.store(0, Ordering::Relaxed);
+        Self {
+            entries: HashMap::new(),
+            len,
+            memory,
+            quotas: None,
+            sorted_keys: Mutex::new(None),
+        }
+    }
+
+    /// Sets the namespace quotas the keys of the slot are accounted to, adding the entries the
+    /// slot already has to their usage.
+    pub fn set_quotas(&mut self, quotas: Option<Arc<Quotas>>) {
+        if let Some(quotas) = quotas.as_ref() {
+            for (key, entry) in self.entries.iter() {
+                quotas.add(key, 1, key.len() + entry.size());
+            }
+        }
+        self.quotas = quotas;
+    }
+
+    /// Inserts an entry, returning the previous one if any
+    pub fn insert(&mut self, key: Bytes, entry: Entry) -> Option<Entry> {
+        self.added(&key, 1, key.len() + entry.size());
+        let previous = self.entries.insert(key.clone(), entry);
+        if let Some(previous) = previous.as_ref() {
+            self.removed(&key, 1, key.len() + previous.size());
+        } else if let Some(sorted_keys) = self.sorted_keys.get_mut() {
+            if let Err(index) = sorted_keys.binary_search(&key) {
+                sorted_keys.insert(index, key);
+            }
+        }
+        self.update_len();
+        previous
+    }
+
+    /// Removes an entry
+    pub fn remove(&mut self, key: &Bytes) -> Option<Entry> {
+        let previous = self.entries.remove(key);
+        if let Some(previous) = previous.as_ref() {
+            self.removed(key, 1, key.len() + previous.size());
+            if let Some(sorted_keys) = self.sorted_keys.get_mut() {
+                if let Ok(index) = sorted_keys.binary_search(key) {
+                    sorted_keys.remove(index);
+                }
+            }
+        }
+        self.update_len();
+        previous
+    }
+
+    /// Removes all entries
+    pub fn clear(&mut self) {
+        for (key, entry) in mem::take(&mut self.entries).iter() {
+            self.removed(key, 1, key.len() + entry.size());
+        }
+        *self.sorted_keys.get_mut() = None;
+        self.update_len();
+    }
+
+    /// Returns the entry at `index` in the order of the keys, which unlike the iteration order
+    /// is the same on every run. The sorted index of the keys is built on the first call.
+    pub fn nth_sorted(&self, index: usize) -> Option<(&Bytes, &Entry)> {
+        let key = self
+            .sorted_keys
+            .lock()
+            .get_or_insert_with(|| {
+                let mut keys = self.entries.keys().cloned().collect::<Vec<_>>();
+                keys.sort_unstable();
+                keys
+            })
+            .get(index)?
+            .clone();
+        self.entries.get_key_value(&key)
+    }
+
+    /// Estimates again the memory used by an entry of the slot after its value was changed in
+    /// place. The slot only needs to be read-locked.
+    pub fn measure(&self, key: &Bytes, entry: &Entry) {
+        let (previous, size) = entry.measure();
+        self.added(key, 0, size);
+        self.removed(key, 0, previous);
+    }
+
+    /// Accounts keys and memory added to the slot
+    #[inline]
+    fn added(&self, key: &[u8], keys: usize, memory: usize) {
+        self.memory.fetch_add(memory, Ordering::Relaxed);
+        if let Some(quotas) = self.quotas.as_ref() {
+            quotas.add(key, keys, memory);
+        }
+    }
+
+    /// Accounts keys and memory removed from the slot
+    #[inline]
+    fn removed(&self, key: &[u8], keys: usize, memory: usize) {
+        self.memory.fetch_sub(memory, Ordering::Relaxed);
+        if let Some(quotas) = self.quotas.as_ref() {
+            quotas.remove(key, keys, memory);
+        }
+    }
+
+    /// Releases the unused capacity of the slot if it is using less than half of it, returning
+    /// an estimation of the reclaimed bytes.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let capacity = self.entries.capacity();
+        if capacity <= 2 * self.entries.len() {
+            return 0;
+        }
+        self.entries.shrink_to_fit();
+        capacity.saturating_sub(self.entries.capacity()) * mem::size_of::<(Bytes, Entry)>()
+    }
+
+    #[inline]
+    fn update_len(&self) {
+        self.len.store(self.entries.len(), Ordering::Relaxed);
+    }
+}
+
+/// Read-only access to the entries, any change must go through the Slot methods.
+impl Deref for Slot {
+    type Target = HashMap<Bytes, Entry>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::{checksum, Value};
+
+    #[test]
+    fn counter() {
+        let len = Arc::new(AtomicUsize::new(10));
+        let mut slot = Slot::new(len.clone(), Arc::new(AtomicUsize::new(0)));
+        assert_eq!(0, len.load(Ordering::Relaxed));
+
+        slot.insert("foo".into(), Entry::new(Value::Null, None));
+        slot.insert("bar".into(), Entry::new(Value::Null, None));
+        slot.insert("foo".into(), Entry::new(Value::Null, None));
+        assert_eq!(2, len.load(Ordering::Relaxed));
+
+        slot.remove(&"foo".into());
+        slot.remove(&"foo".into());
+        assert_eq!(1, len.load(Ordering::Relaxed));
+
+        slot.clear();
+        assert_eq!(0, len.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn sorted() {
+        let mut slot = Slot::new(Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let nth = |slot: &Slot, index| slot.nth_sorted(index).map(|(key, _)| key.clone());
+        for key in ["c", "a", "d"] {
+            slot.insert(key.into(), Entry::new(Value::Null, None));
+        }
+        assert_eq!(Some(Bytes::from("a")), nth(&slot, 0));
+        assert_eq!(Some(Bytes::from("d")), nth(&slot, 2));
+        assert_eq!(None, nth(&slot, 3));
+
+        // the index is kept up to date once built
+        slot.insert("b".into(), Entry::new(Value::Null, None));
+        slot.insert("c".into(), Entry::new(Value::Null, None));
+        slot.remove(&"a".into());
+        let keys = (0..slot.len())
+            .filter_map(|index| nth(&slot, index))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Bytes::from("b"), "c".into(), "d".into()], keys);
+
+        slot.clear();
+        assert_eq!(None, nth(&slot, 0));
+    }
+
+    #[test]
+    fn memory() {
+        let memory = Arc::new(AtomicUsize::new(0));
+        let mut slot = Slot::new(Arc::new(AtomicUsize::new(0)), memory.clone());
+        let used = || memory.load(Ordering::Relaxed);
+
+        slot.insert("foo".into(), Entry::new(Value::Blob("bar".into()), None));
+        let one = used();
+        assert!(one > 6);
+        slot.insert("foo".into(), Entry::new(Value::Blob("bar".into()), None));
+        assert_eq!(one, used());
+
+        slot.insert(
+            "list".into(),
+            Entry::new(Value::List(Default::default()), None),
+        );
+        let empty_list = used();
+        if let Some(entry) = slot.get(&Bytes::from("list")) {
+            if let Value::List(list) = &mut *entry.inner_mut() {
+                list.push_back(checksum::Value::new(Bytes::from("a long enough value")));
+            }
+            slot.measure(&Bytes::from("list"), entry);
+        }
+        assert!(used() > empty_list);
+
+        slot.remove(&"list".into());
+        assert_eq!(one, used());
+        slot.clear();
+        assert_eq!(0, used());
+    }
+}
@@ -0,0 +1,212 @@
+//! # Snapshots
+//!
+//! Exports the keyspace to JSON, and imports it back, to seed test fixtures or to move small
+//! datasets between instances. The format is:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "databases": {
+//!     "0": [
+//!       {"key": "name", "type": "string", "value": "microredis", "ttl": 1500},
+//!       {"key": "queue", "type": "list", "value": ["a", "b"]},
+//!       {"key": "tags", "type": "set", "value": ["x", "y"]},
+//!       {"key": "user", "type": "hash", "value": [["field", "value"]]}
+//!     ]
+//!   }
+//! }
+//! ```
+//!
+//! Keys, values and hash fields are JSON strings when they are valid UTF-8, otherwise they are
+//! written as `{"hex": "<hex encoded bytes>"}`. `ttl` is the time to live in milliseconds, and it
+//! is omitted for keys without expiration. Databases without keys are omitted.
+use super::{pool::Databases, utils::Override};
+use crate::{
+    error::Error,
+    value::{checksum, serialize::BinaryString, Value},
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
+use tokio::time::Duration;
+
+/// Version of the snapshot format
+const VERSION: u32 = 1;
+
+/// Key value
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+enum KeyValue {
+    String(BinaryString),
+    List(Vec<BinaryString>),
+    Set(Vec<BinaryString>),
+    Hash(Vec<(BinaryString, BinaryString)>),
+}
+
+/// A key of the snapshot
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Key {
+    key: BinaryString,
+    #[serde(flatten)]
+    value: KeyValue,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    ttl: Option<u64>,
+}
+
+/// Snapshot of all the databases
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Snapshot {
+    version: u32,
+    databases: BTreeMap<usize, Vec<Key>>,
+}
+
+impl KeyValue {
+    /// Converts a value of the database, None is returned for values that are not stored as keys
+    fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Hash(x) => Self::Hash(
+                x.iter()
+                    .map(|(k, v)| (k.as_ref().into(), v.as_ref().into()))
+                    .collect(),
+            ),
+            Value::List(x) => Self::List(
+                x.iter()
+                    .filter_map(|v| match v.clone_value() {
+                        Value::Blob(v) => Some(v.as_ref().into()),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            Value::Set(x) => Self::Set(x.iter().map(|v| v.as_ref().into()).collect()),
+            Value::Integer(x) => Self::String(BinaryString::Text(x.to_string())),
+            Value::BigInteger(x) => Self::String(BinaryString::Text(x.to_string())),
+            Value::Float(x) => Self::String(BinaryString::Text(x.to_string())),
+            x => Self::String(x.as_bytes()?.into()),
+        })
+    }
+}
+
+impl TryFrom<KeyValue> for Value {
+    type Error = Error;
+
+    fn try_from(value: KeyValue) -> Result<Self, Error> {
+        Ok(match value {
+            KeyValue::String(x) => Value::Blob(x.try_into()?),
+            KeyValue::List(x) => Value::List(
+                x.into_iter()
+                    .map(|v| Ok(checksum::Value::new(v.try_into()?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            KeyValue::Set(x) => Value::Set(
+                x.into_iter()
+                    .map(Bytes::try_from)
+                    .collect::<Result<_, Error>>()?,
+            ),
+            KeyValue::Hash(x) => Value::Hash(
+                x.into_iter()
+                    .map(|(k, v)| Ok((k.try_into()?, v.try_into()?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+        })
+    }
+}
+
+/// Exports all the databases to JSON
+pub fn export(databases: &Databases) -> String {
+    let databases = databases
+        .into_iter()
+        .enumerate()
+        .map(|(id, db)| {
+            let keys = db
+                .entries()
+                .into_iter()
+                .filter_map(|(key, value, ttl)| {
+                    Some(Key {
+                        key: key.as_ref().into(),
+                        value: KeyValue::new(&value)?,
+                        ttl: ttl.map(|ttl| ttl.as_millis().max(1) as u64),
+                    })
+                })
+                .collect::<Vec<_>>();
+            (id, keys)
+        })
+        .filter(|(_, keys)| !keys.is_empty())
+        .collect();
+
+    serde_json::to_string(&Snapshot {
+        version: VERSION,
+        databases,
+    })
+    .unwrap_or_default()
+}
+
+/// Imports a JSON snapshot into the databases, returning the number of keys imported. Existing
+/// keys are only replaced when `replace` is set, otherwise they are skipped.
+///
+/// The whole snapshot is validated before any key is imported.
+pub fn import(databases: &Databases, json: &[u8], replace: bool) -> Result<usize, Error> {
+    let snapshot: Snapshot =
+        serde_json::from_slice(json).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+    if snapshot.version != VERSION {
+        return Err(Error::InvalidSnapshot(format!(
+            "unsupported version {}",
+            snapshot.version
+        )));
+    }
+
+    let mut to_import = vec![];
+    for (id, keys) in snapshot.databases.into_iter() {
+        let db = databases.get(id)?;
+        for key in keys.into_iter() {
+            to_import.push((
+                db.clone(),
+                Bytes::try_from(key.key)?,
+                Value::try_from(key.value)?,
+                key.ttl.map(Duration::from_millis),
+            ));
+        }
+    }
+
+    let override_value = if replace { Override::Yes } else { Override::No };
+    Ok(to_import
+        .into_iter()
+        .map(|(db, key, value, ttl)| db.set_advanced(key, value, ttl, override_value, false, false))
+        .filter(|result| *result != Ok(Value::Integer(0)))
+        .count())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn binary_data() {
+        let (_, databases) = Databases::new(2, 10);
+        let db = databases.get(1).unwrap();
+        db.set(
+            Bytes::from(&b"\xff\x00"[..]),
+            Value::Blob("text".into()),
+            None,
+        );
+
+        let json = export(&databases);
+        assert_eq!(
+            r#"{"version":1,"databases":{"1":[{"key":{"hex":"ff00"},"type":"string","value":"text"}]}}"#,
+            json
+        );
+
+        db.flushdb().unwrap();
+        assert_eq!(Ok(1), import(&databases, json.as_bytes(), false));
+        assert_eq!(
+            Ok(Value::Blob("text".into())),
+            db.get(&Bytes::from(&b"\xff\x00"[..])).into_inner()
+        );
+        assert_eq!(
+            Err(Error::NotSuchDatabase),
+            import(&databases, br#"{"version":1,"databases":{"5":[]}}"#, false)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! # Backing store
+//!
+//! A database can sit in front of an external store (a SQL database, a remote service...), turning
+//! microredis into a cache layer. A GET for a key the database does not have asks the store for it
+//! (read-through), and the changes made to the database are propagated to the store
+//! (write-through).
+//!
+//! Changes are propagated by a background task, so commands never wait for the store. Only the
+//! keys are queued: the value of a changed key is read from the database when the change is
+//! propagated, and a key changed many times before that is written only once, with its latest
+//! value. Keys are propagated in the order they first changed. Keys removed because they expired
+//! are not propagated, expiring is how the cache forgets about a key.
+use crate::value::Value;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use std::{collections::HashMap, fmt, sync::Arc};
+use tokio::{sync::mpsc, time::Duration};
+
+/// External store behind a database
+pub trait BackingStore: Send + Sync + 'static {
+    /// Loads a key that is missing from the database. The value is cached in the database, with
+    /// the returned expiration if any.
+    fn get_missing<'a>(
+        &'a self,
+        key: &'a Bytes,
+    ) -> BoxFuture<'a, Option<(Value, Option<Duration>)>>;
+
+    /// A key was created or changed in the database
+    fn set<'a>(&'a self, key: &'a Bytes, value: &'a Value) -> BoxFuture<'a, ()>;
+
+    /// A key was removed from the database
+    fn delete<'a>(&'a self, key: &'a Bytes) -> BoxFuture<'a, ()>;
+}
+
+/// A change to propagate to the store
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    /// The key was created or changed, its value is read when the change is propagated
+    Write,
+    /// The key was removed
+    Delete,
+}
+
+/// Keys whose changes were not propagated yet. Only the last change of each key is kept, along
+/// with a version to tell whether the key changed again while its change was being propagated.
+#[derive(Debug, Default)]
+struct Pending {
+    changes: HashMap<Bytes, (Change, u64)>,
+    last_version: u64,
+}
+
+/// A registered backing store, along with the changes to propagate to it
+#[derive(Clone)]
+pub struct StoreHandle {
+    store: Arc<dyn BackingStore>,
+    pending: Arc<Mutex<Pending>>,
+    queue: mpsc::UnboundedSender<Bytes>,
+}
+
+impl fmt::Debug for StoreHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreHandle").finish()
+    }
+}
+
+impl StoreHandle {
+    /// Wraps a store, spawning the task that propagates the changes to it. `read` returns the
+    /// current value of a key, or None if the key does not exist or expired. It must be called
+    /// from within a tokio runtime.
+    pub fn new<F>(store: Arc<dyn BackingStore>, read: F) -> Self
+    where
+        F: Fn(&Bytes) -> Option<Value> + Send + Sync + 'static,
+    {
+        let (queue, mut receiver) = mpsc::unbounded_channel::<Bytes>();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let writer = store.clone();
+        let changes = pending.clone();
+        tokio::spawn(async move {
+            while let Some(key) = receiver.recv().await {
+                // The key may change again while its change is propagated, in which case the new
+                // change is propagated right away
+                loop {
+                    let (change, version) = match changes.lock().changes.get(&key) {
+                        Some(change) => *change,
+                        None => break,
+                    };
+                    match change {
+                        Change::Write => {
+                            if let Some(value) = read(&key) {
+                                writer.set(&key, &value).await;
+                            }
+                        }
+                        Change::Delete => writer.delete(&key).await,
+                    }
+                    let mut pending = changes.lock();
+                    if pending.changes.get(&key).map(|(_, v)| *v) == Some(version) {
+                        pending.changes.remove(&key);
+                        break;
+                    }
+                }
+            }
+        });
+        Self {
+            store,
+            pending,
+            queue,
+        }
+    }
+
+    /// Loads a key missing from the database
+    pub async fn get_missing(&self, key: &Bytes) -> Option<(Value, Option<Duration>)> {
+        self.store.get_missing(key).await
+    }
+
+    /// Whether a change of the key is waiting to be propagated. The store is not up to date for
+    /// such a key, so it must not be loaded from the store.
+    pub fn is_pending(&self, key: &Bytes) -> bool {
+        self.pending.lock().changes.contains_key(key)
+    }
+
+    /// Records a change, queueing the key unless it is queued already
+    fn push(&self, key: Bytes, change: Change) {
+        let mut pending = self.pending.lock();
+        pending.last_version += 1;
+        let version = pending.last_version;
+        if pending
+            .changes
+            .insert(key.clone(), (change, version))
+            .is_none()
+        {
+            let _ = self.queue.send(key);
+        }
+    }
+
+    /// Queues a key change
+    pub fn set(&self, key: Bytes) {
+        self.push(key, Change::Write);
+    }
+
+    /// Queues a key removal
+    pub fn delete(&self, key: Bytes) {
+        self.push(key, Change::Delete);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cmd::test::{create_connection, run_command};
+    use futures::FutureExt;
+    use tokio::time::sleep;
+
+    #[derive(Default)]
+    struct MemoryStore {
+        entries: Mutex<HashMap<Bytes, Value>>,
+    }
+
+    impl BackingStore for MemoryStore {
+        fn get_missing<'a>(
+            &'a self,
+            key: &'a Bytes,
+        ) -> BoxFuture<'a, Option<(Value, Option<Duration>)>> {
+            async move { self.entries.lock().get(key).map(|v| (v.clone(), None)) }.boxed()
+        }
+
+        fn set<'a>(&'a self, key: &'a Bytes, value: &'a Value) -> BoxFuture<'a, ()> {
+            async move {
+                self.entries.lock().insert(key.clone(), value.clone());
+            }
+            .boxed()
+        }
+
+        fn delete<'a>(&'a self, key: &'a Bytes) -> BoxFuture<'a, ()> {
+            async move {
+                self.entries.lock().remove(key);
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn read_and_write_through() {
+        let c = create_connection();
+        let store = Arc::new(MemoryStore::default());
+        store
+            .entries
+            .lock()
+            .insert("stored".into(), Value::Blob("from store".into()));
+        c.db().set_backing_store(store.clone());
+
+        // read-through, the value is cached
+        assert_eq!(
+            Ok(Value::Blob("from store".into())),
+            run_command(&c, &["get", "stored"]).await
+        );
+        store.entries.lock().clear();
+        assert_eq!(Ok(1.into()), run_command(&c, &["exists", "stored"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "missing"]).await);
+
+        // write-through
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "bar"]).await);
+        assert_eq!(
+            Ok(2.into()),
+            run_command(&c, &["rpush", "list", "a", "b"]).await
+        );
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            Some(Value::Blob("bar".into())),
+            store.entries.lock().get(&Bytes::from("foo")).cloned()
+        );
+        assert!(store.entries.lock().contains_key(&Bytes::from("list")));
+
+        assert_eq!(Ok(1.into()), run_command(&c, &["del", "foo"]).await);
+        sleep(Duration::from_millis(10)).await;
+        assert!(!store.entries.lock().contains_key(&Bytes::from("foo")));
+    }
+
+    /// Puts the database of the connection in front of a store holding `key`, and removes the
+    /// key with `cmd`. The key must not be loaded back from the store.
+    async fn remove_and_get(cmd: &[&str], key: &str) {
+        let c = create_connection();
+        let store = Arc::new(MemoryStore::default());
+        c.db().set_backing_store(store.clone());
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", key, "bar"]).await);
+        sleep(Duration::from_millis(10)).await;
+        assert!(store
+            .entries
+            .lock()
+            .contains_key(&Bytes::from(key.to_owned())));
+
+        assert!(run_command(&c, cmd).await.is_ok());
+        // before and after the removal reaches the store
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", key]).await);
+        sleep(Duration::from_millis(10)).await;
+        assert!(!store
+            .entries
+            .lock()
+            .contains_key(&Bytes::from(key.to_owned())));
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", key]).await);
+    }
+
+    #[tokio::test]
+    async fn removals_are_deleted_from_the_store() {
+        remove_and_get(&["del", "foo"], "foo").await;
+        remove_and_get(&["getdel", "foo"], "foo").await;
+        remove_and_get(&["rename", "foo", "bar"], "foo").await;
+        remove_and_get(&["move", "foo", "1"], "foo").await;
+        remove_and_get(&["flushdb"], "foo").await;
+    }
+
+    #[tokio::test]
+    async fn renamed_keys_are_written_to_the_store() {
+        let c = create_connection();
+        let store = Arc::new(MemoryStore::default());
+        c.db().set_backing_store(store.clone());
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "foo", "bar"]).await);
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["rename", "foo", "new"]).await
+        );
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            Some(Value::Blob("bar".into())),
+            store.entries.lock().get(&Bytes::from("new")).cloned()
+        );
+        assert_eq!(
+            Ok(Value::Blob("bar".into())),
+            run_command(&c, &["get", "new"]).await
+        );
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "foo"]).await);
+    }
+
+    #[tokio::test]
+    async fn latest_values_are_written() {
+        let c = create_connection();
+        let store = Arc::new(MemoryStore::default());
+        c.db().set_backing_store(store.clone());
+        for i in 0..100 {
+            let _ = run_command(&c, &["rpush", "list", &i.to_string()]).await;
+        }
+        let _ = run_command(&c, &["incr", "counter"]).await;
+        let _ = run_command(&c, &["incr", "counter"]).await;
+        sleep(Duration::from_millis(10)).await;
+
+        let entries = store.entries.lock();
+        match entries.get(&Bytes::from("list")) {
+            Some(Value::List(list)) => assert_eq!(100, list.len()),
+            value => panic!("unexpected value {:?}", value),
+        }
+        assert_eq!(
+            Some(Value::Blob("2".into())),
+            entries.get(&Bytes::from("counter")).cloned()
+        );
+    }
+}
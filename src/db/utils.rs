@@ -1,5 +1,9 @@
-use crate::error::Error;
+use crate::{
+    error::Error,
+    value::{bytes_to_number, float::Float, ParseNumber},
+};
 use bytes::Bytes;
+use num_traits::{CheckedAdd, Zero};
 use std::convert::{TryFrom, TryInto};
 use tokio::time::{Duration, Instant};
 
@@ -7,10 +11,48 @@ pub(crate) fn far_future() -> Instant {
     Instant::now() + Duration::from_secs(86400 * 365 * 30)
 }
 
+/// Numbers that can be incremented by the INCR* and HINCRBY* commands
+///
+/// Both families of commands share the same parsing, overflow checks and errors through this
+/// trait.
+pub trait Number: ToString + ParseNumber + CheckedAdd + Zero + Copy {
+    /// Error returned when a value is not a valid number of this type
+    fn not_a_number() -> Error;
+
+    /// Error returned when the increment cannot be represented by this type
+    fn overflow() -> Error;
+
+    /// Parses a number of this type
+    fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        bytes_to_number(bytes).map_err(|_| Self::not_a_number())
+    }
+}
+
+impl Number for i64 {
+    fn not_a_number() -> Error {
+        Error::NotANumberType("an integer".to_owned())
+    }
+
+    fn overflow() -> Error {
+        Error::Overflow
+    }
+}
+
+impl Number for Float {
+    fn not_a_number() -> Error {
+        Error::NotANumberType("a valid float".to_owned())
+    }
+
+    fn overflow() -> Error {
+        Error::IncrByInfOrNan
+    }
+}
+
 /// Override database entries
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub enum Override {
     /// Allow override
+    #[default]
     Yes,
     /// Do not allow override, only new entries
     No,
@@ -28,12 +70,6 @@ impl From<bool> for Override {
     }
 }
 
-impl Default for Override {
-    fn default() -> Self {
-        Self::Yes
-    }
-}
-
 /// Override database entries
 #[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub struct ExpirationOpts {
@@ -66,9 +102,26 @@ impl TryFrom<&[Bytes]> for ExpirationOpts {
                 "XX" => expiration_opts.replace_only = true,
                 "GT" => expiration_opts.greater_than = true,
                 "LT" => expiration_opts.lower_than = true,
-                invalid => return Err(Error::UnsupportedOption(invalid.to_owned())),
+                _ => {
+                    return Err(Error::UnsupportedOption(
+                        String::from_utf8_lossy(arg).to_string(),
+                    ))
+                }
             }
         }
+
+        if expiration_opts.if_none
+            && (expiration_opts.replace_only
+                || expiration_opts.greater_than
+                || expiration_opts.lower_than)
+        {
+            return Err(Error::OptsNotCompatible("NX and XX, GT or LT".to_owned()));
+        }
+
+        if expiration_opts.greater_than && expiration_opts.lower_than {
+            return Err(Error::OptsNotCompatible("GT and LT".to_owned()));
+        }
+
         Ok(expiration_opts)
     }
 }
@@ -80,17 +133,12 @@ mod test {
 
     #[test]
     fn parsing_expiration_1() {
-        let opts = vec![
-            Bytes::copy_from_slice(b"nx"),
-            Bytes::copy_from_slice(b"Xx"),
-            Bytes::copy_from_slice(b"GT"),
-            Bytes::copy_from_slice(b"lT"),
-        ];
+        let opts = vec![Bytes::copy_from_slice(b"Xx"), Bytes::copy_from_slice(b"GT")];
         let x: ExpirationOpts = opts.as_slice().try_into().unwrap();
-        assert!(x.if_none);
+        assert!(!x.if_none);
         assert!(x.replace_only);
         assert!(x.greater_than);
-        assert!(x.lower_than);
+        assert!(!x.lower_than);
     }
 
     #[test]
@@ -109,6 +157,20 @@ mod test {
         let opts = vec![Bytes::copy_from_slice(b"xxx")];
         let x: Result<ExpirationOpts, _> = opts.as_slice().try_into();
 
-        assert!(x.is_err());
+        assert_eq!(Err(Error::UnsupportedOption("xxx".to_owned())), x);
+    }
+
+    #[test]
+    fn parsing_expiration_not_compatible() {
+        for opts in [
+            &["nx", "xx"][..],
+            &["NX", "gt"],
+            &["lt", "nx"],
+            &["GT", "LT"],
+        ] {
+            let opts = opts.iter().map(|x| Bytes::from(*x)).collect::<Vec<_>>();
+            let x: Result<ExpirationOpts, _> = opts.as_slice().try_into();
+            assert!(matches!(x, Err(Error::OptsNotCompatible(_))));
+        }
     }
 }
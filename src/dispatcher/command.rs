@@ -5,10 +5,14 @@
 //!
 //! Each command is defined with the dispatcher macro, which generates efficient and developer
 //! friendly code.
-use crate::value::Value;
+use super::histogram::Histogram;
+use crate::{
+    error::Error,
+    value::{Protocol, Value},
+};
 use bytes::Bytes;
-use metered::{ErrorCount, HitCount, InFlight, ResponseTime, Throughput};
-use std::{collections::VecDeque, convert::TryInto};
+use metered::{clear::Clear, ErrorCount, HitCount, InFlight, ResponseTime, Throughput};
+use std::{collections::VecDeque, convert::TryInto, fmt};
 
 /// Command Flags
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -43,9 +47,34 @@ pub enum Flag {
     MayReplicate,
 }
 
-impl ToString for Flag {
-    fn to_string(&self) -> String {
-        match self {
+/// Shape of the reply of a command, as RESP3 is able to express more than plain arrays
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReplyType {
+    /// The reply is sent as it is
+    Default,
+    /// The array reply has unique elements
+    Set,
+    /// The array reply has alternating fields and values
+    Map,
+    /// The reply is a map when the given option is passed, and it is sent as it is otherwise
+    MapWithOption(&'static str),
+}
+
+impl ReplyType {
+    /// Converts a reply to the frame expected by the client protocol. RESP2 replies are never
+    /// changed.
+    pub fn format(self, reply: Value, protocol: Protocol) -> Value {
+        match (self, protocol, reply) {
+            (Self::Set, Protocol::Resp3, Value::Array(x)) => Value::SetReply(x),
+            (Self::Map, Protocol::Resp3, Value::Array(x)) => Value::MapReply(x),
+            (_, _, reply) => reply,
+        }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             Self::Write => "write",
             Self::DenyOom => "denyoom",
             Self::ReadOnly => "readonly",
@@ -60,11 +89,208 @@ impl ToString for Flag {
             Self::SkipSlowlog => "skip_slowlog",
             Self::Fast => "fast",
             Self::MayReplicate => "may_replicate",
+        })
+    }
+}
+
+/// Returns the ACL categories for a command group and its flags
+fn acl_categories(group: &'static str, flags: &[Flag]) -> Vec<&'static str> {
+    let mut categories = vec![];
+    for flag in flags.iter() {
+        match flag {
+            Flag::Write => categories.push("@write"),
+            Flag::ReadOnly => categories.push("@read"),
+            Flag::Admin => categories.extend(["@admin", "@dangerous"]),
+            Flag::PubSub => categories.push("@pubsub"),
+            _ => {}
+        }
+    }
+    categories.push(if flags.contains(&Flag::Fast) {
+        "@fast"
+    } else {
+        "@slow"
+    });
+    match group {
+        "string" => categories.push("@string"),
+        "list" => categories.push("@list"),
+        "hash" => categories.push("@hash"),
+        "set" => categories.push("@set"),
+        "keys" => categories.push("@keyspace"),
+        "connection" => categories.push("@connection"),
+        "transaction" => categories.push("@transaction"),
+        "pubsub" if !categories.contains(&"@pubsub") => categories.push("@pubsub"),
+        _ => {}
+    }
+    categories
+}
+
+/// Returns the version of Redis that deprecated a command and what replaces it, if the command is
+/// deprecated
+fn deprecation(name: &str) -> Option<(&'static str, &'static str)> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "BRPOPLPUSH" => ("6.2.0", "`BLMOVE` with the `RIGHT` and `LEFT` arguments"),
+        "GETSET" => ("6.2.0", "`SET` with the `!GET` argument"),
+        "HMSET" => ("4.0.0", "`HSET` with multiple field-value pairs"),
+        "PSETEX" => ("2.6.12", "`SET` with the `PX` argument"),
+        "RPOPLPUSH" => ("6.2.0", "`LMOVE` with the `RIGHT` and `LEFT` arguments"),
+        "SETEX" => ("2.6.12", "`SET` with the `EX` argument"),
+        "SETNX" => ("2.6.12", "`SET` with the `NX` argument"),
+        "SUBSTR" => ("2.0.0", "`GETRANGE`"),
+        _ => return None,
+    })
+}
+
+/// Returns the position of the keys of a command with a `numkeys` argument, `first` being the
+/// position of the first key and `argc` the number of arguments, including the command name.
+fn numkeys_positions(numkeys: Option<&Bytes>, first: usize, argc: usize) -> Vec<usize> {
+    let numkeys = numkeys
+        .and_then(|numkeys| String::from_utf8_lossy(numkeys).parse::<usize>().ok())
+        .unwrap_or(0);
+    (first..first.saturating_add(numkeys).min(argc)).collect()
+}
+
+/// Flags of a key, following the key-specs flags of redis (`RW`, `access`, `delete`, ...)
+pub type KeyFlags = &'static [&'static str];
+
+/// How the keys of a command are found in its arguments
+#[derive(Debug, Clone, Copy)]
+pub enum KeyExtractor {
+    /// Keys are located through the first/last/step metadata of the command
+    Range,
+    /// The number of keys is given at this position, and the keys follow it
+    NumKeys(usize),
+}
+
+/// Key specification of a command
+///
+/// Describes how the keys of a command are found and how the command accesses them. Commands
+/// without a key specification locate their keys by their first/last/step metadata, and their
+/// keys are flagged by whether the command writes or not.
+#[derive(Debug, Clone, Copy)]
+pub struct KeySpec {
+    extractor: KeyExtractor,
+    flags: Option<KeyFlags>,
+    first_key_flags: Option<KeyFlags>,
+}
+
+impl Default for KeySpec {
+    fn default() -> Self {
+        Self::range()
+    }
+}
+
+impl KeySpec {
+    /// Keys are located through the first/last/step metadata of the command
+    pub const fn range() -> Self {
+        Self {
+            extractor: KeyExtractor::Range,
+            flags: None,
+            first_key_flags: None,
+        }
+    }
+
+    /// Keys follow a `numkeys` argument, found at the given position
+    pub const fn numkeys(position: usize) -> Self {
+        Self {
+            extractor: KeyExtractor::NumKeys(position),
+            ..Self::range()
+        }
+    }
+
+    /// Sets the flags of every key
+    pub const fn flags(self, flags: KeyFlags) -> Self {
+        Self {
+            flags: Some(flags),
+            ..self
+        }
+    }
+
+    /// Sets the flags of the first key, which are different from the other keys in commands
+    /// moving data from one key to another
+    pub const fn first_key_flags(self, flags: KeyFlags) -> Self {
+        Self {
+            first_key_flags: Some(flags),
+            ..self
         }
-        .to_owned()
     }
 }
 
+/// Sub-command definition
+///
+/// Container commands (CLIENT, OBJECT, PUBSUB, ...) declare their
+/// sub-commands, each one with their own arity and flags. The dispatcher
+/// validates them before the command handler is called.
+#[derive(Debug)]
+pub struct SubCommand {
+    name: &'static str,
+    flags: &'static [Flag],
+    min_args: i32,
+    reply_type: ReplyType,
+}
+
+impl SubCommand {
+    /// Creates a new sub-command. The arity includes the command and the
+    /// sub-command name, like redis does.
+    pub fn new(
+        name: &'static str,
+        flags: &'static [Flag],
+        min_args: i32,
+        reply_type: ReplyType,
+    ) -> Self {
+        Self {
+            name,
+            flags,
+            min_args,
+            reply_type,
+        }
+    }
+
+    /// Sub-command name
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the sub-command's flags
+    pub fn get_flags(&self) -> Vec<Flag> {
+        self.flags.to_vec()
+    }
+
+    /// Checks if the sub-command has a given flag
+    #[inline]
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Returns the arity of the sub-command
+    pub fn get_min_args(&self) -> i32 {
+        self.min_args
+    }
+
+    /// Checks if a given number of args is expected by this sub-command
+    pub fn check_number_args(&self, n: usize) -> bool {
+        if self.min_args >= 0 {
+            n == self.min_args.try_into().unwrap_or(0)
+        } else {
+            let s: usize = self.min_args.abs().try_into().unwrap_or(0);
+            n >= s
+        }
+    }
+}
+
+/// Length of the longest command name. Longer names are never a command.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Uppercases a command name into `buffer`, without allocating, so it can be matched against the
+/// names of the commands. None is returned if the name is too long or it is not valid UTF-8, as
+/// no command is named like that.
+#[inline]
+pub fn uppercase_name<'a>(name: &[u8], buffer: &'a mut [u8; MAX_NAME_LEN]) -> Option<&'a str> {
+    let buffer = buffer.get_mut(..name.len())?;
+    buffer.copy_from_slice(name);
+    buffer.make_ascii_uppercase();
+    std::str::from_utf8(buffer).ok()
+}
+
 /// Command definition
 #[derive(Debug)]
 pub struct Command {
@@ -75,7 +301,10 @@ pub struct Command {
     key_start: i32,
     key_stop: i32,
     key_step: usize,
+    key_spec: KeySpec,
+    reply_type: ReplyType,
     is_queueable: bool,
+    sub_commands: Vec<SubCommand>,
     metrics: Metrics,
 }
 
@@ -92,6 +321,19 @@ pub struct Metrics {
     pub response_time: ResponseTime,
     /// Throughput
     pub throughput: Throughput,
+    /// Latency histogram, with a microsecond resolution
+    pub latency: Histogram,
+}
+
+impl Metrics {
+    /// Forgets all the metrics, except for the executions in flight
+    pub fn reset(&self) {
+        self.hit_count.clear();
+        self.error_count.clear();
+        self.response_time.clear();
+        self.throughput.clear();
+        self.latency.reset();
+    }
 }
 
 impl Command {
@@ -106,6 +348,9 @@ impl Command {
         key_stop: i32,
         key_step: usize,
         is_queueable: bool,
+        sub_commands: Vec<SubCommand>,
+        key_spec: KeySpec,
+        reply_type: ReplyType,
     ) -> Self {
         Self {
             name,
@@ -115,7 +360,10 @@ impl Command {
             key_start,
             key_stop,
             key_step,
+            key_spec,
+            reply_type,
             is_queueable,
+            sub_commands,
             metrics: Metrics::default(),
         }
     }
@@ -135,33 +383,97 @@ impl Command {
         self.is_queueable
     }
 
-    /// Returns all database keys from the command arguments
-    pub fn get_keys(&self, args: &VecDeque<Bytes>, includes_command: bool) -> Vec<Bytes> {
-        let start = self.key_start;
-        let stop = if self.key_stop > 0 {
-            self.key_stop
-        } else {
-            (args.len() as i32) + self.key_stop
-        };
+    /// Returns the position of every key in the command arguments.
+    ///
+    /// Keys are located with the extractor of the key specification of the command.
+    fn key_positions(&self, args: &VecDeque<Bytes>, includes_command: bool) -> Vec<usize> {
+        // positions are relative to the command name, which may not be part of the arguments
+        let offset = if includes_command { 0 } else { 1 };
+        let argc = args.len() + offset;
 
-        if start == 0 {
-            return Vec::new();
-        }
+        let positions: Vec<usize> = match self.key_spec.extractor {
+            KeyExtractor::NumKeys(at) => numkeys_positions(args.get(at - offset), at + 1, argc),
+            KeyExtractor::Range if self.key_start <= 0 => vec![],
+            KeyExtractor::Range => {
+                let stop = if self.key_stop > 0 {
+                    self.key_stop
+                } else {
+                    argc as i32 + self.key_stop
+                };
+                (self.key_start..stop + 1)
+                    .step_by(self.key_step.max(1))
+                    .map(|i| i as usize)
+                    .collect()
+            }
+        };
 
-        let mut result = Vec::new();
+        positions
+            .into_iter()
+            .filter(|i| *i < argc)
+            .map(|i| i - offset)
+            .collect()
+    }
 
-        for i in (start..stop + 1).step_by(self.key_step) {
-            result.push(
-                args[if includes_command {
-                    i as usize
+    /// Returns the shape of the reply of the command, or of the sub-command being called, `args`
+    /// being the arguments without the command name.
+    pub fn reply_type(
+        &self,
+        sub_command: Option<&SubCommand>,
+        args: &VecDeque<Bytes>,
+    ) -> ReplyType {
+        match sub_command.map_or(self.reply_type, |sub_command| sub_command.reply_type) {
+            ReplyType::MapWithOption(option) => {
+                // options come after the keys, which may be named like the option
+                let options = self
+                    .key_positions(args, false)
+                    .last()
+                    .map_or(0, |position| position + 1);
+                if args
+                    .iter()
+                    .skip(options)
+                    .any(|arg| arg.eq_ignore_ascii_case(option.as_bytes()))
+                {
+                    ReplyType::Map
                 } else {
-                    i as usize - 1
-                }]
-                .clone(),
-            );
+                    ReplyType::Default
+                }
+            }
+            reply_type => reply_type,
         }
+    }
 
-        result
+    /// Returns all database keys from the command arguments
+    pub fn get_keys(&self, args: &VecDeque<Bytes>, includes_command: bool) -> Vec<Bytes> {
+        self.key_positions(args, includes_command)
+            .into_iter()
+            .map(|i| args[i].clone())
+            .collect()
+    }
+
+    /// Returns all database keys from the command arguments alongside with their flags, which
+    /// describe how the command access each key (following the key-specs flags of redis).
+    pub fn get_keys_and_flags(
+        &self,
+        args: &VecDeque<Bytes>,
+        includes_command: bool,
+    ) -> Vec<(Bytes, KeyFlags)> {
+        self.get_keys(args, includes_command)
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| (key, self.key_flags(i)))
+            .collect()
+    }
+
+    /// Returns the flags of the nth key of the command
+    fn key_flags(&self, index: usize) -> KeyFlags {
+        match self.key_spec.first_key_flags {
+            Some(flags) if index == 0 => flags,
+            _ => match self.key_spec.flags {
+                Some(flags) => flags,
+                None if self.has_flag(Flag::Write) => &["RW", "access", "update"],
+                None => &["RO", "access"],
+            },
+        }
     }
 
     /// Checks if a given number of args is expected by this command
@@ -174,6 +486,123 @@ impl Command {
         }
     }
 
+    /// Validates the arguments (without the command name) and returns the
+    /// sub-command being called, if the command has any.
+    ///
+    /// Commands with sub-commands that are called without any argument (like
+    /// COMMAND) are valid as long as their own arity allows it.
+    pub fn check_args(&self, args: &VecDeque<Bytes>) -> Result<Option<&SubCommand>, Error> {
+        if !self.check_number_args(args.len() + 1) {
+            return Err(Error::InvalidArgsCount(self.name.into()));
+        }
+        let name = match args.front() {
+            Some(name) if !self.sub_commands.is_empty() => String::from_utf8_lossy(name),
+            _ => return Ok(None),
+        };
+        match self.get_sub_command(&name) {
+            Some(sub_command) if sub_command.check_number_args(args.len() + 1) => {
+                Ok(Some(sub_command))
+            }
+            _ => Err(Error::SubCommandNotFound(
+                name.to_lowercase(),
+                self.name.to_lowercase(),
+            )),
+        }
+    }
+
+    /// Returns a sub-command by its name
+    pub fn get_sub_command(&self, name: &str) -> Option<&SubCommand> {
+        self.sub_commands
+            .iter()
+            .find(|sub_command| sub_command.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns all the sub-commands
+    pub fn sub_commands(&self) -> &[SubCommand] {
+        &self.sub_commands
+    }
+
+    /// Returns the ACL categories of the command
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        acl_categories(self.group, self.flags)
+    }
+
+    /// Returns the ACL categories of a sub-command. Sub-commands have their
+    /// own flags but they belong to the same group as their command.
+    pub fn sub_command_acl_categories(&self, sub_command: &SubCommand) -> Vec<&'static str> {
+        acl_categories(self.group, sub_command.flags)
+    }
+
+    /// Returns the documentation of this command, following the output of
+    /// the COMMAND DOCS command in redis
+    pub fn get_command_docs(&self) -> Value {
+        let mut docs = vec![
+            "group".into(),
+            self.group.into(),
+            "arity".into(),
+            self.min_args.into(),
+            "flags".into(),
+            Value::Array(self.flags.iter().map(|m| m.to_string().into()).collect()),
+            "acl_categories".into(),
+            Value::Array(
+                self.acl_categories()
+                    .into_iter()
+                    .map(|category| category.into())
+                    .collect(),
+            ),
+        ];
+
+        if let Some((since, replaced_by)) = deprecation(self.name) {
+            docs.extend([
+                "doc_flags".into(),
+                Value::Array(vec!["deprecated".into()]),
+                "deprecated_since".into(),
+                since.into(),
+                "replaced_by".into(),
+                replaced_by.into(),
+            ]);
+        }
+
+        if !self.sub_commands.is_empty() {
+            docs.push("subcommands".into());
+            docs.push(Value::Array(
+                self.sub_commands
+                    .iter()
+                    .flat_map(|sub_command| {
+                        vec![
+                            format!("{}|{}", self.name, sub_command.name)
+                                .to_lowercase()
+                                .into(),
+                            Value::Array(vec![
+                                "group".into(),
+                                self.group.into(),
+                                "arity".into(),
+                                sub_command.min_args.into(),
+                                "flags".into(),
+                                Value::Array(
+                                    sub_command
+                                        .flags
+                                        .iter()
+                                        .map(|m| m.to_string().into())
+                                        .collect(),
+                                ),
+                                "acl_categories".into(),
+                                Value::Array(
+                                    self.sub_command_acl_categories(sub_command)
+                                        .into_iter()
+                                        .map(|category| category.into())
+                                        .collect(),
+                                ),
+                            ]),
+                        ]
+                    })
+                    .collect(),
+            ));
+        }
+
+        Value::Array(docs)
+    }
+
     /// Returns information about this command. The response is encoded as a
     /// Value, following the output of the COMMAND command in redis
     pub fn get_command_info(&self) -> Value {
@@ -197,6 +626,12 @@ impl Command {
         self.flags.to_vec()
     }
 
+    /// Checks if the command has a given flag
+    #[inline]
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
     /// Returns the minimum arguments (including the command name itself) that
     /// this command takes. This is also known as the arity of a command.
     pub fn get_min_args(&self) -> i32 {
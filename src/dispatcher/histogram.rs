@@ -0,0 +1,162 @@
+//! # Latency histogram
+//!
+//! A lightweight HDR-style histogram, updated by the dispatcher on every command execution.
+//!
+//! Values are grouped by their most significant bits: each power of two range is split into 16
+//! linear sub-buckets, so any percentile is reported with a relative error below 1/16 while the
+//! whole `u64` range fits in less than a thousand buckets. Each bucket is an atomic counter, so
+//! recording a value never locks.
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Bits of precision of each power of two range
+const SUB_BUCKET_BITS: u32 = 4;
+
+/// Number of linear sub-buckets of each power of two range
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+
+/// Number of buckets needed to cover the whole `u64` range
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Latency histogram, in microseconds
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("samples", &self.samples())
+            .finish()
+    }
+}
+
+impl Histogram {
+    /// Records the latency of an execution
+    pub fn record(&self, latency: Duration) {
+        let value = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of recorded values
+    pub fn samples(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the latency, in microseconds, below which `percentile` (from 0 to 100) of the
+    /// recorded values are. The highest value of the bucket is returned, so the reported latency
+    /// is never lower than the real one. An empty histogram returns 0.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let samples: u64 = counts.iter().sum();
+        if samples == 0 {
+            return 0;
+        }
+
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * samples as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_highest_value(index);
+            }
+        }
+        bucket_highest_value(BUCKETS - 1)
+    }
+
+    /// Forgets all the recorded values
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Serialize for Histogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(4))?;
+        map.serialize_entry("samples", &self.samples())?;
+        map.serialize_entry("p50", &self.percentile(50.0))?;
+        map.serialize_entry("p99", &self.percentile(99.0))?;
+        map.serialize_entry("p999", &self.percentile(99.9))?;
+        map.end()
+    }
+}
+
+/// Returns the bucket of a value
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    (shift as usize + 1) * SUB_BUCKETS + (value >> shift) as usize - SUB_BUCKETS
+}
+
+/// Returns the highest value stored in a bucket
+fn bucket_highest_value(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let lowest = ((SUB_BUCKETS + index % SUB_BUCKETS) as u64) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for value in [0, 1, 15, 16, 17, 31, 32, 33, 1000, 123_456_789, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < BUCKETS);
+            assert!(value <= bucket_highest_value(index), "{}", value);
+            if index > 0 {
+                assert!(value > bucket_highest_value(index - 1), "{}", value);
+            }
+        }
+        assert_eq!(u64::MAX, bucket_highest_value(BUCKETS - 1));
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::default();
+        assert_eq!(0, histogram.percentile(50.0));
+
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(1000, histogram.samples());
+
+        let p50 = histogram.percentile(50.0);
+        assert!((500..=500 + 500 / 16).contains(&p50), "{}", p50);
+        let p99 = histogram.percentile(99.0);
+        assert!((990..=990 + 990 / 16).contains(&p99), "{}", p99);
+        assert!(histogram.percentile(99.9) >= 999);
+
+        histogram.reset();
+        assert_eq!(0, histogram.samples());
+        assert_eq!(0, histogram.percentile(99.0));
+    }
+}
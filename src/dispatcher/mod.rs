@@ -13,12 +13,14 @@ use crate::{
     value::Value,
 };
 use bytes::Bytes;
-use command::Flag;
+use command::{Flag, KeySpec, ReplyType};
 
 pub mod command;
+pub mod histogram;
 
 // Returns the server time
 dispatcher! {
+    #[cfg(feature = "sets")]
     set {
         SADD {
             cmd::set::sadd,
@@ -28,6 +30,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
         SCARD {
             cmd::set::scard,
@@ -46,6 +49,7 @@ dispatcher! {
             -1,
             1,
             true,
+            reply: ReplyType::Set,
         },
         SDIFFSTORE {
             cmd::set::sdiffstore,
@@ -55,6 +59,9 @@ dispatcher! {
             -1,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["OW", "update"])
+                .flags(&["RO", "access"]),
         },
         SINTER {
             cmd::set::sinter,
@@ -64,15 +71,17 @@ dispatcher! {
             -1,
             1,
             true,
+            reply: ReplyType::Set,
         },
         SINTERCARD {
             cmd::set::sintercard,
             [Flag::ReadOnly],
-            -2,
+            -3,
             1,
             -1,
             1,
             true,
+            keys: KeySpec::numkeys(1),
         },
         SINTERSTORE {
             cmd::set::sinterstore,
@@ -82,6 +91,9 @@ dispatcher! {
             -1,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["OW", "update"])
+                .flags(&["RO", "access"]),
         },
         SISMEMBER {
             cmd::set::sismember,
@@ -100,6 +112,7 @@ dispatcher! {
             1,
             1,
             true,
+            reply: ReplyType::Set,
         },
         SMISMEMBER {
             cmd::set::smismember,
@@ -118,6 +131,9 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["RW", "insert"]),
         },
         SPOP {
             cmd::set::spop,
@@ -127,6 +143,8 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
+            reply: ReplyType::Set,
         },
         SRANDMEMBER {
             cmd::set::srandmember,
@@ -154,6 +172,7 @@ dispatcher! {
             -1,
             1,
             true,
+            reply: ReplyType::Set,
         },
         SUNIONSTORE {
             cmd::set::sunionstore,
@@ -163,6 +182,9 @@ dispatcher! {
             -1,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["OW", "update"])
+                .flags(&["RO", "access"]),
         },
     },
     metrics {
@@ -176,6 +198,7 @@ dispatcher! {
             false,
         },
     },
+    #[cfg(feature = "lists")]
     list {
         BLPOP {
             cmd::list::blpop,
@@ -185,6 +208,7 @@ dispatcher! {
             -2,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
         },
         BRPOP {
             cmd::list::brpop,
@@ -194,6 +218,7 @@ dispatcher! {
             -2,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
         },
         BRPOPLPUSH {
             cmd::list::brpoplpush,
@@ -203,6 +228,9 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["RW", "insert"]),
         },
         BLMOVE {
             cmd::list::blmove,
@@ -212,6 +240,9 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["RW", "insert"]),
         },
         LINDEX {
             cmd::list::lindex,
@@ -230,6 +261,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
         LLEN {
             cmd::list::llen,
@@ -248,15 +280,19 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["RW", "insert"]),
         },
         LPOP {
             cmd::list::lpop,
             [Flag::Write Flag::DenyOom],
             -2,
             1,
-            -2,
+            1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
         },
         LPOS {
             cmd::list::lpos,
@@ -275,6 +311,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
         LPUSHX {
             cmd::list::lpushx,
@@ -284,6 +321,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
         LRANGE {
             cmd::list::lrange,
@@ -329,6 +367,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
         },
         RPOPLPUSH {
             cmd::list::rpoplpush,
@@ -338,6 +377,9 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["RW", "insert"]),
         },
         RPUSH {
             cmd::list::rpush,
@@ -347,6 +389,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
         RPUSHX {
             cmd::list::rpushx,
@@ -356,8 +399,10 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "insert"]),
         },
     },
+    #[cfg(feature = "hashes")]
     hash {
         HDEL {
             cmd::hash::hdel,
@@ -394,6 +439,7 @@ dispatcher! {
             1,
             1,
             true,
+            reply: ReplyType::Map,
         },
         HINCRBY {
             cmd::hash::hincrby_int,
@@ -443,7 +489,7 @@ dispatcher! {
         HMSET {
             cmd::hash::hmset,
             [Flag::Write Flag::DenyOom Flag::Fast],
-            -3,
+            -4,
             1,
             1,
             1,
@@ -504,6 +550,9 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RO", "access"])
+                .flags(&["OW", "update"]),
         },
         DEL {
             cmd::key::del,
@@ -513,6 +562,7 @@ dispatcher! {
             -1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RM", "delete"]),
         },
         EXISTS {
             cmd::key::exists,
@@ -535,7 +585,7 @@ dispatcher! {
         EXPIREAT {
             cmd::key::expire_at,
             [Flag::Write Flag::Fast],
-            3,
+            -3,
             1,
             1,
             1,
@@ -543,7 +593,7 @@ dispatcher! {
         },
         EXPIRETIME {
             cmd::key::expire_time,
-            [Flag::Write Flag::Fast],
+            [Flag::ReadOnly Flag::Fast],
             2,
             1,
             1,
@@ -576,6 +626,11 @@ dispatcher! {
             2,
             1,
             true,
+            {
+                "ENCODING" { [Flag::ReadOnly Flag::Random], 3 },
+                "HELP" { [Flag::ReadOnly Flag::Loading Flag::Stale], 2 },
+                "REFCOUNT" { [Flag::ReadOnly Flag::Random], 3 },
+            },
         },
         PERSIST {
             cmd::key::persist,
@@ -589,7 +644,7 @@ dispatcher! {
         PEXPIRE {
             cmd::key::pexpire,
             [Flag::Write Flag::Fast],
-            3,
+            -3,
             1,
             1,
             1,
@@ -598,7 +653,7 @@ dispatcher! {
         PEXPIREAT {
             cmd::key::pexpire_at,
             [Flag::Write Flag::Fast],
-            3,
+            -3,
             1,
             1,
             1,
@@ -606,7 +661,7 @@ dispatcher! {
         },
         PEXPIRETIME {
             cmd::key::p_expire_time,
-            [Flag::Write Flag::Fast],
+            [Flag::ReadOnly Flag::Fast],
             2,
             1,
             1,
@@ -639,15 +694,21 @@ dispatcher! {
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["OW", "update"]),
         },
         RENAMENX {
             cmd::key::renamenx,
-            [Flag::Write Flag::Write],
+            [Flag::Write Flag::Fast],
             3,
             1,
             2,
             1,
             true,
+            keys: KeySpec::range()
+                .first_key_flags(&["RW", "access", "delete"])
+                .flags(&["OW", "insert"]),
         },
         SCAN {
             cmd::key::scan,
@@ -684,8 +745,10 @@ dispatcher! {
             -1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RM", "delete"]),
         },
     },
+    #[cfg(feature = "strings")]
     string {
         APPEND {
             cmd::string::append,
@@ -749,6 +812,7 @@ dispatcher! {
             1,
             1,
             true,
+            keys: KeySpec::range().flags(&["RW", "access", "delete"]),
         },
         GETSET {
             cmd::string::getset,
@@ -786,6 +850,16 @@ dispatcher! {
             1,
             true,
         },
+        LCS {
+            cmd::string::lcs,
+            [Flag::ReadOnly],
+            -3,
+            1,
+            2,
+            1,
+            true,
+            reply: ReplyType::MapWithOption("IDX"),
+        },
         MGET {
             cmd::string::mget,
             [Flag::ReadOnly Flag::Fast],
@@ -801,7 +875,7 @@ dispatcher! {
             -2,
             1,
             -1,
-            1,
+            2,
             true,
         },
         MSETNX {
@@ -810,7 +884,7 @@ dispatcher! {
             -2,
             1,
             -1,
-            1,
+            2,
             true,
         },
         SET {
@@ -861,7 +935,7 @@ dispatcher! {
         SUBSTR {
             cmd::string::getrange,
             [Flag::ReadOnly],
-            2,
+            4,
             1,
             1,
             1,
@@ -869,7 +943,7 @@ dispatcher! {
         },
         SETRANGE {
             cmd::string::setrange,
-            [Flag::Write],
+            [Flag::Write Flag::DenyOom],
             4,
             1,
             1,
@@ -886,6 +960,17 @@ dispatcher! {
             0,
             0,
             true,
+            {
+                "ID" { [Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+                "INFO" { [Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+                "GETNAME" { [Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+                "KILL" { [Flag::Admin Flag::NoScript Flag::Loading Flag::Stale], -3 },
+                "LIST" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+                "PAUSE" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], -3 },
+                "SETNAME" { [Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+                "UNBLOCK" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], -3 },
+                "UNPAUSE" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+            },
         },
         ECHO {
             cmd::client::echo,
@@ -921,9 +1006,10 @@ dispatcher! {
             0,
             0,
             0,
-            false,
+            true,
         }
     },
+    #[cfg(feature = "transactions")]
     transaction {
         DISCARD {
             cmd::transaction::discard,
@@ -971,6 +1057,7 @@ dispatcher! {
             true,
         },
     },
+    #[cfg(feature = "pubsub")]
     pubsub {
         PUBLISH {
             cmd::pubsub::publish,
@@ -981,6 +1068,19 @@ dispatcher! {
             0,
             true,
         },
+        FEDERATION {
+            cmd::pubsub::federation,
+            [Flag::PubSub Flag::Loading Flag::Stale],
+            -2,
+            0,
+            0,
+            0,
+            true,
+            {
+                "PEERS" { [Flag::PubSub Flag::Random Flag::Loading Flag::Stale], 2 },
+                "PUBLISH" { [Flag::PubSub Flag::Loading Flag::Stale Flag::Fast], 4 },
+            },
+        },
         PUBSUB {
             cmd::pubsub::pubsub,
             [Flag::PubSub Flag::Random Flag::Loading Flag::Stale],
@@ -989,6 +1089,12 @@ dispatcher! {
             0,
             0,
             true,
+            {
+                "CHANNELS" { [Flag::PubSub Flag::Random Flag::Loading Flag::Stale], -2 },
+                "HELP" { [Flag::Loading Flag::Stale], 2 },
+                "NUMPAT" { [Flag::PubSub Flag::Random Flag::Loading Flag::Stale], 2 },
+                "NUMSUB" { [Flag::PubSub Flag::Random Flag::Loading Flag::Stale], -2 },
+            },
         },
         PSUBSCRIBE {
             cmd::pubsub::psubscribe,
@@ -1036,6 +1142,14 @@ dispatcher! {
             0,
             0,
             true,
+            {
+                "COUNT" { [Flag::Random Flag::Loading Flag::Stale], 2 },
+                "DOCS" { [Flag::Random Flag::Loading Flag::Stale], -2, reply: ReplyType::Map },
+                "GETKEYS" { [Flag::Random Flag::Loading Flag::Stale], -3 },
+                "GETKEYSANDFLAGS" { [Flag::Random Flag::Loading Flag::Stale], -3 },
+                "HELP" { [Flag::Random Flag::Loading Flag::Stale], 2 },
+                "INFO" { [Flag::Random Flag::Loading Flag::Stale], -2 },
+            },
         },
         DBSIZE {
             cmd::server::dbsize,
@@ -1046,14 +1160,56 @@ dispatcher! {
             0,
             true,
         },
+        CONFIG {
+            cmd::server::config,
+            [Flag::Admin Flag::NoScript Flag::Loading Flag::Stale],
+            -2,
+            0,
+            0,
+            0,
+            true,
+            {
+                "RELOAD" { [Flag::Admin Flag::NoScript Flag::Loading Flag::Stale], 2 },
+            },
+        },
         DEBUG {
             cmd::server::debug,
-            [Flag::Random Flag::Loading Flag::Stale],
+            [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale],
+            -2,
+            0,
+            0,
+            0,
+            true,
+            {
+                "DELAY-REPLY" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+                "DIGEST-VALUE" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], -2 },
+                "LISTPACK" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+                "OBJECT" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+                "PANIC" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 2 },
+                "SET-ACTIVE-EXPIRE" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+                "SLEEP" { [Flag::Admin Flag::NoScript Flag::Random Flag::Loading Flag::Stale], 3 },
+            },
+        },
+        MEMORY {
+            cmd::server::memory,
+            [Flag::ReadOnly Flag::Random],
             -2,
             0,
             0,
             0,
             true,
+            {
+                "SAMPLE" { [Flag::ReadOnly Flag::Random], -2 },
+            },
+        },
+        HEALTHCHECK {
+            cmd::server::healthcheck,
+            [Flag::Random Flag::Loading Flag::Stale Flag::Fast],
+            1,
+            0,
+            0,
+            0,
+            true,
         },
         INFO {
             cmd::server::info,
@@ -1082,6 +1238,24 @@ dispatcher! {
             0,
             true,
         },
+        EXPORT {
+            cmd::server::export,
+            [Flag::Admin Flag::NoScript Flag::Random],
+            1,
+            0,
+            0,
+            0,
+            true,
+        },
+        IMPORT {
+            cmd::server::import,
+            [Flag::Admin Flag::Write Flag::DenyOom Flag::NoScript],
+            -2,
+            0,
+            0,
+            0,
+            true,
+        },
         TIME {
             cmd::server::time,
             [Flag::Random Flag::Loading Flag::Stale Flag::Fast],
@@ -1102,3 +1276,151 @@ dispatcher! {
         },
     }
 }
+
+/// What the dispatcher does with a command, depending on the status of the connection
+#[derive(Debug, PartialEq)]
+pub enum Disposition {
+    /// Execute the command
+    Execute,
+    /// Queue the command, it is executed by EXEC
+    Queue,
+    /// Reply QUEUED without queueing the command, the transaction is already aborted and EXEC
+    /// replies EXECABORT
+    Discard,
+    /// Reply with an error. A transaction being queued is aborted, if `abort_tx` is set.
+    Reject {
+        /// Error to reply
+        error: Error,
+        /// Whether the transaction being queued must be aborted
+        abort_tx: bool,
+    },
+}
+
+impl Disposition {
+    /// Decides what to do with a command. `command` is the error of an unknown command or one
+    /// which did not pass the pre-validation (arity, read-only, loading...), otherwise the
+    /// command.
+    ///
+    /// Any rejected command while a transaction is being queued aborts it, like Redis does.
+    pub fn new(status: ConnectionStatus, command: Result<&command::Command, Error>) -> Self {
+        match (status, command) {
+            (ConnectionStatus::Multi, Err(error)) => Self::Reject {
+                error,
+                abort_tx: true,
+            },
+            (_, Err(error)) => Self::Reject {
+                error,
+                abort_tx: false,
+            },
+            (ConnectionStatus::Multi, Ok(command)) if command.is_queueable() => Self::Queue,
+            (ConnectionStatus::FailedTx, Ok(command)) if command.is_queueable() => Self::Discard,
+            (ConnectionStatus::Pubsub, Ok(command)) if !command.is_pubsub_executable() => {
+                Self::Reject {
+                    error: Error::PubsubOnly(command.name().to_owned()),
+                    abort_tx: false,
+                }
+            }
+            _ => Self::Execute,
+        }
+    }
+
+    /// Replies the error of a rejected command, aborting the transaction if needed
+    fn reject(conn: &Connection, error: Error, abort_tx: bool) -> Result<Value, Error> {
+        if abort_tx {
+            conn.fail_transaction();
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dispositions() {
+        use ConnectionStatus::*;
+
+        let dispatcher = Dispatcher::new();
+        let command = |name: &str| dispatcher.get_handler_for_command(name);
+        let execute = || Disposition::Execute;
+        let pubsub_only = |name: &str| Disposition::Reject {
+            error: Error::PubsubOnly(name.to_owned()),
+            abort_tx: false,
+        };
+        let invalid = || Err(Error::InvalidArgsCount("GET".to_owned()));
+        let rejected = |abort_tx| Disposition::Reject {
+            error: Error::InvalidArgsCount("GET".to_owned()),
+            abort_tx,
+        };
+
+        let cases = vec![
+            // regular commands
+            (Normal, command("GET"), execute()),
+            (Multi, command("GET"), Disposition::Queue),
+            (FailedTx, command("GET"), Disposition::Discard),
+            (ExecutingTx, command("GET"), execute()),
+            (Pubsub, command("GET"), pubsub_only("GET")),
+            // transaction commands are never queued
+            (Normal, command("EXEC"), execute()),
+            (Multi, command("EXEC"), execute()),
+            (FailedTx, command("EXEC"), execute()),
+            (Multi, command("DISCARD"), execute()),
+            (FailedTx, command("DISCARD"), execute()),
+            (Multi, command("MULTI"), execute()),
+            (FailedTx, command("MULTI"), execute()),
+            // commands allowed in pubsub mode
+            (Pubsub, command("SUBSCRIBE"), execute()),
+            (Pubsub, command("PING"), execute()),
+            (Pubsub, command("EXEC"), pubsub_only("EXEC")),
+            // rejected commands only abort a transaction being queued
+            (Normal, invalid(), rejected(false)),
+            (Multi, invalid(), rejected(true)),
+            (FailedTx, invalid(), rejected(false)),
+            (ExecutingTx, invalid(), rejected(false)),
+            (Pubsub, invalid(), rejected(false)),
+        ];
+
+        for (status, command, expected) in cases.into_iter() {
+            assert_eq!(expected, Disposition::new(status, command), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let dispatcher = Dispatcher::new();
+        for name in ["get", "GET", "gEt"] {
+            assert_eq!(
+                "GET",
+                dispatcher.get_handler_for_command(name).unwrap().name()
+            );
+        }
+        for name in ["", "GETX", "GE", "G".repeat(100).as_str()] {
+            assert_eq!(
+                Err(Error::CommandNotFound(name.to_owned())),
+                dispatcher.get_handler_for_command(name).map(|c| c.name())
+            );
+        }
+
+        let mut buffer = [0; command::MAX_NAME_LEN];
+        assert_eq!(
+            Some("HINCRBYFLOAT"),
+            command::uppercase_name(b"hIncrByFloat", &mut buffer)
+        );
+        assert_eq!(None, command::uppercase_name(&[b'a', 0xff], &mut buffer));
+    }
+
+    #[test]
+    fn debug_is_admin() {
+        let dispatcher = Dispatcher::new();
+        let debug = dispatcher.get_handler_for_command("DEBUG").unwrap();
+        assert!(debug.has_flag(Flag::Admin));
+        for name in ["PANIC", "SLEEP", "DELAY-REPLY"] {
+            let sub_command = debug.get_sub_command(name).unwrap();
+            assert!(sub_command.has_flag(Flag::Admin), "{}", name);
+            assert!(debug
+                .sub_command_acl_categories(sub_command)
+                .contains(&"@dangerous"));
+        }
+    }
+}
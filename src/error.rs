@@ -11,8 +11,14 @@ pub enum Error {
     #[error("IO error {0}")]
     Io(String),
     /// Config
-    #[error("Config error {0}")]
+    #[error("Config error: {0}")]
     Config(#[from] redis_config_parser::de::Error),
+    /// The config cannot be reloaded
+    #[error("The server is running without a config file")]
+    NoConfigFile,
+    /// Invalid command line argument
+    #[error("invalid command line argument '{0}'")]
+    CliArgument(String),
     /// Empty line
     #[error("No command provided")]
     EmptyLine,
@@ -37,6 +43,24 @@ pub enum Error {
     /// Protocol error
     #[error("Protocol error: expected '{1}', got '{0}'")]
     Protocol(String, String),
+    /// Malformed request, the connection is closed after replying
+    #[error("Protocol error: {0}")]
+    InvalidRequest(String),
+    /// The snapshot to import is not valid
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    /// A command of the preload file failed, or could not be parsed
+    #[error("Cannot preload command #{0}: {1}")]
+    Preload(usize, String),
+    /// The reply would have more elements than allowed by max-reply-elements
+    #[error("the reply would have more than {0} elements (max-reply-elements)")]
+    ReplyTooLarge(usize),
+    /// A write command was rejected because a namespace is over its quota
+    #[error("command not allowed when the namespace '{0}' is over its quota")]
+    QuotaExceeded(String),
+    /// The unparsed input of a client is bigger than client-query-buffer-limit
+    #[error("max query buffer length reached")]
+    QueryBufferLimit,
     /// Unexpected argument
     #[error("Unknown subcommand or wrong number of arguments for '{1}'. Try {0} HELP.")]
     WrongArgument(String, String),
@@ -55,6 +79,15 @@ pub enum Error {
     /// String is bigger than max allowed size
     #[error("string exceeds maximum allowed size (proto-max-bulk-len)")]
     MaxAllowedSize,
+    /// DEBUG LISTPACK on a value that is not stored in a compact encoding
+    #[error("Not a listpack encoded object.")]
+    NotListpack,
+    /// The write would make the used memory go beyond maxmemory
+    #[error("command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+    /// The LCS table of two strings is too large
+    #[error("Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len")]
+    LcsTooLarge,
     /// Attempting to move or copy to the same key
     #[error("source and destination objects are the same")]
     SameEntry,
@@ -73,6 +106,18 @@ pub enum Error {
     /// Number overflow
     #[error("increment or decrement would overflow")]
     Overflow,
+    /// A count argument is negative
+    #[error("value is out of range, must be positive")]
+    NotPositive,
+    /// The numkeys argument is not a positive integer
+    #[error("numkeys should be greater than 0")]
+    NumKeysNotPositive,
+    /// The numkeys argument is bigger than the number of keys given
+    #[error("Number of keys can't be greater than number of args")]
+    NumKeysTooBig,
+    /// The LIMIT option is negative or not a number
+    #[error("LIMIT can't be negative")]
+    NegativeLimit,
     /// Unexpected negative number
     #[error("{0} is negative")]
     NegativeNumber(String),
@@ -88,6 +133,9 @@ pub enum Error {
     /// Transaction was aborted
     #[error("Transaction discarded because of previous errors.")]
     TxAborted,
+    /// Too many commands, or too many bytes, were queued in a transaction
+    #[error("Transaction exceeds the maximum allowed size ({0})")]
+    TxTooLarge(String),
     /// The requested database does not exists
     #[error("DB index is out of range")]
     NotSuchDatabase,
@@ -107,6 +155,9 @@ pub enum Error {
     /// through an error.
     #[error("client unblocked via CLIENT UNBLOCK")]
     UnblockByError,
+    /// CLIENT KILL did not find the client to kill
+    #[error("No such client")]
+    NoSuchClient,
     /// Options provided are not compatible
     #[error("{0} options at the same time are not compatible")]
     OptsNotCompatible(String),
@@ -116,6 +167,15 @@ pub enum Error {
     /// Client manual disconnection
     #[error("Manual disconnection")]
     Quit,
+    /// The server is in read-only mode and the command may modify the database
+    #[error("You can't write against a read only replica.")]
+    ReadOnly,
+    /// The server is loading the dataset and the command is not allowed meanwhile
+    #[error("Redis is loading the dataset in memory")]
+    Loading,
+    /// The link with the master is down and serving stale data is not allowed
+    #[error("Link with MASTER is down and replica-serve-stale-data is set to 'no'.")]
+    MasterDown,
 }
 
 impl From<std::io::Error> for Error {
@@ -132,6 +192,11 @@ impl From<Error> for Value {
             Error::NotInTx => "ERR EXEC",
             Error::TxAborted => "EXECABORT",
             Error::UnblockByError => "UNBLOCKED",
+            Error::ReadOnly => "READONLY",
+            Error::Loading => "LOADING",
+            Error::MasterDown => "MASTERDOWN",
+            Error::OutOfMemory => "OOM",
+            Error::QuotaExceeded(_) => "QUOTA",
             _ => "ERR",
         };
 
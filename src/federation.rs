@@ -0,0 +1,241 @@
+//! # Pubsub federation
+//!
+//! Shares pubsub among a small fleet of instances without cluster support. Every PUBLISH is
+//! forwarded to the peers listed in `federation-peers`, through a plain RESP connection to each
+//! one, as `FEDERATION PUBLISH <channel> <message>`. Peers deliver forwarded messages to their
+//! own subscribers only, so messages are never forwarded twice, even if peers list each other.
+//!
+//! Like pubsub itself, delivery is best effort: messages are queued while a peer is unreachable
+//! and the link is retried, but once the queue is full new messages for that peer are dropped.
+use crate::{protocol::RespCodec, value::Value};
+use bytes::Bytes;
+use futures::SinkExt;
+use log::{info, warn};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+use tokio_util::codec::FramedWrite;
+
+/// Number of messages queued for a peer before new messages are dropped
+const PEER_QUEUE_CAPACITY: usize = 10_000;
+
+/// Time to wait before connecting again to a peer
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// State of the link with a peer
+#[derive(Debug, Default)]
+pub struct PeerStatus {
+    connected: AtomicBool,
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl PeerStatus {
+    /// Whether the peer is connected
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages forwarded to the peer
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages dropped because the queue of the peer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// A peer of the federation
+#[derive(Debug, Clone)]
+pub struct Peer {
+    addr: String,
+    messages: mpsc::Sender<(Bytes, Bytes)>,
+    status: Arc<PeerStatus>,
+}
+
+impl Peer {
+    /// Address of the peer, as `host:port`
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// State of the link with the peer
+    pub fn status(&self) -> &PeerStatus {
+        &self.status
+    }
+}
+
+/// Handle to forward the published messages to the peers
+#[derive(Debug, Clone)]
+pub struct Federation {
+    peers: Vec<Peer>,
+}
+
+/// Link with a peer, to be run in the background with [`PeerLink::run`]
+#[derive(Debug)]
+pub struct PeerLink {
+    addr: String,
+    messages: mpsc::Receiver<(Bytes, Bytes)>,
+    status: Arc<PeerStatus>,
+}
+
+impl Federation {
+    /// Creates the federation of the given peers, returning the links with each one, which must
+    /// be run for the messages to be forwarded
+    pub fn new(addrs: &[String]) -> (Self, Vec<PeerLink>) {
+        let (peers, links) = addrs
+            .iter()
+            .map(|addr| {
+                let (sender, receiver) = mpsc::channel(PEER_QUEUE_CAPACITY);
+                let status = Arc::new(PeerStatus::default());
+                (
+                    Peer {
+                        addr: addr.clone(),
+                        messages: sender,
+                        status: status.clone(),
+                    },
+                    PeerLink {
+                        addr: addr.clone(),
+                        messages: receiver,
+                        status,
+                    },
+                )
+            })
+            .unzip();
+        (Self { peers }, links)
+    }
+
+    /// Queues a published message to be forwarded to every peer
+    pub fn forward(&self, channel: &Bytes, message: &Bytes) {
+        for peer in self.peers.iter() {
+            if peer
+                .messages
+                .try_send((channel.clone(), message.clone()))
+                .is_err()
+            {
+                peer.status.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the peers of the federation
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+}
+
+impl PeerLink {
+    /// Forwards the queued messages to the peer, connecting again whenever the connection is
+    /// lost, until every handle of the federation is dropped
+    pub async fn run(mut self) {
+        loop {
+            match TcpStream::connect(&self.addr).await {
+                Ok(stream) => {
+                    info!("Connected to the federation peer {}", self.addr);
+                    self.status.connected.store(true, Ordering::Relaxed);
+                    let finished = self.forward(stream).await;
+                    self.status.connected.store(false, Ordering::Relaxed);
+                    if finished {
+                        return;
+                    }
+                    warn!("Lost the connection to the federation peer {}", self.addr);
+                }
+                Err(e) => warn!("Cannot connect to the federation peer {}: {}", self.addr, e),
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Forwards messages through a connection, returning true once there is nothing left to
+    /// forward, or false if the connection was lost.
+    ///
+    /// The replies of the peer are read only to notice when the connection is closed, and they
+    /// are discarded without being parsed: the codec decodes requests, not replies.
+    async fn forward(&mut self, stream: TcpStream) -> bool {
+        let (mut replies, writer) = stream.into_split();
+        let mut transport = FramedWrite::new(writer, RespCodec::new());
+        let mut discarded = [0; 1024];
+        loop {
+            tokio::select! {
+                message = self.messages.recv() => {
+                    let (channel, message) = match message {
+                        Some(message) => message,
+                        None => return true,
+                    };
+                    let command = Value::Array(vec![
+                        "FEDERATION".into(),
+                        "PUBLISH".into(),
+                        Value::new(&channel),
+                        Value::new(&message),
+                    ]);
+                    if transport.send(command).await.is_err() {
+                        return false;
+                    }
+                    self.status.forwarded.fetch_add(1, Ordering::Relaxed);
+                }
+                read = replies.read(&mut discarded) => match read {
+                    Ok(0) | Err(_) => return false,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cmd::test::{create_connection, run_command};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn forward_published_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let c = create_connection();
+        let (federation, links) = Federation::new(&[addr]);
+        c.all_connections().set_federation(Some(federation));
+        for link in links {
+            tokio::spawn(link.run());
+        }
+
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            run_command(&c, &["publish", "foo", "bar"]).await
+        );
+        // forwarded messages are only delivered locally
+        let _ = run_command(&c, &["federation", "publish", "foo", "baz"]).await;
+        let _ = run_command(&c, &["publish", "foo", "qux"]).await;
+
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let expected = b"*4\r\n$10\r\nFEDERATION\r\n$7\r\nPUBLISH\r\n$3\r\nfoo\r\n$3\r\nbar\r\n\
+*4\r\n$10\r\nFEDERATION\r\n$7\r\nPUBLISH\r\n$3\r\nfoo\r\n$3\r\nqux\r\n";
+        let mut received = vec![0; expected.len()];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&expected[..], &received[..]);
+
+        // the peer replies, and the link keeps using the same connection
+        peer.write_all(b":0\r\n:0\r\n").await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        let _ = run_command(&c, &["publish", "foo", "end"]).await;
+        let expected = b"*4\r\n$10\r\nFEDERATION\r\n$7\r\nPUBLISH\r\n$3\r\nfoo\r\n$3\r\nend\r\n";
+        let mut received = vec![0; expected.len()];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&expected[..], &received[..]);
+
+        let federation = c.all_connections().federation().unwrap();
+        assert!(federation.peers()[0].status().is_connected());
+        assert_eq!(3, federation.peers()[0].status().forwarded());
+        assert_eq!(0, federation.peers()[0].status().dropped());
+    }
+}
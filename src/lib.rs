@@ -4,12 +4,20 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+pub mod audit;
 pub mod cmd;
 pub mod config;
 pub mod connection;
 pub mod db;
 pub mod dispatcher;
 pub mod error;
+pub mod federation;
 pub mod macros;
+pub mod preload;
+pub mod protocol;
+pub mod quota;
 pub mod server;
+pub mod supervisor;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod value;
@@ -11,20 +11,72 @@
 ///
 /// Using macros allow to generate pretty efficient code for run time and easy to extend at
 /// writting time.
+///
+/// Container commands may declare their sub-commands, after the `is_queueable` value, with their
+/// own flags and arity (which includes the command and the sub-command name):
+///
+/// ```ignore
+/// {
+///     "ID" { [Flag::NoScript Flag::Loading Flag::Stale], 2 },
+///     "SETNAME" { [Flag::NoScript Flag::Loading Flag::Stale], 3 },
+/// },
+/// ```
+///
+/// The dispatcher validates the sub-command and its arity, and the sub-command flags are used
+/// instead of the command flags.
+///
+/// Commands whose keys are not found by their first/last/step values, or that access their keys
+/// in a particular way, declare their key specification after the sub-commands. Commands and
+/// sub-commands replying with a set or a map in RESP3 declare their reply type last:
+///
+/// ```ignore
+/// keys: KeySpec::numkeys(1).flags(&["RW", "access", "delete"]),
+/// reply: ReplyType::Set,
+/// ```
+///
+/// Commands are grouped in namespaces. The attributes of a namespace are applied to all its
+/// commands, so a group of commands can be compiled out with a `#[cfg(feature = "...")]`.
 #[macro_export]
 macro_rules! dispatcher {
+    // The namespaces are flattened, one at a time, into a list of commands carrying the
+    // attributes of their namespace.
+    {
+        @flatten [$($commands:tt)*] $(,)?
+    } => {
+        $crate::dispatcher! { @commands $($commands)* }
+    };
     {
-        $($ns:ident {
-            $($command:ident {
-                $handler:expr,
-                [$($tag:expr)+],
-                $min_args:expr,
-                $key_start:expr,
-                $key_stop:expr,
-                $key_step:expr,
-                $is_queueable:expr,
-            }),+$(,)?
-        }),+$(,)?
+        @flatten [$($commands:tt)*]
+        $(#[$meta:meta])* $ns:ident { $($body:tt)* } $(, $($rest:tt)*)?
+    } => {
+        $crate::dispatcher! { @namespace [$($commands)*] [$(#[$meta])*] $ns { $($body)* } [$($($rest)*)?] }
+    };
+    {
+        @namespace [$($commands:tt)*] $attrs:tt $ns:ident {
+            $($command:ident { $($definition:tt)* }),+$(,)?
+        } [$($rest:tt)*]
+    } => {
+        $crate::dispatcher! { @flatten [$($commands)* $(($attrs $ns $command { $($definition)* }))+] $($rest)* }
+    };
+    {
+        @commands $(([$(#[$meta:meta])*] $ns:ident $command:ident {
+            $handler:expr,
+            [$($tag:expr)+],
+            $min_args:expr,
+            $key_start:expr,
+            $key_stop:expr,
+            $key_step:expr,
+            $is_queueable:expr,
+            $({
+                $($sub_command:literal {
+                    [$($sub_tag:expr)+],
+                    $sub_min_args:expr
+                    $(, reply: $sub_reply:expr)?$(,)?
+                }),+$(,)?
+            },)?
+            $(keys: $keys:expr,)?
+            $(reply: $reply:expr,)?
+        }))+
     }=>  {
         use futures::future::FutureExt;
 
@@ -32,9 +84,10 @@ macro_rules! dispatcher {
         #[derive(serde::Serialize)]
         #[allow(non_snake_case, non_camel_case_types)]
         pub struct ServiceMetricRegistry<'a> {
-            $($(
+            $(
+            $(#[$meta])*
             $command: &'a command::Metrics,
-            )+)+
+            )+
         }
 
         /// Dispatcher struct
@@ -43,9 +96,10 @@ macro_rules! dispatcher {
         #[allow(non_snake_case, non_camel_case_types)]
         #[derive(Debug)]
         pub struct Dispatcher {
-            $($(
+            $(
+                $(#[$meta])*
                 $command: command::Command,
-            )+)+
+            )+
         }
 
         impl Default for Dispatcher {
@@ -58,7 +112,8 @@ macro_rules! dispatcher {
             /// Creates a new dispatcher.
             pub fn new() -> Self {
                 Self {
-                    $($(
+                    $(
+                        $(#[$meta])*
                         $command: command::Command::new(
                             stringify!($command),
                             stringify!($ns),
@@ -68,37 +123,58 @@ macro_rules! dispatcher {
                             $key_stop,
                             $key_step,
                             $is_queueable,
+                            vec![$($(
+                                command::SubCommand::new(
+                                    $sub_command,
+                                    &[$($sub_tag,)+],
+                                    $sub_min_args,
+                                    $crate::dispatcher!(@or [$($sub_reply)?] command::ReplyType::Default),
+                                ),
+                            )+)?],
+                            $crate::dispatcher!(@or [$($keys)?] command::KeySpec::default()),
+                            $crate::dispatcher!(@or [$($reply)?] command::ReplyType::Default),
                         ),
-                    )+)+
+                    )+
                 }
             }
 
             /// Returns all metrics objects
-            pub fn get_service_metric_registry(&self) -> ServiceMetricRegistry {
+            pub fn get_service_metric_registry(&self) -> ServiceMetricRegistry<'_> {
                 ServiceMetricRegistry {
-                    $($(
+                    $(
+                        $(#[$meta])*
                         $command: self.$command.metrics(),
-                    )+)+
+                    )+
                 }
             }
 
             /// Returns the handlers for defined commands.
             pub fn get_all_commands(&self) -> Vec<&command::Command> {
-                vec![
-                $($(
-                    &self.$command,
-                )+)+
-                ]
+                let mut commands = vec![];
+                $(
+                    $(#[$meta])*
+                    commands.push(&self.$command);
+                )+
+                commands
             }
 
             /// Returns a command handler for a given command
             #[inline(always)]
             pub fn get_handler_for_command(&self, command: &str) -> Result<&command::Command, Error> {
-                match command.to_uppercase().as_str() {
-                $($(
-                    stringify!($command) => Ok(&self.$command),
-                )+)+
-                    _ => Err(Error::CommandNotFound(command.into())),
+                self.lookup(command.as_bytes())
+                    .ok_or_else(|| Error::CommandNotFound(command.into()))
+            }
+
+            /// Looks up a command by its name, ASCII case-insensitively and without allocating
+            #[inline(always)]
+            fn lookup(&self, name: &[u8]) -> Option<&command::Command> {
+                let mut buffer = [0; command::MAX_NAME_LEN];
+                match command::uppercase_name(name, &mut buffer)? {
+                $(
+                    $(#[$meta])*
+                    stringify!($command) => Some(&self.$command),
+                )+
+                    _ => None,
                 }
             }
 
@@ -109,8 +185,9 @@ macro_rules! dispatcher {
             /// has fewer logic when reading the provided arguments.
             #[inline(always)]
             pub fn get_handler(&self, args: &::std::collections::VecDeque<Bytes>) -> Result<&command::Command, Error> {
-                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
-                let command = self.get_handler_for_command(&command)?;
+                let command = self.lookup(&args[0]).ok_or_else(|| {
+                    Error::CommandNotFound(String::from_utf8_lossy(&args[0]).to_uppercase())
+                })?;
                 if ! command.check_number_args(args.len()) {
                     Err(Error::InvalidArgsCount(command.name().into()))
                 } else {
@@ -126,62 +203,132 @@ macro_rules! dispatcher {
             #[inline(always)]
             pub fn execute<'a>(&'a self, conn: &'a Connection, mut args: std::collections::VecDeque<Bytes>) -> futures::future::BoxFuture<'a, Result<Value, Error>> {
                 async move {
-                    let command = args.pop_front()
-                        .map(|s| String::from_utf8_lossy(&s).to_uppercase())
-                        .ok_or(Error::EmptyLine)?;
-                    match command.as_str() {
-                        $($(
+                    let name = args.pop_front().ok_or(Error::EmptyLine)?;
+                    let mut buffer = [0; command::MAX_NAME_LEN];
+                    match command::uppercase_name(&name, &mut buffer).unwrap_or_default() {
+                        $(
+                            $(#[$meta])*
                             stringify!($command) => {
                                 //log::info!("Command: {} -> {:?}", stringify!($command), args);
                                 let command = &self.$command;
-                                    let status = conn.status();
-                                if ! command.check_number_args(args.len()+1) {
-                                    if status == ConnectionStatus::Multi {
-                                        conn.fail_transaction();
+                                let check = command.check_args(&args).and_then(|sub_command| {
+                                    let reply_type = command.reply_type(sub_command, &args);
+                                    let has_flag = |flag| match sub_command {
+                                        Some(sub_command) => sub_command.has_flag(flag),
+                                        None => command.has_flag(flag),
+                                    };
+                                    let is_write = has_flag(command::Flag::Write);
+                                    if is_write && conn.all_connections().is_read_only() {
+                                        Err(Error::ReadOnly)
+                                    } else if ! has_flag(command::Flag::Loading) && conn.all_connections().is_loading() {
+                                        Err(Error::Loading)
+                                    } else if ! has_flag(command::Flag::Stale) && conn.all_connections().is_stale() {
+                                        Err(Error::MasterDown)
+                                    } else if has_flag(command::Flag::DenyOom) {
+                                        conn.all_connections().check_maxmemory().map(|_| (is_write, true, reply_type))
+                                    } else {
+                                        Ok((is_write, $crate::quota::KEY_MOVING_COMMANDS.contains(&command.name()), reply_type))
                                     }
-                                    Err(Error::InvalidArgsCount(command.name().into()))
-                                } else {
-                                    let metrics = command.metrics();
-                                    let hit_count = &metrics.hit_count;
-                                    let error_count = &metrics.error_count;
-                                    let in_flight = &metrics.in_flight;
-                                    let response_time = &metrics.response_time;
-                                    let throughput = &metrics.throughput;
-
-                                    if status == ConnectionStatus::Multi && command.is_queueable() {
+                                });
+                                let (is_write, check_quotas, reply_type) = check
+                                    .as_ref()
+                                    .map_or((false, false, command::ReplyType::Default), |check| *check);
+                                match Disposition::new(conn.status(), check.map(|_| command)) {
+                                    Disposition::Reject { error, abort_tx } => {
+                                        return Disposition::reject(conn, error, abort_tx);
+                                    }
+                                    Disposition::Queue => {
                                         args.push_front(command.name().into());
                                         conn.tx_keys(command.get_keys(&args, true));
-                                        conn.queue_command(args);
-                                        return Ok(Value::Queued);
-                                    } else if status == ConnectionStatus::FailedTx && command.is_queueable() {
-                                        return Ok(Value::Queued);
-                                    } else if status == ConnectionStatus::Pubsub && ! command.is_pubsub_executable() {
-                                        return Err(Error::PubsubOnly(stringify!($command).to_owned()));
+                                        return conn.queue_command(args).map(|_| Value::Queued);
+                                    }
+                                    Disposition::Discard => return Ok(Value::Queued),
+                                    Disposition::Execute => {}
+                                }
+
+                                // Quotas are checked when the command runs, not when it is queued,
+                                // as the usage of the namespaces may change in between
+                                if check_quotas {
+                                    if let Some(quotas) = conn.all_connections().quotas() {
+                                        quotas.check(&conn.db(), &command.get_keys(&args, false))?;
                                     }
+                                }
 
-                                    metered::measure!(hit_count, {
-                                        metered::measure!(response_time, {
-                                            metered::measure!(throughput, {
-                                                metered::measure!(in_flight, {
-                                                    metered::measure!(error_count, $handler(conn, args).await)
+                                let metrics = command.metrics();
+                                let hit_count = &metrics.hit_count;
+                                let error_count = &metrics.error_count;
+                                let in_flight = &metrics.in_flight;
+                                let response_time = &metrics.response_time;
+                                let throughput = &metrics.throughput;
+
+                                if is_write {
+                                    if let Some(audit_log) = conn.all_connections().audit_log() {
+                                        audit_log.record(conn, command.name(), &args);
+                                    }
+                                }
+
+                                let started_at = std::time::Instant::now();
+                                let result = metered::measure!(hit_count, {
+                                    metered::measure!(response_time, {
+                                        metered::measure!(throughput, {
+                                            metered::measure!(in_flight, {
+                                                metered::measure!(error_count, {
+                                                    // A bug in a handler must not take down the whole server
+                                                    ::std::panic::AssertUnwindSafe($handler(conn, args))
+                                                        .catch_unwind()
+                                                        .await
+                                                        .unwrap_or_else(|panic| {
+                                                            log::error!(
+                                                                "Command {} panicked: {}",
+                                                                stringify!($command),
+                                                                $crate::supervisor::panic_message(&*panic)
+                                                            );
+                                                            Err(Error::Internal)
+                                                        })
                                                 })
                                             })
                                         })
                                     })
-                                }
-                            }
-                        )+)+,
-                        _ => {
-                            if conn.status() == ConnectionStatus::Multi {
-                                conn.fail_transaction();
+                                });
+                                metrics.latency.record(started_at.elapsed());
+                                result.map(|reply| {
+                                    debug_assert!(
+                                        !reply.is_err(),
+                                        "Command {} replied an error as a value, errors must be returned as Err",
+                                        stringify!($command)
+                                    );
+                                    reply_type.format(reply, conn.protocol())
+                                })
                             }
-                            Err(Error::CommandNotFound(command.into()))
+                        )+
+                        _ => match Disposition::new(
+                            conn.status(),
+                            Err(Error::CommandNotFound(String::from_utf8_lossy(&name).to_uppercase())),
+                        ) {
+                            Disposition::Reject { error, abort_tx } => Disposition::reject(conn, error, abort_tx),
+                            _ => unreachable!("unknown commands are always rejected"),
                         },
                     }
                 }.boxed()
             }
         }
-    }
+    };
+    // Optional values of a command definition
+    {
+        @or [] $default:expr
+    } => {
+        $default
+    };
+    {
+        @or [$value:expr] $default:expr
+    } => {
+        $value
+    };
+    {
+        $($namespaces:tt)+
+    } => {
+        $crate::dispatcher! { @flatten [] $($namespaces)+ }
+    };
 }
 
 /// Generate code for From/Into a type to a Value
@@ -211,6 +358,7 @@ macro_rules! value_vec_try_from {
 }
 
 /// Check if a given command argument in a position $pos is eq to a $command
+#[deprecated(note = "use `cmd::is_option`, which does not allocate to compare the argument")]
 #[macro_export]
 macro_rules! check_arg {
     {$args: tt, $pos: tt, $command: tt} => {{
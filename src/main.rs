@@ -1,20 +1,57 @@
 use flexi_logger::{FileSpec, Logger};
 use microredis::{
-    config::{parse, Config},
+    config::{check_file, ConfigSource},
     error::Error,
     server,
 };
-use std::env;
+use std::{env, process};
+
+/// Validates a config file without starting the server. The process exits with a non-zero status
+/// if any error is found.
+async fn check_config(path: Option<String>) -> Result<(), Error> {
+    let path = if let Some(path) = path {
+        path
+    } else {
+        eprintln!("Usage: microredis --check-config <file>");
+        process::exit(1);
+    };
+    let report = check_file(path.clone()).await?;
+
+    for warning in report.warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+    for error in report.errors.iter() {
+        eprintln!("error: {}", error);
+    }
+
+    if report.is_ok() {
+        println!("{}: configuration is valid", path);
+        Ok(())
+    } else {
+        process::exit(1);
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let config = if let Some(path) = env::args().nth(1) {
-        parse(path).await?
-    } else {
-        Config::default()
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(|arg| arg == "--check-config") == Some(true) {
+        return check_config(args.nth(1)).await;
+    }
+    let source = ConfigSource {
+        path: args.next_if(|arg| !arg.starts_with("--")),
+        overrides: args.collect(),
+    };
+    let config = match source.load().await {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
     };
 
-    let logger = Logger::try_with_str(config.log.level.to_string()).unwrap();
+    // The log level is set by the server, since it can be changed by reloading the config
+    let logger = Logger::try_with_str("trace").unwrap();
 
     if let Some(log_path) = config.log.file.as_ref() {
         if log_path.is_empty() {
@@ -29,7 +66,5 @@ async fn main() -> Result<(), Error> {
         logger.log_to_stdout().start().unwrap();
     }
 
-    log::info!("PID: {}", std::process::id());
-
-    server::serve(config).await
+    server::serve(config, source).await
 }
@@ -0,0 +1,118 @@
+//! # Preloading
+//!
+//! Replays a file of commands at startup, before any connection is accepted, to seed test
+//! fixtures and reproducible local environments (`microredis --preload fixtures.resp`).
+//!
+//! The file holds commands as a client would send them: RESP encoded (like the input of
+//! `redis-cli --pipe`, or an AOF file), inline commands one per line, or a mix of both.
+//! Commands run through the dispatcher on a loopback connection, so they behave exactly as if a
+//! client sent them, and `SELECT` changes the database of the following commands. Replies are
+//! discarded, but the first failing command stops the startup.
+use crate::{connection::connections::Connections, db::Db, error::Error, protocol::RespCodec};
+use bytes::BytesMut;
+use std::sync::Arc;
+use tokio_util::codec::Decoder;
+
+/// Address the loopback connection reports in CLIENT LIST
+const PRELOAD_ADDR: &str = "preload";
+
+/// Runs every command of the file, returning the number of commands executed. Empty lines are
+/// skipped, and errors report which command failed, counting from 1.
+pub async fn preload(
+    path: &str,
+    all_connections: &Arc<Connections>,
+    default_db: Arc<Db>,
+) -> Result<usize, Error> {
+    let mut buf = BytesMut::from(&tokio::fs::read(path).await?[..]);
+    let mut codec = RespCodec::new();
+    let (_, conn) = all_connections.new_connection(default_db, PRELOAD_ADDR);
+    let dispatcher = all_connections.get_dispatcher();
+    let mut executed = 0;
+
+    let result = loop {
+        let args = match codec.decode_eof(&mut buf) {
+            Ok(Some(args)) => args,
+            Ok(None) => break Ok(executed),
+            Err(err) => break Err(Error::Preload(executed + 1, err.to_string())),
+        };
+        match dispatcher.execute(&conn, args).await {
+            Ok(_) => executed += 1,
+            Err(Error::EmptyLine) => {}
+            Err(err) => break Err(Error::Preload(executed + 1, err.to_string())),
+        }
+    };
+
+    conn.destroy();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cmd::test::{create_connection, run_command},
+        value::Value,
+    };
+    use std::{env, fs};
+
+    /// Writes a file to preload, returning its path
+    fn write_file(name: &str, content: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("microredis-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn preload_commands() {
+        let path = write_file(
+            "preload.resp",
+            b"*3\r\n$3\r\nset\r\n$3\r\nfoo\r\n$3\r\nbar\r\nrpush list a b c\r\n\r\nselect 1\r\nset foo one\r\n",
+        );
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        let db = all_connections.get_databases().get(0).unwrap();
+
+        assert_eq!(Ok(4), preload(&path, &all_connections, db).await);
+        assert_eq!(
+            Ok(Value::from("bar")),
+            run_command(&c, &["get", "foo"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(3)),
+            run_command(&c, &["llen", "list"]).await
+        );
+        let _ = run_command(&c, &["select", "1"]).await;
+        assert_eq!(
+            Ok(Value::from("one")),
+            run_command(&c, &["get", "foo"]).await
+        );
+        // the loopback connection is gone
+        assert_eq!(1, all_connections.total_connections());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn preload_errors() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        let db = all_connections.get_databases().get(0).unwrap();
+
+        let path = write_file("errors.resp", b"set foo bar\r\nincr foo\r\nset bar foo\r\n");
+        assert_eq!(
+            Err(Error::Preload(
+                2,
+                "value is not an integer or out of range".to_owned()
+            )),
+            preload(&path, &all_connections, db.clone()).await
+        );
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "bar"]).await);
+        fs::remove_file(path).unwrap();
+
+        let path = write_file("partial.resp", b"set foo bar\r\n*3\r\n$3\r\nset\r\n");
+        assert!(matches!(
+            preload(&path, &all_connections, db).await,
+            Err(Error::Preload(2, _))
+        ));
+        fs::remove_file(path).unwrap();
+    }
+}
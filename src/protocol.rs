@@ -0,0 +1,237 @@
+//! # RESP codec
+//!
+//! Streaming codec of the Redis protocol, to be used with `tokio_util::codec::Framed`. Requests,
+//! either multibulk or inline, are decoded into their arguments, and values are encoded as
+//! replies.
+//!
+//! Malformed requests are checked the same way Redis does, so they are rejected instead of being
+//! buffered forever.
+use crate::{error::Error, value::Value};
+use bytes::{Buf, Bytes, BytesMut};
+use redis_zero_protocol_parser::{parse_server, Error as RedisError};
+use std::{collections::VecDeque, io};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Maximum size of an inline request, and of the header lines of a multibulk request
+pub const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+/// Maximum number of arguments of a multibulk request
+pub const PROTO_MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+/// Maximum size of each argument of a multibulk request
+pub const PROTO_MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// RESP Encoder/Decoder
+///
+/// The codec also counts the bytes read and written, so they can be accounted by the server.
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    input_bytes: u64,
+    output_bytes: u64,
+}
+
+impl RespCodec {
+    /// Creates a new codec
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the bytes read and written since the last call
+    pub fn take_net_bytes(&mut self) -> (u64, u64) {
+        (
+            std::mem::take(&mut self.input_bytes),
+            std::mem::take(&mut self.output_bytes),
+        )
+    }
+}
+
+impl Encoder<Value> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: Value, dst: &mut BytesMut) -> io::Result<()> {
+        let v: Vec<u8> = response.into();
+        self.output_bytes += v.len() as u64;
+        dst.extend_from_slice(&v);
+        Ok(())
+    }
+}
+
+/// Raw bytes, already serialized. It is used to write the header of chunked replies.
+impl Encoder<Bytes> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, raw: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.output_bytes += raw.len() as u64;
+        dst.extend_from_slice(&raw);
+        Ok(())
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = VecDeque<Bytes>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        let (frame, proccesed) = {
+            let (unused, val) = match parse_server(src) {
+                Ok((buf, val)) => (buf, val),
+                Err(RedisError::Partial) => {
+                    check_request(src)?;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    log::debug!("{:?}", e);
+                    check_request(src)?;
+
+                    return Err(match e {
+                        RedisError::Protocol(expected, got) if expected.is_ascii_punctuation() => {
+                            Error::Protocol(
+                                (got as char).to_string(),
+                                (expected as char).to_string(),
+                            )
+                        }
+                        _ => Error::InvalidRequest("invalid request".to_owned()),
+                    });
+                }
+            };
+            (
+                val.iter().map(|e| Bytes::copy_from_slice(e)).collect(),
+                src.len() - unused.len(),
+            )
+        };
+
+        src.advance(proccesed);
+        self.input_bytes += proccesed as u64;
+
+        Ok(Some(frame))
+    }
+}
+
+/// Reads the number of a `*<number>\r\n` or `$<number>\r\n` header line, returning it along
+/// with the size of the line. None is returned if the line is not complete yet.
+fn read_header_line(
+    buf: &[u8],
+    too_big: &str,
+    invalid: &str,
+) -> Result<Option<(i64, usize)>, Error> {
+    let end = match buf.iter().position(|b| *b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > PROTO_INLINE_MAX_SIZE => {
+            return Err(Error::InvalidRequest(too_big.to_owned()))
+        }
+        None => return Ok(None),
+    };
+    let line = buf[1..end].strip_suffix(b"\r").unwrap_or(&buf[1..end]);
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|n| n.parse::<i64>().ok())
+        .map(|n| Some((n, end + 1)))
+        .ok_or_else(|| Error::InvalidRequest(invalid.to_owned()))
+}
+
+/// Checks the (maybe incomplete) request at the beginning of the buffer, the same way Redis
+/// does, so malformed requests are rejected instead of being buffered forever.
+fn check_request(buf: &[u8]) -> Result<(), Error> {
+    match buf.first() {
+        Some(b'*') => {}
+        Some(_) if buf.len() > PROTO_INLINE_MAX_SIZE && !buf.contains(&b'\n') => {
+            return Err(Error::InvalidRequest("too big inline request".to_owned()))
+        }
+        _ => return Ok(()),
+    }
+
+    let invalid_multibulk = "invalid multibulk length";
+    let (len, mut pos) =
+        match read_header_line(buf, "too big mbulk count string", invalid_multibulk)? {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+    if !(1..=PROTO_MAX_MULTIBULK_LEN).contains(&len) {
+        return Err(Error::InvalidRequest(invalid_multibulk.to_owned()));
+    }
+
+    for _ in 0..len {
+        let rest = match buf.get(pos..) {
+            Some(rest) if !rest.is_empty() => rest,
+            _ => return Ok(()),
+        };
+        if rest[0] != b'$' {
+            return Err(Error::Protocol(
+                (rest[0] as char).to_string(),
+                "$".to_owned(),
+            ));
+        }
+        let invalid_bulk = "invalid bulk length";
+        let (len, header) = match read_header_line(rest, "too big bulk count string", invalid_bulk)?
+        {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        if !(0..=PROTO_MAX_BULK_LEN).contains(&len) {
+            return Err(Error::InvalidRequest(invalid_bulk.to_owned()));
+        }
+        pos += header + len as usize + 2;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> VecDeque<Bytes> {
+        args.iter()
+            .map(|arg| Bytes::from(arg.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn decode_pipelined_requests() {
+        let mut codec = RespCodec::new();
+        let mut buf =
+            BytesMut::from(&b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n*1\r\n$4\r\nping\r\n*1\r\n"[..]);
+
+        assert_eq!(Ok(Some(args(&["get", "foo"]))), codec.decode(&mut buf));
+        assert_eq!(Ok(Some(args(&["ping"]))), codec.decode(&mut buf));
+        // partial requests are kept in the buffer until they are complete
+        assert_eq!(Ok(None), codec.decode(&mut buf));
+        buf.extend_from_slice(b"$4\r\nping\r\n");
+        assert_eq!(Ok(Some(args(&["ping"]))), codec.decode(&mut buf));
+        assert!(buf.is_empty());
+        assert_eq!((50, 0), codec.take_net_bytes());
+        assert_eq!((0, 0), codec.take_net_bytes());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut codec = RespCodec::new();
+        assert_eq!(
+            Err(Error::Protocol("+".to_owned(), "$".to_owned())),
+            codec.decode(&mut BytesMut::from(&b"*1\r\n+ping\r\n"[..]))
+        );
+        assert_eq!(
+            Err(Error::InvalidRequest("invalid multibulk length".to_owned())),
+            codec.decode(&mut BytesMut::from(&b"*2000000\r\n"[..]))
+        );
+        assert_eq!(
+            Err(Error::InvalidRequest("invalid bulk length".to_owned())),
+            codec.decode(&mut BytesMut::from(&b"*1\r\n$1000000000\r\n"[..]))
+        );
+        // requests are flat arrays, nested arrays are rejected right away
+        assert_eq!(
+            Err(Error::Protocol("*".to_owned(), "$".to_owned())),
+            codec.decode(&mut BytesMut::from(&b"*1\r\n".repeat(100_000)[..]))
+        );
+    }
+
+    #[test]
+    fn encode() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Value::Ok, &mut buf).unwrap();
+        codec.encode(Bytes::from("*2\r\n"), &mut buf).unwrap();
+        codec.encode(Value::Integer(1), &mut buf).unwrap();
+        codec.encode(Value::from("a"), &mut buf).unwrap();
+        assert_eq!(&b"+OK\r\n*2\r\n:1\r\n$1\r\na\r\n"[..], &buf[..]);
+        assert_eq!((0, buf.len() as u64), codec.take_net_bytes());
+    }
+}
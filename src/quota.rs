@@ -0,0 +1,292 @@
+//! # Namespace quotas
+//!
+//! Limits the number of keys and the memory used by the keys sharing a prefix (a namespace), so
+//! several tenants can share a single instance. The rules are set with `namespace-quotas`:
+//!
+//! ```text
+//! namespace-quotas tenant1: 1000 10mb tenant2: 0 1gb
+//! ```
+//!
+//! Each rule is a prefix followed by the maximum number of keys and the maximum memory of the
+//! namespace, zero meaning no limit. Keys in all the databases are counted, and a key matching
+//! several prefixes counts towards each namespace.
+//!
+//! The usage of each namespace is updated by the database slots as keys are written and removed,
+//! so it is never measured by walking the keyspace. Write commands which may grow the data (those
+//! flagged `denyoom`) and the commands creating a key out of another one ([`KEY_MOVING_COMMANDS`])
+//! are checked right before they run, inside a transaction when EXEC runs them. They are rejected
+//! with a `QUOTA` error if they would create a key in a namespace at its key quota, or write to a
+//! namespace at or over its memory quota. The memory a command adds is only known once it runs, so
+//! the last write may take a namespace over its memory quota.
+use crate::{db::Db, error::Error};
+use bytes::Bytes;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Commands without the `denyoom` flag which may still create a key in a namespace, from a key
+/// which may belong to another namespace
+pub const KEY_MOVING_COMMANDS: [&str; 4] = ["RENAME", "RENAMENX", "MOVE", "SMOVE"];
+
+/// Quota of a namespace
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaRule {
+    /// Prefix of the keys of the namespace
+    pub prefix: String,
+    /// Maximum number of keys, zero means no limit
+    pub max_keys: usize,
+    /// Maximum memory used by the keys and their values, in bytes, zero means no limit
+    pub max_memory: usize,
+}
+
+impl QuotaRule {
+    /// Whether the key belongs to the namespace
+    fn matches(&self, key: &[u8]) -> bool {
+        key.starts_with(self.prefix.as_bytes())
+    }
+}
+
+/// Usage of a namespace
+#[derive(Debug, Default)]
+struct Usage {
+    keys: AtomicUsize,
+    memory: AtomicUsize,
+}
+
+/// Namespace quotas, along with the usage of each namespace
+#[derive(Debug)]
+pub struct Quotas {
+    rules: Vec<(QuotaRule, Usage)>,
+}
+
+impl Quotas {
+    /// Creates the quotas. The usage is zero until the quotas are attached to the databases with
+    /// [`Db::set_quotas`].
+    pub fn new(rules: Vec<QuotaRule>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, Usage::default()))
+                .collect(),
+        }
+    }
+
+    /// Accounts keys and memory added to the namespaces the key belongs to
+    pub fn add(&self, key: &[u8], keys: usize, memory: usize) {
+        for (_, usage) in self.rules.iter().filter(|(rule, _)| rule.matches(key)) {
+            usage.keys.fetch_add(keys, Ordering::Relaxed);
+            usage.memory.fetch_add(memory, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts keys and memory removed from the namespaces the key belongs to
+    pub fn remove(&self, key: &[u8], keys: usize, memory: usize) {
+        for (_, usage) in self.rules.iter().filter(|(rule, _)| rule.matches(key)) {
+            usage.keys.fetch_sub(keys, Ordering::Relaxed);
+            usage.memory.fetch_sub(memory, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns each rule along with the number of keys and memory of the namespace
+    pub fn usage(&self) -> Vec<(QuotaRule, usize, usize)> {
+        self.rules
+            .iter()
+            .map(|(rule, usage)| {
+                (
+                    rule.clone(),
+                    usage.keys.load(Ordering::Relaxed),
+                    usage.memory.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// Checks that a command may write to the given keys of the database. Nothing is accounted,
+    /// the keys the command creates are accounted once it writes them.
+    pub fn check(&self, db: &Db, keys: &[Bytes]) -> Result<(), Error> {
+        let mut new_keys = vec![0; self.rules.len()];
+        for key in keys.iter() {
+            let mut is_new = None;
+            for ((rule, usage), new_keys) in self.rules.iter().zip(new_keys.iter_mut()) {
+                if !rule.matches(key) {
+                    continue;
+                }
+                if rule.max_memory > 0 && usage.memory.load(Ordering::Relaxed) >= rule.max_memory {
+                    return Err(Error::QuotaExceeded(rule.prefix.clone()));
+                }
+                if rule.max_keys == 0 {
+                    continue;
+                }
+                if !*is_new.get_or_insert_with(|| db.exists(&[key.clone()]) == 0) {
+                    continue;
+                }
+                if usage.keys.load(Ordering::Relaxed) + *new_keys >= rule.max_keys {
+                    return Err(Error::QuotaExceeded(rule.prefix.clone()));
+                }
+                *new_keys += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        cmd::test::{create_connection, run_command},
+        config::Config,
+        error::Error,
+        value::Value,
+    };
+
+    #[tokio::test]
+    async fn enforce_quotas() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        let _ = run_command(&c, &["set", "t1:a", "1"]).await;
+        all_connections.apply_config(Config {
+            namespace_quotas: vec![
+                super::QuotaRule {
+                    prefix: "t1:".to_owned(),
+                    max_keys: 2,
+                    max_memory: 0,
+                },
+                super::QuotaRule {
+                    prefix: "t2:".to_owned(),
+                    max_keys: 0,
+                    max_memory: 10,
+                },
+            ],
+            ..Config::default()
+        });
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "t1:b", "2"]).await);
+        assert_eq!(
+            Err(Error::QuotaExceeded("t1:".to_owned())),
+            run_command(&c, &["set", "t1:c", "3"]).await
+        );
+        assert_eq!(
+            Err(Error::QuotaExceeded("t1:".to_owned())),
+            run_command(&c, &["mset", "other", "1", "t1:c", "3"]).await
+        );
+        // existing keys can still be written, and keys can be removed
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "t1:a", "4"]).await);
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["del", "t1:b"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "t1:c", "3"]).await);
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "other", "3"]).await);
+
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["set", "t2:a", "a long value"]).await
+        );
+        assert_eq!(
+            Err(Error::QuotaExceeded("t2:".to_owned())),
+            run_command(&c, &["append", "t2:a", "more"]).await
+        );
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c, &["del", "t2:a"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["set", "t2:a", "a"]).await);
+
+        all_connections.apply_config(Config::default());
+        assert!(all_connections.quotas().is_none());
+    }
+
+    #[tokio::test]
+    async fn usage_is_accounted_on_write() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        let _ = run_command(&c, &["set", "t1:a", "1"]).await;
+        all_connections.set_namespace_quotas(vec![super::QuotaRule {
+            prefix: "t1:".to_owned(),
+            max_keys: 0,
+            max_memory: 0,
+        }]);
+        let quotas = all_connections.quotas().unwrap();
+        let usage = || {
+            let (_, keys, memory) = quotas.usage()[0].clone();
+            (keys, memory)
+        };
+
+        let (keys, one_key) = usage();
+        assert_eq!(1, keys);
+        assert!(one_key > 0);
+
+        let _ = run_command(&c, &["rpush", "t1:list", "a", "b", "c"]).await;
+        let (keys, with_list) = usage();
+        assert_eq!(2, keys);
+        let _ = run_command(&c, &["rpush", "t1:list", "a long element"]).await;
+        assert!(usage().1 > with_list);
+
+        let _ = run_command(&c, &["rename", "t1:list", "other"]).await;
+        assert_eq!((1, one_key), usage());
+        let _ = run_command(&c, &["select", "1"]).await;
+        let _ = run_command(&c, &["set", "t1:b", "1"]).await;
+        assert_eq!(2, usage().0);
+        let _ = run_command(&c, &["flushdb"]).await;
+        assert_eq!((1, one_key), usage());
+    }
+
+    #[tokio::test]
+    async fn key_moving_commands() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        let _ = run_command(&c, &["set", "t1:a", "1"]).await;
+        let _ = run_command(&c, &["set", "other", "1"]).await;
+        all_connections.set_namespace_quotas(vec![super::QuotaRule {
+            prefix: "t1:".to_owned(),
+            max_keys: 1,
+            max_memory: 0,
+        }]);
+
+        for command in [
+            vec!["rename", "other", "t1:b"],
+            vec!["renamenx", "other", "t1:b"],
+            vec!["copy", "other", "t1:b"],
+        ] {
+            assert_eq!(
+                Err(Error::QuotaExceeded("t1:".to_owned())),
+                run_command(&c, &command).await
+            );
+        }
+        assert_eq!(Ok(Value::Null), run_command(&c, &["get", "t1:b"]).await);
+    }
+
+    #[tokio::test]
+    async fn quotas_are_checked_at_exec() {
+        let c = create_connection();
+        let all_connections = c.all_connections();
+        all_connections.set_namespace_quotas(vec![super::QuotaRule {
+            prefix: "t1:".to_owned(),
+            max_keys: 1,
+            max_memory: 0,
+        }]);
+
+        // a discarded transaction does not consume the quota
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "t1:a", "1"]).await
+        );
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["discard"]).await);
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["multi"]).await);
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "t1:a", "1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Queued),
+            run_command(&c, &["set", "t1:b", "1"]).await
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                Value::Ok,
+                Error::QuotaExceeded("t1:".to_owned()).into(),
+            ])),
+            run_command(&c, &["exec"]).await
+        );
+    }
+}
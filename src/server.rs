@@ -3,72 +3,90 @@
 //! Redis TCP server. This module also includes a simple HTTP server to dump the prometheus
 //! metrics.
 use crate::{
-    config::Config,
-    connection::{connections::Connections, Connection},
-    db::{pool::Databases, Db},
+    audit::{write_records, AuditLog, AuditSink},
+    config::{Config, ConfigSource, KeyspaceEvents},
+    connection::{
+        connections::{Connections, ServerEvent},
+        Connection,
+    },
+    db::{pool::Databases, update_lru_clock, Db},
     dispatcher::Dispatcher,
     error::Error,
+    federation::Federation,
+    preload::preload,
+    protocol::RespCodec,
     value::Value,
 };
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use futures::{future, SinkExt};
 use log::{info, trace, warn};
-use redis_zero_protocol_parser::{parse_server, Error as RedisError};
-use std::{collections::VecDeque, io, sync::Arc};
-#[cfg(unix)]
-use tokio::net::UnixListener;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-    time::{sleep, Duration},
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::broadcast::error::RecvError,
+    time::{sleep, sleep_until, Duration, Instant},
+};
+#[cfg(unix)]
+use tokio::{
+    net::UnixListener,
+    signal::unix::{signal, SignalKind},
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
-/// Redis Parser Encoder/Decoder
-struct RedisParser;
+/// Codec of the client connections
+///
+/// It is the RESP codec, which also reports the size of the unparsed input (the query buffer) to
+/// the connection.
+#[derive(Default)]
+struct RedisParser {
+    codec: RespCodec,
+    conn: Option<Arc<Connection>>,
+}
+
+impl RedisParser {
+    /// Returns the bytes read and written since the last call
+    fn take_net_bytes(&mut self) -> (u64, u64) {
+        self.codec.take_net_bytes()
+    }
+}
 
-impl Encoder<Value> for RedisParser {
+impl<T> Encoder<T> for RedisParser
+where
+    RespCodec: Encoder<T, Error = io::Error>,
+{
     type Error = io::Error;
 
-    fn encode(&mut self, response: Value, dst: &mut BytesMut) -> io::Result<()> {
-        let v: Vec<u8> = response.into();
-        dst.extend_from_slice(&v);
-        Ok(())
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> io::Result<()> {
+        self.codec.encode(item, dst)
     }
 }
 
 impl Decoder for RedisParser {
     type Item = VecDeque<Bytes>;
-    type Error = io::Error;
-
-    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
-        let (frame, proccesed) = {
-            let (unused, val) = match parse_server(src) {
-                Ok((buf, val)) => (buf, val),
-                Err(RedisError::Partial) => return Ok(None),
-                Err(e) => {
-                    log::debug!("{:?}", e);
-
-                    return Err(io::Error::new(io::ErrorKind::Other, "something"));
-                }
-            };
-            (
-                val.iter().map(|e| Bytes::copy_from_slice(e)).collect(),
-                src.len() - unused.len(),
-            )
-        };
+    type Error = Error;
 
-        src.advance(proccesed);
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        if let Some(conn) = &self.conn {
+            conn.set_query_buffer(src.len());
+            let limit = conn.all_connections().client_query_buffer_limit();
+            if limit > 0 && src.len() > limit {
+                return Err(Error::QueryBufferLimit);
+            }
+        }
 
-        Ok(Some(frame))
+        self.codec.decode(src)
     }
 }
 
-/// Spawn a very simple HTTP server to serve metrics.
+/// Spawn a very simple HTTP server to serve metrics and health checks.
 ///
-/// The incoming HTTP request is discarded and the response is always the metrics in a prometheus
-/// format
+/// Only the request line of the incoming HTTP request is read. `/health/live` always responds
+/// with a 200 while the process is running, `/health/ready` responds with a 200 only when the
+/// server is ready to serve requests (503 while loading or shutting down). Any other path returns
+/// the metrics in a prometheus format.
 async fn server_metrics(all_connections: Arc<Connections>) -> Result<(), Error> {
     info!("Listening on 127.0.0.1:7878 for metrics");
     let listener = tokio::net::TcpListener::bind("127.0.0.1:7878")
@@ -82,24 +100,45 @@ async fn server_metrics(all_connections: Arc<Connections>) -> Result<(), Error>
         let (mut stream, _) = listener.accept().await.expect("accept client");
         let mut buf = vec![0; 1024];
 
-        let _ = match stream.read(&mut buf).await {
+        let n = match stream.read(&mut buf).await {
             Ok(n) => n,
             Err(_) => continue,
         };
 
-        let serialized = serde_prometheus::to_string(
-            &all_connections
-                .get_dispatcher()
-                .get_service_metric_registry(),
-            Some("redis"),
-            globals.clone(),
-        )
-        .unwrap_or_else(|_| "".to_owned());
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+        let (status, body) = match path {
+            "/health/live" => ("200 OK", "OK".to_owned()),
+            "/health/ready" => {
+                let health = all_connections.health();
+                (
+                    if health.is_ready() {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
+                    },
+                    health.to_string(),
+                )
+            }
+            _ => (
+                "200 OK",
+                serde_prometheus::to_string(
+                    &all_connections
+                        .get_dispatcher()
+                        .get_service_metric_registry(),
+                    Some("redis"),
+                    globals.clone(),
+                )
+                .unwrap_or_else(|_| "".to_owned()),
+            ),
+        };
 
         let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            serialized.len(),
-            serialized
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
         );
 
         let _ = stream.write_all(response.as_bytes()).await;
@@ -107,19 +146,54 @@ async fn server_metrics(all_connections: Arc<Connections>) -> Result<(), Error>
     }
 }
 
+/// Binds a TCP listener with a given backlog size.
+///
+/// Like redis, IPv6 sockets only accept IPv6 connections, that way an IPv4 and an IPv6 address
+/// can be bound to the same port (for instance `bind 0.0.0.0 ::`).
+async fn bind_tcp(addr: &str, backlog: u32) -> Result<TcpListener, Error> {
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("Cannot resolve {}", addr),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Accepts a TCP connection, and sets the socket options of the config (`tcp-nodelay`) on it
+async fn accept_tcp(
+    listener: &TcpListener,
+    config: &Config,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let (socket, addr) = listener.accept().await?;
+    if let Err(e) = socket.set_nodelay(config.tcp_nodelay) {
+        warn!("error setting TCP_NODELAY; error = {:?}", e);
+    }
+    Ok((socket, addr))
+}
+
 /// Spawn the TCP/IP micro-redis server.
 async fn serve_tcp(
     addr: &str,
+    config: Arc<Config>,
     default_db: Arc<Db>,
     all_connections: Arc<Connections>,
 ) -> Result<(), Error> {
-    let listener = TcpListener::bind(addr).await?;
+    let listener = bind_tcp(addr, config.tcp_backlog).await?;
     info!("Starting server {}", addr);
     info!("Ready to accept connections on {}", addr);
     loop {
-        match listener.accept().await {
+        match accept_tcp(&listener, &config).await {
             Ok((socket, addr)) => {
-                let transport = Framed::new(socket, RedisParser);
+                let transport = Framed::new(socket, RedisParser::default());
                 let all_connections = all_connections.clone();
                 let default_db = default_db.clone();
 
@@ -146,7 +220,7 @@ async fn serve_unixsocket(
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
-                let transport = Framed::new(socket, RedisParser);
+                let transport = Framed::new(socket, RedisParser::default());
                 let all_connections = all_connections.clone();
                 let default_db = default_db.clone();
 
@@ -173,7 +247,11 @@ async fn execute_command(
     dispatcher: &Dispatcher,
     args: VecDeque<Bytes>,
 ) -> Option<Value> {
-    match dispatcher.execute(conn, args).await {
+    let result = dispatcher.execute(conn, args).await;
+    if let Some(delay) = conn.reply_delay() {
+        sleep(delay).await;
+    }
+    match result {
         Ok(result) => Some(result),
         Err(Error::EmptyLine) => Some(Value::Ignore),
         Err(Error::Quit) => None,
@@ -181,6 +259,50 @@ async fn execute_command(
     }
 }
 
+/// Writes a reply to the client.
+///
+/// Arrays are not serialized as a whole, instead each element is serialized and written on its
+/// own. The transport flushes its write buffer to the socket once it is big enough, waiting for
+/// the socket to be writable, so huge replies (like LRANGE 0 -1 on a huge list) are written in
+/// chunks and the serialized reply is never fully held in memory.
+async fn send_reply<T: AsyncWriteExt + Unpin>(
+    transport: &mut Framed<T, RedisParser>,
+    reply: Value,
+) -> io::Result<()> {
+    let mut stack = vec![vec![reply].into_iter()];
+    while let Some(values) = stack.last_mut() {
+        match values.next() {
+            Some(value) => match value.aggregate_header() {
+                Some(header) => {
+                    transport.feed(Bytes::from(header)).await?;
+                    if let Value::Array(values)
+                    | Value::SetReply(values)
+                    | Value::MapReply(values) = value
+                    {
+                        stack.push(values.into_iter());
+                    }
+                }
+                None => transport.feed(value).await?,
+            },
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    SinkExt::<Value>::flush(transport).await
+}
+
+/// Counts an executed command and yields to other tasks every `threshold` commands, so a single
+/// connection pipelining lots of commands cannot monopolize a worker.
+#[inline]
+async fn yield_every(executed: &mut usize, threshold: usize) {
+    *executed += 1;
+    if threshold > 0 && *executed >= threshold {
+        *executed = 0;
+        tokio::task::yield_now().await;
+    }
+}
+
 /// Handles a new connection
 ///
 /// The new connection can be created from a new TCP or Unix stream.
@@ -195,10 +317,35 @@ async fn handle_new_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, A: ToStr
     let dispatcher = all_connections.get_dispatcher();
     // Commands are being buffered when the client is blocked.
     let mut buffered_commands: Vec<VecDeque<Bytes>> = vec![];
+    let yield_threshold = all_connections.pipeline_yield_threshold();
+    let mut executed = 0;
+    let mut events = all_connections.subscribe_to_events();
+    transport.codec_mut().conn = Some(conn.clone());
     trace!("New connection {}", conn.id());
 
     loop {
+        let (input, output) = transport.codec_mut().take_net_bytes();
+        conn.add_net_bytes(input, output);
+        // While paused no command is read from the client
+        let paused_until = all_connections.paused_until();
+
         tokio::select! {
+            // Administrative events go first, so a pause or kill request is never raced by
+            // commands already waiting to be read
+            biased;
+            event = events.recv() => match event {
+                Ok(event) if event.applies_to(conn.id()) => match event {
+                    ServerEvent::Kill(_) | ServerEvent::Shutdown => break,
+                    // The pause deadline is read again on the next iteration
+                    ServerEvent::Pause(_) | ServerEvent::Unpause => {}
+                },
+                // Missed events may have been a kill or a shutdown, the pause deadline is read
+                // again anyway
+                Err(RecvError::Lagged(_)) if conn.is_killed() || all_connections.is_shutting_down() => break,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {},
             Some(msg) = pubsub.recv() => {
                 // Pub-sub message
                 if transport.send(msg).await.is_err() {
@@ -208,7 +355,7 @@ async fn handle_new_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, A: ToStr
                     // Client sent commands while the connection was blocked,
                     // now it is time to process them one by one
                     match execute_command(&conn, &dispatcher, args.clone()).await {
-                        Some(result) => if result != Value::Ignore && transport.send(result).await.is_err() {
+                        Some(result) => if result != Value::Ignore && send_reply(&mut transport, result).await.is_err() {
                             break 'outer;
                         },
                         None => {
@@ -216,17 +363,18 @@ async fn handle_new_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, A: ToStr
                             break 'outer;
                         }
                     }
+                    yield_every(&mut executed, yield_threshold).await;
                 }
                 buffered_commands.clear();
             },
-            result = transport.next() => match result {
+            result = transport.next(), if paused_until.is_none() => match result {
                 Some(Ok(args)) => {
                         if conn.is_blocked() {
                             buffered_commands.push(args);
                             continue;
                         }
                         match execute_command(&conn, &dispatcher, args).await {
-                            Some(result) => if result != Value::Ignore && transport.send(result).await.is_err() {
+                            Some(result) => if result != Value::Ignore && send_reply(&mut transport, result).await.is_err() {
                                break;
                             },
                             None => {
@@ -234,20 +382,118 @@ async fn handle_new_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, A: ToStr
                                 break;
                             }
                         };
+                        yield_every(&mut executed, yield_threshold).await;
                 },
-                Some(Err(e)) => {
+                Some(Err(Error::Io(e))) => {
                     warn!("error on decoding from socket; error = {:?}", e);
                     break;
                 },
+                Some(Err(e)) => {
+                    // Malformed or oversized input: reply with the error and close the
+                    // connection, as there is no way to tell where the next request starts
+                    warn!("closing connection {}; error = {}", conn.id(), e);
+                    let _ = transport.send(Value::from(e)).await;
+                    break;
+                },
                 None => break,
             },
             else => {
             }
         }
     }
+    let (input, output) = transport.codec_mut().take_net_bytes();
+    conn.add_net_bytes(input, output);
     conn.destroy();
 }
 
+/// Publishes the keyspace events of the removed keys of a database, named after why the key was
+/// removed (see [`crate::db::RemovalReason::event`]), to the `__keyspace@<db>__:<key>` and
+/// `__keyevent@<db>__:<event>` channels. Like any keyspace notification, they are only published
+/// when notify-keyspace-events enables their class and channel.
+async fn publish_removed_keys(db_index: usize, db: Arc<Db>, all_connections: Arc<Connections>) {
+    let mut removed_keys = db.subscribe_to_removals();
+    let pubsub = all_connections.pubsub();
+
+    loop {
+        let removed = match removed_keys.recv().await {
+            Ok(removed) => removed,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("{} removed keys events were not published", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let events = all_connections.keyspace_events();
+        let event = match removed.reason.event() {
+            Some(event) if events.contains(removed.reason.class()) => event,
+            _ => continue,
+        };
+        if events.contains('K') {
+            let mut keyspace_channel =
+                BytesMut::from(format!("__keyspace@{}__:", db_index).as_str());
+            keyspace_channel.extend_from_slice(&removed.key);
+            pubsub
+                .publish(
+                    &keyspace_channel.freeze(),
+                    &Bytes::from_static(event.as_bytes()),
+                )
+                .await;
+        }
+        if events.contains('E') {
+            pubsub
+                .publish(
+                    &Bytes::from(format!("__keyevent@{}__:{}", db_index, event)),
+                    &removed.key,
+                )
+                .await;
+        }
+    }
+}
+
+/// Input traffic, in kbps, under which the server is considered idle
+const DEFRAG_IDLE_INPUT_KBPS: f64 = 16.0;
+
+/// Releases the unused capacity of the databases, one slot at a time, while the server is idle.
+/// The task does nothing unless `activedefrag` is enabled.
+async fn active_defrag(all_connections: Arc<Connections>) {
+    let is_idle = |connections: &Connections| {
+        connections.is_active_defrag()
+            && connections.instantaneous_kbps().0 < DEFRAG_IDLE_INPUT_KBPS
+    };
+
+    loop {
+        sleep(Duration::from_millis(10_000)).await;
+        for db in all_connections.get_databases().into_iter() {
+            for slot_id in 0..db.number_of_slots() {
+                if !is_idle(&all_connections) {
+                    break;
+                }
+                all_connections.add_defrag_reclaimed_bytes(db.defrag_slot(slot_id));
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+/// Returns the startup banner, printed when `always-show-logo` is enabled. There is no cluster
+/// support, so the server always runs in standalone mode.
+fn banner(config: &Config) -> String {
+    format!(
+        r#"
+           _                            _ _
+ _ __ ___ (_) ___ _ __ ___  _ __ ___  __| (_)___     microredis {} ({} bit)
+| '_ ` _ \| |/ __| '__/ _ \| '__/ _ \/ _` | / __|
+| | | | | | | (__| | | (_) | | |  __/ (_| | \__ \    Running in standalone mode
+|_| |_| |_|_|\___|_|  \___/|_|  \___|\__,_|_|___/    Port: {}
+                                                     PID: {}
+"#,
+        env!("CARGO_PKG_VERSION"),
+        usize::BITS,
+        config.port,
+        std::process::id()
+    )
+}
+
 /// Spawn redis server
 ///
 /// Spawn a redis server. This function will create Connections object, the in-memory database, the
@@ -256,27 +502,149 @@ async fn handle_new_connection<T: AsyncReadExt + AsyncWriteExt + Unpin, A: ToStr
 /// This process is also listening for any incoming message through the internal pub-sub.
 ///
 /// This function will block the main thread and will never exit.
-pub async fn serve(config: Config) -> Result<(), Error> {
-    let (default_db, all_dbs) = Databases::new(16, 1000);
+///
+/// The config is loaded again from `source` on SIGHUP or CONFIG RELOAD.
+pub async fn serve(config: Config, source: ConfigSource) -> Result<(), Error> {
+    info!(
+        "microredis version={}, bits={}, pid={}, just started",
+        env!("CARGO_PKG_VERSION"),
+        usize::BITS,
+        std::process::id()
+    );
+    if config.always_show_logo {
+        println!("{}", banner(&config));
+    } else {
+        info!("Running mode=standalone, port={}.", config.port);
+    }
+
+    let (default_db, all_dbs) = Databases::builder()
+        .databases(config.databases.into())
+        .build();
+    info!(
+        "Server initialized with {} databases of {} slots",
+        config.databases,
+        default_db.number_of_slots()
+    );
     let all_connections = Arc::new(Connections::new(all_dbs.clone()));
+    all_connections.apply_config(config.clone());
+    all_connections.set_config_source(source);
     let all_connections_for_metrics = all_connections.clone();
+    let all_connections_for_stats = all_connections.clone();
+    let all_connections_for_locks = all_connections.clone();
+    let all_connections_for_defrag = all_connections.clone();
+    let supervisor = all_connections.supervisor();
+
+    supervisor.spawn_critical("net_stats", move || {
+        let all_connections = all_connections_for_stats.clone();
+        async move {
+            loop {
+                all_connections.sample_net_stats();
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    });
+
+    supervisor.spawn_critical("lru_clock", || async {
+        loop {
+            update_lru_clock();
+            sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    supervisor.spawn_critical("orphan_locks", move || {
+        let all_connections = all_connections_for_locks.clone();
+        async move {
+            loop {
+                let released = all_connections.unlock_orphan_keys();
+                if released > 0 {
+                    warn!("Released {} keys locked by dead connections", released);
+                }
+                sleep(Duration::from_millis(1000)).await;
+            }
+        }
+    });
+
+    supervisor.spawn_critical("active_defrag", move || {
+        active_defrag(all_connections_for_defrag.clone())
+    });
+
+    #[cfg(unix)]
+    {
+        let all_connections = all_connections.clone();
+        supervisor.spawn("sighup", async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(err) => {
+                    warn!("Cannot listen to SIGHUP: {}", err);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading the config");
+                if let Err(err) = all_connections.reload_config().await {
+                    warn!("Cannot reload the config: {}", err);
+                }
+            }
+        });
+    }
+
+    if let Some(audit_log) = &config.audit_log {
+        info!("Recording write commands to the audit log {}", audit_log);
+        let (audit, records) = AuditLog::new();
+        all_connections.set_audit_log(Some(audit));
+        supervisor.spawn(
+            "audit_log",
+            write_records(
+                AuditSink::new(audit_log, config.audit_log_max_size),
+                records,
+                all_connections.clone(),
+            ),
+        );
+    }
+
+    if !config.federation_peers.is_empty() {
+        info!(
+            "Forwarding published messages to {}",
+            config.federation_peers.join(", ")
+        );
+        let (federation, links) = Federation::new(&config.federation_peers);
+        all_connections.set_federation(Some(federation));
+        for link in links {
+            supervisor.spawn("federation", link.run());
+        }
+    }
 
     all_dbs
         .into_iter()
-        .map(|db_for_purging| {
-            tokio::spawn(async move {
-                loop {
-                    db_for_purging.purge();
-                    sleep(Duration::from_millis(5000)).await;
+        .enumerate()
+        .map(|(db_index, db_for_purging)| {
+            let db_for_events = db_for_purging.clone();
+            let all_connections = all_connections.clone();
+            supervisor.spawn_critical("removed_keys_events", move || {
+                publish_removed_keys(db_index, db_for_events.clone(), all_connections.clone())
+            });
+            supervisor.spawn_critical("purge", move || {
+                let db = db_for_purging.clone();
+                async move {
+                    loop {
+                        db.purge();
+                        sleep(Duration::from_millis(5000)).await;
+                    }
                 }
             });
         })
         .for_each(drop);
 
+    if let Some(path) = &config.preload {
+        let executed = preload(path, &all_connections, default_db.clone()).await?;
+        info!("Preloaded {} commands from {}", executed, path);
+    }
+
     let mut services = vec![tokio::spawn(async move {
         server_metrics(all_connections_for_metrics).await
     })];
 
+    let tcp_config = Arc::new(config.clone());
     config
         .get_tcp_hostnames()
         .iter()
@@ -284,8 +652,9 @@ pub async fn serve(config: Config) -> Result<(), Error> {
             let default_db = default_db.clone();
             let all_connections = all_connections.clone();
             let host = host.clone();
+            let config = tcp_config.clone();
             services.push(tokio::spawn(async move {
-                serve_tcp(&host, default_db, all_connections).await
+                serve_tcp(&host, config, default_db, all_connections).await
             }));
         })
         .for_each(drop);
@@ -301,3 +670,441 @@ pub async fn serve(config: Config) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cmd::test::{
+            create_connection_and_pubsub, create_new_connection_from_connection, run_command,
+        },
+        protocol::PROTO_INLINE_MAX_SIZE,
+    };
+
+    #[tokio::test]
+    async fn default_pipeline_yield_threshold() {
+        // the server yields before any config is applied
+        let c = create_connection_and_pubsub().1;
+        assert_eq!(
+            crate::config::DEFAULT_PIPELINE_YIELD_THRESHOLD,
+            c.all_connections().pipeline_yield_threshold()
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_reply() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut transport = Framed::new(server, RedisParser::default());
+        let reply = Value::Array(vec![
+            Value::Array((0..10_000).map(|i| i.into()).collect()),
+            Value::Array(vec![]),
+            Value::SetReply(vec!["a".into(), Value::Array(vec![1.into()])]),
+            Value::MapReply(vec!["f".into(), "v".into()]),
+            Value::Null,
+            "foo".into(),
+        ]);
+        let expected: Vec<u8> = (&reply).into();
+
+        let reader = tokio::spawn(async move {
+            let mut client = client;
+            let mut buf = vec![];
+            let _ = client.read_to_end(&mut buf).await;
+            buf
+        });
+
+        send_reply(&mut transport, reply).await.unwrap();
+        assert_eq!(
+            (0, expected.len() as u64),
+            transport.codec_mut().take_net_bytes()
+        );
+        drop(transport);
+
+        assert_eq!(expected, reader.await.unwrap());
+    }
+
+    #[test]
+    fn startup_banner() {
+        let config = Config {
+            port: 7777,
+            ..Config::default()
+        };
+        let banner = banner(&config);
+        assert!(banner.contains(env!("CARGO_PKG_VERSION")));
+        assert!(banner.contains("standalone mode"));
+        assert!(banner.contains("Port: 7777"));
+        assert!(banner.contains(&format!("PID: {}", std::process::id())));
+    }
+
+    #[tokio::test]
+    async fn tcp_socket_options_from_config() {
+        for (setting, nodelay) in [("yes", true), ("no", false)] {
+            let config: Config = redis_config_parser::de::from_str(&format!(
+                "tcp-backlog 16\ntcp-nodelay {}\n",
+                setting
+            ))
+            .unwrap();
+            let listener = bind_tcp("127.0.0.1:0", config.tcp_backlog).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (socket, _) = accept_tcp(&listener, &config).await.unwrap();
+            assert_eq!(nodelay, socket.nodelay().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_ipv4_and_ipv6() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            eprintln!("skipping bind_ipv4_and_ipv6, IPv6 is not available");
+            return;
+        }
+        let ipv4 = bind_tcp("127.0.0.1:0", 16).await.unwrap();
+        let port = ipv4.local_addr().unwrap().port();
+        let ipv6 = bind_tcp(&format!("[::]:{}", port), 16).await.unwrap();
+        assert!(ipv6.local_addr().unwrap().is_ipv6());
+
+        let _client = TcpStream::connect(format!("[::1]:{}", port)).await.unwrap();
+        let (_, addr) = ipv6.accept().await.unwrap();
+        assert!(addr.is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn expired_keys_events() {
+        let (mut sub, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+        let db = c1.all_connections().get_databases().get(0).unwrap();
+        c1.all_connections().apply_config(Config {
+            notify_keyspace_events: KeyspaceEvents::parse("KEA").unwrap(),
+            ..Config::default()
+        });
+        tokio::spawn(publish_removed_keys(0, db, c1.all_connections()));
+
+        let _ = run_command(&c1, &["psubscribe", "__key*__:*"]).await;
+        let _ = run_command(&c2, &["set", "foo", "bar", "px", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(Ok(Value::Null), run_command(&c2, &["get", "foo"]).await);
+        assert_eq!(Ok(Value::Null), run_command(&c2, &["get", "foo"]).await);
+
+        let _confirmation = sub.recv().await;
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "__key*__:*".into(),
+                "__keyspace@0__:foo".into(),
+                "expired".into()
+            ])),
+            sub.recv().await
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "__key*__:*".into(),
+                "__keyevent@0__:expired".into(),
+                "foo".into()
+            ])),
+            sub.recv().await
+        );
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deleted_keys_events() {
+        let (mut sub, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+        let db = c1.all_connections().get_databases().get(0).unwrap();
+        c1.all_connections().apply_config(Config {
+            notify_keyspace_events: KeyspaceEvents::parse("KEA").unwrap(),
+            ..Config::default()
+        });
+        tokio::spawn(publish_removed_keys(0, db, c1.all_connections()));
+
+        let _ = run_command(&c1, &["psubscribe", "__keyevent@0__:*"]).await;
+        let _ = run_command(&c2, &["set", "foo", "bar"]).await;
+        let _ = run_command(&c2, &["set", "bar", "foo", "px", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c2, &["del", "foo", "bar"]).await
+        );
+
+        let _confirmation = sub.recv().await;
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "__keyevent@0__:*".into(),
+                "__keyevent@0__:del".into(),
+                "foo".into()
+            ])),
+            sub.recv().await
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "__keyevent@0__:*".into(),
+                "__keyevent@0__:expired".into(),
+                "bar".into()
+            ])),
+            sub.recv().await
+        );
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn keyspace_events_are_gated() {
+        let (mut sub, c1) = create_connection_and_pubsub();
+        let (_, c2) = create_new_connection_from_connection(&c1);
+        let db = c1.all_connections().get_databases().get(0).unwrap();
+        tokio::spawn(publish_removed_keys(0, db, c1.all_connections()));
+
+        let _ = run_command(&c1, &["psubscribe", "__key*__:*"]).await;
+        let _confirmation = sub.recv().await;
+
+        // disabled by default
+        let _ = run_command(&c2, &["set", "foo", "bar", "px", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(Ok(Value::Null), run_command(&c2, &["get", "foo"]).await);
+        sleep(Duration::from_millis(5)).await;
+        assert!(sub.try_recv().is_err());
+
+        // expired keys to the keyevent channel only, deleted keys are not published
+        c1.all_connections()
+            .set_keyspace_events(KeyspaceEvents::parse("Ex").unwrap());
+        let _ = run_command(&c2, &["set", "foo", "bar"]).await;
+        let _ = run_command(&c2, &["set", "bar", "foo", "px", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            run_command(&c2, &["del", "foo", "bar"]).await
+        );
+        assert_eq!(
+            Some(Value::Array(vec![
+                "pmessage".into(),
+                "__key*__:*".into(),
+                "__keyevent@0__:expired".into(),
+                "bar".into()
+            ])),
+            sub.recv().await
+        );
+        sleep(Duration::from_millis(5)).await;
+        assert!(sub.try_recv().is_err());
+    }
+
+    /// Serves a connection through an in-memory stream, returning the client side of the stream
+    /// and the connection ID
+    async fn serve_in_memory(
+        all_connections: &Arc<Connections>,
+        name: &str,
+    ) -> (tokio::io::DuplexStream, u128, tokio::task::JoinHandle<()>) {
+        let (client, server) = tokio::io::duplex(1024);
+        let transport = Framed::new(server, RedisParser::default());
+        let db = all_connections.get_databases().get(0).unwrap();
+        let handle = tokio::spawn(handle_new_connection(
+            transport,
+            all_connections.clone(),
+            db,
+            name.to_owned(),
+        ));
+        sleep(Duration::from_millis(10)).await;
+        let mut id = 0;
+        all_connections.iter(&mut |conn: Arc<Connection>| {
+            if conn.addr() == name {
+                id = conn.id();
+            }
+        });
+        (client, id, handle)
+    }
+
+    /// Sends a raw request to a new connection, returning everything read until the server
+    /// closes it
+    async fn send_raw_request(request: Vec<u8>) -> String {
+        let c = create_connection_and_pubsub().1;
+        let (client, _, handle) = serve_in_memory(&c.all_connections(), "raw").await;
+        let (mut reader, mut writer) = tokio::io::split(client);
+        // The server may close the connection before the whole request is written
+        tokio::spawn(async move { writer.write_all(&request).await });
+
+        let mut reply = vec![];
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut reply))
+            .await
+            .expect("connection closed")
+            .unwrap();
+        handle.await.unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn protocol_errors() {
+        assert_eq!(
+            "+PONG\r\n-ERR Protocol error: expected '$', got '+'\r\n",
+            send_raw_request(b"*1\r\n$4\r\nping\r\n*1\r\n+ping\r\n*1\r\n$4\r\nping\r\n".to_vec())
+                .await
+        );
+        assert_eq!(
+            "-ERR Protocol error: expected '*', got '+'\r\n",
+            send_raw_request(b"+ping\r\n".to_vec()).await
+        );
+        assert_eq!(
+            "-ERR Protocol error: invalid multibulk length\r\n",
+            send_raw_request(b"*x\r\n".to_vec()).await
+        );
+        assert_eq!(
+            "-ERR Protocol error: invalid multibulk length\r\n",
+            send_raw_request(b"*2000000\r\n".to_vec()).await
+        );
+        assert_eq!(
+            "-ERR Protocol error: invalid bulk length\r\n",
+            send_raw_request(b"*1\r\n$-4\r\nping\r\n".to_vec()).await
+        );
+        assert_eq!(
+            "-ERR Protocol error: invalid bulk length\r\n",
+            send_raw_request(b"*1\r\n$1000000000\r\n".to_vec()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_errors_on_overlong_lines() {
+        assert_eq!(
+            "-ERR Protocol error: too big inline request\r\n",
+            send_raw_request(vec![b'a'; PROTO_INLINE_MAX_SIZE + 1]).await
+        );
+        let mut request = b"*".to_vec();
+        request.extend(vec![b'1'; PROTO_INLINE_MAX_SIZE + 1]);
+        assert_eq!(
+            "-ERR Protocol error: too big mbulk count string\r\n",
+            send_raw_request(request).await
+        );
+        let mut request = b"*1\r\n$".to_vec();
+        request.extend(vec![b'1'; PROTO_INLINE_MAX_SIZE + 1]);
+        assert_eq!(
+            "-ERR Protocol error: too big bulk count string\r\n",
+            send_raw_request(request).await
+        );
+    }
+
+    #[tokio::test]
+    async fn client_query_buffer_limit() {
+        let c = create_connection_and_pubsub().1;
+        let all_connections = c.all_connections();
+        all_connections.set_client_query_buffer_limit(4096);
+        let (client, id, handle) = serve_in_memory(&all_connections, "qbuf").await;
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        // An incomplete request is buffered while it is below the limit
+        writer
+            .write_all(b"*2\r\n$4\r\necho\r\n$10000\r\n")
+            .await
+            .unwrap();
+        writer.write_all(&[b'x'; 2048]).await.unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let conn = all_connections.get_by_conn_id(id).unwrap();
+        assert!(conn.query_buffer() > 2048);
+        assert!(conn
+            .to_string()
+            .contains(&format!("qbuf={}", conn.query_buffer())));
+
+        tokio::spawn(async move { writer.write_all(&[b'x'; 4096]).await });
+        let mut reply = vec![];
+        tokio::time::timeout(Duration::from_secs(1), reader.read_to_end(&mut reply))
+            .await
+            .expect("connection closed")
+            .unwrap();
+        handle.await.unwrap();
+        assert_eq!(b"-ERR max query buffer length reached\r\n".to_vec(), reply);
+        assert!(conn.query_buffer_peak() > 4096);
+    }
+
+    #[tokio::test]
+    async fn kill_blocked_connection() {
+        let c = create_connection_and_pubsub().1;
+        let all_connections = c.all_connections();
+        let (mut client, id, handle) = serve_in_memory(&all_connections, "blocked").await;
+
+        client
+            .write_all(b"*3\r\n$5\r\nblpop\r\n$3\r\nfoo\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let conn = all_connections.get_by_conn_id(id).unwrap();
+        assert!(conn.is_blocked());
+        let client_id = conn.client_id().to_string();
+        drop(conn);
+
+        assert_eq!(
+            Ok(1.into()),
+            run_command(&c, &["client", "kill", "id", &client_id]).await
+        );
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("connection closed")
+            .unwrap();
+        assert!(all_connections.get_by_conn_id(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn kill_lagging_connection() {
+        let c = create_connection_and_pubsub().1;
+        let all_connections = c.all_connections();
+        let (_client, id, handle) = serve_in_memory(&all_connections, "lagging").await;
+
+        // The kill event is pushed out of the buffer before the connection reads it
+        assert!(all_connections.kill(id));
+        for _ in 0..100 {
+            all_connections.broadcast(ServerEvent::Unpause);
+        }
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("connection closed")
+            .unwrap();
+        assert!(all_connections.get_by_conn_id(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn pause_connections() {
+        let c = create_connection_and_pubsub().1;
+        let all_connections = c.all_connections();
+        let (mut client, _, _) = serve_in_memory(&all_connections, "paused").await;
+        let mut reply = [0; 7];
+
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["client", "pause", "10000"]).await
+        );
+        client.write_all(b"*1\r\n$4\r\nping\r\n").await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), client.read(&mut reply))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(Ok(Value::Ok), run_command(&c, &["client", "unpause"]).await);
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut reply))
+            .await
+            .expect("reply after unpause")
+            .unwrap();
+        assert_eq!(b"+PONG\r\n", &reply);
+
+        // Pauses expire by themselves
+        assert_eq!(
+            Ok(Value::Ok),
+            run_command(&c, &["client", "pause", "20", "all"]).await
+        );
+        client.write_all(b"*1\r\n$4\r\nping\r\n").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut reply))
+            .await
+            .expect("reply after the pause expired")
+            .unwrap();
+        assert_eq!(b"+PONG\r\n", &reply);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections() {
+        let c = create_connection_and_pubsub().1;
+        let all_connections = c.all_connections();
+        let (_client, _, handle) = serve_in_memory(&all_connections, "shutdown").await;
+
+        all_connections.start_shutdown();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("connection closed")
+            .unwrap();
+    }
+}
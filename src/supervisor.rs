@@ -0,0 +1,173 @@
+//! # Background task supervisor
+//!
+//! Background tasks are spawned through the supervisor so their panics are never lost: a panic is
+//! logged along with the name of the task, and critical tasks (like the purge loops) are restarted
+//! after a short delay.
+//!
+//! Tasks are grouped by name, and the health of each group is reported by the INFO command.
+use log::{error, warn};
+use parking_lot::RwLock;
+use std::{any::Any, collections::BTreeMap, future::Future, sync::Arc};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+
+/// Time to wait before restarting a critical task that panicked
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Health of a group of tasks sharing the same name
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TaskHealth {
+    /// Number of tasks currently running
+    pub running: usize,
+    /// Number of panics
+    pub panics: u64,
+    /// Number of restarts after a panic
+    pub restarts: u64,
+    /// Message of the last panic
+    pub last_panic: Option<String>,
+}
+
+/// Background task supervisor
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    tasks: RwLock<BTreeMap<&'static str, TaskHealth>>,
+}
+
+impl Supervisor {
+    /// Creates a new supervisor
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns a task. A panic is logged and accounted, but the task is not restarted.
+    pub fn spawn<T>(self: &Arc<Self>, name: &'static str, task: T) -> JoinHandle<()>
+    where
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        self.update(name, |health| health.running += 1);
+        tokio::spawn(async move {
+            let result = tokio::spawn(task).await;
+            supervisor.update(name, |health| health.running -= 1);
+            if let Err(err) = result {
+                if err.is_panic() {
+                    supervisor.panicked(name, err.into_panic());
+                }
+            }
+        })
+    }
+
+    /// Spawns a critical task, built by `task`. If the task panics it is built and spawned again,
+    /// after a short delay, until it finishes without panicking.
+    pub fn spawn_critical<F, T>(self: &Arc<Self>, name: &'static str, task: F) -> JoinHandle<()>
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        self.update(name, |health| health.running += 1);
+        tokio::spawn(async move {
+            loop {
+                match tokio::spawn(task()).await {
+                    Err(err) if err.is_panic() => {
+                        supervisor.panicked(name, err.into_panic());
+                        sleep(RESTART_DELAY).await;
+                        warn!("Restarting task {}", name);
+                        supervisor.update(name, |health| health.restarts += 1);
+                    }
+                    _ => break,
+                }
+            }
+            supervisor.update(name, |health| health.running -= 1);
+        })
+    }
+
+    /// Returns the health of all the tasks, by name
+    pub fn health(&self) -> Vec<(&'static str, TaskHealth)> {
+        self.tasks
+            .read()
+            .iter()
+            .map(|(name, health)| (*name, health.clone()))
+            .collect()
+    }
+
+    fn update<F: FnOnce(&mut TaskHealth)>(&self, name: &'static str, f: F) {
+        f(self.tasks.write().entry(name).or_default())
+    }
+
+    fn panicked(&self, name: &'static str, panic: Box<dyn Any + Send>) {
+        let message = panic_message(&*panic);
+        error!("Task {} panicked: {}", name, message);
+        self.update(name, |health| {
+            health.panics += 1;
+            health.last_panic = Some(message);
+        });
+    }
+}
+
+/// Returns the message of a panic payload
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|m| m.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn panics_are_accounted() {
+        let supervisor = Arc::new(Supervisor::new());
+        supervisor.spawn("ok", async {}).await.unwrap();
+        supervisor
+            .spawn("faulty", async { panic!("something went wrong") })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                (
+                    "faulty",
+                    TaskHealth {
+                        running: 0,
+                        panics: 1,
+                        restarts: 0,
+                        last_panic: Some("something went wrong".to_owned()),
+                    }
+                ),
+                ("ok", TaskHealth::default()),
+            ],
+            supervisor.health()
+        );
+    }
+
+    #[tokio::test]
+    async fn critical_tasks_are_restarted() {
+        let supervisor = Arc::new(Supervisor::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_for_task = attempts.clone();
+        supervisor
+            .spawn_critical("critical", move || {
+                let attempts = attempts_for_task.clone();
+                async move {
+                    if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                        panic!("attempt failed");
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(3, attempts.load(Ordering::Relaxed));
+        let health = supervisor.health();
+        assert_eq!(0, health[0].1.running);
+        assert_eq!(2, health[0].1.panics);
+        assert_eq!(2, health[0].1.restarts);
+    }
+}
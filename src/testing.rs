@@ -0,0 +1,68 @@
+//! # Testing harness
+//!
+//! Helpers to run commands against an in-memory server, without any socket. They are used by the
+//! unit tests of this crate and, behind the `testing` feature, by applications embedding
+//! microredis to test their own commands and configurations.
+//!
+//! ```ignore
+//! use microredis::testing::{create_connection, run_command};
+//!
+//! let conn = create_connection();
+//! run_command(&conn, &["set", "foo", "bar"]).await?;
+//! assert_eq!(Ok("bar".into()), run_command(&conn, &["get", "foo"]).await);
+//! ```
+use crate::{
+    connection::{connections::Connections, Connection},
+    db::pool::Databases,
+    error::Error,
+    value::Value,
+};
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Address every test connection comes from
+fn client_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080)
+}
+
+/// Creates a new server, with 16 databases, and returns a connection to it
+pub fn create_connection() -> Arc<Connection> {
+    create_connection_and_pubsub().1
+}
+
+/// Creates a new server, with 16 databases, and returns a connection to it along with the
+/// receiver of the messages published to the connection (pubsub messages, invalidations...).
+pub fn create_connection_and_pubsub() -> (Receiver<Value>, Arc<Connection>) {
+    let (default_db, all_dbs) = Databases::new(16, 1000);
+    let all_connections = Arc::new(Connections::new(all_dbs));
+
+    all_connections.new_connection(default_db, client_addr())
+}
+
+/// Creates a new connection to the same server `conn` is connected to. The new connection uses
+/// the first database.
+pub fn create_new_connection_from_connection(
+    conn: &Connection,
+) -> (Receiver<Value>, Arc<Connection>) {
+    let all_connections = conn.all_connections();
+
+    all_connections.new_connection(
+        all_connections.get_databases().get(0).expect("DB(0)"),
+        client_addr(),
+    )
+}
+
+/// Runs a command, the command name included in `cmd`, as if it was sent by the client of the
+/// connection.
+pub async fn run_command(conn: &Connection, cmd: &[&str]) -> Result<Value, Error> {
+    let args: VecDeque<Bytes> = cmd.iter().map(|s| Bytes::from(s.to_string())).collect();
+
+    // The dispatcher is big, a new instance would live in the future of every test
+    let dispatcher = conn.all_connections().get_dispatcher();
+    dispatcher.execute(conn, args).await
+}
@@ -55,6 +55,11 @@ impl Value {
         value::Value::new(&self.bytes)
     }
 
+    /// Returns the underlying bytes
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
     /// Whether it has a checksum or not
     pub fn has_checksum(&self) -> bool {
         self.checksum.is_some()
@@ -3,7 +3,7 @@
 use byteorder::{LittleEndian, WriteBytesExt};
 use bytes::Bytes;
 use crc32fast::Hasher as Crc32Hasher;
-use std::{convert::TryFrom, num::ParseIntError, str::FromStr};
+use std::{convert::TryFrom, fmt, num::ParseIntError, str::FromStr};
 use thiserror::Error;
 
 /// Error
@@ -86,9 +86,9 @@ impl TryFrom<&Bytes> for Cursor {
     }
 }
 
-impl ToString for Cursor {
-    fn to_string(&self) -> String {
-        self.serialize().to_string()
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.serialize())
     }
 }
 
@@ -33,12 +33,9 @@ impl Expiration {
         let base_time = now().as_millis() as i64;
 
         let millis = if is_absolute {
-            if millis.is_negative() {
-                millis.checked_add(base_time)
-            } else {
-                millis.checked_sub(base_time)
-            }
-            .ok_or_else(|| Error::InvalidExpire(command.to_string()))?
+            millis
+                .checked_sub(base_time)
+                .ok_or_else(|| Error::InvalidExpire(command.to_string()))?
         } else {
             if millis.checked_add(base_time).is_none() {
                 return Err(Error::InvalidExpire(command.to_string()));
@@ -1,7 +1,19 @@
 //! # Thin wrapper for f64 numbers to provide safe maths (checked_add) for incr/hincr operations
-use num_traits::CheckedAdd;
+//!
+//! Redis uses `long double` for INCRBYFLOAT and HINCRBYFLOAT, which on x86 has a 64 bits mantissa,
+//! while this wrapper uses f64, with 53 bits. This is a deliberate divergence:
+//!
+//! * Integers up to 2^53 are exact, bigger integers are rounded to the nearest f64. For instance
+//!   `9007199254740993` (2^53 + 1) is read as `9007199254740992`, while Redis keeps integers exact
+//!   up to 2^64.
+//! * Integral results are formatted with all their digits, never in scientific notation, so a
+//!   result like 2^64 is `18446744073709551616`, the same as Redis.
+//! * Any result which is not finite is rejected, as Redis does. Since the range of f64 is smaller
+//!   than `long double`, a result above `f64::MAX` (around 1.8e308) is an overflow error.
+use num_traits::{CheckedAdd, Zero};
 use std::{
     convert::{TryFrom, TryInto},
+    fmt,
     num::ParseFloatError,
     ops::{Add, Deref},
     str::FromStr,
@@ -9,7 +21,7 @@ use std::{
 
 use crate::error::Error;
 
-use super::Value;
+use super::{ParseNumber, Value};
 
 /// Float struct (a thing wrapper on top of f64)
 #[derive(Copy, Debug, Clone, PartialEq)]
@@ -49,9 +61,40 @@ impl Add for Float {
     }
 }
 
-impl ToString for Float {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+/// Maximum number of decimal digits, redis formats float numbers with `%.17Lf`
+const MAX_DECIMAL_DIGITS: usize = 17;
+
+impl Zero for Float {
+    fn zero() -> Self {
+        Float(0.0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == 0.0
+    }
+}
+
+impl fmt::Display for Float {
+    /// Formats the number the same way redis does: never in scientific notation, with at most 17
+    /// decimal digits and without trailing zeros.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.fract() == 0.0 {
+            // Integers, even when they are too big to be exact, are written with all their digits
+            let number = format!("{:.0}", self.0);
+            return f.write_str(if number == "-0" { "0" } else { &number });
+        }
+        let shortest = self.0.to_string();
+        let number = match shortest.split_once('.') {
+            Some((_, decimals)) if decimals.len() > MAX_DECIMAL_DIGITS => {
+                let number = format!("{:.*}", MAX_DECIMAL_DIGITS, self.0);
+                number
+                    .trim_end_matches('0')
+                    .trim_end_matches('.')
+                    .to_owned()
+            }
+            _ => shortest,
+        };
+        f.write_str(if number == "-0" { "0" } else { &number })
     }
 }
 
@@ -62,6 +105,12 @@ impl FromStr for Float {
     }
 }
 
+impl ParseNumber for Float {
+    fn parse_number(input: &str) -> Option<Self> {
+        f64::parse_number(input).map(Float)
+    }
+}
+
 impl CheckedAdd for Float {
     fn checked_add(&self, v: &Self) -> Option<Self> {
         let n = self.0 + v.0;
@@ -72,3 +121,74 @@ impl CheckedAdd for Float {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!("10.6", Float(10.5 + 0.1).to_string());
+        assert_eq!("3", Float(3.0).to_string());
+        assert_eq!("-3.5", Float(-3.5).to_string());
+        assert_eq!("0", Float(-0.0).to_string());
+        assert_eq!("5000", Float(5.0e3).to_string());
+        assert_eq!("1000000000000000000000", Float(1.0e21).to_string());
+        assert_eq!("0.00000000000000001", Float(1.0e-17).to_string());
+        assert_eq!("0", Float(1.0e-20).to_string());
+        assert_eq!("0", Float(-1.0e-20).to_string());
+        assert_eq!("1.1", Float(1.1).to_string());
+    }
+
+    #[test]
+    fn big_integers() {
+        let two_pow_53 = 9_007_199_254_740_992.0;
+        assert_eq!(
+            Some(Float(two_pow_53)),
+            Float::parse_number("9007199254740992")
+        );
+        // 2^53 + 1 is not representable, it is rounded to the nearest even
+        assert_eq!(
+            Some(Float(two_pow_53)),
+            Float::parse_number("9007199254740993")
+        );
+        assert_eq!(
+            Some(Float(two_pow_53)),
+            Float(two_pow_53).checked_add(&Float(1.0))
+        );
+        assert_eq!(
+            Some(Float(two_pow_53 + 2.0)),
+            Float(two_pow_53).checked_add(&Float(2.0))
+        );
+
+        assert_eq!(
+            "9223372036854775808",
+            Float::parse_number(&i64::MAX.to_string())
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!("18446744073709551616", Float(2f64.powi(64)).to_string());
+        assert_eq!("-18446744073709551616", Float(-(2f64.powi(64))).to_string());
+        assert_eq!(
+            "123456789012345680",
+            Float::parse_number("123456789012345678")
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let max = Float(f64::MAX);
+        assert_eq!(None, max.checked_add(&max));
+        assert_eq!(None, Float(-f64::MAX).checked_add(&Float(-f64::MAX)));
+        assert_eq!(None, max.checked_add(&Float(f64::INFINITY)));
+        assert_eq!(Some(max), max.checked_add(&Float(1.0)));
+        assert_eq!(Some(Float::zero()), max.checked_add(&Float(-f64::MAX)));
+        assert_eq!(
+            Some(Float(f64::INFINITY)),
+            Float::parse_number("1e309"),
+            "parsing does not fail, the increment does"
+        );
+    }
+}
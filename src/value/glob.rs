@@ -0,0 +1,178 @@
+//! # Binary-safe glob-style pattern matching
+//!
+//! Port of redis' `stringmatchlen`, it works with bytes instead of strings so binary-safe names
+//! can be matched, and like redis any pattern is valid.
+//!
+//! Supported patterns:
+//!
+//!  * `?` matches any single byte
+//!  * `*` matches any number of bytes, including none
+//!  * `[ae]`, `[^e]` and `[a-z]` match (or exclude) a set or a range of bytes
+//!  * `\` escapes the next byte
+
+/// Checks if a string matches a glob-style pattern
+///
+/// The pattern is matched iteratively, remembering only the position of the last `*` seen (and
+/// the position of the string it was tried at). On a mismatch the last `*` is made to consume one
+/// more byte and matching resumes right after it, so a pattern is matched in O(pattern * string)
+/// time at worst, however many stars it has.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    let mut last_star = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            last_star = Some((p, s));
+            continue;
+        }
+        if let Some(next) = match_byte(pattern, p, string[s]) {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match last_star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                last_star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    while pattern.get(p) == Some(&b'*') {
+        p += 1;
+    }
+
+    p == pattern.len()
+}
+
+/// Matches a single byte against the pattern element (a byte, `?`, a set or an escaped byte) at
+/// `p`. Returns the position of the next element if the byte matches.
+fn match_byte(pattern: &[u8], mut p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            p += 1;
+            let not = pattern.get(p) == Some(&b'^');
+            if not {
+                p += 1;
+            }
+            let mut is_match = false;
+            loop {
+                if p >= pattern.len() {
+                    // unterminated set, the last byte is treated as the closing bracket
+                    p -= 1;
+                    break;
+                } else if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    if pattern[p] == byte {
+                        is_match = true;
+                    }
+                } else if pattern[p] == b']' {
+                    break;
+                } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                    let (start, end) = if pattern[p] <= pattern[p + 2] {
+                        (pattern[p], pattern[p + 2])
+                    } else {
+                        (pattern[p + 2], pattern[p])
+                    };
+                    p += 2;
+                    if (start..=end).contains(&byte) {
+                        is_match = true;
+                    }
+                } else if pattern[p] == byte {
+                    is_match = true;
+                }
+                p += 1;
+            }
+            if is_match == not {
+                None
+            } else {
+                Some(p + 1)
+            }
+        }
+        b'\\' if p + 1 < pattern.len() => {
+            if pattern[p + 1] == byte {
+                Some(p + 2)
+            } else {
+                None
+            }
+        }
+        expected => {
+            if expected == byte {
+                Some(p + 1)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(matches(b"foo", b"foo"));
+        assert!(!matches(b"foo", b"fooo"));
+        assert!(!matches(b"fooo", b"foo"));
+        assert!(matches(b"", b""));
+        assert!(!matches(b"", b"foo"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"foo"));
+        assert!(matches(b"f*", b"foo"));
+        assert!(matches(b"f**o", b"foo"));
+        assert!(matches(b"*o", b"foo"));
+        assert!(matches(b"f*o*", b"fo"));
+        assert!(!matches(b"f*x", b"foo"));
+        assert!(matches(b"f?o", b"foo"));
+        assert!(!matches(b"f?o", b"fo"));
+        assert!(matches(b"h*llo", b"heeeello"));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(matches(b"news.[ae]*", b"news.art"));
+        assert!(matches(b"news.[ae]*", b"news.e"));
+        assert!(!matches(b"news.[ae]*", b"news.sport"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(matches(b"h[b-a]llo", b"hallo"));
+        assert!(!matches(b"h[a-b]llo", b"hcllo"));
+        assert!(matches(b"h[\\]]llo", b"h]llo"));
+        assert!(matches(b"h[a", b"ha"));
+    }
+
+    #[test]
+    fn escape() {
+        assert!(matches(b"h\\*llo", b"h*llo"));
+        assert!(!matches(b"h\\*llo", b"hello"));
+        assert!(matches(b"foo\\", b"foo\\"));
+    }
+
+    #[test]
+    fn binary() {
+        assert!(matches(b"\x00\xff*", b"\x00\xff\x01"));
+        assert!(matches(b"?\xff", b"\x00\xff"));
+        assert!(!matches(b"\x00\xfe*", b"\x00\xff\x01"));
+    }
+
+    #[test]
+    fn pathological() {
+        let pattern = [&b"*a".repeat(50)[..], b"b"].concat();
+        let string = b"a".repeat(200);
+        let start = std::time::Instant::now();
+        assert!(!matches(&pattern, &string));
+        assert!(matches(&pattern, &[&string[..], b"b"].concat()));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -5,6 +5,10 @@ pub mod checksum;
 pub mod cursor;
 pub mod expiration;
 pub mod float;
+pub mod glob;
+pub mod range;
+pub mod rdb;
+pub mod serialize;
 pub mod typ;
 
 use crate::{error::Error, value_try_from, value_vec_try_from};
@@ -14,15 +18,26 @@ use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     convert::{TryFrom, TryInto},
-    str::FromStr,
+    mem, str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use self::typ::ValueTyp;
 
+/// Strings longer than this are reported with the "raw" encoding instead of "embstr", like Redis
+/// does.
+pub const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Integers from zero up to this value (excluded) are shared objects in Redis
+pub const SHARED_INTEGERS: i64 = 10_000;
+
+/// Reference count Redis reports for shared objects
+pub const SHARED_REFCOUNT: usize = i32::MAX as usize;
+
 /// Redis Value.
 ///
 /// This enum represents all data structures that are supported by Redis
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum Value {
     /// Hash. This type cannot be serialized
     Hash(HashMap<Bytes, Bytes>),
@@ -32,6 +47,10 @@ pub enum Value {
     Set(HashSet<Bytes>),
     /// Vector/Array of values
     Array(Vec<Value>),
+    /// Array of unique values, replied as a Set in RESP3
+    SetReply(Vec<Value>),
+    /// Flat array of alternating fields and values, replied as a Map in RESP3
+    MapReply(Vec<Value>),
     /// Bytes/Strings/Binary data
     Blob(Bytes),
     /// bytes/String/Binary but that has been modified by bit operations, this
@@ -52,6 +71,7 @@ pub enum Value {
     /// Big number
     BigInteger(i128),
     /// Null
+    #[default]
     Null,
     /// The command has been Queued
     Queued,
@@ -61,27 +81,57 @@ pub enum Value {
     Ignore,
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::Null
-    }
+/// Protocol spoken by a client
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Protocol {
+    /// RESP2, every aggregated reply is an array
+    #[default]
+    Resp2,
+    /// RESP3, aggregated replies can also be sets and maps
+    Resp3,
 }
 
+/// Biggest value of the LRU clock, which has a 24 bits resolution like in Redis
+const LRU_CLOCK_MAX: u64 = (1 << 24) - 1;
+
 /// Value debug struct
 #[derive(Debug)]
 pub struct VDebug {
+    /// Address of the value
+    pub address: usize,
+    /// Number of references to the value
+    pub refcount: usize,
     /// Value encoding
     pub encoding: &'static str,
-    /// Length of serialized value
+    /// Length of the RDB encoded value
     pub serialize_len: usize,
+    /// LRU clock (seconds, modulo 2^24) of the last access to the value
+    pub lru: u64,
+    /// Seconds elapsed since the last access to the value
+    pub lru_seconds_idle: u64,
+}
+
+impl VDebug {
+    /// Sets the time elapsed since the last access to the value
+    pub fn idle(mut self, idle: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(idle);
+        self.lru = now.as_secs() & LRU_CLOCK_MAX;
+        self.lru_seconds_idle = idle.as_secs();
+        self
+    }
 }
 
 impl From<VDebug> for Value {
     fn from(v: VDebug) -> Self {
-        Value::Blob(format!(
-            "Value at:0x6000004a8840 refcount:1 encoding:{} serializedlength:{} lru:13421257 lru_seconds_idle:367",
-            v.encoding, v.serialize_len
-            ).into()
+        Value::Blob(
+            format!(
+                "Value at:{:#x} refcount:{} encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}",
+                v.address, v.refcount, v.encoding, v.serialize_len, v.lru, v.lru_seconds_idle
+            )
+            .into(),
         )
     }
 }
@@ -103,12 +153,58 @@ impl Value {
     }
 
     /// Returns the internal encoding of the redis
+    ///
+    /// Strings follow Redis: "int" for strings that are valid 64-bit integers, "raw" for strings
+    /// longer than 44 bytes and "embstr" for anything else.
     pub fn encoding(&self) -> &'static str {
         match self {
             Self::Hash(_) | Self::Set(_) => "hashtable",
             Self::List(_) => "linkedlist",
-            Self::Array(_) => "vector",
-            _ => "embstr",
+            Self::Array(_) | Self::SetReply(_) | Self::MapReply(_) => "vector",
+            Self::Integer(_) => "int",
+            _ => match self.as_bytes() {
+                Some(bytes) if bytes_to_int::<i64>(bytes).is_ok() => "int",
+                Some(bytes) if bytes.len() > EMBSTR_SIZE_LIMIT => "raw",
+                _ => "embstr",
+            },
+        }
+    }
+
+    /// Returns the number of references to the value, as Redis reports it. Redis shares the
+    /// objects of the integers below [`SHARED_INTEGERS`] and reports them as referenced
+    /// [`SHARED_REFCOUNT`] times, any other value is referenced once.
+    pub fn refcount(&self) -> usize {
+        let number = match self {
+            Self::Integer(x) => Some(*x),
+            _ => self
+                .as_bytes()
+                .and_then(|bytes| bytes_to_int::<i64>(bytes).ok()),
+        };
+        match number {
+            Some(number) if (0..SHARED_INTEGERS).contains(&number) => SHARED_REFCOUNT,
+            _ => 1,
+        }
+    }
+
+    /// Returns the bytes of a string value, regardless of whether it is stored as a Blob, BlobRw or
+    /// String. Any other value returns None.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Blob(x) => Some(x),
+            Self::BlobRw(x) => Some(x),
+            Self::String(x) => Some(x.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Returns the header of aggregated replies (arrays, sets and maps), or None for any other
+    /// value. Maps are prefixed by their number of fields rather than elements.
+    pub fn aggregate_header(&self) -> Option<String> {
+        match self {
+            Self::Array(x) => Some(format!("*{}\r\n", x.len())),
+            Self::SetReply(x) => Some(format!("~{}\r\n", x.len())),
+            Self::MapReply(x) => Some(format!("%{}\r\n", x.len() / 2)),
+            _ => None,
         }
     }
 
@@ -119,11 +215,15 @@ impl Value {
 
     /// Return debug information for the type
     pub fn debug(&self) -> VDebug {
-        let bytes: Vec<u8> = self.into();
         VDebug {
+            address: self as *const Self as usize,
+            refcount: self.refcount(),
             encoding: self.encoding(),
-            serialize_len: bytes.len(),
+            serialize_len: rdb::serialized_len(self),
+            lru: 0,
+            lru_seconds_idle: 0,
         }
+        .idle(Duration::ZERO)
     }
 
     /// Returns the hash of the value
@@ -133,6 +233,65 @@ impl Value {
         hasher.update(&bytes);
         hasher.finalize().to_vec()
     }
+
+    /// Returns the number of elements of a collection, or the length in bytes of a string
+    pub fn length(&self) -> usize {
+        match self {
+            Self::Hash(x) => x.len(),
+            Self::List(x) => x.len(),
+            Self::Set(x) => x.len(),
+            Self::Array(x) | Self::SetReply(x) | Self::MapReply(x) => x.len(),
+            _ => self.as_bytes().map_or(0, |x| x.len()),
+        }
+    }
+
+    /// Returns an estimation of the memory used by the value, in bytes. The allocated capacity of
+    /// collections is accounted, the allocator overhead is not.
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + match self {
+                Self::Hash(x) => {
+                    x.capacity() * mem::size_of::<(Bytes, Bytes)>()
+                        + x.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+                }
+                Self::List(x) => {
+                    x.capacity() * mem::size_of::<checksum::Value>()
+                        + x.iter().map(|v| v.bytes().len()).sum::<usize>()
+                }
+                Self::Set(x) => {
+                    x.capacity() * mem::size_of::<Bytes>()
+                        + x.iter().map(|v| v.len()).sum::<usize>()
+                }
+                Self::Array(x) | Self::SetReply(x) | Self::MapReply(x) => {
+                    x.iter().map(|v| v.memory_usage()).sum::<usize>()
+                }
+                Self::BlobRw(x) => x.capacity(),
+                _ => self.as_bytes().map_or(0, |x| x.len()),
+            }
+    }
+
+    /// Releases the unused capacity of oversized collections, those using less than half of their
+    /// capacity. Returns an estimation of the reclaimed bytes.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        macro_rules! shrink {
+            ($collection:expr, $item:ty) => {{
+                let capacity = $collection.capacity();
+                if capacity <= 2 * $collection.len() {
+                    return 0;
+                }
+                $collection.shrink_to_fit();
+                capacity.saturating_sub($collection.capacity()) * mem::size_of::<$item>()
+            }};
+        }
+
+        match self {
+            Self::Hash(x) => shrink!(x, (Bytes, Bytes)),
+            Self::List(x) => shrink!(x, checksum::Value),
+            Self::Set(x) => shrink!(x, Bytes),
+            Self::Array(x) | Self::SetReply(x) | Self::MapReply(x) => shrink!(x, Value),
+            _ => 0,
+        }
+    }
 }
 
 impl From<&Value> for Vec<u8> {
@@ -140,8 +299,8 @@ impl From<&Value> for Vec<u8> {
         match value {
             Value::Ignore => b"".to_vec(),
             Value::Null => b"*-1\r\n".to_vec(),
-            Value::Array(x) => {
-                let mut s: Vec<u8> = format!("*{}\r\n", x.len()).into();
+            Value::Array(x) | Value::SetReply(x) | Value::MapReply(x) => {
+                let mut s: Vec<u8> = value.aggregate_header().unwrap_or_default().into();
                 for i in x.iter() {
                     let b: Vec<u8> = i.into();
                     s.extend(b);
@@ -165,7 +324,11 @@ impl From<&Value> for Vec<u8> {
                 s.extend_from_slice(b"\r\n");
                 s.to_vec()
             }
-            Value::Err(x, y) => format!("-{} {}\r\n", x, y).into(),
+            Value::Err(x, y) => {
+                // New lines would break the framing of the reply
+                let message = format!("{} {}", x, y).replace(|c: char| c == '\r' || c == '\n', " ");
+                format!("-{}\r\n", message.trim_end()).into()
+            }
             Value::String(x) => format!("+{}\r\n", x).into(),
             Value::Boolean(x) => {
                 if *x {
@@ -189,7 +352,7 @@ impl TryFrom<&Value> for i64 {
             Value::BigInteger(x) => (*x).try_into().map_err(|_| Error::NotANumber),
             Value::Integer(x) => Ok(*x),
             Value::Blob(x) => bytes_to_number::<i64>(x),
-            Value::String(x) => x.parse::<i64>().map_err(|_| Error::NotANumber),
+            Value::String(x) => i64::parse_number(x).ok_or(Error::NotANumber),
             _ => Err(Error::NotANumber),
         }
     }
@@ -202,27 +365,83 @@ impl TryFrom<&Value> for f64 {
         match val {
             Value::Float(x) => Ok(*x),
             Value::Blob(x) => bytes_to_number::<f64>(x),
-            Value::String(x) => x.parse::<f64>().map_err(|_| Error::NotANumber),
+            Value::String(x) => f64::parse_number(x).ok_or(Error::NotANumber),
             _ => Err(Error::NotANumber),
         }
     }
 }
 
+/// Numbers parsed from arguments and stored values
+///
+/// Integers follow the strict rules of Redis: an optional `-` sign followed by digits, without
+/// `+` sign, leading zeros, `-0` or whitespace, and the number must fit in the type. Floats are
+/// parsed by `FromStr`, which rejects whitespace, but NaN is rejected.
+pub trait ParseNumber: Sized {
+    /// Parses a number, None is returned if the input is not a valid number of this type
+    fn parse_number(input: &str) -> Option<Self>;
+}
+
+/// Checks whether the input is an integer written the only way Redis accepts it
+fn is_strict_integer(input: &str) -> bool {
+    let digits = input.strip_prefix('-').unwrap_or(input);
+    let canonical = if digits == "0" {
+        digits.len() == input.len()
+    } else {
+        !digits.starts_with('0')
+    };
+    !digits.is_empty() && canonical && digits.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+macro_rules! parse_integer {
+    {$($type:ty),+ $(,)?} => {
+        $(
+            impl ParseNumber for $type {
+                fn parse_number(input: &str) -> Option<Self> {
+                    if is_strict_integer(input) {
+                        input.parse().ok()
+                    } else {
+                        None
+                    }
+                }
+            }
+        )+
+    };
+}
+
+parse_integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl ParseNumber for f64 {
+    fn parse_number(input: &str) -> Option<Self> {
+        input.parse::<f64>().ok().filter(|number| !number.is_nan())
+    }
+}
+
 /// Tries to convert bytes data into a number
 ///
 /// If the conversion fails a Error::NotANumber error is returned.
 #[inline]
-pub fn bytes_to_number<T: FromStr>(bytes: &[u8]) -> Result<T, Error> {
-    let x = String::from_utf8_lossy(bytes);
-    x.parse::<T>().map_err(|_| Error::NotANumber)
+pub fn bytes_to_number<T: ParseNumber>(bytes: &[u8]) -> Result<T, Error> {
+    str::from_utf8(bytes)
+        .ok()
+        .and_then(T::parse_number)
+        .ok_or(Error::NotANumber)
 }
 
 /// Tries to convert bytes data into an integer number
 #[inline]
-pub fn bytes_to_int<T: FromStr>(bytes: &[u8]) -> Result<T, Error> {
-    let x = String::from_utf8_lossy(bytes);
-    x.parse::<T>()
-        .map_err(|_| Error::NotANumberType("an integer".to_owned()))
+pub fn bytes_to_int<T: ParseNumber>(bytes: &[u8]) -> Result<T, Error> {
+    str::from_utf8(bytes)
+        .ok()
+        .and_then(T::parse_number)
+        .ok_or_else(|| Error::NotANumberType("an integer".to_owned()))
+}
+
+/// Parses the count argument of the commands returning up to count elements, rejecting negative
+/// counts
+#[inline]
+pub fn bytes_to_count(bytes: &[u8]) -> Result<usize, Error> {
+    let count: i64 = bytes_to_int(bytes)?;
+    usize::try_from(count).map_err(|_| Error::NotPositive)
 }
 
 impl<'a> From<&ParsedValue<'a>> for Value {
@@ -329,6 +548,85 @@ mod test {
     use super::*;
     use paste::paste;
 
+    #[test]
+    fn string_encoding() {
+        assert_eq!("int", Value::Integer(10).encoding());
+        assert_eq!("int", Value::new(b"-9223372036854775808").encoding());
+        assert_eq!("embstr", Value::new(b"9223372036854775808").encoding());
+        assert_eq!("embstr", Value::new(b"010").encoding());
+        assert_eq!("embstr", Value::new(&[b'a'; 44]).encoding());
+        assert_eq!("raw", Value::new(&[b'a'; 45]).encoding());
+        assert_eq!("raw", Value::BlobRw((&[b'a'; 45][..]).into()).encoding());
+    }
+
+    #[test]
+    fn strict_integers() {
+        let cases: &[(&[u8], Option<i64>)] = &[
+            (b"0", Some(0)),
+            (b"10", Some(10)),
+            (b"-10", Some(-10)),
+            (b"9223372036854775807", Some(i64::MAX)),
+            (b"-9223372036854775808", Some(i64::MIN)),
+            (b"9223372036854775808", None),
+            (b"-9223372036854775809", None),
+            (b"+10", None),
+            (b" 11", None),
+            (b"11 ", None),
+            (b"1 1", None),
+            (b"\t1", None),
+            (b"1\r\n", None),
+            (b"01", None),
+            (b"-01", None),
+            (b"00", None),
+            (b"-0", None),
+            (b"-", None),
+            (b"", None),
+            (b"1e3", None),
+            (b"1.0", None),
+            (b"0x10", None),
+            (b"\xff1", None),
+        ];
+
+        for (input, expected) in cases.iter() {
+            assert_eq!(
+                *expected,
+                bytes_to_number::<i64>(input).ok(),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+
+        assert_eq!(Ok(10usize), bytes_to_number::<usize>(b"10"));
+        assert_eq!(Err(Error::NotANumber), bytes_to_number::<usize>(b"-10"));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(Ok(1.5), bytes_to_number::<f64>(b"1.5"));
+        assert_eq!(Ok(1.5), bytes_to_number::<f64>(b"+1.5"));
+        assert_eq!(Ok(10.0), bytes_to_number::<f64>(b"1e1"));
+        assert_eq!(Err(Error::NotANumber), bytes_to_number::<f64>(b" 1.5"));
+        assert_eq!(Err(Error::NotANumber), bytes_to_number::<f64>(b"1.5 "));
+        assert_eq!(Err(Error::NotANumber), bytes_to_number::<f64>(b"nan"));
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut set: HashSet<Bytes> = (0..1000).map(|i| Bytes::from(i.to_string())).collect();
+        set.retain(|x| x.len() == 1);
+        let mut value = Value::Set(set);
+        assert!(value.shrink_to_fit() > 0);
+        assert_eq!(0, value.shrink_to_fit());
+        assert!(matches!(&value, Value::Set(x) if x.len() == 10));
+
+        let mut list = VecDeque::with_capacity(100);
+        list.push_back(checksum::Value::new("foo".into()));
+        let mut value = Value::List(list);
+        assert!(value.shrink_to_fit() > 0);
+
+        assert_eq!(0, Value::Blob("foo".into()).shrink_to_fit());
+    }
+
     macro_rules! serialize_deserialize {
         ($name:ty, $x:expr, $str:expr) => {
             paste! {
@@ -398,10 +696,36 @@ mod test {
         Err(Error::NotANumber)
     );
 
+    #[test]
+    fn refcount() {
+        assert_eq!(SHARED_REFCOUNT, Value::Blob("9999".into()).refcount());
+        assert_eq!(SHARED_REFCOUNT, Value::Integer(0).refcount());
+        assert_eq!(1, Value::Blob("10000".into()).refcount());
+        assert_eq!(1, Value::Blob("-1".into()).refcount());
+        assert_eq!(1, Value::Blob("007".into()).refcount());
+        assert_eq!(1, Value::List(Default::default()).refcount());
+    }
+
     #[test]
     fn debug() {
-        let x = Value::Null;
-        assert_eq!(Value::Blob("Value at:0x6000004a8840 refcount:1 encoding:embstr serializedlength:5 lru:13421257 lru_seconds_idle:367".into()), x.debug().into());
+        let x = Value::Blob("foo".into());
+        let debug = x.debug();
+        assert_eq!(&x as *const Value as usize, debug.address);
+        assert_eq!(4, debug.serialize_len);
+        assert_eq!(0, debug.lru_seconds_idle);
+
+        let debug = debug.idle(Duration::from_secs(367));
+        assert_eq!(367, debug.lru_seconds_idle);
+        assert_eq!(
+            Value::Blob(
+                format!(
+                    "Value at:{:#x} refcount:1 encoding:embstr serializedlength:4 lru:{} lru_seconds_idle:367",
+                    debug.address, debug.lru
+                )
+                .into()
+            ),
+            debug.into()
+        );
     }
 
     #[test]
@@ -422,6 +746,23 @@ mod test {
         assert_eq!(Value::Blob("test".into()), x.as_ref().into());
     }
 
+    #[test]
+    fn serialize_resp3_aggregates() {
+        let elements = vec![Value::new(b"a"), Value::Integer(1)];
+        let bytes: Vec<u8> = Value::SetReply(elements.clone()).into();
+        assert_eq!(b"~2\r\n$1\r\na\r\n:1\r\n".to_vec(), bytes);
+        let bytes: Vec<u8> = Value::MapReply(elements).into();
+        assert_eq!(b"%1\r\n$1\r\na\r\n:1\r\n".to_vec(), bytes);
+    }
+
+    #[test]
+    fn serialize_errors() {
+        let bytes: Vec<u8> = Value::Err("ERR".to_owned(), "foo\r\nbar".to_owned()).into();
+        assert_eq!(b"-ERR foo  bar\r\n".to_vec(), bytes);
+        let bytes: Vec<u8> = Value::Err("EXECABORT".to_owned(), "".to_owned()).into();
+        assert_eq!(b"-EXECABORT\r\n".to_vec(), bytes);
+    }
+
     #[test]
     fn test_is_err() {
         assert!(Value::Err("foo".to_owned(), "bar".to_owned()).is_err());
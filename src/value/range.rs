@@ -0,0 +1,105 @@
+//! # Index resolution
+//!
+//! Range commands (LINDEX, LSET, LRANGE, LTRIM, GETRANGE...) accept negative indexes, which count
+//! from the end of the sequence: -1 is the last element, -2 the penultimate and so on. The
+//! arithmetic is done with 128 bits integers, so no index (not even `i64::MIN`) can overflow.
+use std::ops::RangeInclusive;
+
+/// Resolves an index of a sequence of `len` elements into a position. None is returned if the
+/// index is out of bounds.
+pub fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let len = len as i128;
+    let index = if index < 0 {
+        index as i128 + len
+    } else {
+        index as i128
+    };
+
+    if (0..len).contains(&index) {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Resolves an inclusive range of indexes of a sequence of `len` elements, clamping it to the
+/// bounds of the sequence like Redis does. None is returned if the range is empty.
+pub fn resolve_range(start: i64, end: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let len = len as i128;
+    let resolve = |index: i64| {
+        if index < 0 {
+            index as i128 + len
+        } else {
+            index as i128
+        }
+    };
+    let start = resolve(start).max(0);
+    let end = resolve(end).min(len - 1);
+
+    if start > end || start >= len {
+        None
+    } else {
+        Some(start as usize..=end as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn index() {
+        let cases = [
+            (0, 3, Some(0)),
+            (2, 3, Some(2)),
+            (3, 3, None),
+            (-1, 3, Some(2)),
+            (-3, 3, Some(0)),
+            (-4, 3, None),
+            (0, 0, None),
+            (-1, 0, None),
+            (i64::MAX, 3, None),
+            (i64::MIN, 3, None),
+            (i64::MIN, 0, None),
+        ];
+
+        for (index, len, expected) in cases.iter() {
+            assert_eq!(expected, &resolve_index(*index, *len), "{} {}", index, len);
+        }
+    }
+
+    #[test]
+    fn range() {
+        let cases = [
+            (0, -1, 3, Some(0..=2)),
+            (0, 0, 3, Some(0..=0)),
+            (1, 1, 3, Some(1..=1)),
+            (0, 100, 3, Some(0..=2)),
+            (-100, 100, 3, Some(0..=2)),
+            (-2, -1, 3, Some(1..=2)),
+            (-1, -2, 3, None),
+            (2, 1, 3, None),
+            (3, 10, 3, None),
+            (0, -4, 3, None),
+            (0, 0, 0, None),
+            (0, -1, 0, None),
+            (-1, -1, 0, None),
+            (i64::MIN, i64::MAX, 3, Some(0..=2)),
+            (i64::MIN, i64::MIN, 3, None),
+            (i64::MAX, i64::MAX, 3, None),
+            (i64::MAX, i64::MIN, 3, None),
+            (i64::MIN, i64::MAX, 0, None),
+        ];
+
+        for (start, end, len, expected) in cases.iter() {
+            assert_eq!(
+                expected,
+                &resolve_range(*start, *end, *len),
+                "{} {} {}",
+                start,
+                end,
+                len
+            );
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! # RDB encoded length
+//!
+//! There is no RDB persistence yet, but tools parsing `DEBUG OBJECT` expect `serializedlength` to
+//! be the number of bytes the value takes in a RDB file. This module computes that length using
+//! the classic RDB encodings: strings are integer encoded when possible, and lists, sets and hashes
+//! are written as a length followed by their elements. Strings are never LZF compressed, so long
+//! compressible strings report a bigger length than Redis does.
+use super::{ParseNumber, Value};
+use std::convert::TryFrom;
+
+/// Biggest string that Redis attempts to encode as an integer
+const MAX_INTEGER_ENCODED_LEN: usize = 11;
+
+/// Returns the length of a length prefix
+fn length_len(len: usize) -> usize {
+    if len < 1 << 6 {
+        1
+    } else if len < 1 << 14 {
+        2
+    } else if u32::try_from(len).is_ok() {
+        5
+    } else {
+        9
+    }
+}
+
+/// Returns the length of an integer, if it can be integer encoded
+fn integer_len(number: i64) -> Option<usize> {
+    if i8::try_from(number).is_ok() {
+        Some(2)
+    } else if i16::try_from(number).is_ok() {
+        Some(3)
+    } else if i32::try_from(number).is_ok() {
+        Some(5)
+    } else {
+        None
+    }
+}
+
+/// Returns the length of a string. Strings which are the canonical representation of a 32 bits
+/// integer are integer encoded.
+fn string_len(bytes: &[u8]) -> usize {
+    if bytes.len() <= MAX_INTEGER_ENCODED_LEN {
+        if let Some(len) = std::str::from_utf8(bytes)
+            .ok()
+            .and_then(i64::parse_number)
+            .and_then(integer_len)
+        {
+            return len;
+        }
+    }
+    length_len(bytes.len()) + bytes.len()
+}
+
+/// Returns the length of the value encoded as RDB, without the leading type byte.
+pub fn serialized_len(value: &Value) -> usize {
+    match value {
+        Value::Blob(x) => string_len(x),
+        Value::BlobRw(x) => string_len(x),
+        Value::String(x) => string_len(x.as_bytes()),
+        Value::Integer(x) => {
+            integer_len(*x).unwrap_or_else(|| string_len(x.to_string().as_bytes()))
+        }
+        Value::List(x) => {
+            length_len(x.len()) + x.iter().map(|v| string_len(v.bytes())).sum::<usize>()
+        }
+        Value::Set(x) => length_len(x.len()) + x.iter().map(|v| string_len(v)).sum::<usize>(),
+        Value::Hash(x) => {
+            length_len(x.len())
+                + x.iter()
+                    .map(|(k, v)| string_len(k) + string_len(v))
+                    .sum::<usize>()
+        }
+        _ => Vec::<u8>::from(value).len(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::checksum;
+    use bytes::Bytes;
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    #[test]
+    fn strings() {
+        let long = "x".repeat(64);
+        let cases: [(&str, usize); 10] = [
+            ("", 1),
+            ("foo", 4),
+            ("1", 2),
+            ("-128", 2),
+            ("128", 3),
+            ("-32769", 5),
+            ("2147483648", 11),
+            ("01", 3),
+            ("+1", 3),
+            (long.as_str(), 66),
+        ];
+
+        for (input, expected) in cases.iter() {
+            assert_eq!(
+                *expected,
+                serialized_len(&Value::new(input.as_bytes())),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn aggregates() {
+        let list: VecDeque<checksum::Value> = ["foo", "1"]
+            .iter()
+            .map(|v| checksum::Value::new(Bytes::from(*v)))
+            .collect();
+        assert_eq!(1 + 4 + 2, serialized_len(&Value::List(list)));
+
+        let set: HashSet<Bytes> = ["foo"].iter().map(|v| Bytes::from(*v)).collect();
+        assert_eq!(1 + 4, serialized_len(&Value::Set(set)));
+
+        let mut hash = HashMap::new();
+        hash.insert(Bytes::from("field"), Bytes::from("100"));
+        assert_eq!(1 + 6 + 2, serialized_len(&Value::Hash(hash)));
+    }
+}
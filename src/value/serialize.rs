@@ -0,0 +1,224 @@
+//! # Value serialization
+//!
+//! Binary safe strings for the text formats the values are written to, and, behind the
+//! `value-serde` feature, serde support for [`Value`](super::Value).
+//!
+//! With the feature enabled a Value is serialized as an externally tagged enum, named after the
+//! variant (`{"Blob": "foo"}`, `{"List": ["a", "b"]}`, `"Null"`...). Bytes are strings when they
+//! are valid UTF-8, otherwise `{"hex": "<hex encoded bytes>"}`.
+use crate::error::Error;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Binary safe string: a string when the bytes are valid UTF-8, otherwise the hex encoded bytes
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BinaryString {
+    /// Valid UTF-8
+    Text(String),
+    /// Any other bytes, hex encoded
+    Binary {
+        /// Hex encoded bytes
+        hex: String,
+    },
+}
+
+impl From<&[u8]> for BinaryString {
+    fn from(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self::Text(text.to_owned()),
+            Err(_) => Self::Binary {
+                hex: hex::encode(bytes),
+            },
+        }
+    }
+}
+
+impl TryFrom<BinaryString> for Bytes {
+    type Error = Error;
+
+    fn try_from(data: BinaryString) -> Result<Self, Error> {
+        match data {
+            BinaryString::Text(text) => Ok(text.into()),
+            BinaryString::Binary { hex } => hex::decode(hex)
+                .map(Bytes::from)
+                .map_err(|_| Error::InvalidSnapshot("invalid hex string".to_owned())),
+        }
+    }
+}
+
+#[cfg(feature = "value-serde")]
+mod value {
+    use super::BinaryString;
+    use crate::{
+        error::Error,
+        value::{checksum, Value},
+    };
+    use bytes::{Bytes, BytesMut};
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+    use std::convert::{TryFrom, TryInto};
+
+    /// Serialized form of a Value
+    #[derive(Serialize, Deserialize)]
+    enum Repr {
+        Hash(Vec<(BinaryString, BinaryString)>),
+        List(Vec<BinaryString>),
+        Set(Vec<BinaryString>),
+        Array(Vec<Value>),
+        SetReply(Vec<Value>),
+        MapReply(Vec<Value>),
+        Blob(BinaryString),
+        BlobRw(BinaryString),
+        String(String),
+        Err(String, String),
+        Integer(i64),
+        Boolean(bool),
+        Float(f64),
+        BigInteger(i128),
+        Null,
+        Queued,
+        Ok,
+        Ignore,
+    }
+
+    impl TryFrom<&Value> for Repr {
+        type Error = Error;
+
+        fn try_from(value: &Value) -> Result<Self, Self::Error> {
+            Ok(match value {
+                Value::Hash(x) => Self::Hash(
+                    x.iter()
+                        .map(|(k, v)| (k.as_ref().into(), v.as_ref().into()))
+                        .collect(),
+                ),
+                Value::List(x) => Self::List(
+                    x.iter()
+                        .map(|v| match v.clone_value() {
+                            Value::Blob(v) => Ok(v.as_ref().into()),
+                            _ => Err(Error::Internal),
+                        })
+                        .collect::<Result<_, Self::Error>>()?,
+                ),
+                Value::Set(x) => Self::Set(x.iter().map(|v| v.as_ref().into()).collect()),
+                Value::Array(x) => Self::Array(x.clone()),
+                Value::SetReply(x) => Self::SetReply(x.clone()),
+                Value::MapReply(x) => Self::MapReply(x.clone()),
+                Value::Blob(x) => Self::Blob(x.as_ref().into()),
+                Value::BlobRw(x) => Self::BlobRw(x.as_ref().into()),
+                Value::String(x) => Self::String(x.clone()),
+                Value::Err(x, y) => Self::Err(x.clone(), y.clone()),
+                Value::Integer(x) => Self::Integer(*x),
+                Value::Boolean(x) => Self::Boolean(*x),
+                Value::Float(x) => Self::Float(*x),
+                Value::BigInteger(x) => Self::BigInteger(*x),
+                Value::Null => Self::Null,
+                Value::Queued => Self::Queued,
+                Value::Ok => Self::Ok,
+                Value::Ignore => Self::Ignore,
+            })
+        }
+    }
+
+    impl TryFrom<Repr> for Value {
+        type Error = Error;
+
+        fn try_from(repr: Repr) -> Result<Self, Self::Error> {
+            Ok(match repr {
+                Repr::Hash(x) => Value::Hash(
+                    x.into_iter()
+                        .map(|(k, v)| Ok((k.try_into()?, v.try_into()?)))
+                        .collect::<Result<_, Self::Error>>()?,
+                ),
+                Repr::List(x) => Value::List(
+                    x.into_iter()
+                        .map(|v| Ok(checksum::Value::new(v.try_into()?)))
+                        .collect::<Result<_, Self::Error>>()?,
+                ),
+                Repr::Set(x) => Value::Set(x.into_iter().map(Bytes::try_from).collect::<Result<
+                    _,
+                    Self::Error,
+                >>(
+                )?),
+                Repr::Array(x) => Value::Array(x),
+                Repr::SetReply(x) => Value::SetReply(x),
+                Repr::MapReply(x) => Value::MapReply(x),
+                Repr::Blob(x) => Value::Blob(x.try_into()?),
+                Repr::BlobRw(x) => Value::BlobRw(BytesMut::from(&Bytes::try_from(x)?[..])),
+                Repr::String(x) => Value::String(x),
+                Repr::Err(x, y) => Value::Err(x, y),
+                Repr::Integer(x) => Value::Integer(x),
+                Repr::Boolean(x) => Value::Boolean(x),
+                Repr::Float(x) => Value::Float(x),
+                Repr::BigInteger(x) => Value::BigInteger(x),
+                Repr::Null => Value::Null,
+                Repr::Queued => Value::Queued,
+                Repr::Ok => Value::Ok,
+                Repr::Ignore => Value::Ignore,
+            })
+        }
+    }
+
+    impl Serialize for Value {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            Repr::try_from(self)
+                .map_err(ser::Error::custom)?
+                .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Value {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Repr::deserialize(deserializer)?
+                .try_into()
+                .map_err(de::Error::custom)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let values = vec![
+                Value::Hash(vec![("f".into(), "v".into())].into_iter().collect()),
+                Value::List(vec![checksum::Value::new("a".into())].into_iter().collect()),
+                Value::Set(vec![Bytes::from("x")].into_iter().collect()),
+                Value::Array(vec![Value::Integer(1), Value::Null]),
+                Value::SetReply(vec![Value::Integer(1)]),
+                Value::MapReply(vec![Value::new(b"f"), Value::Integer(1)]),
+                Value::Blob(Bytes::from(&b"\xff\x00"[..])),
+                Value::BlobRw("rw".into()),
+                Value::String("str".into()),
+                Value::Err("ERR".into(), "message".into()),
+                Value::Integer(-1),
+                Value::Boolean(true),
+                Value::Float(1.5),
+                Value::BigInteger(i128::MAX),
+                Value::Null,
+                Value::Queued,
+                Value::Ok,
+                Value::Ignore,
+            ];
+            for value in values.into_iter() {
+                let json = serde_json::to_string(&value).unwrap();
+                assert_eq!(value, serde_json::from_str(&json).unwrap(), "{}", json);
+            }
+        }
+
+        #[test]
+        fn format() {
+            assert_eq!(
+                r#"{"Array":[{"Blob":"foo"},{"Blob":{"hex":"ff"}},{"Integer":1},"Null"]}"#,
+                serde_json::to_string(&Value::Array(vec![
+                    Value::Blob("foo".into()),
+                    Value::Blob(Bytes::from(&b"\xff"[..])),
+                    Value::Integer(1),
+                    Value::Null,
+                ]))
+                .unwrap()
+            );
+        }
+    }
+}
@@ -1,26 +1,96 @@
 //! # Value Type mod
 //!
 use crate::value::Value;
-use std::str::FromStr;
-use strum_macros::{Display, EnumString};
+use parking_lot::{const_rwlock, RwLock};
+use std::{fmt, str::FromStr};
+
+/// Types registered by modules. Their names are leaked since a type is never unregistered.
+static MODULE_TYPES: RwLock<Vec<&'static str>> = const_rwlock(Vec::new());
 
 /// Value Type
-#[derive(EnumString, Display, PartialEq, Copy, Clone)]
+///
+/// The name of each type is the one used by TYPE and SCAN TYPE.
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ValueTyp {
     /// Set
-    #[strum(ascii_case_insensitive)]
     Set,
     /// Hash
-    #[strum(ascii_case_insensitive)]
     Hash,
     /// List
-    #[strum(ascii_case_insensitive)]
     List,
+    /// Sorted set
+    ZSet,
+    /// Stream
+    Stream,
+    /// A type registered by a module
+    Module(&'static str),
     /// Fallback
-    #[strum(ascii_case_insensitive)]
     String,
 }
 
+impl ValueTyp {
+    /// Registers a new module type, or returns the existing one if it was registered already.
+    /// Type names are case-insensitive.
+    pub fn register_module_type(name: &str) -> Self {
+        if let Ok(typ) = name.parse() {
+            return typ;
+        }
+        let mut types = MODULE_TYPES.write();
+        // check again, it may have been registered meanwhile
+        if let Some(name) = types.iter().find(|typ| typ.eq_ignore_ascii_case(name)) {
+            return Self::Module(name);
+        }
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        types.push(name);
+        Self::Module(name)
+    }
+
+    /// Returns the name of the type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Set => "set",
+            Self::Hash => "hash",
+            Self::List => "list",
+            Self::ZSet => "zset",
+            Self::Stream => "stream",
+            Self::Module(name) => name,
+            Self::String => "string",
+        }
+    }
+}
+
+impl fmt::Display for ValueTyp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ValueTyp {
+    type Err = strum::ParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Set,
+            Self::Hash,
+            Self::List,
+            Self::ZSet,
+            Self::Stream,
+            Self::String,
+        ]
+        .iter()
+        .find(|typ| typ.name().eq_ignore_ascii_case(input))
+        .copied()
+        .or_else(|| {
+            MODULE_TYPES
+                .read()
+                .iter()
+                .find(|name| name.eq_ignore_ascii_case(input))
+                .map(|name| Self::Module(name))
+        })
+        .ok_or(strum::ParseError::VariantNotFound)
+    }
+}
+
 /// Type
 //
 // The type is a filter to query the database for a values with certain types. The type can be negated
@@ -78,4 +148,26 @@ mod test {
         let t = Typ::from_str("set").unwrap();
         assert!(!t.is_negated());
     }
+
+    #[test]
+    fn type_names() {
+        for name in ["string", "list", "set", "hash", "zset", "stream"] {
+            assert_eq!(name, ValueTyp::from_str(name).unwrap().to_string());
+        }
+        assert_eq!(Ok(ValueTyp::ZSet), ValueTyp::from_str("ZSET"));
+        assert!(ValueTyp::from_str("foo").is_err());
+    }
+
+    #[test]
+    fn module_types() {
+        assert!(Typ::from_str("mytype-01").is_err());
+        let typ = ValueTyp::register_module_type("MyType-01");
+        assert_eq!("MyType-01", typ.name());
+        assert_eq!(typ, ValueTyp::register_module_type("mytype-01"));
+        assert_eq!(Ok(typ), ValueTyp::from_str("mytype-01"));
+        assert_eq!(ValueTyp::Hash, ValueTyp::register_module_type("hash"));
+
+        let filter = Typ::from_str("!mytype-01").unwrap();
+        assert!(filter.check_type(&Value::Hash(Default::default())));
+    }
 }
@@ -135,7 +135,7 @@ start_server {
     } {3}
 
     test "SINTERCARD against non-existing key" {
-        assert_equal 0 [r sintercard non-existing-key]
+        assert_equal 0 [r sintercard 1 non-existing-key]
     }
 
     foreach {type} {hashtable intset} {
@@ -178,7 +178,7 @@ start_server {
         }
 
         test "SINTERCARD with two sets - $type" {
-            assert_equal 6 [r sintercard set1{t} set2{t}]
+            assert_equal 6 [r sintercard 2 set1{t} set2{t}]
         }
 
         test "SINTERSTORE with two sets - $type" {
@@ -211,7 +211,7 @@ start_server {
         }
 
         test "SINTERCARD against three sets - $type" {
-            assert_equal 3 [r sintercard set1{t} set2{t} set3{t}]
+            assert_equal 3 [r sintercard 3 set1{t} set2{t} set3{t}]
         }
 
         test "SINTERSTORE with three sets - $type" {